@@ -0,0 +1,441 @@
+// Admin API
+// A small HTTP/1.1 server with JSON endpoints for the peer table, one request per connection
+//   GET    /peers        every known peer
+//   POST   /peers        add a static peer, body as a [[peers]] entry of the config file
+//   GET    /peers/{id}   one peer by node id, or by ip:port
+//   DELETE /peers/{id}   forget a peer by node id, or by ip:port, and stop probing it
+//   POST   /probe        probe {"address": "ip:port"} or {"node_id": "..."} now instead of waiting for its next tick
+//   GET    /self         this node
+// Meant for operators on a trusted network, there is no authentication
+
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use log::{info, warn};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use uuid::Uuid;
+
+use crate::channel::MeteredSender;
+use crate::config::PeerSection;
+use crate::control::{ControlRequest, ControlResponse, PeerControl};
+use crate::health_check_network_broker::{HealthCheckNetworkBrokerMessage, LiveSettings};
+use crate::health_check_scheduler::{InFlightProbes, probe};
+use crate::network::{NetworkDetails, NetworkDetailsStore};
+
+const MAX_HEADER_BYTES: usize = 8 * 1024;
+const MAX_BODY_BYTES: usize = 64 * 1024;
+/**
+A client that stalls mid request is dropped after this long, so it can't hold up the next one.
+ */
+pub const CONNECTION_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct HttpRequest {
+    pub method: String,
+    /**
+    Without the query string.
+    */
+    pub path: String,
+    pub body: Vec<u8>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct HttpResponse {
+    pub status: u16,
+    pub body: Value,
+}
+
+impl HttpResponse {
+    fn new(status: u16, body: Value) -> HttpResponse {
+        HttpResponse { status, body }
+    }
+
+    fn error(status: u16, message: impl Into<String>) -> HttpResponse {
+        HttpResponse::new(status, json!({ "error": message.into() }))
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ProbeRequest {
+    address: Option<SocketAddr>,
+    node_id: Option<Uuid>,
+}
+
+pub struct AdminApi {
+    listener: TcpListener,
+    node_id: Uuid,
+    /**
+    Address the health check socket is bound to.
+    */
+    bind_addr: SocketAddr,
+    started_at: Instant,
+    request_sender: MeteredSender<HealthCheckNetworkBrokerMessage>,
+    network_details_store: Arc<NetworkDetailsStore>,
+    in_flight_probes: Arc<InFlightProbes>,
+    live_settings: LiveSettings,
+    peer_control: PeerControl,
+}
+
+impl AdminApi {
+    pub fn bind(listen_addr: SocketAddr,
+                node_id: Uuid,
+                bind_addr: SocketAddr,
+                request_sender: MeteredSender<HealthCheckNetworkBrokerMessage>,
+                network_details_store: Arc<NetworkDetailsStore>,
+                in_flight_probes: Arc<InFlightProbes>,
+                live_settings: LiveSettings) -> io::Result<AdminApi> {
+        Ok(AdminApi {
+            listener: TcpListener::bind(listen_addr)?,
+            node_id,
+            bind_addr,
+            started_at: Instant::now(),
+            request_sender,
+            peer_control: PeerControl::new(live_settings.static_peers.clone(), network_details_store.clone()),
+            network_details_store,
+            in_flight_probes,
+            live_settings,
+        })
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.listener.local_addr()
+    }
+
+    pub fn run(self) {
+        info!("Admin API listening on {}", self.listener.local_addr().map(|addr| addr.to_string()).unwrap_or_default());
+        for stream in self.listener.incoming() {
+            let result = stream.and_then(|stream| self.serve_connection(stream));
+            if let Err(err) = result {
+                warn!("Admin API connection failed: {}", err);
+            }
+        }
+    }
+
+    fn serve_connection(&self, stream: TcpStream) -> io::Result<()> {
+        stream.set_read_timeout(Some(CONNECTION_TIMEOUT))?;
+        stream.set_write_timeout(Some(CONNECTION_TIMEOUT))?;
+        let mut writer = stream.try_clone()?;
+        let response = match read_request(&mut BufReader::new(stream)) {
+            Ok(request) => self.route(&request),
+            Err(err) if err.kind() == io::ErrorKind::InvalidData => HttpResponse::error(400, err.to_string()),
+            Err(err) => return Err(err)
+        };
+        write_response(&mut writer, &response)
+    }
+
+    pub fn route(&self, request: &HttpRequest) -> HttpResponse {
+        let segments: Vec<&str> = request.path.split('/').filter(|segment| !segment.is_empty()).collect();
+        match (request.method.as_str(), segments.as_slice()) {
+            ("GET", ["peers"]) => HttpResponse::new(200, json!(self.network_details_store.get_all_network_details())),
+            ("POST", ["peers"]) => self.add_peer(&request.body),
+            ("GET", ["peers", id]) => match self.find_peer(id) {
+                Ok(record) => HttpResponse::new(200, json!(record)),
+                Err(response) => response
+            },
+            ("DELETE", ["peers", id]) => self.remove_peer(id),
+            ("POST", ["probe"]) => self.probe(&request.body),
+            ("GET", ["self"]) => self.describe_self(),
+            (_, ["peers"] | ["peers", _] | ["probe"] | ["self"]) => HttpResponse::error(405, format!("{} is not allowed on {}", request.method, request.path)),
+            _ => HttpResponse::error(404, format!("No such endpoint {}", request.path))
+        }
+    }
+
+    fn add_peer(&self, body: &[u8]) -> HttpResponse {
+        let peer: PeerSection = match serde_json::from_slice(body) {
+            Ok(peer) => peer,
+            Err(err) => return HttpResponse::error(400, format!("Invalid peer: {}", err))
+        };
+        let health_check_defaults = self.live_settings.health_check_defaults.read().unwrap().clone();
+        let errors = peer.validate("peer", health_check_defaults.probe_interval, health_check_defaults.probe_timeout);
+        if !errors.is_empty() {
+            return HttpResponse::new(400, json!({ "error": "Invalid peer", "errors": errors }));
+        }
+        match self.peer_control.handle(ControlRequest::AddPeer(peer.static_peer())) {
+            ControlResponse::PeerAdded { addr, new } => HttpResponse::new(if new { 201 } else { 200 }, json!({ "address": addr, "new": new })),
+            response => HttpResponse::error(500, format!("Unexpected response {:?}", response))
+        }
+    }
+
+    fn remove_peer(&self, id: &str) -> HttpResponse {
+        // Peers that never acked have no record yet, but can still be removed by address
+        let addr = match (self.find_peer(id), id.parse::<SocketAddr>()) {
+            (Ok(record), _) => record.socket_addr(),
+            (Err(_), Ok(addr)) => addr,
+            (Err(response), Err(_)) => return response
+        };
+        match self.peer_control.handle(ControlRequest::RemovePeer { addr }) {
+            ControlResponse::PeerRemoved { addr } => HttpResponse::new(200, json!({ "removed": addr })),
+            ControlResponse::Error(message) => HttpResponse::error(404, message),
+            response => HttpResponse::error(500, format!("Unexpected response {:?}", response))
+        }
+    }
+
+    fn probe(&self, body: &[u8]) -> HttpResponse {
+        let probe_request: ProbeRequest = match serde_json::from_slice(body) {
+            Ok(probe_request) => probe_request,
+            Err(err) => return HttpResponse::error(400, format!("Invalid probe request: {}", err))
+        };
+        let (target, node_id) = match (probe_request.address, probe_request.node_id) {
+            (Some(address), None) => {
+                let node_id = self.network_details_store.get_network_details_by_socket_addr(&address).ok().map(|record| record.node_id);
+                (address, node_id)
+            }
+            (None, Some(node_id)) => match self.network_details_store.get_network_details_by_node_id(&node_id) {
+                Ok(record) => (record.socket_addr(), Some(node_id)),
+                Err(_) => return HttpResponse::error(404, format!("Unknown peer {}", node_id))
+            },
+            _ => return HttpResponse::error(400, "Expected exactly one of address or node_id")
+        };
+
+        let health_check_defaults = self.live_settings.health_check_defaults.read().unwrap().clone();
+        let timeout = node_id.and_then(|node_id| self.network_details_store.get_network_details_by_node_id(&node_id).ok())
+            .map(|record| record.health_check.configuration.effective(&health_check_defaults).probe_timeout)
+            .unwrap_or(health_check_defaults.probe_timeout);
+        // The result lands in the peer's record like any scheduled probe, so it shows up in GET /peers/{id}
+        probe(&self.request_sender, &self.in_flight_probes, target, node_id, timeout, Instant::now());
+        HttpResponse::new(202, json!({ "address": target, "node_id": node_id, "timeout_ms": timeout.as_millis() as u64 }))
+    }
+
+    fn describe_self(&self) -> HttpResponse {
+        HttpResponse::new(200, json!({
+            "node_id": self.node_id,
+            "address": self.bind_addr,
+            "uptime_secs": self.started_at.elapsed().as_secs(),
+            "peers": self.network_details_store.get_all_network_details().len(),
+            "hosts": self.network_details_store.get_all_host_health().len(),
+            "static_peers": self.live_settings.static_peers.list().len(),
+            "probes_in_flight": self.in_flight_probes.count(),
+        }))
+    }
+
+    /**
+    Looks a peer up by node id or ip:port, Err holds the response to send back when it isn't found.
+    */
+    fn find_peer(&self, id: &str) -> Result<NetworkDetails, HttpResponse> {
+        let record = if let Ok(node_id) = id.parse::<Uuid>() {
+            self.network_details_store.get_network_details_by_node_id(&node_id)
+        } else if let Ok(addr) = id.parse::<SocketAddr>() {
+            self.network_details_store.get_network_details_by_socket_addr(&addr)
+        } else {
+            return Err(HttpResponse::error(400, format!("{} is neither a node id nor an ip:port address", id)));
+        };
+        record.map_err(|_| HttpResponse::error(404, format!("Unknown peer {}", id)))
+    }
+}
+
+/**
+Reads the request line, the headers and a body of Content-Length bytes. Malformed or oversized requests fail with `InvalidData`.
+ */
+pub fn read_request(reader: &mut impl BufRead) -> io::Result<HttpRequest> {
+    let invalid = |message: String| io::Error::new(io::ErrorKind::InvalidData, message);
+    let mut header_bytes = 0;
+    let mut read_line = |reader: &mut dyn BufRead| -> io::Result<String> {
+        let mut line = String::new();
+        header_bytes += (&mut *reader).take((MAX_HEADER_BYTES - header_bytes) as u64).read_line(&mut line)?;
+        if !line.ends_with('\n') {
+            return Err(invalid(String::from("Request headers too large or cut short")));
+        }
+        Ok(line.trim_end().to_string())
+    };
+
+    let request_line = read_line(reader)?;
+    let mut parts = request_line.split_whitespace();
+    let (Some(method), Some(target), Some(version)) = (parts.next(), parts.next(), parts.next()) else {
+        return Err(invalid(format!("Malformed request line [{}]", request_line)));
+    };
+    if !version.starts_with("HTTP/1.") {
+        return Err(invalid(format!("Unsupported HTTP version {}", version)));
+    }
+    let method = method.to_string();
+    let path = target.split('?').next().unwrap_or_default().to_string();
+
+    let mut content_length = 0;
+    loop {
+        let header = read_line(reader)?;
+        if header.is_empty() {
+            break;
+        }
+        let Some((name, value)) = header.split_once(':') else {
+            return Err(invalid(format!("Malformed header [{}]", header)));
+        };
+        if name.trim().eq_ignore_ascii_case("content-length") {
+            content_length = value.trim().parse().map_err(|_| invalid(format!("Invalid Content-Length [{}]", value.trim())))?;
+        }
+    }
+    if content_length > MAX_BODY_BYTES {
+        return Err(invalid(format!("Body of {} bytes is over the {} byte limit", content_length, MAX_BODY_BYTES)));
+    }
+    let mut body = vec![0; content_length];
+    reader.read_exact(&mut body)?;
+    Ok(HttpRequest { method, path, body })
+}
+
+fn write_response(writer: &mut impl Write, response: &HttpResponse) -> io::Result<()> {
+    let body = serde_json::to_vec_pretty(&response.body)?;
+    write_http_response(writer, response.status, "application/json", &body)
+}
+
+/**
+Writes a complete response and asks the client to close, shared with the metrics endpoint.
+ */
+pub fn write_http_response(writer: &mut impl Write, status: u16, content_type: &str, body: &[u8]) -> io::Result<()> {
+    write!(writer, "HTTP/1.1 {} {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+           status, reason_phrase(status), content_type, body.len())?;
+    writer.write_all(body)?;
+    writer.flush()
+}
+
+fn reason_phrase(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        201 => "Created",
+        202 => "Accepted",
+        400 => "Bad Request",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        _ => "Internal Server Error"
+    }
+}
+
+#[cfg(test)]
+mod admin_api_tests {
+    use std::io::{BufReader, Read, Write};
+    use std::net::{IpAddr, SocketAddr, TcpStream};
+    use std::sync::{Arc, RwLock};
+    use std::thread;
+    use std::time::UNIX_EPOCH;
+    use serde_json::{json, Value};
+    use uuid::Uuid;
+    use crate::admin_api::{AdminApi, HttpRequest, read_request};
+    use crate::channel::{metered_channel, MeteredReceiver};
+    use crate::health_check_network_broker::{HealthCheckNetworkBrokerMessage, LiveSettings};
+    use crate::health_check_scheduler::{InFlightProbes, StaticPeers};
+    use crate::health_history::HealthHistory;
+    use crate::network::{HealthCheck, HealthCheckConfiguration, HealthCheckDefaults, HealthStatus, HealthStatusDetails, IP, NetworkDetails, NetworkDetailsStore};
+    use crate::reaper::ReaperConfiguration;
+
+    fn dummy_record(port: u16) -> NetworkDetails {
+        NetworkDetails {
+            node_id: Uuid::new_v4(),
+            addr: IpAddr::V4(IP),
+            last_seen: UNIX_EPOCH,
+            status_since: UNIX_EPOCH,
+            stale: false,
+            health_history: HealthHistory::default(),
+            health_check: HealthCheck {
+                status_details: HealthStatusDetails {
+                    current_status: HealthStatus::Healthy,
+                    lives_remaining: 3,
+                },
+                configuration: HealthCheckConfiguration {
+                    health_check_port: port,
+                    ..HealthCheckConfiguration::default()
+                }
+            }
+        }
+    }
+
+    fn admin_api(store: Arc<NetworkDetailsStore>) -> (AdminApi, MeteredReceiver<HealthCheckNetworkBrokerMessage>) {
+        let (sender, receiver) = metered_channel();
+        let live_settings = LiveSettings {
+            health_check_defaults: Arc::new(RwLock::new(HealthCheckDefaults::default())),
+            static_peers: Arc::new(StaticPeers::new(Vec::new())),
+            reaper_configuration: Arc::new(RwLock::new(ReaperConfiguration::default())),
+        };
+        let admin_api = AdminApi::bind("127.0.0.1:0".parse().unwrap(),
+                                       Uuid::new_v4(),
+                                       SocketAddr::new(IpAddr::V4(IP), 3450),
+                                       sender,
+                                       store,
+                                       Arc::new(InFlightProbes::new()),
+                                       live_settings).unwrap();
+        (admin_api, receiver)
+    }
+
+    fn request(method: &str, path: &str, body: Value) -> HttpRequest {
+        HttpRequest {
+            method: method.to_string(),
+            path: path.to_string(),
+            body: if body.is_null() { Vec::new() } else { serde_json::to_vec(&body).unwrap() },
+        }
+    }
+
+    #[test]
+    fn requests_are_parsed_with_their_body() {
+        let raw = "POST /peers?verbose=1 HTTP/1.1\r\nHost: localhost\r\ncontent-length: 2\r\n\r\n{}";
+        let parsed = read_request(&mut BufReader::new(raw.as_bytes())).unwrap();
+        assert_eq!(HttpRequest { method: String::from("POST"), path: String::from("/peers"), body: b"{}".to_vec() }, parsed);
+
+        for malformed in ["GARBAGE\r\n\r\n", "GET / SPDY/3\r\n\r\n", "GET / HTTP/1.1\r\nContent-Length: 1000000\r\n\r\n", "GET / HTTP/1.1\r\nHost"] {
+            assert!(read_request(&mut BufReader::new(malformed.as_bytes())).is_err(), "We expect [{}] to be rejected", malformed);
+        }
+    }
+
+    #[test]
+    fn peers_are_listed_added_and_removed() {
+        let store = Arc::new(NetworkDetailsStore::new());
+        let known = dummy_record(3451);
+        store.put_network_details(&known);
+        let (admin_api, _receiver) = admin_api(store.clone());
+
+        let response = admin_api.route(&request("GET", "/peers", Value::Null));
+        assert_eq!(200, response.status);
+        assert_eq!(1, response.body.as_array().unwrap().len());
+        assert_eq!(200, admin_api.route(&request("GET", &format!("/peers/{}", known.node_id), Value::Null)).status);
+        assert_eq!(200, admin_api.route(&request("GET", "/peers/127.0.0.1:3451", Value::Null)).status);
+        assert_eq!(404, admin_api.route(&request("GET", &format!("/peers/{}", Uuid::new_v4()), Value::Null)).status);
+        assert_eq!(400, admin_api.route(&request("GET", "/peers/somebody", Value::Null)).status);
+
+        let response = admin_api.route(&request("POST", "/peers", json!({ "address": "127.0.0.1:3452", "tags": ["eu"], "priority": 4 })));
+        assert_eq!(201, response.status, "We expect a new peer to be created, got {:?}", response);
+        assert_eq!(Some(4), admin_api.live_settings.static_peers.get(&"127.0.0.1:3452".parse().unwrap()).unwrap().priority);
+        let response = admin_api.route(&request("POST", "/peers", json!({ "address": "127.0.0.1:3452", "max_lives": 0 })));
+        assert_eq!(400, response.status);
+        assert_eq!(json!(["peer.max_lives must be at least 1"]), response.body["errors"]);
+
+        // Not acked yet, so only known by address
+        assert_eq!(200, admin_api.route(&request("DELETE", "/peers/127.0.0.1:3452", Value::Null)).status);
+        assert_eq!(200, admin_api.route(&request("DELETE", &format!("/peers/{}", known.node_id), Value::Null)).status);
+        assert!(store.get_all_network_details().is_empty());
+        assert_eq!(404, admin_api.route(&request("DELETE", "/peers/127.0.0.1:3452", Value::Null)).status);
+
+        assert_eq!(405, admin_api.route(&request("PUT", "/peers", Value::Null)).status);
+        assert_eq!(404, admin_api.route(&request("GET", "/metrics", Value::Null)).status);
+    }
+
+    #[test]
+    fn probe_sends_a_syn_through_the_broker() {
+        let store = Arc::new(NetworkDetailsStore::new());
+        let (admin_api, receiver) = admin_api(store);
+
+        let response = admin_api.route(&request("POST", "/probe", json!({ "address": "127.0.0.1:3459" })));
+        assert_eq!(202, response.status);
+        assert_eq!("127.0.0.1:3459".parse::<SocketAddr>().unwrap(), receiver.try_recv().unwrap().remote_addr);
+        assert_eq!(1, admin_api.in_flight_probes.count(), "We expect the probe to be tracked so its ack records the rtt");
+
+        assert_eq!(404, admin_api.route(&request("POST", "/probe", json!({ "node_id": Uuid::new_v4() }))).status);
+        assert_eq!(400, admin_api.route(&request("POST", "/probe", json!({}))).status);
+    }
+
+    #[test]
+    fn self_is_served_over_http() {
+        let (admin_api, _receiver) = admin_api(Arc::new(NetworkDetailsStore::new()));
+        let addr = admin_api.local_addr().unwrap();
+        let node_id = admin_api.node_id;
+        thread::spawn(move || admin_api.run());
+
+        let mut stream = TcpStream::connect(addr).unwrap();
+        stream.write_all(b"GET /self HTTP/1.1\r\nHost: localhost\r\n\r\n").unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "Got {}", response);
+        let (_, body) = response.split_once("\r\n\r\n").unwrap();
+        let body: Value = serde_json::from_str(body).unwrap();
+        assert_eq!(json!(node_id), body["node_id"]);
+        assert_eq!(json!("127.0.0.1:3450"), body["address"]);
+    }
+}
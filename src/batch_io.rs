@@ -0,0 +1,362 @@
+// Batched UDP I/O
+// The broker reads and writes up to BATCH_SIZE datagrams per syscall with recvmmsg and sendmmsg on Linux,
+// elsewhere it falls back to one recv_from or send_to per datagram behind the same API
+// Datagram buffers are allocated once and reused for every batch
+
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, UdpSocket};
+use std::time::Duration;
+use crate::health_check::HEALTH_CHECK_PACKET_SIZE;
+
+pub const BATCH_SIZE: usize = 64;
+
+pub const RECEIVE_ERROR_BACKOFF_MIN: Duration = Duration::from_millis(10);
+pub const RECEIVE_ERROR_BACKOFF_MAX: Duration = Duration::from_secs(1);
+
+/**
+One byte bigger than a packet, so oversized datagrams show up as such instead of being cut to size.
+ */
+pub const DATAGRAM_BUFFER_SIZE: usize = HEALTH_CHECK_PACKET_SIZE + 1;
+
+#[derive(Clone, Debug)]
+pub struct Datagram {
+    pub buf: [u8; DATAGRAM_BUFFER_SIZE],
+    pub len: usize,
+    pub addr: SocketAddr,
+}
+
+impl Default for Datagram {
+    fn default() -> Datagram {
+        Datagram {
+            buf: [0; DATAGRAM_BUFFER_SIZE],
+            len: 0,
+            addr: SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), 0),
+        }
+    }
+}
+
+impl Datagram {
+    pub fn payload(&self) -> &[u8] {
+        &self.buf[..self.len]
+    }
+}
+
+/**
+Receives datagrams into buffers it keeps across calls.
+ */
+pub struct BatchReceiver {
+    datagrams: Vec<Datagram>,
+}
+
+impl Default for BatchReceiver {
+    fn default() -> BatchReceiver {
+        BatchReceiver {
+            datagrams: vec![Datagram::default(); BATCH_SIZE],
+        }
+    }
+}
+
+impl BatchReceiver {
+    /**
+    Waits for a datagram, then takes the ones already waiting behind it without blocking, at most BATCH_SIZE.
+    The datagrams are valid until the next call.
+    */
+    pub fn recv(&mut self, socket: &UdpSocket) -> io::Result<&[Datagram]> {
+        loop {
+            match sys::recv_batch(socket, &mut self.datagrams) {
+                Ok(received) => return Ok(&self.datagrams[..received]),
+                // A signal, like the SIGHUP that reloads the config, landed before any datagram
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => {}
+                Err(err) => return Err(err)
+            }
+        }
+    }
+}
+
+/**
+How long to hold off receiving after errors, so a socket that keeps failing doesn't spin its reader and flood the log.
+ */
+#[derive(Debug, Default)]
+pub struct ReceiveErrorBackoff {
+    consecutive_errors: u32,
+}
+
+impl ReceiveErrorBackoff {
+    /**
+    Records a failed receive, returns the wait before the next one, doubling with every error in a row up to the max.
+    */
+    pub fn failed(&mut self) -> Duration {
+        let backoff = RECEIVE_ERROR_BACKOFF_MIN.saturating_mul(1 << self.consecutive_errors.min(16)).min(RECEIVE_ERROR_BACKOFF_MAX);
+        self.consecutive_errors = self.consecutive_errors.saturating_add(1);
+        backoff
+    }
+
+    pub fn succeeded(&mut self) {
+        self.consecutive_errors = 0;
+    }
+}
+
+/**
+Sends every datagram, returns the index and error of the ones that could not be sent.
+ */
+pub fn send_batch(socket: &UdpSocket, datagrams: &[Datagram]) -> Vec<(usize, io::Error)> {
+    let mut failed = Vec::new();
+    let mut next = 0;
+    while next < datagrams.len() {
+        match sys::send_batch(socket, &datagrams[next..]) {
+            Ok(sent) => next += sent,
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => {}
+            // The error belongs to the first datagram of the batch, the rest still get their turn
+            Err(err) => {
+                failed.push((next, err));
+                next += 1;
+            }
+        }
+    }
+    failed
+}
+
+#[cfg(target_os = "linux")]
+mod sys {
+    use std::io;
+    use std::mem::{self, MaybeUninit};
+    use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6, UdpSocket};
+    use std::os::fd::AsRawFd;
+    use std::ptr;
+    use crate::batch_io::{BATCH_SIZE, Datagram};
+
+    pub fn recv_batch(socket: &UdpSocket, datagrams: &mut [Datagram]) -> io::Result<usize> {
+        let count = datagrams.len().min(BATCH_SIZE);
+        // Zeroed rather than uninitialized, the kernel only writes the fields it has something for
+        let mut addresses: [libc::sockaddr_storage; BATCH_SIZE] = unsafe { mem::zeroed() };
+        let mut iovecs: [libc::iovec; BATCH_SIZE] = unsafe { mem::zeroed() };
+        let mut headers: [libc::mmsghdr; BATCH_SIZE] = unsafe { mem::zeroed() };
+        for i in 0..count {
+            iovecs[i] = libc::iovec {
+                iov_base: datagrams[i].buf.as_mut_ptr() as *mut libc::c_void,
+                iov_len: datagrams[i].buf.len(),
+            };
+            headers[i].msg_hdr.msg_name = &mut addresses[i] as *mut libc::sockaddr_storage as *mut libc::c_void;
+            headers[i].msg_hdr.msg_namelen = mem::size_of::<libc::sockaddr_storage>() as libc::socklen_t;
+            headers[i].msg_hdr.msg_iov = &mut iovecs[i];
+            headers[i].msg_hdr.msg_iovlen = 1;
+        }
+        // MSG_WAITFORONE blocks for the first datagram only
+        let received = unsafe {
+            libc::recvmmsg(socket.as_raw_fd(), headers.as_mut_ptr(), count as libc::c_uint, libc::MSG_WAITFORONE, ptr::null_mut())
+        };
+        if received < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let received = received as usize;
+        for i in 0..received {
+            // Datagrams bigger than the buffer are cut to it and flagged, their length is reported as the buffer's
+            datagrams[i].len = (headers[i].msg_len as usize).min(datagrams[i].buf.len());
+            datagrams[i].addr = socket_addr(&addresses[i])?;
+        }
+        Ok(received)
+    }
+
+    pub fn send_batch(socket: &UdpSocket, datagrams: &[Datagram]) -> io::Result<usize> {
+        let count = datagrams.len().min(BATCH_SIZE);
+        let mut addresses: [libc::sockaddr_storage; BATCH_SIZE] = unsafe { mem::zeroed() };
+        let mut iovecs: [libc::iovec; BATCH_SIZE] = unsafe { mem::zeroed() };
+        let mut headers: [libc::mmsghdr; BATCH_SIZE] = unsafe { mem::zeroed() };
+        for i in 0..count {
+            let address_len = write_socket_addr(&datagrams[i].addr, &mut addresses[i]);
+            iovecs[i] = libc::iovec {
+                // sendmmsg only reads the buffer
+                iov_base: datagrams[i].buf.as_ptr() as *mut libc::c_void,
+                iov_len: datagrams[i].len,
+            };
+            headers[i].msg_hdr.msg_name = &mut addresses[i] as *mut libc::sockaddr_storage as *mut libc::c_void;
+            headers[i].msg_hdr.msg_namelen = address_len;
+            headers[i].msg_hdr.msg_iov = &mut iovecs[i];
+            headers[i].msg_hdr.msg_iovlen = 1;
+        }
+        let sent = unsafe { libc::sendmmsg(socket.as_raw_fd(), headers.as_mut_ptr(), count as libc::c_uint, 0) };
+        if sent < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(sent as usize)
+    }
+
+    fn socket_addr(storage: &libc::sockaddr_storage) -> io::Result<SocketAddr> {
+        match storage.ss_family as libc::c_int {
+            libc::AF_INET => {
+                let address = unsafe { &*(storage as *const libc::sockaddr_storage as *const libc::sockaddr_in) };
+                Ok(SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::from(u32::from_be(address.sin_addr.s_addr)), u16::from_be(address.sin_port))))
+            }
+            libc::AF_INET6 => {
+                let address = unsafe { &*(storage as *const libc::sockaddr_storage as *const libc::sockaddr_in6) };
+                Ok(SocketAddr::V6(SocketAddrV6::new(Ipv6Addr::from(address.sin6_addr.s6_addr),
+                                                    u16::from_be(address.sin6_port),
+                                                    address.sin6_flowinfo,
+                                                    address.sin6_scope_id)))
+            }
+            family => Err(io::Error::new(io::ErrorKind::InvalidData, format!("Unexpected address family {}", family)))
+        }
+    }
+
+    fn write_socket_addr(addr: &SocketAddr, storage: &mut libc::sockaddr_storage) -> libc::socklen_t {
+        match addr {
+            SocketAddr::V4(addr) => {
+                let mut address: libc::sockaddr_in = unsafe { MaybeUninit::zeroed().assume_init() };
+                address.sin_family = libc::AF_INET as libc::sa_family_t;
+                address.sin_port = addr.port().to_be();
+                address.sin_addr.s_addr = u32::from(*addr.ip()).to_be();
+                unsafe { ptr::write(storage as *mut libc::sockaddr_storage as *mut libc::sockaddr_in, address) };
+                mem::size_of::<libc::sockaddr_in>() as libc::socklen_t
+            }
+            SocketAddr::V6(addr) => {
+                let mut address: libc::sockaddr_in6 = unsafe { MaybeUninit::zeroed().assume_init() };
+                address.sin6_family = libc::AF_INET6 as libc::sa_family_t;
+                address.sin6_port = addr.port().to_be();
+                address.sin6_flowinfo = addr.flowinfo();
+                address.sin6_addr.s6_addr = addr.ip().octets();
+                address.sin6_scope_id = addr.scope_id();
+                unsafe { ptr::write(storage as *mut libc::sockaddr_storage as *mut libc::sockaddr_in6, address) };
+                mem::size_of::<libc::sockaddr_in6>() as libc::socklen_t
+            }
+        }
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+mod sys {
+    use std::io;
+    use std::net::UdpSocket;
+    use crate::batch_io::Datagram;
+
+    pub fn recv_batch(socket: &UdpSocket, datagrams: &mut [Datagram]) -> io::Result<usize> {
+        let datagram = &mut datagrams[0];
+        let (len, addr) = socket.recv_from(&mut datagram.buf)?;
+        datagram.len = len;
+        datagram.addr = addr;
+        Ok(1)
+    }
+
+    pub fn send_batch(socket: &UdpSocket, datagrams: &[Datagram]) -> io::Result<usize> {
+        let datagram = &datagrams[0];
+        socket.send_to(datagram.payload(), datagram.addr)?;
+        Ok(1)
+    }
+}
+
+#[cfg(test)]
+mod batch_io_tests {
+    use std::net::{IpAddr, Ipv6Addr, SocketAddr, UdpSocket};
+    use std::time::{Duration, Instant};
+    use crate::batch_io::{BATCH_SIZE, BatchReceiver, Datagram, DATAGRAM_BUFFER_SIZE, RECEIVE_ERROR_BACKOFF_MAX, RECEIVE_ERROR_BACKOFF_MIN, ReceiveErrorBackoff, send_batch};
+    use crate::health_check::HEALTH_CHECK_PACKET_SIZE;
+    use crate::network::IP;
+
+    fn datagram(first_byte: u8, len: usize, addr: SocketAddr) -> Datagram {
+        let mut datagram = Datagram { len, addr, ..Datagram::default() };
+        datagram.buf[0] = first_byte;
+        datagram
+    }
+
+    fn bind() -> UdpSocket {
+        let socket = UdpSocket::bind(SocketAddr::new(IpAddr::V4(IP), 0)).unwrap();
+        socket.set_read_timeout(Some(Duration::from_secs(1))).unwrap();
+        socket
+    }
+
+    #[test]
+    fn batches_are_sent_and_received_with_their_addresses() {
+        let receiver_socket = bind();
+        let sender_socket = bind();
+        let receiver_addr = receiver_socket.local_addr().unwrap();
+        let unreachable = SocketAddr::new(IpAddr::V6(Ipv6Addr::LOCALHOST), 9);
+        let datagrams = vec![
+            datagram(1, HEALTH_CHECK_PACKET_SIZE, receiver_addr),
+            datagram(2, HEALTH_CHECK_PACKET_SIZE, unreachable),
+            datagram(3, DATAGRAM_BUFFER_SIZE, receiver_addr),
+        ];
+
+        let failed = send_batch(&sender_socket, &datagrams);
+        assert_eq!(vec![1], failed.iter().map(|(index, _)| *index).collect::<Vec<usize>>(),
+                   "We expect an IPv6 destination to fail on an IPv4 socket without holding up the rest");
+
+        let mut batch_receiver = BatchReceiver::default();
+        let mut received = Vec::new();
+        while received.len() < 2 {
+            for datagram in batch_receiver.recv(&receiver_socket).unwrap() {
+                received.push((datagram.buf[0], datagram.len, datagram.addr));
+            }
+        }
+        let sender_addr = sender_socket.local_addr().unwrap();
+        assert_eq!(vec![(1, HEALTH_CHECK_PACKET_SIZE, sender_addr), (3, DATAGRAM_BUFFER_SIZE, sender_addr)], received);
+    }
+
+    #[test]
+    fn receive_errors_back_off_until_a_receive_succeeds() {
+        let mut backoff = ReceiveErrorBackoff::default();
+        assert_eq!(RECEIVE_ERROR_BACKOFF_MIN, backoff.failed());
+        assert_eq!(RECEIVE_ERROR_BACKOFF_MIN * 2, backoff.failed());
+        for _ in 0..100 {
+            backoff.failed();
+        }
+        assert_eq!(RECEIVE_ERROR_BACKOFF_MAX, backoff.failed());
+        backoff.succeeded();
+        assert_eq!(RECEIVE_ERROR_BACKOFF_MIN, backoff.failed());
+    }
+
+    /**
+    Packets per second over loopback with one syscall per packet against sendmmsg and recvmmsg, run with
+    cargo test --release -- --ignored --nocapture bench_
+    */
+    #[test]
+    #[ignore]
+    fn bench_batched_against_per_packet_io() {
+        const PACKETS: usize = 1_000_000;
+        for batched in [false, true] {
+            let mode = if batched { "batched" } else { "per-packet" };
+            let receiver_socket = bind();
+            let receiver_addr = receiver_socket.local_addr().unwrap();
+            let datagrams = vec![datagram(1, HEALTH_CHECK_PACKET_SIZE, receiver_addr); BATCH_SIZE];
+
+            // Nobody reads, so this is the cost of the sending syscalls alone
+            let sender_socket = bind();
+            let started_at = Instant::now();
+            for _ in 0..PACKETS / BATCH_SIZE {
+                if batched {
+                    send_batch(&sender_socket, &datagrams);
+                } else {
+                    for datagram in &datagrams {
+                        let _ = sender_socket.send_to(datagram.payload(), datagram.addr);
+                    }
+                }
+            }
+            let elapsed = started_at.elapsed();
+            println!("send    {:<10} packets={} elapsed={:?} throughput={:.0} pps", mode, PACKETS / BATCH_SIZE * BATCH_SIZE, elapsed,
+                     (PACKETS / BATCH_SIZE * BATCH_SIZE) as f64 / elapsed.as_secs_f64());
+
+            // Fill the socket, then time draining it without blocking, so the sender never competes for the CPU
+            receiver_socket.set_nonblocking(true).unwrap();
+            let mut received = 0;
+            let mut elapsed = Duration::ZERO;
+            let mut batch_receiver = BatchReceiver::default();
+            let mut buf = [0; DATAGRAM_BUFFER_SIZE];
+            while received < PACKETS {
+                send_batch(&sender_socket, &datagrams[..BATCH_SIZE / 2]);
+                send_batch(&sender_socket, &datagrams[..BATCH_SIZE / 2]);
+                let started_at = Instant::now();
+                loop {
+                    let drained = if batched {
+                        batch_receiver.recv(&receiver_socket).map(|datagrams| datagrams.len())
+                    } else {
+                        receiver_socket.recv_from(&mut buf).map(|_| 1)
+                    };
+                    match drained {
+                        Ok(drained) => received += drained,
+                        Err(_) => break
+                    }
+                }
+                elapsed += started_at.elapsed();
+            }
+            println!("receive {:<10} packets={} elapsed={:?} throughput={:.0} pps", mode, received, elapsed, received as f64 / elapsed.as_secs_f64());
+        }
+    }
+}
@@ -0,0 +1,491 @@
+// Metered channels
+// Channels that keep count of the messages waiting in them and of the ones they dropped, so the queue depth and
+// overload of the broker's request and response channels can be exported as metrics
+// A bounded channel applies its DropPolicy once it holds `capacity` messages, an unbounded one grows without limit
+// Built on a mutex and condvars rather than std mpsc, which can't take back the oldest message from the sending side
+// With the tokio feature either end can also wait as a future, woken by the other end, see send_async and recv_async
+// A receiver polled with poll_recv is woken the same way, which is how the event loop hears about requests
+
+use std::collections::VecDeque;
+use std::fmt::{self, Display, Formatter};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::mpsc::{RecvError, RecvTimeoutError, SendError, TryRecvError};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+#[cfg(any(feature = "tokio", feature = "mio"))]
+use std::task::{Context, Poll};
+use std::task::Waker;
+use std::time::{Duration, Instant};
+
+pub const DEFAULT_CHANNEL_CAPACITY: usize = 4096;
+
+/**
+What a full bounded channel does with a message sent to it.
+ */
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum DropPolicy {
+    /**
+    The message being sent is dropped, the send still succeeds.
+    */
+    #[default]
+    DropNewest,
+    /**
+    The message that has waited longest is dropped to make room.
+    */
+    DropOldest,
+    /**
+    The sender waits for room, nothing is dropped.
+    */
+    Block,
+}
+
+impl FromStr for DropPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "drop_newest" => Ok(DropPolicy::DropNewest),
+            "drop_oldest" => Ok(DropPolicy::DropOldest),
+            "block" => Ok(DropPolicy::Block),
+            _ => Err(format!("Unknown drop policy [{}], expected one of drop_newest, drop_oldest, block", s))
+        }
+    }
+}
+
+impl Display for DropPolicy {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            DropPolicy::DropNewest => write!(f, "drop_newest"),
+            DropPolicy::DropOldest => write!(f, "drop_oldest"),
+            DropPolicy::Block => write!(f, "block"),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct ChannelBounds {
+    pub capacity: usize,
+    pub drop_policy: DropPolicy,
+}
+
+impl Default for ChannelBounds {
+    fn default() -> ChannelBounds {
+        ChannelBounds {
+            capacity: DEFAULT_CHANNEL_CAPACITY,
+            drop_policy: DropPolicy::default(),
+        }
+    }
+}
+
+/**
+Number of messages sent on a channel and not received yet, and of messages the channel dropped, shared by both ends.
+ */
+#[derive(Clone, Debug, Default)]
+pub struct QueueDepth {
+    waiting: Arc<AtomicUsize>,
+    dropped: Arc<AtomicU64>,
+}
+
+impl QueueDepth {
+    pub fn get(&self) -> usize {
+        self.waiting.load(Ordering::Relaxed)
+    }
+
+    pub fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+}
+
+#[derive(Debug)]
+struct Queue<T> {
+    messages: VecDeque<T>,
+    senders: usize,
+    receiver_alive: bool,
+    /**
+    Receiver waiting in recv_async.
+    */
+    receiver_waker: Option<Waker>,
+    /**
+    Senders waiting in send_async for room.
+    */
+    sender_wakers: Vec<Waker>,
+}
+
+#[derive(Debug)]
+struct Shared<T> {
+    queue: Mutex<Queue<T>>,
+    not_empty: Condvar,
+    not_full: Condvar,
+    /**
+    None for an unbounded channel.
+    */
+    bounds: Option<ChannelBounds>,
+    depth: QueueDepth,
+}
+
+impl<T> Shared<T> {
+    fn lock(&self) -> MutexGuard<'_, Queue<T>> {
+        // The queue is consistent between every statement holding the lock, so a panicked holder left nothing half done
+        self.queue.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    fn is_full(&self, queue: &Queue<T>) -> bool {
+        self.bounds.is_some_and(|bounds| queue.messages.len() >= bounds.capacity)
+    }
+
+    fn dropped(&self) {
+        self.depth.dropped.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /**
+    Full and blocking, the sender has to wait for room.
+    */
+    fn must_wait(&self, queue: &Queue<T>) -> bool {
+        queue.receiver_alive && self.is_full(queue) && self.bounds.is_some_and(|bounds| bounds.drop_policy == DropPolicy::Block)
+    }
+
+    /**
+    Queues the message once the sender doesn't have to wait anymore, applying the drop policy if the channel is full.
+    */
+    fn push(&self, mut queue: MutexGuard<'_, Queue<T>>, message: T) -> Result<(), SendError<T>> {
+        if !queue.receiver_alive {
+            return Err(SendError(message));
+        }
+        if self.is_full(&queue) {
+            match self.bounds.map(|bounds| bounds.drop_policy) {
+                Some(DropPolicy::DropOldest) => {
+                    queue.messages.pop_front();
+                    self.dropped();
+                }
+                _ => {
+                    self.dropped();
+                    return Ok(());
+                }
+            }
+        }
+        queue.messages.push_back(message);
+        self.depth.waiting.store(queue.messages.len(), Ordering::Relaxed);
+        self.not_empty.notify_one();
+        if let Some(waker) = queue.receiver_waker.take() {
+            waker.wake();
+        }
+        Ok(())
+    }
+
+    fn pop(&self, queue: &mut Queue<T>) -> Option<T> {
+        let message = queue.messages.pop_front()?;
+        self.depth.waiting.store(queue.messages.len(), Ordering::Relaxed);
+        self.not_full.notify_one();
+        queue.sender_wakers.drain(..).for_each(Waker::wake);
+        Some(message)
+    }
+}
+
+#[derive(Debug)]
+pub struct MeteredSender<T> {
+    shared: Arc<Shared<T>>,
+}
+
+// Derived Clone would require T: Clone
+impl<T> Clone for MeteredSender<T> {
+    fn clone(&self) -> MeteredSender<T> {
+        self.shared.lock().senders += 1;
+        MeteredSender {
+            shared: self.shared.clone(),
+        }
+    }
+}
+
+impl<T> Drop for MeteredSender<T> {
+    fn drop(&mut self) {
+        let mut queue = self.shared.lock();
+        queue.senders -= 1;
+        if queue.senders == 0 {
+            // Wakes a receiver waiting on an empty channel, so it sees the disconnect
+            self.shared.not_empty.notify_all();
+            if let Some(waker) = queue.receiver_waker.take() {
+                waker.wake();
+            }
+        }
+    }
+}
+
+impl<T> MeteredSender<T> {
+    /**
+    Fails only when the receiver is gone, a message dropped by a full channel still counts as sent.
+    */
+    pub fn send(&self, message: T) -> Result<(), SendError<T>> {
+        let shared = &self.shared;
+        let mut queue = shared.lock();
+        while shared.must_wait(&queue) {
+            queue = shared.not_full.wait(queue).unwrap_or_else(|poisoned| poisoned.into_inner());
+        }
+        shared.push(queue, message)
+    }
+
+    /**
+    Like send, but a full blocking channel is waited on without blocking the thread.
+    */
+    #[cfg(feature = "tokio")]
+    pub async fn send_async(&self, message: T) -> Result<(), SendError<T>> {
+        let mut message = Some(message);
+        std::future::poll_fn(|context| {
+            let shared = &self.shared;
+            let mut queue = shared.lock();
+            if shared.must_wait(&queue) {
+                // A sender polled again without room is already waiting, its waker is only kept once
+                if !queue.sender_wakers.iter().any(|waker| waker.will_wake(context.waker())) {
+                    queue.sender_wakers.push(context.waker().clone());
+                }
+                return Poll::Pending;
+            }
+            Poll::Ready(shared.push(queue, message.take().expect("Message sent once")))
+        }).await
+    }
+
+    pub fn depth(&self) -> QueueDepth {
+        self.shared.depth.clone()
+    }
+}
+
+#[derive(Debug)]
+pub struct MeteredReceiver<T> {
+    shared: Arc<Shared<T>>,
+}
+
+impl<T> Drop for MeteredReceiver<T> {
+    fn drop(&mut self) {
+        let mut queue = self.shared.lock();
+        queue.receiver_alive = false;
+        // Nothing will receive them anymore, and senders blocked on a full channel have to give up
+        queue.messages.clear();
+        self.shared.depth.waiting.store(0, Ordering::Relaxed);
+        self.shared.not_full.notify_all();
+        queue.sender_wakers.drain(..).for_each(Waker::wake);
+    }
+}
+
+impl<T> MeteredReceiver<T> {
+    pub fn recv(&self) -> Result<T, RecvError> {
+        let mut queue = self.shared.lock();
+        loop {
+            if let Some(message) = self.shared.pop(&mut queue) {
+                return Ok(message);
+            }
+            if queue.senders == 0 {
+                return Err(RecvError);
+            }
+            queue = self.shared.not_empty.wait(queue).unwrap_or_else(|poisoned| poisoned.into_inner());
+        }
+    }
+
+    /**
+    Like recv, but waits without blocking the thread.
+    */
+    #[cfg(feature = "tokio")]
+    pub async fn recv_async(&self) -> Result<T, RecvError> {
+        std::future::poll_fn(|context| self.poll_recv(context)).await
+    }
+
+    /**
+    Takes a message if one is waiting, otherwise the context's waker is woken by the next send or the last sender going.
+    */
+    #[cfg(any(feature = "tokio", feature = "mio"))]
+    pub fn poll_recv(&self, context: &mut Context<'_>) -> Poll<Result<T, RecvError>> {
+        let mut queue = self.shared.lock();
+        if let Some(message) = self.shared.pop(&mut queue) {
+            return Poll::Ready(Ok(message));
+        }
+        if queue.senders == 0 {
+            return Poll::Ready(Err(RecvError));
+        }
+        queue.receiver_waker = Some(context.waker().clone());
+        Poll::Pending
+    }
+
+    pub fn try_recv(&self) -> Result<T, TryRecvError> {
+        let mut queue = self.shared.lock();
+        match self.shared.pop(&mut queue) {
+            Some(message) => Ok(message),
+            None if queue.senders == 0 => Err(TryRecvError::Disconnected),
+            None => Err(TryRecvError::Empty)
+        }
+    }
+
+    pub fn recv_timeout(&self, timeout: Duration) -> Result<T, RecvTimeoutError> {
+        let deadline = Instant::now() + timeout;
+        let mut queue = self.shared.lock();
+        loop {
+            if let Some(message) = self.shared.pop(&mut queue) {
+                return Ok(message);
+            }
+            if queue.senders == 0 {
+                return Err(RecvTimeoutError::Disconnected);
+            }
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining.is_zero() {
+                return Err(RecvTimeoutError::Timeout);
+            }
+            queue = self.shared.not_empty.wait_timeout(queue, remaining).unwrap_or_else(|poisoned| poisoned.into_inner()).0;
+        }
+    }
+
+    /**
+    Every message already waiting, without blocking.
+    */
+    pub fn try_iter(&self) -> impl Iterator<Item = T> + '_ {
+        std::iter::from_fn(|| self.try_recv().ok())
+    }
+
+    pub fn depth(&self) -> QueueDepth {
+        self.shared.depth.clone()
+    }
+
+    /**
+    Capacity of a bounded channel, None for an unbounded one.
+    */
+    pub fn capacity(&self) -> Option<usize> {
+        self.shared.bounds.map(|bounds| bounds.capacity)
+    }
+}
+
+fn channel<T>(bounds: Option<ChannelBounds>) -> (MeteredSender<T>, MeteredReceiver<T>) {
+    let shared = Arc::new(Shared {
+        queue: Mutex::new(Queue {
+            messages: VecDeque::new(),
+            senders: 1,
+            receiver_alive: true,
+            receiver_waker: None,
+            sender_wakers: Vec::new(),
+        }),
+        not_empty: Condvar::new(),
+        not_full: Condvar::new(),
+        bounds,
+        depth: QueueDepth::default(),
+    });
+    (MeteredSender { shared: shared.clone() }, MeteredReceiver { shared })
+}
+
+/**
+An unbounded channel, like std mpsc.
+ */
+pub fn metered_channel<T>() -> (MeteredSender<T>, MeteredReceiver<T>) {
+    channel(None)
+}
+
+pub fn bounded_channel<T>(bounds: ChannelBounds) -> (MeteredSender<T>, MeteredReceiver<T>) {
+    channel(Some(bounds))
+}
+
+#[cfg(test)]
+mod channel_tests {
+    #[cfg(feature = "tokio")]
+    use std::future::Future;
+    use std::thread;
+    use std::time::Duration;
+    use crate::channel::{bounded_channel, ChannelBounds, DropPolicy, metered_channel};
+
+    #[test]
+    fn depth_follows_sends_and_receives() {
+        let (sender, receiver) = metered_channel();
+        let depth = receiver.depth();
+        sender.send(1).unwrap();
+        sender.clone().send(2).unwrap();
+        assert_eq!(2, depth.get(), "We expect both senders to share the depth");
+
+        assert_eq!(1, receiver.recv().unwrap());
+        assert_eq!(2, receiver.recv_timeout(Duration::from_millis(10)).unwrap());
+        assert_eq!(0, depth.get());
+        assert!(receiver.try_recv().is_err());
+        assert_eq!(0, depth.get(), "We expect a failed receive to leave the depth alone");
+
+        drop(receiver);
+        assert!(sender.send(3).is_err());
+        assert_eq!(0, sender.depth().get(), "We expect a failed send not to be counted");
+    }
+
+    #[test]
+    fn full_channels_drop_as_per_their_policy() {
+        for (drop_policy, expected) in [(DropPolicy::DropNewest, vec![1, 2]), (DropPolicy::DropOldest, vec![3, 4])] {
+            let (sender, receiver) = bounded_channel(ChannelBounds { capacity: 2, drop_policy });
+            for message in 1..=4 {
+                sender.send(message).unwrap();
+            }
+            assert_eq!(2, receiver.depth().get());
+            assert_eq!(2, receiver.depth().dropped(), "We expect every drop to be counted with {}", drop_policy);
+            assert_eq!(expected, receiver.try_iter().collect::<Vec<i32>>(), "We expect {} to keep the right messages", drop_policy);
+        }
+    }
+
+    #[test]
+    fn blocking_channels_wait_for_room() {
+        let (sender, receiver) = bounded_channel(ChannelBounds { capacity: 1, drop_policy: DropPolicy::Block });
+        sender.send(1).unwrap();
+        let blocked_sender = sender.clone();
+        let handle = thread::spawn(move || {
+            blocked_sender.send(2).unwrap();
+        });
+        thread::sleep(Duration::from_millis(50));
+        assert!(!handle.is_finished(), "We expect the sender to wait while the channel is full");
+        assert_eq!(1, receiver.recv().unwrap());
+        handle.join().unwrap();
+        assert_eq!(2, receiver.recv_timeout(Duration::from_secs(1)).unwrap());
+        assert_eq!(0, receiver.depth().dropped());
+
+        sender.send(3).unwrap();
+        let blocked_sender = sender.clone();
+        let handle = thread::spawn(move || blocked_sender.send(4));
+        thread::sleep(Duration::from_millis(50));
+        drop(receiver);
+        assert!(handle.join().unwrap().is_err(), "We expect a blocked sender to give up once the receiver is gone");
+    }
+
+    #[cfg(feature = "tokio")]
+    #[test]
+    fn async_ends_wake_each_other() {
+        // One thread, so every wait has to hand over to the other end instead of blocking it
+        let runtime = tokio::runtime::Builder::new_current_thread().build().unwrap();
+        runtime.block_on(async {
+            let (sender, receiver) = bounded_channel(ChannelBounds { capacity: 1, drop_policy: DropPolicy::Block });
+            let receiving = tokio::spawn(async move {
+                let mut received = Vec::new();
+                while let Ok(message) = receiver.recv_async().await {
+                    received.push(message);
+                }
+                received
+            });
+            for message in 1..=3 {
+                sender.send_async(message).await.unwrap();
+            }
+            drop(sender);
+            assert_eq!(vec![1, 2, 3], receiving.await.unwrap(), "We expect every message to get through a full channel and the disconnect to end the receive");
+        });
+    }
+
+    #[cfg(feature = "tokio")]
+    #[test]
+    fn waiting_senders_keep_one_waker() {
+        let (sender, receiver) = bounded_channel(ChannelBounds { capacity: 1, drop_policy: DropPolicy::Block });
+        sender.send(1).unwrap();
+        let mut sending = Box::pin(sender.send_async(2));
+        struct Noop;
+        impl std::task::Wake for Noop {
+            fn wake(self: std::sync::Arc<Self>) {}
+        }
+        let waker = std::task::Waker::from(std::sync::Arc::new(Noop));
+        let mut context = std::task::Context::from_waker(&waker);
+        for _ in 0..3 {
+            assert!(sending.as_mut().poll(&mut context).is_pending());
+        }
+        assert_eq!(1, receiver.shared.lock().sender_wakers.len(), "We expect a sender polled again without room to be kept once");
+    }
+
+    #[test]
+    fn receivers_see_their_senders_go() {
+        let (sender, receiver) = metered_channel::<i32>();
+        let handle = thread::spawn(move || receiver.recv());
+        thread::sleep(Duration::from_millis(50));
+        drop(sender);
+        assert!(handle.join().unwrap().is_err());
+    }
+}
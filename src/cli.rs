@@ -0,0 +1,267 @@
+// Command line
+// The first argument names the command, serve when it is left out so existing invocations keep running a node
+// Flags belonging to the command are parsed here, everything else is handed on to CliArguments
+
+use std::net::SocketAddr;
+use std::str::FromStr;
+use std::time::Duration;
+
+use crate::config::{CliArguments, ConfigurationError};
+use crate::health_check_scheduler::StaticPeer;
+use crate::network::{FailurePolicy, HealthCheckConfiguration};
+use crate::probe::ProbeOptions;
+
+const PROBE_FLAGS: [&str; 7] = ["--count", "--interval-ms", "--timeout-ms", "--warning-loss", "--critical-loss", "--warning-rtt-ms", "--critical-rtt-ms"];
+const ADD_PEER_FLAGS: [&str; 7] = ["--tag", "--priority", "--probe-interval-ms", "--probe-timeout-ms", "--max-lives", "--failure-policy", "--disabled"];
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Command {
+    Serve,
+    Probe(ProbeOptions),
+    Peers,
+    AddPeer(StaticPeer),
+    RemovePeer(SocketAddr),
+    Config,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Cli {
+    pub command: Command,
+    /**
+    Print machine readable JSON instead of text.
+    */
+    pub json: bool,
+    /**
+    Locate the node and its configuration, shared by every command.
+    */
+    pub arguments: CliArguments,
+}
+
+impl Cli {
+    pub fn parse(args: impl IntoIterator<Item = String>) -> Result<Cli, ConfigurationError> {
+        let mut args = args.into_iter().peekable();
+        let name = match args.next_if(|arg| !arg.starts_with('-')) {
+            Some(name) => name,
+            None => String::from("serve")
+        };
+        let command_flags: &[&str] = match name.as_str() {
+            "probe" => &PROBE_FLAGS,
+            "add-peer" => &ADD_PEER_FLAGS,
+            "serve" | "peers" | "remove-peer" | "config" => &[],
+            _ => return Err(ConfigurationError::Arguments(format!("Unknown command {}", name)))
+        };
+
+        let mut json = false;
+        let mut positionals: Vec<String> = Vec::new();
+        let mut flags: Vec<(String, String)> = Vec::new();
+        let mut global_arguments: Vec<String> = Vec::new();
+        while let Some(arg) = args.next() {
+            if arg == "--json" {
+                json = true;
+            } else if arg == "--disabled" && command_flags.contains(&arg.as_str()) {
+                flags.push((arg, String::new()));
+            } else if command_flags.contains(&arg.as_str()) {
+                let value = args.next().ok_or_else(|| ConfigurationError::Arguments(format!("Missing value for {}", arg)))?;
+                flags.push((arg, value));
+            } else if arg.starts_with("--") {
+                // Left for CliArguments, which reports unknown flags and missing values
+                let takes_value = arg != "--check-config";
+                global_arguments.push(arg);
+                if takes_value {
+                    global_arguments.extend(args.next());
+                }
+            } else {
+                positionals.push(arg);
+            }
+        }
+        let arguments = CliArguments::parse(global_arguments)?;
+
+        let command = match name.as_str() {
+            "serve" => Command::Serve,
+            "peers" => Command::Peers,
+            "config" => Command::Config,
+            "probe" => Command::Probe(probe_options(single_addr(&name, &positionals)?, &flags)?),
+            "add-peer" => Command::AddPeer(static_peer(single_addr(&name, &positionals)?, &flags)?),
+            _ => Command::RemovePeer(single_addr(&name, &positionals)?)
+        };
+        if !positionals.is_empty() && matches!(command, Command::Serve | Command::Peers | Command::Config) {
+            return Err(ConfigurationError::Arguments(format!("Unexpected argument {}", positionals[0])));
+        }
+        Ok(Cli { command, json, arguments })
+    }
+}
+
+fn single_addr(command: &str, positionals: &[String]) -> Result<SocketAddr, ConfigurationError> {
+    match positionals {
+        [addr] => addr.parse().map_err(|_| ConfigurationError::Arguments(format!("{} [{}] is not a valid ip:port address", command, addr))),
+        [] => Err(ConfigurationError::Arguments(format!("{} needs an ip:port address", command))),
+        [_, unexpected, ..] => Err(ConfigurationError::Arguments(format!("Unexpected argument {}", unexpected)))
+    }
+}
+
+fn parse_value<T: FromStr>(flag: &str, value: &str) -> Result<T, ConfigurationError> {
+    value.trim().parse()
+        .map_err(|_| ConfigurationError::Arguments(format!("{} [{}] is not a valid {}", flag, value, std::any::type_name::<T>())))
+}
+
+fn probe_options(addr: SocketAddr, flags: &[(String, String)]) -> Result<ProbeOptions, ConfigurationError> {
+    let mut options = ProbeOptions::new(addr);
+    for (flag, value) in flags {
+        match flag.as_str() {
+            "--count" => options.count = parse_value(flag, value)?,
+            "--interval-ms" => options.interval = Duration::from_millis(parse_value(flag, value)?),
+            "--timeout-ms" => options.timeout = Duration::from_millis(parse_value(flag, value)?),
+            "--warning-loss" => options.thresholds.warning_loss_percent = parse_value(flag, value)?,
+            "--critical-loss" => options.thresholds.critical_loss_percent = parse_value(flag, value)?,
+            "--warning-rtt-ms" => options.thresholds.warning_rtt = Some(Duration::from_millis(parse_value(flag, value)?)),
+            _ => options.thresholds.critical_rtt = Some(Duration::from_millis(parse_value(flag, value)?))
+        }
+    }
+
+    let thresholds = &options.thresholds;
+    if options.count == 0 {
+        return Err(ConfigurationError::Arguments(String::from("--count must be at least 1")));
+    }
+    if options.timeout.is_zero() {
+        return Err(ConfigurationError::Arguments(String::from("--timeout-ms must be greater than 0")));
+    }
+    if !(0.0..=100.0).contains(&thresholds.warning_loss_percent) || !(0.0..=100.0).contains(&thresholds.critical_loss_percent) {
+        return Err(ConfigurationError::Arguments(String::from("--warning-loss and --critical-loss must be percentages between 0 and 100")));
+    }
+    if thresholds.warning_loss_percent > thresholds.critical_loss_percent {
+        return Err(ConfigurationError::Arguments(String::from("--warning-loss must not be above --critical-loss")));
+    }
+    if let (Some(warning_rtt), Some(critical_rtt)) = (thresholds.warning_rtt, thresholds.critical_rtt) {
+        if warning_rtt > critical_rtt {
+            return Err(ConfigurationError::Arguments(String::from("--warning-rtt-ms must not be above --critical-rtt-ms")));
+        }
+    }
+    Ok(options)
+}
+
+fn static_peer(addr: SocketAddr, flags: &[(String, String)]) -> Result<StaticPeer, ConfigurationError> {
+    let mut configuration = HealthCheckConfiguration {
+        health_check_port: addr.port(),
+        ..HealthCheckConfiguration::default()
+    };
+    for (flag, value) in flags {
+        match flag.as_str() {
+            "--tag" => {
+                configuration.tags.insert(value.clone());
+            }
+            "--priority" => configuration.priority = Some(parse_value(flag, value)?),
+            "--probe-interval-ms" => configuration.probe_interval = Some(Duration::from_millis(parse_value(flag, value)?)),
+            "--probe-timeout-ms" => configuration.probe_timeout = Some(Duration::from_millis(parse_value(flag, value)?)),
+            "--max-lives" => configuration.max_lives = Some(parse_value(flag, value)?),
+            "--failure-policy" => configuration.failure_policy = Some(FailurePolicy::from_str(value)
+                .map_err(|err| ConfigurationError::Arguments(format!("{}: {}", flag, err)))?),
+            _ => configuration.enabled = false
+        }
+    }
+
+    if configuration.max_lives == Some(0) {
+        return Err(ConfigurationError::Arguments(String::from("--max-lives must be at least 1")));
+    }
+    if configuration.probe_interval.is_some_and(|probe_interval| probe_interval.is_zero())
+        || configuration.probe_timeout.is_some_and(|probe_timeout| probe_timeout.is_zero()) {
+        return Err(ConfigurationError::Arguments(String::from("--probe-interval-ms and --probe-timeout-ms must be greater than 0")));
+    }
+    // Only checked when both are given, the other one is inherited from the running node's defaults
+    if let (Some(probe_interval), Some(probe_timeout)) = (configuration.probe_interval, configuration.probe_timeout) {
+        if probe_timeout >= probe_interval {
+            return Err(ConfigurationError::Arguments(String::from("--probe-timeout-ms must be less than --probe-interval-ms")));
+        }
+    }
+    Ok(StaticPeer { addr, configuration })
+}
+
+#[cfg(test)]
+mod cli_tests {
+    use std::collections::BTreeSet;
+    use std::net::SocketAddr;
+    use std::path::PathBuf;
+    use std::time::Duration;
+    use crate::cli::{Cli, Command};
+    use crate::config::ConfigurationError;
+    use crate::network::FailurePolicy;
+    use crate::probe::{ProbeOptions, ProbeThresholds};
+
+    fn parse(args: &[&str]) -> Result<Cli, ConfigurationError> {
+        Cli::parse(args.iter().map(|arg| arg.to_string()))
+    }
+
+    #[test]
+    fn command_defaults_to_serve() {
+        let cli = parse(&["--config", "node.toml", "--check-config"]).unwrap();
+        assert_eq!(Command::Serve, cli.command);
+        assert!(!cli.json);
+        assert_eq!(Some(PathBuf::from("node.toml")), cli.arguments.config_file);
+        assert!(cli.arguments.check_config, "We expect --check-config to keep working without a command");
+    }
+
+    #[test]
+    fn commands_take_their_own_flags_and_the_global_ones() {
+        let addr: SocketAddr = "127.0.0.1:3451".parse().unwrap();
+
+        let cli = parse(&["probe", "127.0.0.1:3451", "--timeout-ms", "500", "--count", "5", "--interval-ms", "100",
+            "--warning-loss", "10", "--critical-loss", "50.5", "--warning-rtt-ms", "20", "--critical-rtt-ms", "80", "--json"]).unwrap();
+        let expected = ProbeOptions {
+            addr,
+            count: 5,
+            interval: Duration::from_millis(100),
+            timeout: Duration::from_millis(500),
+            thresholds: ProbeThresholds {
+                warning_loss_percent: 10.0,
+                critical_loss_percent: 50.5,
+                warning_rtt: Some(Duration::from_millis(20)),
+                critical_rtt: Some(Duration::from_millis(80)),
+            }
+        };
+        assert_eq!(Command::Probe(expected), cli.command);
+        assert!(cli.json);
+        assert_eq!(Command::Probe(ProbeOptions::new(addr)), parse(&["probe", "127.0.0.1:3451"]).unwrap().command);
+
+        let cli = parse(&["add-peer", "--data-dir", "node_a", "127.0.0.1:3451", "--tag", "eu", "--tag", "db",
+            "--priority", "7", "--probe-interval-ms", "1000", "--probe-timeout-ms", "500", "--failure-policy", "fail_fast", "--disabled"]).unwrap();
+        let Command::AddPeer(static_peer) = cli.command else {
+            panic!("Expected add-peer, got {:?}", cli.command);
+        };
+        assert_eq!(addr, static_peer.addr);
+        assert_eq!(3451, static_peer.configuration.health_check_port);
+        assert_eq!(BTreeSet::from([String::from("db"), String::from("eu")]), static_peer.configuration.tags);
+        assert_eq!(Some(7), static_peer.configuration.priority);
+        assert_eq!(Some(Duration::from_millis(500)), static_peer.configuration.probe_timeout);
+        assert_eq!(Some(FailurePolicy::FailFast), static_peer.configuration.failure_policy);
+        assert!(!static_peer.configuration.enabled);
+        assert_eq!(Some(PathBuf::from("node_a")), cli.arguments.data_dir);
+
+        assert_eq!(Command::RemovePeer(addr), parse(&["remove-peer", "127.0.0.1:3451"]).unwrap().command);
+        assert_eq!(Command::Peers, parse(&["peers", "--json"]).unwrap().command);
+        assert_eq!(Command::Config, parse(&["config"]).unwrap().command);
+    }
+
+    #[test]
+    fn invalid_arguments_are_rejected() {
+        for args in [
+            vec!["--verbose"],
+            vec!["--port"],
+            vec!["restart"],
+            vec!["probe"],
+            vec!["probe", "nowhere"],
+            vec!["probe", "127.0.0.1:3451", "127.0.0.1:3452"],
+            vec!["probe", "127.0.0.1:3451", "--timeout-ms", "0"],
+            vec!["probe", "127.0.0.1:3451", "--count", "0"],
+            vec!["probe", "127.0.0.1:3451", "--critical-loss", "120"],
+            vec!["probe", "127.0.0.1:3451", "--warning-loss", "50", "--critical-loss", "40"],
+            vec!["probe", "127.0.0.1:3451", "--warning-rtt-ms", "50", "--critical-rtt-ms", "40"],
+            vec!["peers", "127.0.0.1:3451"],
+            vec!["peers", "--tag", "eu"],
+            vec!["add-peer", "127.0.0.1:3451", "--max-lives", "0"],
+            vec!["add-peer", "127.0.0.1:3451", "--failure-policy", "sometimes"],
+            vec!["add-peer", "127.0.0.1:3451", "--probe-interval-ms", "500", "--probe-timeout-ms", "500"],
+        ] {
+            let result = parse(&args);
+            assert!(matches!(result, Err(ConfigurationError::Arguments(_))), "We expect {:?} to be rejected, got {:?}", args, result);
+        }
+    }
+}
@@ -0,0 +1,965 @@
+// Configuration
+// Layered from the defaults, then an optional TOML file, then environment variables, then command line arguments
+// Every layer is validated together at the end, so all problems are reported at once instead of one expect at a time
+
+use std::collections::{BTreeSet, HashSet};
+use std::fmt::{Display, Formatter};
+use std::net::{IpAddr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::Duration;
+use std::{fmt, fs, io};
+use log::LevelFilter;
+use serde::{Deserialize, Serialize};
+
+use crate::channel::{ChannelBounds, DEFAULT_CHANNEL_CAPACITY, DropPolicy};
+use crate::health_check_network_broker::RuntimeMode;
+use crate::health_check_scheduler::StaticPeer;
+use crate::logging::LogFormat;
+use crate::network::{FailurePolicy, HealthCheckConfiguration, HealthCheckDefaults};
+use crate::peer_store::PeerStoreBackend;
+use crate::reaper::ReaperConfiguration;
+use crate::trace::ExportDestination;
+
+pub const CONFIG_FILE_ENV_KEY: &str = "HEALTH_CHECK_CONFIG";
+pub const IP_ADDRESS_ENV_KEY: &str = "HEALTH_CHECK_IP_ADDRESS";
+pub const UDP_PORT_ENV_KEY: &str = "HEALTH_CHECK_UDP_PORT";
+pub const DATA_DIR_ENV_KEY: &str = "HEALTH_CHECK_DATA_DIR";
+pub const STORE_BACKEND_ENV_KEY: &str = "HEALTH_CHECK_STORE_BACKEND";
+pub const SEEDS_ENV_KEY: &str = "HEALTH_CHECK_SEEDS";
+pub const UNHEALTHY_TTL_SECS_ENV_KEY: &str = "HEALTH_CHECK_UNHEALTHY_TTL_SECS";
+pub const TOMBSTONE_TTL_SECS_ENV_KEY: &str = "HEALTH_CHECK_TOMBSTONE_TTL_SECS";
+pub const PROBE_INTERVAL_SECS_ENV_KEY: &str = "HEALTH_CHECK_PROBE_INTERVAL_SECS";
+pub const PROBE_TIMEOUT_MILLIS_ENV_KEY: &str = "HEALTH_CHECK_PROBE_TIMEOUT_MILLIS";
+pub const MAX_LIVES_ENV_KEY: &str = "HEALTH_CHECK_MAX_LIVES";
+pub const LOG_LEVEL_ENV_KEY: &str = "HEALTH_CHECK_LOG_LEVEL";
+pub const LOG_FORMAT_ENV_KEY: &str = "HEALTH_CHECK_LOG_FORMAT";
+pub const ADMIN_LISTEN_ADDRESS_ENV_KEY: &str = "HEALTH_CHECK_ADMIN_LISTEN_ADDRESS";
+pub const METRICS_LISTEN_ADDRESS_ENV_KEY: &str = "HEALTH_CHECK_METRICS_LISTEN_ADDRESS";
+pub const CHANNEL_CAPACITY_ENV_KEY: &str = "HEALTH_CHECK_CHANNEL_CAPACITY";
+pub const CHANNEL_DROP_POLICY_ENV_KEY: &str = "HEALTH_CHECK_CHANNEL_DROP_POLICY";
+pub const LISTENER_WORKERS_ENV_KEY: &str = "HEALTH_CHECK_LISTENER_WORKERS";
+pub const RUNTIME_MODE_ENV_KEY: &str = "HEALTH_CHECK_RUNTIME_MODE";
+pub const OTLP_FILE_ENV_KEY: &str = "HEALTH_CHECK_TRACING_OTLP_FILE";
+pub const OTLP_ENDPOINT_ENV_KEY: &str = "HEALTH_CHECK_TRACING_OTLP_ENDPOINT";
+
+const SECURITY_KEY_SIZE_BYTES: usize = 32;
+const REDACTED: &str = "<redacted>";
+
+pub const USAGE: &str = "Usage: swizzy_decent [<command>] [--json] [--config <file>] [--check-config] [--bind <ip>] [--port <port>] \
+[--data-dir <dir>] [--store-backend <memory|sled>] [--log-level <level>] [--log-format <human|json>] [--seed <ip:port>]... [--admin-listen <ip:port>] \
+[--metrics-listen <ip:port>] [--listener-workers <n>] \
+[--channel-capacity <n>] [--channel-drop-policy <drop_newest|drop_oldest|block>] [--runtime <threads|tokio|event_loop>] [--otlp-file <file> | --otlp-endpoint <ip:port>]
+
+Commands:
+  serve                      Run a node, the default
+  probe <ip:port>            Probe a node and exit 0, 1, 2 or 3 for OK, WARNING, CRITICAL or UNKNOWN
+                             [--count <n>] [--interval-ms <ms>] [--timeout-ms <ms>] [--warning-loss <percent>]
+                             [--critical-loss <percent>] [--warning-rtt-ms <ms>] [--critical-rtt-ms <ms>]
+  peers                      List the peers of the running node
+  add-peer <ip:port>         Add a static peer to the running node [--tag <tag>]... [--priority <n>]
+                             [--probe-interval-ms <ms>] [--probe-timeout-ms <ms>] [--max-lives <n>]
+                             [--failure-policy <gradual|reset_on_success|fail_fast>] [--disabled]
+  remove-peer <ip:port>      Remove a peer from the running node
+  config                     Print the effective configuration";
+
+#[derive(Debug)]
+pub enum ConfigurationError {
+    Read {
+        path: PathBuf,
+        source: io::Error
+    },
+    Parse {
+        path: PathBuf,
+        message: String
+    },
+    Arguments(String),
+    /**
+    Every problem found across the layers, one readable message each.
+    */
+    Invalid(Vec<String>),
+}
+
+impl Display for ConfigurationError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigurationError::Read { path, source } => write!(f, "Could not read config file {}: {}", path.display(), source),
+            ConfigurationError::Parse { path, message } => write!(f, "Could not parse config file {}:\n{}", path.display(), message),
+            ConfigurationError::Arguments(message) => write!(f, "{}\n{}", message, USAGE),
+            ConfigurationError::Invalid(errors) => {
+                write!(f, "Invalid configuration:")?;
+                for error in errors {
+                    write!(f, "\n  - {}", error)?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Configuration {
+    /**
+    Addresses probed on startup to join the network, with the default health check settings.
+    */
+    pub seeds: Vec<String>,
+    pub node: NodeSection,
+    pub health_check: HealthCheckSection,
+    pub reaper: ReaperSection,
+    pub security: SecuritySection,
+    pub logging: LoggingSection,
+    pub admin: AdminSection,
+    pub metrics: MetricsSection,
+    pub listener: ListenerSection,
+    pub channels: ChannelsSection,
+    pub runtime: RuntimeSection,
+    pub tracing: TracingSection,
+    pub peers: Vec<PeerSection>,
+}
+
+#[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct NodeSection {
+    pub bind_address: String,
+    pub port: u16,
+    pub data_dir: PathBuf,
+    pub store_backend: String,
+}
+
+impl Default for NodeSection {
+    fn default() -> Self {
+        NodeSection {
+            bind_address: String::from("127.0.0.1"),
+            port: 3450,
+            data_dir: PathBuf::from("data"),
+            store_backend: String::from("memory"),
+        }
+    }
+}
+
+/**
+Defaults for every peer, see HealthCheckDefaults.
+ */
+#[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct HealthCheckSection {
+    pub probe_interval_ms: u64,
+    pub probe_timeout_ms: u64,
+    pub max_lives: u8,
+    pub failure_policy: String,
+    pub priority: u8,
+}
+
+impl Default for HealthCheckSection {
+    fn default() -> Self {
+        HealthCheckSection {
+            probe_interval_ms: 5000,
+            probe_timeout_ms: 2000,
+            max_lives: 3,
+            failure_policy: String::from("gradual"),
+            priority: 0,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ReaperSection {
+    pub unhealthy_ttl_secs: u64,
+    pub tombstone_ttl_secs: u64,
+    pub interval_secs: u64,
+}
+
+impl Default for ReaperSection {
+    fn default() -> Self {
+        ReaperSection {
+            unhealthy_ttl_secs: 300,
+            tombstone_ttl_secs: 1800,
+            interval_secs: 10,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SecuritySection {
+    /**
+    Hex encoded 32 byte keys shared by the nodes of the network. Only validated for now, packets are not signed yet.
+    */
+    pub shared_keys: Vec<String>,
+}
+
+#[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LoggingSection {
+    pub level: String,
+    /**
+    human or json, see LogFormat.
+    */
+    pub format: String,
+}
+
+impl Default for LoggingSection {
+    fn default() -> Self {
+        LoggingSection {
+            level: String::from("debug"),
+            format: LogFormat::default().to_string(),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AdminSection {
+    /**
+    Address of the admin HTTP API, see AdminApi. Disabled when left out.
+    */
+    pub listen_address: Option<String>,
+}
+
+#[derive(Clone, Debug, Default, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct MetricsSection {
+    /**
+    Address of the Prometheus endpoint, see MetricsServer. Disabled when left out.
+    */
+    pub listen_address: Option<String>,
+}
+
+#[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ListenerSection {
+    /**
+    Threads handling received messages, each peer sticks to one so its messages are handled in order.
+    */
+    pub workers: usize,
+}
+
+impl Default for ListenerSection {
+    fn default() -> ListenerSection {
+        ListenerSection {
+            workers: 1,
+        }
+    }
+}
+
+/**
+Bounds of the channels between the network broker and the listener.
+ */
+#[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ChannelsSection {
+    pub capacity: usize,
+    pub drop_policy: String,
+}
+
+/**
+What the stack runs on, see RuntimeMode.
+ */
+#[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RuntimeSection {
+    pub mode: String,
+}
+
+impl Default for RuntimeSection {
+    fn default() -> RuntimeSection {
+        RuntimeSection {
+            mode: RuntimeMode::default().to_string(),
+        }
+    }
+}
+
+impl Default for ChannelsSection {
+    fn default() -> ChannelsSection {
+        ChannelsSection {
+            capacity: DEFAULT_CHANNEL_CAPACITY,
+            drop_policy: DropPolicy::default().to_string(),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TracingSection {
+    /**
+    Log every span of a packet's hops with its timing, at debug level.
+    */
+    pub log_spans: bool,
+    /**
+    File the spans are appended to as OTLP JSON, see OtlpExporter.
+    */
+    pub otlp_file: Option<PathBuf>,
+    /**
+    OTLP/HTTP collector the spans are posted to as JSON, at most one of otlp_file and otlp_endpoint.
+    */
+    pub otlp_endpoint: Option<String>,
+}
+
+/**
+A static peer, settings left out are inherited from the health check section.
+ */
+#[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct PeerSection {
+    pub address: String,
+    #[serde(default)]
+    pub tags: BTreeSet<String>,
+    #[serde(default = "enabled_by_default")]
+    pub enabled: bool,
+    pub probe_interval_ms: Option<u64>,
+    pub probe_timeout_ms: Option<u64>,
+    pub max_lives: Option<u8>,
+    pub failure_policy: Option<String>,
+    pub priority: Option<u8>,
+}
+
+fn enabled_by_default() -> bool {
+    true
+}
+
+/**
+Command line arguments, every value is optional and overrides the file and environment.
+ */
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct CliArguments {
+    pub config_file: Option<PathBuf>,
+    /**
+    Validate the configuration, print it and exit instead of starting the server.
+    */
+    pub check_config: bool,
+    pub bind_address: Option<String>,
+    pub port: Option<String>,
+    pub data_dir: Option<PathBuf>,
+    pub store_backend: Option<String>,
+    pub log_level: Option<String>,
+    pub log_format: Option<String>,
+    pub admin_listen_address: Option<String>,
+    pub metrics_listen_address: Option<String>,
+    pub listener_workers: Option<String>,
+    pub channel_capacity: Option<String>,
+    pub channel_drop_policy: Option<String>,
+    pub runtime_mode: Option<String>,
+    pub otlp_file: Option<PathBuf>,
+    pub otlp_endpoint: Option<String>,
+    /**
+    Replaces the seeds from the file and environment when given.
+    */
+    pub seeds: Vec<String>,
+}
+
+impl CliArguments {
+    pub fn parse(args: impl IntoIterator<Item = String>) -> Result<CliArguments, ConfigurationError> {
+        let mut cli_arguments = CliArguments::default();
+        let mut args = args.into_iter();
+        while let Some(arg) = args.next() {
+            if arg == "--check-config" {
+                cli_arguments.check_config = true;
+                continue;
+            }
+            let mut value = || args.next().ok_or_else(|| ConfigurationError::Arguments(format!("Missing value for {}", arg)));
+            match arg.as_str() {
+                "--config" => cli_arguments.config_file = Some(PathBuf::from(value()?)),
+                "--bind" => cli_arguments.bind_address = Some(value()?),
+                "--port" => cli_arguments.port = Some(value()?),
+                "--data-dir" => cli_arguments.data_dir = Some(PathBuf::from(value()?)),
+                "--store-backend" => cli_arguments.store_backend = Some(value()?),
+                "--log-level" => cli_arguments.log_level = Some(value()?),
+                "--log-format" => cli_arguments.log_format = Some(value()?),
+                "--admin-listen" => cli_arguments.admin_listen_address = Some(value()?),
+                "--metrics-listen" => cli_arguments.metrics_listen_address = Some(value()?),
+                "--listener-workers" => cli_arguments.listener_workers = Some(value()?),
+                "--channel-capacity" => cli_arguments.channel_capacity = Some(value()?),
+                "--channel-drop-policy" => cli_arguments.channel_drop_policy = Some(value()?),
+                "--runtime" => cli_arguments.runtime_mode = Some(value()?),
+                "--otlp-file" => cli_arguments.otlp_file = Some(PathBuf::from(value()?)),
+                "--otlp-endpoint" => cli_arguments.otlp_endpoint = Some(value()?),
+                "--seed" => cli_arguments.seeds.push(value()?),
+                _ => return Err(ConfigurationError::Arguments(format!("Unknown argument {}", arg)))
+            }
+        }
+        Ok(cli_arguments)
+    }
+}
+
+impl Configuration {
+    /**
+    Builds the configuration from the file named by the cli or environment, then the environment, then the cli,
+    and validates the result. `env_var` looks up an environment variable, so tests don't have to touch the real one.
+    */
+    pub fn load(cli_arguments: &CliArguments, env_var: impl Fn(&str) -> Option<String>) -> Result<Configuration, ConfigurationError> {
+        let mut configuration = match config_file(cli_arguments, &env_var) {
+            Some(path) => Configuration::from_file(&path)?,
+            None => Configuration::default()
+        };
+
+        let mut errors = Vec::new();
+        configuration.apply_env(&env_var, &mut errors);
+        configuration.apply_cli(cli_arguments, &mut errors);
+        errors.extend(configuration.validate());
+        if errors.is_empty() {
+            Ok(configuration)
+        } else {
+            Err(ConfigurationError::Invalid(errors))
+        }
+    }
+
+    pub fn from_file(path: &Path) -> Result<Configuration, ConfigurationError> {
+        let contents = fs::read_to_string(path).map_err(|source| ConfigurationError::Read {
+            path: path.to_path_buf(),
+            source
+        })?;
+        toml::from_str(&contents).map_err(|err| ConfigurationError::Parse {
+            path: path.to_path_buf(),
+            message: err.to_string()
+        })
+    }
+
+    fn apply_env(&mut self, env_var: &impl Fn(&str) -> Option<String>, errors: &mut Vec<String>) {
+        if let Some(bind_address) = env_var(IP_ADDRESS_ENV_KEY) {
+            self.node.bind_address = bind_address;
+        }
+        if let Some(port) = env_var(UDP_PORT_ENV_KEY) {
+            parse_override(UDP_PORT_ENV_KEY, &port, &mut self.node.port, errors);
+        }
+        if let Some(data_dir) = env_var(DATA_DIR_ENV_KEY) {
+            self.node.data_dir = PathBuf::from(data_dir);
+        }
+        if let Some(store_backend) = env_var(STORE_BACKEND_ENV_KEY) {
+            self.node.store_backend = store_backend;
+        }
+        if let Some(seeds) = env_var(SEEDS_ENV_KEY) {
+            self.seeds = seeds.split(',')
+                .map(|seed| seed.trim().to_string())
+                .filter(|seed| !seed.is_empty())
+                .collect();
+        }
+        if let Some(unhealthy_ttl_secs) = env_var(UNHEALTHY_TTL_SECS_ENV_KEY) {
+            parse_override(UNHEALTHY_TTL_SECS_ENV_KEY, &unhealthy_ttl_secs, &mut self.reaper.unhealthy_ttl_secs, errors);
+        }
+        if let Some(tombstone_ttl_secs) = env_var(TOMBSTONE_TTL_SECS_ENV_KEY) {
+            parse_override(TOMBSTONE_TTL_SECS_ENV_KEY, &tombstone_ttl_secs, &mut self.reaper.tombstone_ttl_secs, errors);
+        }
+        if let Some(probe_interval_secs) = env_var(PROBE_INTERVAL_SECS_ENV_KEY) {
+            let mut secs: u64 = 0;
+            if parse_override(PROBE_INTERVAL_SECS_ENV_KEY, &probe_interval_secs, &mut secs, errors) {
+                self.health_check.probe_interval_ms = secs.saturating_mul(1000);
+            }
+        }
+        if let Some(probe_timeout_millis) = env_var(PROBE_TIMEOUT_MILLIS_ENV_KEY) {
+            parse_override(PROBE_TIMEOUT_MILLIS_ENV_KEY, &probe_timeout_millis, &mut self.health_check.probe_timeout_ms, errors);
+        }
+        if let Some(max_lives) = env_var(MAX_LIVES_ENV_KEY) {
+            parse_override(MAX_LIVES_ENV_KEY, &max_lives, &mut self.health_check.max_lives, errors);
+        }
+        if let Some(log_level) = env_var(LOG_LEVEL_ENV_KEY) {
+            self.logging.level = log_level;
+        }
+        if let Some(log_format) = env_var(LOG_FORMAT_ENV_KEY) {
+            self.logging.format = log_format;
+        }
+        if let Some(admin_listen_address) = env_var(ADMIN_LISTEN_ADDRESS_ENV_KEY) {
+            self.admin.listen_address = Some(admin_listen_address);
+        }
+        if let Some(metrics_listen_address) = env_var(METRICS_LISTEN_ADDRESS_ENV_KEY) {
+            self.metrics.listen_address = Some(metrics_listen_address);
+        }
+        if let Some(listener_workers) = env_var(LISTENER_WORKERS_ENV_KEY) {
+            parse_override(LISTENER_WORKERS_ENV_KEY, &listener_workers, &mut self.listener.workers, errors);
+        }
+        if let Some(channel_capacity) = env_var(CHANNEL_CAPACITY_ENV_KEY) {
+            parse_override(CHANNEL_CAPACITY_ENV_KEY, &channel_capacity, &mut self.channels.capacity, errors);
+        }
+        if let Some(channel_drop_policy) = env_var(CHANNEL_DROP_POLICY_ENV_KEY) {
+            self.channels.drop_policy = channel_drop_policy;
+        }
+        if let Some(runtime_mode) = env_var(RUNTIME_MODE_ENV_KEY) {
+            self.runtime.mode = runtime_mode;
+        }
+        if let Some(otlp_file) = env_var(OTLP_FILE_ENV_KEY) {
+            self.tracing.otlp_file = Some(PathBuf::from(otlp_file));
+        }
+        if let Some(otlp_endpoint) = env_var(OTLP_ENDPOINT_ENV_KEY) {
+            self.tracing.otlp_endpoint = Some(otlp_endpoint);
+        }
+    }
+
+    fn apply_cli(&mut self, cli_arguments: &CliArguments, errors: &mut Vec<String>) {
+        if let Some(bind_address) = &cli_arguments.bind_address {
+            self.node.bind_address = bind_address.clone();
+        }
+        if let Some(port) = &cli_arguments.port {
+            parse_override("--port", port, &mut self.node.port, errors);
+        }
+        if let Some(data_dir) = &cli_arguments.data_dir {
+            self.node.data_dir = data_dir.clone();
+        }
+        if let Some(store_backend) = &cli_arguments.store_backend {
+            self.node.store_backend = store_backend.clone();
+        }
+        if let Some(log_level) = &cli_arguments.log_level {
+            self.logging.level = log_level.clone();
+        }
+        if let Some(log_format) = &cli_arguments.log_format {
+            self.logging.format = log_format.clone();
+        }
+        if let Some(admin_listen_address) = &cli_arguments.admin_listen_address {
+            self.admin.listen_address = Some(admin_listen_address.clone());
+        }
+        if let Some(metrics_listen_address) = &cli_arguments.metrics_listen_address {
+            self.metrics.listen_address = Some(metrics_listen_address.clone());
+        }
+        if let Some(listener_workers) = &cli_arguments.listener_workers {
+            parse_override("--listener-workers", listener_workers, &mut self.listener.workers, errors);
+        }
+        if let Some(channel_capacity) = &cli_arguments.channel_capacity {
+            parse_override("--channel-capacity", channel_capacity, &mut self.channels.capacity, errors);
+        }
+        if let Some(channel_drop_policy) = &cli_arguments.channel_drop_policy {
+            self.channels.drop_policy = channel_drop_policy.clone();
+        }
+        if let Some(runtime_mode) = &cli_arguments.runtime_mode {
+            self.runtime.mode = runtime_mode.clone();
+        }
+        if let Some(otlp_file) = &cli_arguments.otlp_file {
+            self.tracing.otlp_file = Some(otlp_file.clone());
+        }
+        if let Some(otlp_endpoint) = &cli_arguments.otlp_endpoint {
+            self.tracing.otlp_endpoint = Some(otlp_endpoint.clone());
+        }
+        if !cli_arguments.seeds.is_empty() {
+            self.seeds = cli_arguments.seeds.clone();
+        }
+    }
+
+    /**
+    Returns one readable message per problem, empty when the configuration is valid.
+    */
+    pub fn validate(&self) -> Vec<String> {
+        let mut errors = Vec::new();
+
+        if IpAddr::from_str(&self.node.bind_address).is_err() {
+            errors.push(format!("node.bind_address [{}] is not a valid IP address", self.node.bind_address));
+        }
+        if let Err(err) = PeerStoreBackend::from_str(&self.node.store_backend) {
+            errors.push(format!("node.store_backend: {}", err));
+        }
+
+        let health_check = &self.health_check;
+        validate_probe_timings("health_check", health_check.probe_interval_ms, health_check.probe_timeout_ms, &mut errors);
+        if health_check.max_lives == 0 {
+            errors.push(String::from("health_check.max_lives must be at least 1"));
+        }
+        if let Err(err) = FailurePolicy::from_str(&health_check.failure_policy) {
+            errors.push(format!("health_check.failure_policy: {}", err));
+        }
+
+        if self.reaper.unhealthy_ttl_secs == 0 {
+            errors.push(String::from("reaper.unhealthy_ttl_secs must be greater than 0"));
+        }
+        if self.reaper.tombstone_ttl_secs == 0 {
+            errors.push(String::from("reaper.tombstone_ttl_secs must be greater than 0"));
+        }
+        if self.reaper.interval_secs == 0 {
+            errors.push(String::from("reaper.interval_secs must be greater than 0"));
+        }
+
+        for (i, shared_key) in self.security.shared_keys.iter().enumerate() {
+            if decode_hex(shared_key).map(|key| key.len()) != Some(SECURITY_KEY_SIZE_BYTES) {
+                errors.push(format!("security.shared_keys[{}] must be {} hex characters ({} bytes)", i, SECURITY_KEY_SIZE_BYTES * 2, SECURITY_KEY_SIZE_BYTES));
+            }
+        }
+
+        if LevelFilter::from_str(&self.logging.level).is_err() {
+            errors.push(format!("logging.level [{}] is not one of off, error, warn, info, debug, trace", self.logging.level));
+        }
+        if LogFormat::from_str(&self.logging.format).is_err() {
+            errors.push(format!("logging.format [{}] is not one of human, json", self.logging.format));
+        }
+
+        if let Some(listen_address) = &self.admin.listen_address {
+            if SocketAddr::from_str(listen_address).is_err() {
+                errors.push(format!("admin.listen_address [{}] is not a valid ip:port address", listen_address));
+            }
+        }
+
+        if let Some(listen_address) = &self.metrics.listen_address {
+            if SocketAddr::from_str(listen_address).is_err() {
+                errors.push(format!("metrics.listen_address [{}] is not a valid ip:port address", listen_address));
+            }
+        }
+
+        if self.listener.workers == 0 {
+            errors.push(String::from("listener.workers must be at least 1"));
+        }
+
+        if self.channels.capacity == 0 {
+            errors.push(String::from("channels.capacity must be at least 1"));
+        }
+        if let Err(err) = DropPolicy::from_str(&self.channels.drop_policy) {
+            errors.push(format!("channels.drop_policy: {}", err));
+        }
+
+        match RuntimeMode::from_str(&self.runtime.mode) {
+            #[cfg(feature = "mio")]
+            Ok(RuntimeMode::EventLoop) => {
+                if self.listener.workers > 1 {
+                    errors.push(String::from("listener.workers must be 1 with runtime.mode event_loop, handlers run on the loop"));
+                }
+                if DropPolicy::from_str(&self.channels.drop_policy) == Ok(DropPolicy::Block) {
+                    errors.push(String::from("channels.drop_policy block can't be used with runtime.mode event_loop, the loop would wait on itself"));
+                }
+            }
+            Ok(_) => {}
+            Err(err) => errors.push(format!("runtime.mode: {}", err))
+        }
+
+        if let Some(otlp_endpoint) = &self.tracing.otlp_endpoint {
+            if SocketAddr::from_str(otlp_endpoint).is_err() {
+                errors.push(format!("tracing.otlp_endpoint [{}] is not a valid ip:port address", otlp_endpoint));
+            }
+            if self.tracing.otlp_file.is_some() {
+                errors.push(String::from("tracing.otlp_file and tracing.otlp_endpoint can't both be set"));
+            }
+        }
+
+        for (i, seed) in self.seeds.iter().enumerate() {
+            if SocketAddr::from_str(seed).is_err() {
+                errors.push(format!("seeds[{}] [{}] is not a valid ip:port address", i, seed));
+            }
+        }
+
+        let mut peer_addresses = HashSet::new();
+        for (i, peer) in self.peers.iter().enumerate() {
+            let name = format!("peers[{}]", i);
+            if let Ok(address) = SocketAddr::from_str(&peer.address) {
+                if !peer_addresses.insert(address) {
+                    errors.push(format!("{}.address [{}] is listed more than once", name, peer.address));
+                }
+            }
+            errors.extend(peer.validate(&name, Duration::from_millis(health_check.probe_interval_ms), Duration::from_millis(health_check.probe_timeout_ms)));
+        }
+
+        errors
+    }
+
+    // Conversions below expect a configuration that passed validation, as returned by load
+
+    pub fn bind_addr(&self) -> SocketAddr {
+        let ip = IpAddr::from_str(&self.node.bind_address).expect("Validated bind address");
+        SocketAddr::new(ip, self.node.port)
+    }
+
+    pub fn store_backend(&self) -> PeerStoreBackend {
+        self.node.store_backend.parse().expect("Validated store backend")
+    }
+
+    pub fn log_level(&self) -> LevelFilter {
+        self.logging.level.parse().expect("Validated log level")
+    }
+
+    pub fn log_format(&self) -> LogFormat {
+        self.logging.format.parse().expect("Validated log format")
+    }
+
+    pub fn admin_listen_addr(&self) -> Option<SocketAddr> {
+        self.admin.listen_address.as_ref().map(|listen_address| listen_address.parse().expect("Validated admin listen address"))
+    }
+
+    pub fn metrics_listen_addr(&self) -> Option<SocketAddr> {
+        self.metrics.listen_address.as_ref().map(|listen_address| listen_address.parse().expect("Validated metrics listen address"))
+    }
+
+    pub fn channel_bounds(&self) -> ChannelBounds {
+        ChannelBounds {
+            capacity: self.channels.capacity,
+            drop_policy: self.channels.drop_policy.parse().expect("Validated drop policy"),
+        }
+    }
+
+    pub fn runtime_mode(&self) -> RuntimeMode {
+        self.runtime.mode.parse().expect("Validated runtime mode")
+    }
+
+    /**
+    Where spans are exported to, None when they aren't.
+    */
+    pub fn otlp_destination(&self) -> Option<ExportDestination> {
+        match (&self.tracing.otlp_file, &self.tracing.otlp_endpoint) {
+            (Some(otlp_file), _) => Some(ExportDestination::File(otlp_file.clone())),
+            (None, Some(otlp_endpoint)) => Some(ExportDestination::Collector(otlp_endpoint.parse().expect("Validated otlp endpoint"))),
+            (None, None) => None
+        }
+    }
+
+    pub fn health_check_defaults(&self) -> HealthCheckDefaults {
+        HealthCheckDefaults {
+            probe_interval: Duration::from_millis(self.health_check.probe_interval_ms),
+            probe_timeout: Duration::from_millis(self.health_check.probe_timeout_ms),
+            max_lives: self.health_check.max_lives,
+            failure_policy: self.health_check.failure_policy.parse().expect("Validated failure policy"),
+            priority: self.health_check.priority,
+        }
+    }
+
+    pub fn reaper_configuration(&self) -> ReaperConfiguration {
+        ReaperConfiguration {
+            unhealthy_ttl: Duration::from_secs(self.reaper.unhealthy_ttl_secs),
+            tombstone_ttl: Duration::from_secs(self.reaper.tombstone_ttl_secs),
+            interval: Duration::from_secs(self.reaper.interval_secs),
+        }
+    }
+
+    /**
+    Seeds and peers, a peer listed under both keeps its peer settings.
+    */
+    pub fn static_peers(&self) -> Vec<StaticPeer> {
+        let mut static_peers: Vec<StaticPeer> = self.peers.iter().map(PeerSection::static_peer).collect();
+        for seed in &self.seeds {
+            let addr: SocketAddr = seed.parse().expect("Validated seed address");
+            if !static_peers.iter().any(|static_peer| static_peer.addr == addr) {
+                static_peers.push(StaticPeer {
+                    addr,
+                    configuration: HealthCheckConfiguration {
+                        health_check_port: addr.port(),
+                        ..HealthCheckConfiguration::default()
+                    }
+                });
+            }
+        }
+        static_peers
+    }
+
+    /**
+    Copy of the configuration that is safe to print, with every shared key replaced.
+    */
+    pub fn redacted(&self) -> Configuration {
+        let mut redacted = self.clone();
+        for shared_key in &mut redacted.security.shared_keys {
+            *shared_key = String::from(REDACTED);
+        }
+        redacted
+    }
+}
+
+impl PeerSection {
+    /**
+    Returns one readable message per problem, prefixed with `name`. Timings left out are checked with the defaults they inherit.
+    */
+    pub fn validate(&self, name: &str, default_probe_interval: Duration, default_probe_timeout: Duration) -> Vec<String> {
+        let mut errors = Vec::new();
+        if SocketAddr::from_str(&self.address).is_err() {
+            errors.push(format!("{}.address [{}] is not a valid ip:port address", name, self.address));
+        }
+        validate_probe_timings(name,
+                               self.probe_interval_ms.unwrap_or(default_probe_interval.as_millis() as u64),
+                               self.probe_timeout_ms.unwrap_or(default_probe_timeout.as_millis() as u64),
+                               &mut errors);
+        if self.max_lives == Some(0) {
+            errors.push(format!("{}.max_lives must be at least 1", name));
+        }
+        if let Some(Err(err)) = self.failure_policy.as_deref().map(FailurePolicy::from_str) {
+            errors.push(format!("{}.failure_policy: {}", name, err));
+        }
+        errors
+    }
+
+    /**
+    Expects a peer that passed validation.
+    */
+    pub fn static_peer(&self) -> StaticPeer {
+        let addr: SocketAddr = self.address.parse().expect("Validated peer address");
+        StaticPeer {
+            addr,
+            configuration: HealthCheckConfiguration {
+                health_check_port: addr.port(),
+                tags: self.tags.clone(),
+                enabled: self.enabled,
+                probe_interval: self.probe_interval_ms.map(Duration::from_millis),
+                probe_timeout: self.probe_timeout_ms.map(Duration::from_millis),
+                max_lives: self.max_lives,
+                failure_policy: self.failure_policy.as_ref().map(|failure_policy| failure_policy.parse().expect("Validated failure policy")),
+                priority: self.priority,
+            }
+        }
+    }
+}
+
+/**
+The config file named on the command line, or else in the environment, if any.
+ */
+pub fn config_file(cli_arguments: &CliArguments, env_var: &impl Fn(&str) -> Option<String>) -> Option<PathBuf> {
+    cli_arguments.config_file.clone()
+        .or_else(|| env_var(CONFIG_FILE_ENV_KEY).map(PathBuf::from))
+}
+
+/**
+Parses `value` into `target`, recording an error naming `source` instead when it doesn't parse.
+ */
+fn parse_override<T: FromStr>(source: &str, value: &str, target: &mut T, errors: &mut Vec<String>) -> bool {
+    match value.trim().parse() {
+        Ok(parsed) => {
+            *target = parsed;
+            true
+        }
+        Err(_) => {
+            errors.push(format!("{} [{}] is not a valid {}", source, value, std::any::type_name::<T>()));
+            false
+        }
+    }
+}
+
+fn validate_probe_timings(name: &str, probe_interval_ms: u64, probe_timeout_ms: u64, errors: &mut Vec<String>) {
+    if probe_interval_ms == 0 {
+        errors.push(format!("{}.probe_interval_ms must be greater than 0", name));
+    }
+    if probe_timeout_ms == 0 {
+        errors.push(format!("{}.probe_timeout_ms must be greater than 0", name));
+    } else if probe_timeout_ms >= probe_interval_ms {
+        errors.push(format!("{}.probe_timeout_ms ({}) must be less than the probe interval ({})", name, probe_timeout_ms, probe_interval_ms));
+    }
+}
+
+fn decode_hex(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| hex.get(i..i + 2).and_then(|byte| u8::from_str_radix(byte, 16).ok()))
+        .collect()
+}
+
+#[cfg(test)]
+mod config_tests {
+    use std::collections::HashMap;
+    use std::net::SocketAddr;
+    use std::path::PathBuf;
+    use std::time::Duration;
+    use uuid::Uuid;
+    use crate::channel::{ChannelBounds, DEFAULT_CHANNEL_CAPACITY, DropPolicy};
+    use crate::config::{CHANNEL_DROP_POLICY_ENV_KEY, CliArguments, Configuration, ConfigurationError, IP_ADDRESS_ENV_KEY, LOG_FORMAT_ENV_KEY, OTLP_FILE_ENV_KEY, UDP_PORT_ENV_KEY};
+    use crate::health_check_network_broker::RuntimeMode;
+    use crate::logging::LogFormat;
+    use crate::trace::ExportDestination;
+    use crate::network::FailurePolicy;
+
+    const EXAMPLE_CONFIG: &str = include_str!("../health_check.example.toml");
+
+    fn no_env(_: &str) -> Option<String> {
+        None
+    }
+
+    fn args(args: &[&str]) -> CliArguments {
+        CliArguments::parse(args.iter().map(|arg| arg.to_string())).unwrap()
+    }
+
+    fn invalid_errors(result: Result<Configuration, ConfigurationError>) -> Vec<String> {
+        match result {
+            Err(ConfigurationError::Invalid(errors)) => errors,
+            other => panic!("Expected validation errors, got {:?}", other)
+        }
+    }
+
+    #[test]
+    fn example_config_file_is_valid() {
+        let configuration: Configuration = toml::from_str(EXAMPLE_CONFIG).unwrap();
+        assert!(configuration.validate().is_empty(), "We expect the example config to pass validation: {:?}", configuration.validate());
+
+        let static_peers = configuration.static_peers();
+        assert_eq!(3, static_peers.len());
+        let fast_peer = &static_peers[0].configuration;
+        assert_eq!(Some(Duration::from_millis(500)), fast_peer.probe_timeout);
+        assert_eq!(Some(FailurePolicy::FailFast), fast_peer.failure_policy);
+        assert!(!static_peers[1].configuration.enabled);
+        assert_eq!("127.0.0.1:3451".parse::<SocketAddr>().unwrap(), static_peers[2].addr);
+    }
+
+    #[test]
+    fn cli_overrides_env_overrides_file() {
+        let path = std::env::temp_dir().join(format!("swizzy_decent_config_{}.toml", Uuid::new_v4()));
+        std::fs::write(&path, "[node]\nbind_address = \"10.0.0.1\"\nport = 4000\ndata_dir = \"from_file\"\n").unwrap();
+        let env = HashMap::from([
+            (IP_ADDRESS_ENV_KEY, "10.0.0.2"),
+            (UDP_PORT_ENV_KEY, "4001"),
+            (LOG_FORMAT_ENV_KEY, "json"),
+            (OTLP_FILE_ENV_KEY, "spans.jsonl"),
+            (CHANNEL_DROP_POLICY_ENV_KEY, "drop_oldest"),
+        ]);
+        let cli_arguments = args(&["--config", path.to_str().unwrap(), "--port", "4002"]);
+
+        let configuration = Configuration::load(&cli_arguments, |key| env.get(key).map(|value| value.to_string())).unwrap();
+        assert_eq!("10.0.0.2:4002".parse::<SocketAddr>().unwrap(), configuration.bind_addr());
+        assert_eq!(LogFormat::Json, configuration.log_format());
+        assert_eq!(Some(ExportDestination::File(PathBuf::from("spans.jsonl"))), configuration.otlp_destination());
+        assert_eq!(ChannelBounds { capacity: DEFAULT_CHANNEL_CAPACITY, drop_policy: DropPolicy::DropOldest }, configuration.channel_bounds());
+        assert_eq!(PathBuf::from("from_file"), configuration.node.data_dir);
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn validation_reports_every_problem() {
+        let path = std::env::temp_dir().join(format!("swizzy_decent_config_{}.toml", Uuid::new_v4()));
+        std::fs::write(&path, "seeds = [\"nowhere\"]\n[health_check]\nprobe_timeout_ms = 6000\nfailure_policy = \"sometimes\"\n[[peers]]\naddress = \"127.0.0.1:3451\"\n[[peers]]\naddress = \"127.0.0.1:3451\"\nmax_lives = 0\n").unwrap();
+        let cli_arguments = args(&["--config", path.to_str().unwrap(), "--bind", "localhost", "--port", "70000"]);
+
+        let errors = invalid_errors(Configuration::load(&cli_arguments, no_env));
+        assert_eq!(vec![
+            String::from("--port [70000] is not a valid u16"),
+            String::from("node.bind_address [localhost] is not a valid IP address"),
+            String::from("health_check.probe_timeout_ms (6000) must be less than the probe interval (5000)"),
+            String::from("health_check.failure_policy: Unknown failure policy [sometimes], expected one of gradual, reset_on_success, fail_fast"),
+            String::from("seeds[0] [nowhere] is not a valid ip:port address"),
+            String::from("peers[0].probe_timeout_ms (6000) must be less than the probe interval (5000)"),
+            String::from("peers[1].address [127.0.0.1:3451] is listed more than once"),
+            String::from("peers[1].probe_timeout_ms (6000) must be less than the probe interval (5000)"),
+            String::from("peers[1].max_lives must be at least 1"),
+        ], errors);
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn runtime_modes_need_their_feature() {
+        assert_eq!(RuntimeMode::Threads, Configuration::load(&args(&[]), no_env).unwrap().runtime_mode());
+
+        let result = Configuration::load(&args(&["--runtime", "tokio"]), no_env);
+        #[cfg(feature = "tokio")]
+        assert_eq!(RuntimeMode::Tokio, result.unwrap().runtime_mode());
+        #[cfg(not(feature = "tokio"))]
+        assert_eq!(vec![String::from("runtime.mode: Runtime mode [tokio] needs a build with the tokio feature")], invalid_errors(result),
+                   "We expect a mode that isn't built in to be rejected up front");
+
+        let result = Configuration::load(&args(&["--runtime", "event_loop", "--listener-workers", "2", "--channel-drop-policy", "block"]), no_env);
+        #[cfg(feature = "mio")]
+        assert_eq!(vec![
+            String::from("listener.workers must be 1 with runtime.mode event_loop, handlers run on the loop"),
+            String::from("channels.drop_policy block can't be used with runtime.mode event_loop, the loop would wait on itself"),
+        ], invalid_errors(result), "We expect settings that need more than one thread to be rejected");
+        #[cfg(not(feature = "mio"))]
+        assert_eq!(vec![String::from("runtime.mode: Runtime mode [event_loop] needs a build with the mio feature")], invalid_errors(result));
+    }
+
+    #[test]
+    fn unknown_keys_and_arguments_are_rejected() {
+        let path = std::env::temp_dir().join(format!("swizzy_decent_config_{}.toml", Uuid::new_v4()));
+        std::fs::write(&path, "[node]\nprot = 3450\n").unwrap();
+        let result = Configuration::load(&args(&["--config", path.to_str().unwrap()]), no_env);
+        assert!(matches!(result, Err(ConfigurationError::Parse { .. })), "We expect a typo in a key to be reported, got {:?}", result);
+        std::fs::remove_file(path).unwrap();
+
+        assert!(matches!(CliArguments::parse(vec![String::from("--verbose")]), Err(ConfigurationError::Arguments(_))));
+        assert!(matches!(CliArguments::parse(vec![String::from("--port")]), Err(ConfigurationError::Arguments(_))));
+        assert!(args(&["--check-config"]).check_config);
+    }
+
+    #[test]
+    fn shared_keys_are_redacted_for_printing() {
+        let mut configuration = Configuration::default();
+        configuration.security.shared_keys = vec!["ab".repeat(32), "cd".repeat(32)];
+
+        let printed = toml::to_string_pretty(&configuration.redacted()).unwrap();
+        assert!(!printed.contains(&"ab".repeat(32)) && !printed.contains(&"cd".repeat(32)), "We expect no key in {}", printed);
+        assert_eq!(vec!["<redacted>", "<redacted>"], configuration.redacted().security.shared_keys);
+    }
+}
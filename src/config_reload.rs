@@ -0,0 +1,290 @@
+// Config reload
+// Reloads the config file on SIGHUP, or when its modification time changes, and applies the difference to the running stack
+// A config that fails to load or validate is rejected as a whole, the running settings are left untouched
+// The node section (bind address, port, data directory, store backend) and security keys are only read at startup
+
+use std::path::Path;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, SystemTime};
+use std::{env, fs, thread};
+use log::{error, info, warn};
+
+use crate::config::{CliArguments, config_file, Configuration, ConfigurationError};
+use crate::health_check_network_broker::LiveSettings;
+use crate::health_check_scheduler::StaticPeersDiff;
+use crate::network::NetworkDetailsStore;
+
+/**
+How often the config file is checked for changes.
+ */
+pub const RELOAD_POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+#[derive(Debug, Default, Eq, PartialEq)]
+pub struct ReloadSummary {
+    pub static_peers: StaticPeersDiff,
+    pub health_check_defaults_changed: bool,
+    pub reaper_configuration_changed: bool,
+    pub log_level_changed: bool,
+    /**
+    Sections that changed but are only read at startup.
+    */
+    pub requires_restart: Vec<&'static str>,
+}
+
+pub struct ConfigurationReloader {
+    cli_arguments: CliArguments,
+    /**
+    The config currently applied, reloads are diffed against it.
+    */
+    configuration: Configuration,
+    live_settings: LiveSettings,
+    network_details_store: Arc<NetworkDetailsStore>,
+}
+
+impl ConfigurationReloader {
+    pub fn new(cli_arguments: CliArguments,
+               configuration: Configuration,
+               live_settings: LiveSettings,
+               network_details_store: Arc<NetworkDetailsStore>) -> ConfigurationReloader {
+        ConfigurationReloader {
+            cli_arguments,
+            configuration,
+            live_settings,
+            network_details_store,
+        }
+    }
+
+    pub fn run(mut self) {
+        let reload_requested = Arc::new(AtomicBool::new(false));
+        #[cfg(unix)]
+        if let Err(err) = signal_hook::flag::register(signal_hook::consts::SIGHUP, reload_requested.clone()) {
+            error!("Failed to register SIGHUP handler, config is only reloaded when the file changes: {}", err);
+        }
+
+        let env_var = |key: &str| env::var(key).ok();
+        let mut last_modified = config_file(&self.cli_arguments, &env_var).and_then(|path| modified(&path));
+        loop {
+            thread::sleep(RELOAD_POLL_INTERVAL);
+            let modified_at = config_file(&self.cli_arguments, &env_var).and_then(|path| modified(&path));
+            let file_changed = modified_at != last_modified;
+            last_modified = modified_at;
+            if !reload_requested.swap(false, Ordering::AcqRel) && !file_changed {
+                continue;
+            }
+
+            match self.reload(env_var) {
+                Ok(summary) => {
+                    info!("Reloaded config: {:?}", summary);
+                    if !summary.requires_restart.is_empty() {
+                        warn!("Changes to {:?} only take effect after a restart", summary.requires_restart);
+                    }
+                }
+                Err(err) => error!("Rejected config reload, keeping the running config. {}", err)
+            }
+        }
+    }
+
+    /**
+    Loads the config again and applies what changed. Nothing is applied if the new config doesn't load or validate.
+    */
+    pub fn reload(&mut self, env_var: impl Fn(&str) -> Option<String>) -> Result<ReloadSummary, ConfigurationError> {
+        let configuration = Configuration::load(&self.cli_arguments, env_var)?;
+        let summary = self.apply(&configuration);
+        self.configuration = configuration;
+        Ok(summary)
+    }
+
+    fn apply(&self, configuration: &Configuration) -> ReloadSummary {
+        let mut summary = ReloadSummary::default();
+        if self.configuration.node != configuration.node {
+            summary.requires_restart.push("node");
+        }
+        if self.configuration.security != configuration.security {
+            summary.requires_restart.push("security");
+        }
+        // The logger is installed once, only its level can change
+        if self.configuration.logging.format != configuration.logging.format {
+            summary.requires_restart.push("logging.format");
+        }
+        if self.configuration.admin != configuration.admin {
+            summary.requires_restart.push("admin");
+        }
+        if self.configuration.metrics != configuration.metrics {
+            summary.requires_restart.push("metrics");
+        }
+        if self.configuration.listener != configuration.listener {
+            summary.requires_restart.push("listener");
+        }
+        if self.configuration.channels != configuration.channels {
+            summary.requires_restart.push("channels");
+        }
+        if self.configuration.runtime != configuration.runtime {
+            summary.requires_restart.push("runtime");
+        }
+        if self.configuration.tracing != configuration.tracing {
+            summary.requires_restart.push("tracing");
+        }
+
+        let health_check_defaults = configuration.health_check_defaults();
+        let mut live_health_check_defaults = self.live_settings.health_check_defaults.write().unwrap();
+        if *live_health_check_defaults != health_check_defaults {
+            *live_health_check_defaults = health_check_defaults;
+            summary.health_check_defaults_changed = true;
+        }
+        drop(live_health_check_defaults);
+
+        let reaper_configuration = configuration.reaper_configuration();
+        let mut live_reaper_configuration = self.live_settings.reaper_configuration.write().unwrap();
+        if *live_reaper_configuration != reaper_configuration {
+            *live_reaper_configuration = reaper_configuration;
+            summary.reaper_configuration_changed = true;
+        }
+        drop(live_reaper_configuration);
+
+        if self.configuration.logging.level != configuration.logging.level {
+            log::set_max_level(configuration.log_level());
+            summary.log_level_changed = true;
+        }
+
+        let static_peers = &self.live_settings.static_peers;
+        summary.static_peers = static_peers.replace(configuration.static_peers());
+        // Added peers are picked up by the scheduler on its next tick, known peers take their new settings now,
+        // including one added back after it was removed and disabled
+        for addr in summary.static_peers.updated.iter().chain(&summary.static_peers.added) {
+            let (Ok(record), Some(peer_configuration)) = (self.network_details_store.get_network_details_by_socket_addr(addr), static_peers.get(addr)) else {
+                continue;
+            };
+            let _ = self.network_details_store.update_network_details(&record.node_id, |record| {
+                record.health_check.configuration = peer_configuration;
+            });
+        }
+        // Peers dropped from the config are disabled so they stop being probed, their identity and history are kept
+        // in case the node hears from them again
+        for addr in &summary.static_peers.removed {
+            let Ok(record) = self.network_details_store.get_network_details_by_socket_addr(addr) else {
+                continue;
+            };
+            let _ = self.network_details_store.update_network_details(&record.node_id, |record| {
+                record.health_check.configuration.enabled = false;
+            });
+        }
+        summary
+    }
+}
+
+fn modified(path: &Path) -> Option<SystemTime> {
+    fs::metadata(path).and_then(|metadata| metadata.modified()).ok()
+}
+
+#[cfg(test)]
+mod config_reload_tests {
+    use std::fs;
+    use std::net::{IpAddr, SocketAddr};
+    use std::path::Path;
+    use std::sync::{Arc, RwLock};
+    use std::time::{Duration, Instant, UNIX_EPOCH};
+    use uuid::Uuid;
+    use crate::channel::metered_channel;
+    use crate::config::{CliArguments, Configuration, ConfigurationError};
+    use crate::config_reload::ConfigurationReloader;
+    use crate::health_check_network_broker::LiveSettings;
+    use crate::health_check_scheduler::{HealthCheckScheduler, InFlightProbes, StaticPeers};
+    use crate::health_history::HealthHistory;
+    use crate::metrics::Metrics;
+    use crate::network::{HealthCheck, HealthCheckConfiguration, HealthStatus, HealthStatusDetails, IP, NetworkDetails, NetworkDetailsStore};
+
+    fn no_env(_: &str) -> Option<String> {
+        None
+    }
+
+    fn dummy_record(port: u16) -> NetworkDetails {
+        NetworkDetails {
+            node_id: Uuid::new_v4(),
+            addr: IpAddr::V4(IP),
+            last_seen: UNIX_EPOCH,
+            status_since: UNIX_EPOCH,
+            stale: false,
+            health_history: HealthHistory::default(),
+            health_check: HealthCheck {
+                status_details: HealthStatusDetails {
+                    current_status: HealthStatus::Healthy,
+                    lives_remaining: 3,
+                },
+                configuration: HealthCheckConfiguration {
+                    health_check_port: port,
+                    ..HealthCheckConfiguration::default()
+                }
+            }
+        }
+    }
+
+    fn reloader(path: &Path, store: Arc<NetworkDetailsStore>) -> ConfigurationReloader {
+        let cli_arguments = CliArguments {
+            config_file: Some(path.to_path_buf()),
+            ..CliArguments::default()
+        };
+        let configuration = Configuration::load(&cli_arguments, no_env).unwrap();
+        let live_settings = LiveSettings {
+            health_check_defaults: Arc::new(RwLock::new(configuration.health_check_defaults())),
+            static_peers: Arc::new(StaticPeers::new(configuration.static_peers())),
+            reaper_configuration: Arc::new(RwLock::new(configuration.reaper_configuration())),
+        };
+        ConfigurationReloader::new(cli_arguments, configuration, live_settings, store)
+    }
+
+    #[test]
+    fn reload_applies_the_diff_to_the_running_stack() {
+        let path = std::env::temp_dir().join(format!("swizzy_decent_reload_{}.toml", Uuid::new_v4()));
+        fs::write(&path, "[[peers]]\naddress = \"127.0.0.1:3451\"\n[[peers]]\naddress = \"127.0.0.1:3452\"\n").unwrap();
+        let store = Arc::new(NetworkDetailsStore::new());
+        let removed = dummy_record(3451);
+        let updated = dummy_record(3452);
+        store.put_network_details(&removed);
+        store.put_network_details(&updated);
+        let mut reloader = reloader(&path, store.clone());
+
+        fs::write(&path, "[health_check]\nprobe_timeout_ms = 1000\n[[peers]]\naddress = \"127.0.0.1:3452\"\npriority = 5\n[[peers]]\naddress = \"127.0.0.1:3453\"\n").unwrap();
+        let summary = reloader.reload(no_env).unwrap();
+
+        let addr = |port: u16| SocketAddr::new(IpAddr::V4(IP), port);
+        assert_eq!(vec![addr(3453)], summary.static_peers.added);
+        assert_eq!(vec![addr(3451)], summary.static_peers.removed);
+        assert_eq!(vec![addr(3452)], summary.static_peers.updated);
+        assert!(summary.health_check_defaults_changed);
+        assert!(!summary.reaper_configuration_changed);
+        assert!(summary.requires_restart.is_empty());
+        assert_eq!(Duration::from_millis(1000), reloader.live_settings.health_check_defaults.read().unwrap().probe_timeout);
+        let (sender, receiver) = metered_channel();
+        let mut scheduler = HealthCheckScheduler::new(reloader.live_settings.health_check_defaults.clone(),
+                                                      sender,
+                                                      store.clone(),
+                                                      Arc::new(InFlightProbes::new()),
+                                                      reloader.live_settings.static_peers.clone(),
+                                                      Arc::new(Metrics::default()));
+        scheduler.tick(Instant::now(), UNIX_EPOCH);
+        let probed: Vec<SocketAddr> = receiver.try_iter().map(|probe| probe.remote_addr).collect();
+        assert!(!probed.contains(&addr(3451)), "We expect peers removed from the config to stop being probed, probed {:?}", probed);
+        assert!(probed.contains(&addr(3452)) && probed.contains(&addr(3453)));
+        assert_eq!(Some(5), store.get_network_details_by_node_id(&updated.node_id).unwrap().health_check.configuration.priority);
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn invalid_reload_is_rejected_as_a_whole() {
+        let path = std::env::temp_dir().join(format!("swizzy_decent_reload_{}.toml", Uuid::new_v4()));
+        fs::write(&path, "[[peers]]\naddress = \"127.0.0.1:3451\"\n").unwrap();
+        let store = Arc::new(NetworkDetailsStore::new());
+        let mut reloader = reloader(&path, store);
+
+        // Valid peers and defaults, but one bad peer address
+        fs::write(&path, "[health_check]\nprobe_timeout_ms = 1000\n[[peers]]\naddress = \"127.0.0.1:3452\"\n[[peers]]\naddress = \"nowhere\"\n").unwrap();
+        let result = reloader.reload(no_env);
+
+        assert!(matches!(result, Err(ConfigurationError::Invalid(_))));
+        assert_eq!(Duration::from_millis(2000), reloader.live_settings.health_check_defaults.read().unwrap().probe_timeout);
+        let static_peers: Vec<SocketAddr> = reloader.live_settings.static_peers.list().iter().map(|static_peer| static_peer.addr).collect();
+        assert_eq!(vec![SocketAddr::new(IpAddr::V4(IP), 3451)], static_peers);
+        fs::remove_file(path).unwrap();
+    }
+}
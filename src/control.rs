@@ -0,0 +1,241 @@
+// Control socket
+// A running node listens on a Unix socket in its data directory, so the peers, add-peer and remove-peer commands can reach it
+// One JSON request per line, answered by one JSON response per line
+// Unix sockets are unix only, elsewhere the commands report that they can't reach the node
+
+use std::io;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use log::info;
+use serde::{Deserialize, Serialize};
+#[cfg(unix)]
+use std::{fs, io::{BufRead, BufReader, Write}, os::unix::net::{UnixListener, UnixStream}};
+#[cfg(unix)]
+use log::warn;
+
+use crate::health_check_scheduler::{StaticPeer, StaticPeers};
+use crate::network::{NetworkDetails, NetworkDetailsStore};
+
+pub const CONTROL_SOCKET_FILE_NAME: &str = "control.sock";
+
+#[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
+pub enum ControlRequest {
+    Peers,
+    AddPeer(StaticPeer),
+    RemovePeer {
+        addr: SocketAddr
+    },
+}
+
+#[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
+pub enum ControlResponse {
+    Peers(Vec<NetworkDetails>),
+    PeerAdded {
+        addr: SocketAddr,
+        /**
+        False when a peer added earlier at the same address was replaced.
+        */
+        new: bool
+    },
+    PeerRemoved {
+        addr: SocketAddr
+    },
+    Error(String),
+}
+
+pub fn control_socket_path(data_dir: &Path) -> PathBuf {
+    data_dir.join(CONTROL_SOCKET_FILE_NAME)
+}
+
+/**
+Applies control requests to the running stack, shared by the control socket and the admin API.
+ */
+#[derive(Clone, Debug)]
+pub struct PeerControl {
+    static_peers: Arc<StaticPeers>,
+    network_details_store: Arc<NetworkDetailsStore>,
+}
+
+impl PeerControl {
+    pub fn new(static_peers: Arc<StaticPeers>, network_details_store: Arc<NetworkDetailsStore>) -> PeerControl {
+        PeerControl {
+            static_peers,
+            network_details_store,
+        }
+    }
+
+    pub fn handle(&self, request: ControlRequest) -> ControlResponse {
+        match request {
+            ControlRequest::Peers => ControlResponse::Peers(self.network_details_store.get_all_network_details()),
+            ControlRequest::AddPeer(static_peer) => {
+                let addr = static_peer.addr;
+                let configuration = static_peer.configuration.clone();
+                let new = self.static_peers.add(static_peer);
+                // A peer we already know takes the new settings now, an unknown one is probed on the scheduler's next tick
+                if let Ok(record) = self.network_details_store.get_network_details_by_socket_addr(&addr) {
+                    let _ = self.network_details_store.update_network_details(&record.node_id, |record| {
+                        record.health_check.configuration = configuration;
+                    });
+                }
+                info!("Added peer {}", addr);
+                ControlResponse::PeerAdded { addr, new }
+            }
+            ControlRequest::RemovePeer { addr } => {
+                let static_peer_removed = self.static_peers.remove(&addr);
+                let record_removed = self.network_details_store.delete_network_details_by_socket_addr(&addr).is_ok();
+                if !static_peer_removed && !record_removed {
+                    return ControlResponse::Error(format!("Unknown peer {}", addr));
+                }
+                info!("Removed peer {}", addr);
+                ControlResponse::PeerRemoved { addr }
+            }
+        }
+    }
+}
+
+#[cfg(unix)]
+pub struct ControlServer {
+    listener: UnixListener,
+    path: PathBuf,
+    peer_control: PeerControl,
+}
+
+#[cfg(unix)]
+impl ControlServer {
+    /**
+    Binds the socket at `path`, replacing a socket file left behind by a node that didn't shut down cleanly.
+    */
+    pub fn bind(path: &Path, peer_control: PeerControl) -> io::Result<ControlServer> {
+        if UnixStream::connect(path).is_ok() {
+            return Err(io::Error::new(io::ErrorKind::AddrInUse, format!("Another node is listening on {}", path.display())));
+        }
+        match fs::remove_file(path) {
+            Err(err) if err.kind() != io::ErrorKind::NotFound => return Err(err),
+            _ => {}
+        }
+        Ok(ControlServer {
+            listener: UnixListener::bind(path)?,
+            path: path.to_path_buf(),
+            peer_control,
+        })
+    }
+
+    pub fn run(self) {
+        info!("Control socket listening on {}", self.path.display());
+        for stream in self.listener.incoming() {
+            let result = stream.and_then(|stream| self.serve_connection(stream));
+            if let Err(err) = result {
+                warn!("Control connection failed: {}", err);
+            }
+        }
+    }
+
+    fn serve_connection(&self, stream: UnixStream) -> io::Result<()> {
+        let mut writer = stream.try_clone()?;
+        for line in BufReader::new(stream).lines() {
+            let response = match serde_json::from_str(&line?) {
+                Ok(request) => self.peer_control.handle(request),
+                Err(err) => ControlResponse::Error(format!("Invalid request: {}", err))
+            };
+            writeln!(writer, "{}", serde_json::to_string(&response)?)?;
+        }
+        Ok(())
+    }
+}
+
+/**
+Sends one request to the node listening on `path` and waits for its response.
+ */
+#[cfg(unix)]
+pub fn send_control_request(path: &Path, request: &ControlRequest) -> io::Result<ControlResponse> {
+    let stream = UnixStream::connect(path)
+        .map_err(|err| io::Error::new(err.kind(), format!("Could not reach a running node on {}: {}", path.display(), err)))?;
+    let mut writer = stream.try_clone()?;
+    writeln!(writer, "{}", serde_json::to_string(request)?)?;
+    let mut line = String::new();
+    BufReader::new(stream).read_line(&mut line)?;
+    Ok(serde_json::from_str(&line)?)
+}
+
+#[cfg(not(unix))]
+pub fn send_control_request(path: &Path, _request: &ControlRequest) -> io::Result<ControlResponse> {
+    Err(io::Error::new(io::ErrorKind::Unsupported, format!("Could not reach a running node on {}, control sockets need unix", path.display())))
+}
+
+#[cfg(all(test, unix))]
+mod control_tests {
+    use std::fs;
+    use std::net::{IpAddr, SocketAddr};
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::UNIX_EPOCH;
+    use uuid::Uuid;
+    use crate::control::{control_socket_path, ControlRequest, ControlResponse, ControlServer, PeerControl, send_control_request};
+    use crate::health_check_scheduler::{StaticPeer, StaticPeers};
+    use crate::health_history::HealthHistory;
+    use crate::network::{HealthCheck, HealthCheckConfiguration, HealthStatus, HealthStatusDetails, IP, NetworkDetails, NetworkDetailsStore};
+
+    fn dummy_record(port: u16) -> NetworkDetails {
+        NetworkDetails {
+            node_id: Uuid::new_v4(),
+            addr: IpAddr::V4(IP),
+            last_seen: UNIX_EPOCH,
+            status_since: UNIX_EPOCH,
+            stale: false,
+            health_history: HealthHistory::default(),
+            health_check: HealthCheck {
+                status_details: HealthStatusDetails {
+                    current_status: HealthStatus::Healthy,
+                    lives_remaining: 3,
+                },
+                configuration: HealthCheckConfiguration {
+                    health_check_port: port,
+                    ..HealthCheckConfiguration::default()
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn requests_round_trip_over_the_socket() {
+        let data_dir = std::env::temp_dir().join(format!("swizzy_decent_control_{}", Uuid::new_v4()));
+        fs::create_dir_all(&data_dir).unwrap();
+        let path = control_socket_path(&data_dir);
+        let store = Arc::new(NetworkDetailsStore::new());
+        let known = dummy_record(3451);
+        store.put_network_details(&known);
+        let static_peers = Arc::new(StaticPeers::new(Vec::new()));
+        let server = ControlServer::bind(&path, PeerControl::new(static_peers.clone(), store.clone())).unwrap();
+        thread::spawn(move || server.run());
+
+        let response = send_control_request(&path, &ControlRequest::Peers).unwrap();
+        assert_eq!(ControlResponse::Peers(vec![known.clone()]), response);
+
+        let addr = SocketAddr::new(IpAddr::V4(IP), 3451);
+        let static_peer = StaticPeer {
+            addr,
+            configuration: HealthCheckConfiguration {
+                health_check_port: 3451,
+                priority: Some(9),
+                ..HealthCheckConfiguration::default()
+            }
+        };
+        let response = send_control_request(&path, &ControlRequest::AddPeer(static_peer)).unwrap();
+        assert_eq!(ControlResponse::PeerAdded { addr, new: true }, response);
+        assert_eq!(Some(9), static_peers.get(&addr).unwrap().priority);
+        assert_eq!(Some(9), store.get_network_details_by_node_id(&known.node_id).unwrap().health_check.configuration.priority,
+                   "We expect a known peer to take the added settings straight away");
+
+        let response = send_control_request(&path, &ControlRequest::RemovePeer { addr }).unwrap();
+        assert_eq!(ControlResponse::PeerRemoved { addr }, response);
+        assert!(static_peers.get(&addr).is_none());
+        assert!(store.get_network_details_by_node_id(&known.node_id).is_err());
+
+        let response = send_control_request(&path, &ControlRequest::RemovePeer { addr }).unwrap();
+        assert!(matches!(response, ControlResponse::Error(_)), "We expect removing an unknown peer to fail, got {:?}", response);
+
+        assert!(ControlServer::bind(&path, PeerControl::new(static_peers, store)).is_err(), "We expect a second node on the same data directory to be refused");
+        fs::remove_dir_all(data_dir).unwrap();
+    }
+}
@@ -0,0 +1,244 @@
+// Event loop
+// Runs a whole stack on one thread for small deployments: socket readiness, the scheduler's probes and timeouts,
+// the reaper, store checkpoints and handler dispatch all take turns around a single mio poll
+// Only the stack is on the loop. The node's services around it keep a thread each when they run: the repl on stdin,
+// the control socket, the config reloader, and the admin API and metrics server when their listen address is set
+// Requests queued from other threads, like the repl or the admin API, wake the poll through the request channel's waker,
+// the store is written from other threads without waking it, so its metrics follow within a scheduler tick
+// Handlers run inline, a slow one holds up everything else, and a blocking request channel would wait on the loop itself,
+// see Configuration::validate
+
+use std::io;
+use std::net::SocketAddr;
+use std::sync::mpsc::Receiver;
+use std::sync::Arc;
+use std::task::{Context, Poll, Wake, Waker};
+use std::time::{Instant, SystemTime};
+use log::{error, info};
+use mio::{Events, Interest, Token};
+use mio::net::UdpSocket;
+
+use crate::batch_io::{Datagram, ReceiveErrorBackoff};
+use crate::health_check_network_broker::{HealthCheckNetworkBroker, HealthCheckNetworkBrokerMessage};
+use crate::health_check_network_handlers::HealthCheckNetworkBrokerMessageListener;
+use crate::health_check_scheduler::{HealthCheckScheduler, SCHEDULER_TICK};
+use crate::metrics::Metrics;
+use crate::network::NetworkDetailsStore;
+use crate::peer_store::{CHECKPOINT_INTERVAL, PeerStoreEvent};
+use crate::reaper::NetworkDetailsReaper;
+
+const SOCKET: Token = Token(0);
+const REQUESTS: Token = Token(1);
+const EVENTS_CAPACITY: usize = 64;
+/**
+Datagrams handled per call to receive, so a flood can't hold off the timers.
+ */
+const RECEIVE_BUDGET: usize = 256;
+
+/**
+Wakes the poll when a request is queued.
+ */
+struct RequestWaker(mio::Waker);
+
+impl Wake for RequestWaker {
+    fn wake(self: Arc<Self>) {
+        self.wake_by_ref();
+    }
+
+    fn wake_by_ref(self: &Arc<Self>) {
+        if let Err(err) = self.0.wake() {
+            error!("Failed to wake the event loop: {}", err);
+        }
+    }
+}
+
+pub struct EventLoop {
+    poll: mio::Poll,
+    socket: UdpSocket,
+    /**
+    Left with the request channel whenever it runs empty.
+    */
+    request_waker: Waker,
+    network_broker: HealthCheckNetworkBroker,
+    listener: HealthCheckNetworkBrokerMessageListener,
+    scheduler: HealthCheckScheduler,
+    reaper: NetworkDetailsReaper,
+    network_details_store: Arc<NetworkDetailsStore>,
+    store_events: Receiver<PeerStoreEvent>,
+    metrics: Arc<Metrics>,
+    /**
+    A request the socket had no room for, sent first once it is writable again.
+    */
+    unsent: Option<HealthCheckNetworkBrokerMessage>,
+    /**
+    Reused for every datagram received and sent.
+    */
+    datagram: Datagram,
+    tick_at: Instant,
+    reap_at: Instant,
+    receive_backoff: ReceiveErrorBackoff,
+    /**
+    Set while receiving backs off after an error, or when the last receive used up its budget. The socket isn't
+    reported readable again until it is drained, so it is read again then whether or not an event comes in.
+    */
+    receive_at: Option<Instant>,
+    /**
+    None for a store that isn't persisted.
+    */
+    checkpoint_at: Option<Instant>,
+}
+
+impl EventLoop {
+    /**
+    Binds the broker's socket, the components are only driven once run is called.
+    */
+    pub fn new(network_broker: HealthCheckNetworkBroker,
+               listener: HealthCheckNetworkBrokerMessageListener,
+               scheduler: HealthCheckScheduler,
+               reaper: NetworkDetailsReaper,
+               network_details_store: Arc<NetworkDetailsStore>,
+               metrics: Arc<Metrics>) -> io::Result<EventLoop> {
+        let poll = mio::Poll::new()?;
+        let mut socket = UdpSocket::bind(network_broker.socket_addr())?;
+        // Readiness is edge triggered, writable only comes up again after a send found the socket full
+        poll.registry().register(&mut socket, SOCKET, Interest::READABLE | Interest::WRITABLE)?;
+        let request_waker = Waker::from(Arc::new(RequestWaker(mio::Waker::new(poll.registry(), REQUESTS)?)));
+        let now = Instant::now();
+        Ok(EventLoop {
+            poll,
+            socket,
+            request_waker,
+            store_events: network_details_store.watch(),
+            reap_at: now + reaper.interval(),
+            checkpoint_at: network_details_store.is_persistent().then(|| now + CHECKPOINT_INTERVAL),
+            tick_at: now,
+            receive_backoff: ReceiveErrorBackoff::default(),
+            receive_at: None,
+            network_broker,
+            listener,
+            scheduler,
+            reaper,
+            network_details_store,
+            metrics,
+            unsent: None,
+            datagram: Datagram::default(),
+        })
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.socket.local_addr()
+    }
+
+    pub fn run(mut self) {
+        info!("Starting event loop on {}", self.local_addr().map(|addr| addr.to_string()).unwrap_or_default());
+        let mut events = Events::with_capacity(EVENTS_CAPACITY);
+        let request_waker = self.request_waker.clone();
+        loop {
+            let timeout = self.next_timer().saturating_duration_since(Instant::now());
+            if let Err(err) = self.poll.poll(&mut events, Some(timeout)) {
+                if err.kind() == io::ErrorKind::Interrupted {
+                    continue;
+                }
+                panic!("Event loop poll failed: {}", err);
+            }
+            // A wake up of REQUESTS only means there is something to send, which is checked below every time anyway
+            if self.receive_at.is_none() && events.iter().any(|event| event.token() == SOCKET && event.is_readable()) {
+                self.receive();
+            }
+            self.run_timers(Instant::now());
+            // After the handlers and the scheduler, so their requests go out in this turn
+            self.send_requests(&request_waker);
+            for event in self.store_events.try_iter() {
+                self.metrics.store_event(&event);
+            }
+        }
+    }
+
+    /**
+    Reads and handles datagrams until the socket would block, mio only reports it readable again after that.
+    Past `RECEIVE_BUDGET` datagrams the rest are left for after the timers.
+    */
+    fn receive(&mut self) {
+        for _ in 0..RECEIVE_BUDGET {
+            match self.socket.recv_from(&mut self.datagram.buf) {
+                Ok((len, addr)) => {
+                    self.receive_backoff.succeeded();
+                    self.datagram.len = len;
+                    self.datagram.addr = addr;
+                    let message = self.network_broker.read_datagram(&self.datagram);
+                    self.listener.handle(message);
+                    // Replies go out as they come so a burst can't overflow the request channel, nothing needs waking
+                    // up meanwhile as the loop sends again before it polls
+                    self.send_requests(Waker::noop());
+                }
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock => return,
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => {}
+                // The timers keep running meanwhile, see receive_at
+                Err(err) => {
+                    let backoff = self.receive_backoff.failed();
+                    error!("Failed to receive health check packet, receiving again in {:?}: {}", backoff, err);
+                    self.receive_at = Some(Instant::now() + backoff);
+                    return;
+                }
+            }
+        }
+        self.receive_at = Some(Instant::now());
+    }
+
+    /**
+    Sends requests until the channel is empty, or the socket is full and the rest waits for it to be writable.
+    `request_waker` is left with the empty channel.
+    */
+    fn send_requests(&mut self, request_waker: &Waker) {
+        let mut context = Context::from_waker(request_waker);
+        loop {
+            let mut message = match self.unsent.take() {
+                Some(message) => message,
+                None => match self.network_broker.poll_request(&mut context) {
+                    Poll::Ready(Ok(message)) => message,
+                    // Pending leaves the waker with the channel, it is never disconnected as the stack keeps a sender
+                    Poll::Ready(Err(_)) | Poll::Pending => return
+                }
+            };
+            let send_span = self.network_broker.write_datagram(&mut message, &mut self.datagram);
+            let result = send_span.in_scope(|| self.socket.send_to(self.datagram.payload(), self.datagram.addr));
+            match result {
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock => {
+                    // Meanwhile the channel fills up and drops as per its policy
+                    self.unsent = Some(message);
+                    return;
+                }
+                result => self.network_broker.datagram_sent(&message, result.map(|_| ()))
+            }
+        }
+    }
+
+    fn next_timer(&self) -> Instant {
+        [self.checkpoint_at, self.receive_at].into_iter()
+            .flatten()
+            .fold(self.tick_at.min(self.reap_at), Instant::min)
+    }
+
+    fn run_timers(&mut self, now: Instant) {
+        if self.tick_at <= now {
+            self.scheduler.tick(now, SystemTime::now());
+            // Like the threaded scheduler, a late tick pushes back the ones after it
+            self.tick_at = now + SCHEDULER_TICK;
+        }
+        if self.reap_at <= now {
+            self.reaper.reap_now();
+            self.reap_at = now + self.reaper.interval();
+        }
+        if self.checkpoint_at.is_some_and(|checkpoint_at| checkpoint_at <= now) {
+            if let Err(err) = self.network_details_store.checkpoint() {
+                error!("Failed to checkpoint network details store: {}", err);
+            }
+            self.checkpoint_at = Some(now + CHECKPOINT_INTERVAL);
+        }
+        // Last, so the other timers get their turn between budgets of a flood
+        if self.receive_at.is_some_and(|receive_at| receive_at <= now) {
+            self.receive_at = None;
+            self.receive();
+        }
+    }
+}
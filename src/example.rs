@@ -2,18 +2,23 @@ use std::{env, io, thread};
 use std::io::BufRead;
 use std::net::{IpAddr, SocketAddr};
 use std::str::FromStr;
-use std::sync::mpsc;
+use std::sync::Arc;
 use std::time::{Duration, SystemTime, UNIX_EPOCH};
-use crate::{DEFAULT_IP_ADDRESS, IP_ADDRESS_ENV_KEY, UDP_PORT_DEFAULT, UDP_PORT_ENV_KEY};
+use uuid::Uuid;
+use crate::channel::{ChannelBounds, metered_channel};
+use crate::config::{IP_ADDRESS_ENV_KEY, NodeSection, UDP_PORT_ENV_KEY};
 use crate::health_check::{DeserializePacket, HEALTH_CHECK_SYN_OPCODE, HealthCheckPacket, SerializePacket};
 use crate::health_check_network_broker::{build_health_check_stack, HealthCheckNetworkBroker, HealthCheckNetworkBrokerMessage};
 use crate::health_check_network_handlers::HealthCheckNetworkBrokerMessageListener;
-use crate::network::{health_check_receiver, health_check_sender, IP, RECEIVER_PORT, SENDER_PORT};
+use crate::metrics::Metrics;
+use crate::reaper::ReaperConfiguration;
+use crate::network::{health_check_receiver, health_check_sender, HealthCheckDefaults, IP, NetworkDetailsStore, RECEIVER_PORT, SENDER_PORT};
+use crate::trace::MessageTrace;
 
 
 fn main_with_stacks() {
     let sender_addr = SocketAddr::new(IpAddr::V4(IP), SENDER_PORT);
-    let stack = build_health_check_stack(sender_addr);
+    let stack = build_health_check_stack(sender_addr, Uuid::new_v4(), NetworkDetailsStore::new(), HealthCheckDefaults::default(), Vec::new(), ReaperConfiguration::default(), ChannelBounds::default());
     // let network_broker1 = &stack.network_broker;
 
     let first_handle = thread::spawn(|| {
@@ -21,7 +26,7 @@ fn main_with_stacks() {
     });
 
     let sender_addr2 = SocketAddr::new(IpAddr::V4(IP), RECEIVER_PORT);
-    let stack2 = build_health_check_stack(sender_addr2);
+    let stack2 = build_health_check_stack(sender_addr2, Uuid::new_v4(), NetworkDetailsStore::new(), HealthCheckDefaults::default(), Vec::new(), ReaperConfiguration::default(), ChannelBounds::default());
     let network_broker2 = &stack2.network_broker;
     let test_message_sender = network_broker2.request_sender.clone();
     // let network_broker2 = network_broker2;
@@ -34,9 +39,11 @@ fn main_with_stacks() {
     let message = HealthCheckNetworkBrokerMessage {
         payload: HealthCheckPacket {
             header: 1,
-            nonce: [2,3,6,1,7,3,1,3,8,9,3,2,6,3,7,3]
+            nonce: [2,3,6,1,7,3,1,3,8,9,3,2,6,3,7,3],
+            node_id: [0; 16]
         },
-        remote_addr: SocketAddr::new(IpAddr::V4(IP), RECEIVER_PORT)
+        remote_addr: SocketAddr::new(IpAddr::V4(IP), RECEIVER_PORT),
+        trace: MessageTrace::default()
     };
 
     // println!("Sending message");
@@ -50,9 +57,11 @@ fn main_with_stacks() {
         let message = HealthCheckNetworkBrokerMessage {
             payload: HealthCheckPacket {
                 header: 1,
-                nonce: [2,3,6,1,7,3,1,3,8,9,3,2,6,3,7,3]
+                nonce: [2,3,6,1,7,3,1,3,8,9,3,2,6,3,7,3],
+                node_id: [0; 16]
             },
-            remote_addr: SocketAddr::new(IpAddr::V4(IP), RECEIVER_PORT)
+            remote_addr: SocketAddr::new(IpAddr::V4(IP), RECEIVER_PORT),
+            trace: MessageTrace::default()
         };
 
         // println!("Sending message");
@@ -85,13 +94,14 @@ Default IP = 127.0.0.1
  */
 fn single_instance_main() {
 
-    let ip_address_str = env::var(IP_ADDRESS_ENV_KEY).unwrap_or(String::from(DEFAULT_IP_ADDRESS));
-    let listener_port_str = env::var(UDP_PORT_ENV_KEY).unwrap_or(UDP_PORT_DEFAULT.to_string());
+    let node_defaults = NodeSection::default();
+    let ip_address_str = env::var(IP_ADDRESS_ENV_KEY).unwrap_or(node_defaults.bind_address);
+    let listener_port_str = env::var(UDP_PORT_ENV_KEY).unwrap_or(node_defaults.port.to_string());
     let listener_port: u16 = listener_port_str.parse().expect("Valid string number");
     let ip = IpAddr::from_str(&ip_address_str.as_str()).expect("Valid IP address");
 
     let sender_addr = SocketAddr::new(ip, listener_port);
-    let stack = build_health_check_stack(sender_addr);
+    let stack = build_health_check_stack(sender_addr, Uuid::new_v4(), NetworkDetailsStore::new(), HealthCheckDefaults::default(), Vec::new(), ReaperConfiguration::default(), ChannelBounds::default());
     // let network_broker = stack.network_broker;
     // let request_sender = network_broker.get_request_sender().clone();
     let request_sender = stack.request_sender.clone();
@@ -109,9 +119,11 @@ fn single_instance_main() {
                 request_sender.send(HealthCheckNetworkBrokerMessage {
                     payload: HealthCheckPacket {
                         header: HEALTH_CHECK_SYN_OPCODE,
-                        nonce: [2,3,6,1,7,3,1,3,8,9,3,2,6,3,7,3]
+                        nonce: [2,3,6,1,7,3,1,3,8,9,3,2,6,3,7,3],
+                        node_id: [0; 16]
                     },
                     remote_addr: sender_addr,
+                    trace: MessageTrace::default(),
 
                 }).unwrap();
             }
@@ -200,7 +212,7 @@ fn single_instance_main() {
 //     let message_sender_1_handle = thread::spawn(move || {
 //         let (message_sender1, message_receiver1) = mpsc::channel();
 //         let sender_addr = SocketAddr::new(IpAddr::V4(IP), SENDER_PORT);
-//         let message_broker_1 = HealthCheckNetworkBroker::new(sender_addr, message_sender1.clone() /* Not intended to be used in this example*/, message_receiver1, message_sender1);
+//         let message_broker_1 = HealthCheckNetworkBroker::new(sender_addr, Uuid::new_v4(), message_sender1.clone() /* Not intended to be used in this example*/, message_receiver1, message_sender1);
 //         println!("Created message_broker_1");
 //         println!("Attempting to run message_broker_1");
 //         message_broker_1.run();
@@ -214,7 +226,7 @@ fn single_instance_main() {
 //     let message_sender_2_handle = thread::spawn(move || {
 //         let _message_sender = message_sender2.clone();
 //         let receiver_addr = SocketAddr::new(IpAddr::V4(IP), RECEIVER_PORT);
-//         let message_broker_2 = HealthCheckNetworkBroker::new(receiver_addr, message_sender2.clone() /* Not intended to be used in this example*/, message_receiver2, consumer_sender2);
+//         let message_broker_2 = HealthCheckNetworkBroker::new(receiver_addr, Uuid::new_v4(), message_sender2.clone() /* Not intended to be used in this example*/, message_receiver2, consumer_sender2);
 //         println!("Created message_broker_2");
 //         println!("Attempting to run message_broker_2");
 //         message_broker_2.run();
@@ -252,23 +264,23 @@ fn main_health_check_broker_example() {
     println!("HealthCheckBroker example main");
 
     let message_sender_1_handle = thread::spawn(move || {
-        let (message_sender1, message_receiver1) = mpsc::channel();
+        let (message_sender1, message_receiver1) = metered_channel();
         let sender_addr = SocketAddr::new(IpAddr::V4(IP), SENDER_PORT);
-        let message_broker_1 = HealthCheckNetworkBroker::new(sender_addr, message_sender1.clone() /* Not intended to be used in this example*/, message_receiver1, message_sender1);
+        let message_broker_1 = HealthCheckNetworkBroker::new(sender_addr, Uuid::new_v4(), message_sender1.clone() /* Not intended to be used in this example*/, message_receiver1, message_sender1, Arc::new(Metrics::default()));
         println!("Created message_broker_1");
         println!("Attempting to run message_broker_1");
         message_broker_1.run();
         println!("message_broker_1 finished running");
     });
 
-    let (message_sender2, message_receiver2) = mpsc::channel();
-    let (consumer_sender2, consumer_receiver2) = mpsc::channel();
+    let (message_sender2, message_receiver2) = metered_channel();
+    let (consumer_sender2, consumer_receiver2) = metered_channel();
 
     let test_message_sender = message_sender2.clone();
     let message_sender_2_handle = thread::spawn(move || {
         let _message_sender = message_sender2.clone();
         let receiver_addr = SocketAddr::new(IpAddr::V4(IP), RECEIVER_PORT);
-        let message_broker_2 = HealthCheckNetworkBroker::new(receiver_addr, message_sender2.clone() /* Not intended to be used in this example*/, message_receiver2, consumer_sender2);
+        let message_broker_2 = HealthCheckNetworkBroker::new(receiver_addr, Uuid::new_v4(), message_sender2.clone() /* Not intended to be used in this example*/, message_receiver2, consumer_sender2, Arc::new(Metrics::default()));
         println!("Created message_broker_2");
         println!("Attempting to run message_broker_2");
         message_broker_2.run();
@@ -279,9 +291,11 @@ fn main_health_check_broker_example() {
     let message = HealthCheckNetworkBrokerMessage {
         payload: HealthCheckPacket {
             header: 1,
-            nonce: [2,3,6,1,7,3,1,3,8,9,3,2,6,3,7,3]
+            nonce: [2,3,6,1,7,3,1,3,8,9,3,2,6,3,7,3],
+            node_id: [0; 16]
         },
-        remote_addr: SocketAddr::new(IpAddr::V4(IP), RECEIVER_PORT)
+        remote_addr: SocketAddr::new(IpAddr::V4(IP), RECEIVER_PORT),
+        trace: MessageTrace::default()
     };
     println!("Sending message");
     test_message_sender.send(message).expect("Message sent");
@@ -311,7 +325,8 @@ fn main_receiver_poc() {
 fn main_serialization() {
     let packet = HealthCheckPacket {
         header: 1,
-        nonce: [2,3,6,1,7,3,1,3,8,9,3,2,6,3,7,3]
+        nonce: [2,3,6,1,7,3,1,3,8,9,3,2,6,3,7,3],
+        node_id: [0; 16]
     };
 
     println!("Printing raw packet: ");
@@ -1,20 +1,33 @@
 use std::collections::HashSet;
+use std::ops::RangeInclusive;
+use log::debug;
 
 const HEADER_SIZE_BYTES: usize = 1;
 const NONCE_SIZE_BYTES: usize = 16;
-pub const HEALTH_CHECK_PACKET_SIZE: usize = HEADER_SIZE_BYTES + NONCE_SIZE_BYTES;
+pub const NODE_ID_SIZE_BYTES: usize = 16;
+pub const HEALTH_CHECK_PACKET_SIZE: usize = HEADER_SIZE_BYTES + NONCE_SIZE_BYTES + NODE_ID_SIZE_BYTES;
 const HEADER_INDEX: usize = 0;
 const NONCE_INDEX: usize = 1;
+const NODE_ID_INDEX: usize = NONCE_INDEX + NONCE_SIZE_BYTES;
 
 pub const NOOP_OPCODE: u8 = 0;
 
 pub const HEALTH_CHECK_SYN_OPCODE: u8 = 1;
 pub const HEALTH_CHECK_ACK_OPCODE: u8 = 2;
 
+/**
+Opcodes applications can register their own handlers for, see OpcodeHandler. Everything below is reserved for the health check protocol.
+ */
+pub const APPLICATION_OPCODES: RangeInclusive<u8> = 128..=255;
+
 #[derive(Debug, Clone, Eq, PartialEq)]
 pub struct HealthCheckPacket {
     pub header: u8,
-    pub nonce: [u8; NONCE_SIZE_BYTES]
+    pub nonce: [u8; NONCE_SIZE_BYTES],
+    /**
+    Uuid bytes of the node that sent the packet, stamped by the network broker on send.
+    */
+    pub node_id: [u8; NODE_ID_SIZE_BYTES]
 }
 
 pub trait SerializePacket {
@@ -25,6 +38,25 @@ pub fn get_health_check_opcodes() -> HashSet<u8> {
     return HashSet::from([HEALTH_CHECK_SYN_OPCODE, HEALTH_CHECK_ACK_OPCODE]);
 }
 
+/**
+Name of the opcode in logs and metrics, opcodes we don't know by their number.
+ */
+pub fn opcode_name(opcode: u8) -> String {
+    match opcode {
+        NOOP_OPCODE => "noop".to_string(),
+        HEALTH_CHECK_SYN_OPCODE => "syn".to_string(),
+        HEALTH_CHECK_ACK_OPCODE => "ack".to_string(),
+        opcode => opcode.to_string()
+    }
+}
+
+/**
+Hex of the nonce, an ACK echoes the nonce of its SYN so it ties one exchange together across the logs of both nodes.
+ */
+pub fn format_nonce(nonce: &[u8; NONCE_SIZE_BYTES]) -> String {
+    nonce.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
 pub trait DeserializePacket {
     // const HEALTH_CHECK_OPCODES: HashSet<u8> =
 
@@ -35,32 +67,51 @@ pub trait DeserializePacket {
 impl SerializePacket for HealthCheckPacket {
     fn serialize(&self) -> Vec<u8>
     {
-        let header: u8 = self.header;
-        let nonce: Vec<u8> = Vec::from(self.nonce);
-        let mut serialized: Vec<u8> = Vec::from([header]);
-        serialized.extend(nonce);
+        let mut serialized = vec![0; HEALTH_CHECK_PACKET_SIZE];
+        self.write_to(&mut serialized);
         return serialized
     }
 }
 
 impl DeserializePacket for HealthCheckPacket {
     fn deserialize(raw: Vec<u8>) -> HealthCheckPacket
+    {
+        HealthCheckPacket::read_from(&raw)
+    }
+}
+
+impl HealthCheckPacket {
+    /**
+    Serializes into the first HEALTH_CHECK_PACKET_SIZE bytes of `buf`, so send buffers can be reused.
+    */
+    pub fn write_to(&self, buf: &mut [u8]) {
+        buf[HEADER_INDEX] = self.header;
+        buf[NONCE_INDEX..NONCE_INDEX+NONCE_SIZE_BYTES].copy_from_slice(&self.nonce);
+        buf[NODE_ID_INDEX..NODE_ID_INDEX+NODE_ID_SIZE_BYTES].copy_from_slice(&self.node_id);
+    }
+
+    /**
+    Deserializes a received datagram without copying it first, see DeserializePacket.
+    */
+    pub fn read_from(raw: &[u8]) -> HealthCheckPacket
     {
 
         if raw.len() != HEALTH_CHECK_PACKET_SIZE {
-            println!("Invalid raw packet attempted to deserialize for HealthCheckPacket");
+            debug!(size = raw.len(); "Invalid raw packet attempted to deserialize for HealthCheckPacket");
             return HealthCheckPacket {
                 header: NOOP_OPCODE,
-                nonce: [0; 16]
+                nonce: [0; 16],
+                node_id: [0; 16]
             }
         }
 
         let header: u8 = raw[HEADER_INDEX];
-        if !get_health_check_opcodes().contains(&header) {
-            println!("Invalid op code received");
+        if !get_health_check_opcodes().contains(&header) && !APPLICATION_OPCODES.contains(&header) {
+            debug!(opcode = header; "Invalid op code received");
             return HealthCheckPacket {
                 header: NOOP_OPCODE,
-                nonce: [0; 16]
+                nonce: [0; 16],
+                node_id: [0; 16]
             }
         }
 
@@ -69,9 +120,13 @@ impl DeserializePacket for HealthCheckPacket {
             nonce[i] = raw[i+NONCE_INDEX];
         }
 
+        let mut node_id = [0;NODE_ID_SIZE_BYTES];
+        node_id.copy_from_slice(&raw[NODE_ID_INDEX..NODE_ID_INDEX+NODE_ID_SIZE_BYTES]);
+
         let result = HealthCheckPacket {
             header: header.into(),
-            nonce
+            nonce,
+            node_id
         };
         return result
     }
@@ -79,27 +134,29 @@ impl DeserializePacket for HealthCheckPacket {
 
 #[cfg(test)]
 mod health_check_tests {
-    use crate::health_check::{DeserializePacket, HEALTH_CHECK_SYN_OPCODE, HealthCheckPacket, NOOP_OPCODE, SerializePacket};
+    use crate::health_check::{APPLICATION_OPCODES, DeserializePacket, HEALTH_CHECK_SYN_OPCODE, HealthCheckPacket, NOOP_OPCODE, SerializePacket};
 
     #[test]
     fn serialize_happy_case() {
         let packet = HealthCheckPacket {
             header: HEALTH_CHECK_SYN_OPCODE,
-            nonce: [2,3,6,1,7,3,1,3,8,9,3,2,6,3,7,3]
+            nonce: [2,3,6,1,7,3,1,3,8,9,3,2,6,3,7,3],
+            node_id: [9,8,7,6,5,4,3,2,1,0,1,2,3,4,5,6]
         };
 
         let serialized = packet.serialize();
-        let expected: Vec<u8> = Vec::from([1, 2, 3, 6, 1, 7, 3, 1, 3, 8, 9, 3, 2, 6, 3, 7, 3]);
+        let expected: Vec<u8> = Vec::from([1, 2, 3, 6, 1, 7, 3, 1, 3, 8, 9, 3, 2, 6, 3, 7, 3, 9, 8, 7, 6, 5, 4, 3, 2, 1, 0, 1, 2, 3, 4, 5, 6]);
         assert_eq!(expected, serialized, "We expect the serialized data: {:?} to equal the expected vector: {:?}", serialized, expected);
     }
 
     #[test]
     fn deserialize_happy_case() {
-        let serialized: Vec<u8> = Vec::from([1, 2, 3, 6, 1, 7, 3, 1, 3, 8, 9, 3, 2, 6, 3, 7, 3]);
+        let serialized: Vec<u8> = Vec::from([1, 2, 3, 6, 1, 7, 3, 1, 3, 8, 9, 3, 2, 6, 3, 7, 3, 9, 8, 7, 6, 5, 4, 3, 2, 1, 0, 1, 2, 3, 4, 5, 6]);
 
         let expected = HealthCheckPacket {
             header: HEALTH_CHECK_SYN_OPCODE,
-            nonce: [2,3,6,1,7,3,1,3,8,9,3,2,6,3,7,3]
+            nonce: [2,3,6,1,7,3,1,3,8,9,3,2,6,3,7,3],
+            node_id: [9,8,7,6,5,4,3,2,1,0,1,2,3,4,5,6]
         };
 
         let deserialized = HealthCheckPacket::deserialize(serialized);
@@ -110,11 +167,12 @@ mod health_check_tests {
     #[test]
     fn deserialize_packet_size_too_small() {
         // expect no op opcode with 0'd data
-        let serialized: Vec<u8> = Vec::from([1, 2, 3, 6, 1, 7, 3, 1, 3, 8, 9, 3, 2, 6, 3, 7]);
+        let serialized: Vec<u8> = Vec::from([1, 2, 3, 6, 1, 7, 3, 1, 3, 8, 9, 3, 2, 6, 3, 7, 3, 9, 8, 7, 6, 5, 4, 3, 2, 1, 0, 1, 2, 3, 4, 5]);
 
         let expected = HealthCheckPacket {
             header: NOOP_OPCODE,
-            nonce: [0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0]
+            nonce: [0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0],
+            node_id: [0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0]
         };
 
         let deserialized = HealthCheckPacket::deserialize(serialized);
@@ -124,11 +182,12 @@ mod health_check_tests {
     #[test]
     fn deserialize_packet_size_too_big() {
         // expect no op opcode with 0'd data
-        let serialized: Vec<u8> = Vec::from([1, 2, 3, 6, 1, 7, 3, 1, 3, 8, 9, 3, 2, 6, 3, 7, 3, 9]);
+        let serialized: Vec<u8> = Vec::from([1, 2, 3, 6, 1, 7, 3, 1, 3, 8, 9, 3, 2, 6, 3, 7, 3, 9, 8, 7, 6, 5, 4, 3, 2, 1, 0, 1, 2, 3, 4, 5, 6, 7]);
 
         let expected = HealthCheckPacket {
             header: NOOP_OPCODE,
-            nonce: [0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0]
+            nonce: [0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0],
+            node_id: [0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0]
         };
 
         let deserialized = HealthCheckPacket::deserialize(serialized);
@@ -139,14 +198,25 @@ mod health_check_tests {
     fn deserialize_invalid_op_code() {
         // expect no op opcode with 0'd data
         // expect no op opcode with 0'd data
-        let serialized: Vec<u8> = Vec::from([3, 2, 3, 6, 1, 7, 3, 1, 3, 8, 9, 3, 2, 6, 3, 7, 3]);
+        let serialized: Vec<u8> = Vec::from([3, 2, 3, 6, 1, 7, 3, 1, 3, 8, 9, 3, 2, 6, 3, 7, 3, 9, 8, 7, 6, 5, 4, 3, 2, 1, 0, 1, 2, 3, 4, 5, 6]);
 
         let expected = HealthCheckPacket {
             header: NOOP_OPCODE,
-            nonce: [0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0]
+            nonce: [0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0],
+            node_id: [0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0]
         };
 
         let deserialized = HealthCheckPacket::deserialize(serialized);
         assert_eq!(expected, deserialized, "We expect the deserialized data: {:?} to equal the expected HealthCheckPacket: {:?} , with NOOP code and 0'd data when opcode is invalid", deserialized, expected);
     }
+
+    #[test]
+    fn deserialize_application_op_code() {
+        let mut serialized: Vec<u8> = Vec::from([0, 2, 3, 6, 1, 7, 3, 1, 3, 8, 9, 3, 2, 6, 3, 7, 3, 9, 8, 7, 6, 5, 4, 3, 2, 1, 0, 1, 2, 3, 4, 5, 6]);
+        serialized[0] = *APPLICATION_OPCODES.start();
+
+        let deserialized = HealthCheckPacket::deserialize(serialized);
+        assert_eq!(*APPLICATION_OPCODES.start(), deserialized.header, "We expect application opcodes to reach their handlers");
+        assert_eq!([2,3,6,1,7,3,1,3,8,9,3,2,6,3,7,3], deserialized.nonce);
+    }
 }
\ No newline at end of file
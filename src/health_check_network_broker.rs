@@ -1,14 +1,33 @@
-use std::fmt::Debug;
+use std::fmt::{self, Debug, Display, Formatter};
+use std::io;
 use std::net::{SocketAddr, UdpSocket};
-use std::sync::mpsc;
-use std::sync::mpsc::{Receiver, Sender};
+use std::str::FromStr;
+#[cfg(feature = "mio")]
+use std::sync::mpsc::RecvError;
+#[cfg(feature = "mio")]
+use std::task::{Context, Poll};
+use std::sync::{Arc, RwLock};
 use std::thread;
-use log::info;
+use log::{debug, error, info, warn};
+#[cfg(feature = "tokio")]
+use tracing::Instrument;
+use tracing::{info_span, Span};
+use uuid::Uuid;
 
 
-use crate::health_check::{DeserializePacket, HEALTH_CHECK_PACKET_SIZE, HealthCheckPacket, SerializePacket};
-use crate::health_check_network_handlers::HealthCheckNetworkBrokerMessageListener;
-use crate::network::{IP, NetworkDetailsStore, RECEIVER_PORT, SENDER_PORT};
+use crate::batch_io::{BATCH_SIZE, BatchReceiver, Datagram, ReceiveErrorBackoff, send_batch};
+use crate::channel::{bounded_channel, ChannelBounds, MeteredReceiver, MeteredSender};
+#[cfg(feature = "mio")]
+use crate::event_loop::EventLoop;
+use crate::health_check::{format_nonce, HEALTH_CHECK_PACKET_SIZE, HealthCheckPacket, NOOP_OPCODE, opcode_name};
+use crate::health_check_network_handlers::{HealthCheckNetworkBrokerMessageListener, OpcodeHandler, RegistrationError};
+use crate::health_check_scheduler::{HealthCheckScheduler, InFlightProbes, StaticPeer, StaticPeers};
+use crate::metrics::Metrics;
+use crate::middleware::HandlerMiddleware;
+use crate::network::{HealthCheckDefaults, IP, NetworkDetailsStore, RECEIVER_PORT, SENDER_PORT};
+use crate::peer_store::CHECKPOINT_INTERVAL;
+use crate::reaper::{NetworkDetailsReaper, ReaperConfiguration};
+use crate::trace::MessageTrace;
 
 #[derive(Clone, Debug)]
 pub struct HealthCheckNetworkBrokerMessage {
@@ -16,47 +35,79 @@ pub struct HealthCheckNetworkBrokerMessage {
     /*
         SocketAddress of remote host where the payload was sent to or received from.
     */
-    pub remote_addr: SocketAddr
+    pub remote_addr: SocketAddr,
+    /**
+    Spans of the exchange this message belongs to, see trace.
+    */
+    pub trace: MessageTrace
 }
 
 pub struct HealthCheckNetworkBroker {
     socket_addr: SocketAddr,
-    pub request_sender: Sender<HealthCheckNetworkBrokerMessage>,
-    request_receiver: Receiver<HealthCheckNetworkBrokerMessage>,
-    response_sender: Sender<HealthCheckNetworkBrokerMessage>
+    /**
+    Id of the local node, stamped on every outgoing packet.
+    */
+    node_id: Uuid,
+    pub request_sender: MeteredSender<HealthCheckNetworkBrokerMessage>,
+    request_receiver: MeteredReceiver<HealthCheckNetworkBrokerMessage>,
+    response_sender: MeteredSender<HealthCheckNetworkBrokerMessage>,
+    metrics: Arc<Metrics>
 }
 
 impl HealthCheckNetworkBroker {
     pub fn new(socket_addr: SocketAddr,
-               request_sender: Sender<HealthCheckNetworkBrokerMessage>,
-               request_receiver: Receiver<HealthCheckNetworkBrokerMessage>,
-               response_sender: Sender<HealthCheckNetworkBrokerMessage>) -> HealthCheckNetworkBroker {
+               node_id: Uuid,
+               request_sender: MeteredSender<HealthCheckNetworkBrokerMessage>,
+               request_receiver: MeteredReceiver<HealthCheckNetworkBrokerMessage>,
+               response_sender: MeteredSender<HealthCheckNetworkBrokerMessage>,
+               metrics: Arc<Metrics>) -> HealthCheckNetworkBroker {
         HealthCheckNetworkBroker {
             socket_addr,
+            node_id,
             request_sender,
             request_receiver,
-            response_sender
+            response_sender,
+            metrics
         }
     }
 
     pub fn run(self) {
-        println!("Starting run process for HealthCheckNetworkBroker");
+        info!("Starting HealthCheckNetworkBroker on {}", self.socket_addr);
         let socket = UdpSocket::bind(self.socket_addr).expect("Socket bound");
         let receiver_socket = socket.try_clone().expect("Receiver socket cloned");
 
         let response_sender = self.response_sender;
+        let receiver_metrics = self.metrics.clone();
         let receiver_handle = thread::spawn(move || {
+            let mut batch_receiver = BatchReceiver::default();
+            let mut receive_backoff = ReceiveErrorBackoff::default();
             loop  {
-                let receiver_socket = receiver_socket.try_clone().expect("Cloned receiver socket");
-                let response_sender = response_sender.clone();
-                health_check_receiver(receiver_socket, response_sender).expect("Health check receiver succeeded");
+                let datagrams = match batch_receiver.recv(&receiver_socket) {
+                    Ok(datagrams) => datagrams,
+                    // e.g. an ICMP port unreachable from an earlier send, the socket itself is still fine
+                    Err(err) => {
+                        let backoff = receive_backoff.failed();
+                        error!("Failed to receive health check packets, receiving again in {:?}: {}", backoff, err);
+                        thread::sleep(backoff);
+                        continue;
+                    }
+                };
+                receive_backoff.succeeded();
+                for datagram in datagrams {
+                    response_sender.send(health_check_receiver(datagram, &receiver_metrics))
+                        .expect("HealthCheckBroken receiver forwards received messages to response_sender channel");
+                }
             }
         });
 
         let sender_socket = socket.try_clone().expect("Sender socket cloned");
 
         let request_receiver = self.request_receiver;
+        let node_id = self.node_id;
+        let sender_metrics = self.metrics;
         let send_handle = thread::spawn(move || {
+            let mut requests = Vec::with_capacity(BATCH_SIZE);
+            let mut datagrams = Vec::with_capacity(BATCH_SIZE);
             loop {
                 let next_request = request_receiver.recv().expect("HealthCheckNetworkBrokerMessage received from request_receiver"); // TODO: uncomment after perf testing
                 // let res = request_receiver.recv_timeout(Duration::new(1, 0));
@@ -64,87 +115,351 @@ impl HealthCheckNetworkBroker {
                 //     break;
                 // }
                 // let next_request = res.unwrap();
-                let sender_socket = sender_socket.try_clone().expect("Cloned");
-                    health_check_sender(sender_socket, next_request).expect("Sent HealthCheckNetworkBrokerMessage to remote addr");
+                // Whatever queued up behind it goes out in the same syscall
+                requests.push(next_request);
+                requests.extend(request_receiver.try_iter().take(BATCH_SIZE - 1));
+                health_check_sender(&sender_socket, node_id, &mut requests, &mut datagrams, &sender_metrics);
+                requests.clear();
                 // sleep(Duration::new(0,1));
             }
         });
-        println!("Started threads for HealthCheckNetworkBroker");
+        debug!("Started threads for HealthCheckNetworkBroker");
         send_handle.join().unwrap();
         receiver_handle.join().unwrap();
-        println!("HealthCheckNetworkBroker run complete");
+        info!("HealthCheckNetworkBroker run complete");
+    }
+
+    /**
+    Runs the broker as two tasks on the current tokio runtime instead of two threads, one datagram per syscall.
+    Received messages are queued with send_async, so a blocking response channel holds up the task rather than the thread.
+    */
+    #[cfg(feature = "tokio")]
+    pub async fn run_async(self) {
+        info!("Starting async HealthCheckNetworkBroker on {}", self.socket_addr);
+        let socket = Arc::new(tokio::net::UdpSocket::bind(self.socket_addr).await.expect("Socket bound"));
+
+        let receiver_socket = socket.clone();
+        let response_sender = self.response_sender;
+        let receiver_metrics = self.metrics.clone();
+        let receiver_handle = tokio::spawn(async move {
+            let mut datagram = Datagram::default();
+            let mut receive_backoff = ReceiveErrorBackoff::default();
+            loop {
+                let (amt, src) = match receiver_socket.recv_from(&mut datagram.buf).await {
+                    Ok(received) => received,
+                    // Like run, the socket itself is still fine after e.g. an ICMP port unreachable
+                    Err(err) => {
+                        let backoff = receive_backoff.failed();
+                        error!("Failed to receive health check packet, receiving again in {:?}: {}", backoff, err);
+                        tokio::time::sleep(backoff).await;
+                        continue;
+                    }
+                };
+                receive_backoff.succeeded();
+                datagram.len = amt;
+                datagram.addr = src;
+                let message = health_check_receiver(&datagram, &receiver_metrics);
+                response_sender.send_async(message).await
+                    .expect("HealthCheckBroken receiver forwards received messages to response_sender channel");
+            }
+        });
+
+        let request_receiver = self.request_receiver;
+        let node_id = self.node_id;
+        let sender_metrics = self.metrics;
+        let send_handle = tokio::spawn(async move {
+            let mut datagram = Datagram::default();
+            loop {
+                let mut next_request = request_receiver.recv_async().await.expect("HealthCheckNetworkBrokerMessage received from request_receiver");
+                let send_span = write_request(&mut next_request, node_id, &mut datagram);
+                let result = socket.send_to(datagram.payload(), datagram.addr).instrument(send_span).await;
+                request_sent(&next_request, result.map(|_| ()), &sender_metrics);
+            }
+        });
+        debug!("Started tasks for HealthCheckNetworkBroker");
+        send_handle.await.unwrap();
+        receiver_handle.await.unwrap();
+        info!("HealthCheckNetworkBroker run complete");
+    }
+
+    #[cfg(feature = "mio")]
+    pub fn socket_addr(&self) -> SocketAddr {
+        self.socket_addr
+    }
+
+    /**
+    Next request to send, for the event loop which drives the socket instead of the broker's threads, see MeteredReceiver::poll_recv.
+    */
+    #[cfg(feature = "mio")]
+    pub fn poll_request(&self, context: &mut Context<'_>) -> Poll<Result<HealthCheckNetworkBrokerMessage, RecvError>> {
+        self.request_receiver.poll_recv(context)
+    }
+
+    /**
+    Reads a datagram the event loop received into the message for the listener.
+    */
+    #[cfg(feature = "mio")]
+    pub fn read_datagram(&self, datagram: &Datagram) -> HealthCheckNetworkBrokerMessage {
+        health_check_receiver(datagram, &self.metrics)
+    }
+
+    /**
+    Writes a request into the datagram the event loop sends, returns the span the send runs in.
+    */
+    #[cfg(feature = "mio")]
+    pub fn write_datagram(&self, message: &mut HealthCheckNetworkBrokerMessage, datagram: &mut Datagram) -> Span {
+        write_request(message, self.node_id, datagram)
     }
 
-    pub fn get_request_sender(self) -> Sender<HealthCheckNetworkBrokerMessage>{
+    /**
+    Counts and logs a request the event loop sent, or failed to.
+    */
+    #[cfg(feature = "mio")]
+    pub fn datagram_sent(&self, message: &HealthCheckNetworkBrokerMessage, result: io::Result<()>) {
+        request_sent(message, result, &self.metrics)
+    }
+
+    pub fn get_request_sender(self) -> MeteredSender<HealthCheckNetworkBrokerMessage>{
         return self.request_sender.clone()
     }
 }
 
-fn health_check_receiver(socket: UdpSocket, response_sender: Sender<HealthCheckNetworkBrokerMessage>) -> std::io::Result<()> {
-    {
-        println!("Health Check receiver waiting for messages");
-        // Receives a single datagram message on the socket. If `buf` is too small to hold
-        // the message, it will be cut off.
-        let mut buf = [0; HEALTH_CHECK_PACKET_SIZE+1];
-        let (_amt, src) = socket.recv_from(&mut buf)?;
-        let buf = &buf[..HEALTH_CHECK_PACKET_SIZE];
-        let buf_vec = buf.to_vec();
-        let health_check_packet = HealthCheckPacket::deserialize(buf_vec);
-
-        println!("Received: {:?}", health_check_packet);
-        response_sender.send(HealthCheckNetworkBrokerMessage {
-            payload: health_check_packet,
-            remote_addr: src
-        }).expect("HealthCheckBroken receiver forwards received messages to response_sender channel");
-        println!("Response sent to response_sender channel")
-    } // the socket is closed here
-    Ok(())
+/**
+Reads the packet in a received datagram, into the message forwarded to the listener.
+ */
+fn health_check_receiver(datagram: &Datagram, metrics: &Metrics) -> HealthCheckNetworkBrokerMessage {
+    let amt = datagram.len;
+    let src = datagram.addr;
+    // Cut to packet size, an oversized datagram is one byte longer and caught by its amt below
+    let health_check_packet = HealthCheckPacket::read_from(&datagram.buf[..HEALTH_CHECK_PACKET_SIZE.min(amt)]);
+    let trace = MessageTrace::start(&format_nonce(&health_check_packet.nonce), src, &opcode_name(health_check_packet.header), "inbound");
+    let _receive = info_span!(parent: &trace.exchange, "receive", size = amt).entered();
+    // Packets with an unknown opcode come out of read_from as NOOP, the buffer is one byte too big to spot oversized ones
+    if amt != HEALTH_CHECK_PACKET_SIZE || health_check_packet.header == NOOP_OPCODE {
+        metrics.malformed_packet();
+        warn!(peer:% = src, size = amt; "Malformed health check packet received");
+    } else {
+        metrics.packet_received(health_check_packet.header);
+        debug!(peer:% = src, opcode:% = opcode_name(health_check_packet.header), nonce:% = format_nonce(&health_check_packet.nonce);
+            "Health check packet received");
+    }
+
+    HealthCheckNetworkBrokerMessage {
+        payload: health_check_packet,
+        remote_addr: src,
+        trace: trace.response_queued()
+    }
+}
+
+/**
+Sends a batch of requests, `datagrams` is cleared and refilled so its buffers are reused across batches.
+ */
+fn health_check_sender(socket: &UdpSocket,
+                       node_id: Uuid,
+                       requests: &mut [HealthCheckNetworkBrokerMessage],
+                       datagrams: &mut Vec<Datagram>,
+                       metrics: &Metrics) {
+    datagrams.resize_with(requests.len(), Datagram::default);
+    // Open until the batch is sent, every message in it waits on the same syscall
+    let send_spans: Vec<Span> = requests.iter_mut().zip(datagrams.iter_mut())
+        .map(|(message, datagram)| write_request(message, node_id, datagram))
+        .collect();
+
+    let mut failed = send_batch(socket, datagrams).into_iter().peekable();
+    for (index, message) in requests.iter().enumerate() {
+        let result = match failed.next_if(|(failed_index, _)| *failed_index == index) {
+            Some((_, err)) => Err(err),
+            None => Ok(())
+        };
+        request_sent(message, result, metrics);
+    }
+    drop(send_spans);
 }
 
-fn health_check_sender(socket: UdpSocket, message: HealthCheckNetworkBrokerMessage) -> std::io::Result<()> {
-    {
-        println!("Health check sender invoked");
-        let request_object = message.payload;
+/**
+Stamps the local node id on a request and writes it into `datagram`, returns the span the send runs in.
+ */
+fn write_request(message: &mut HealthCheckNetworkBrokerMessage, node_id: Uuid, datagram: &mut Datagram) -> Span {
+    message.trace.dequeued();
+    let send_span = info_span!(parent: &message.trace.exchange, "send");
+    message.payload.node_id = node_id.into_bytes();
+    message.payload.write_to(&mut datagram.buf);
+    datagram.len = HEALTH_CHECK_PACKET_SIZE;
+    datagram.addr = message.remote_addr;
+    send_span
+}
 
-        let buf = &mut [0;HEALTH_CHECK_PACKET_SIZE];
-        let raw = request_object.serialize();
-        for i in 0..HEALTH_CHECK_PACKET_SIZE {
-            buf[i] = raw[i];
+fn request_sent(message: &HealthCheckNetworkBrokerMessage, result: io::Result<()>, metrics: &Metrics) {
+    match result {
+        Ok(()) => {
+            metrics.packet_sent(message.payload.header);
+            debug!(peer:% = message.remote_addr, opcode:% = opcode_name(message.payload.header), nonce:% = format_nonce(&message.payload.nonce);
+                "Health check packet sent");
         }
+        Err(err) => error!(peer:% = message.remote_addr, opcode:% = opcode_name(message.payload.header), nonce:% = format_nonce(&message.payload.nonce);
+            "Failed to send health check packet: {}", err)
+    }
+}
 
-        let dst = message.remote_addr;
-        let _amt = socket.send_to(buf, &dst)?;
-        println!("Health check message sent")
-    } // the socket is closed here
-    Ok(())
+/**
+Settings the stack reads live, so the config reloader can swap them without a restart.
+ */
+#[derive(Clone, Debug)]
+pub struct LiveSettings {
+    pub health_check_defaults: Arc<RwLock<HealthCheckDefaults>>,
+    pub static_peers: Arc<StaticPeers>,
+    pub reaper_configuration: Arc<RwLock<ReaperConfiguration>>,
+}
+
+/**
+What the stack's broker, listener, scheduler and reaper run on.
+ */
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum RuntimeMode {
+    /**
+    A thread each, see HealthCheckStack::run.
+    */
+    #[default]
+    Threads,
+    /**
+    Tasks on a tokio runtime, see HealthCheckStack::run_async.
+    */
+    #[cfg(feature = "tokio")]
+    Tokio,
+    /**
+    A single thread for the whole stack, see EventLoop. The repl, control socket, config reloader, admin API and
+    metrics server are not part of the stack and keep their own threads.
+    */
+    #[cfg(feature = "mio")]
+    EventLoop,
+}
+
+impl FromStr for RuntimeMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "threads" => Ok(RuntimeMode::Threads),
+            #[cfg(feature = "tokio")]
+            "tokio" => Ok(RuntimeMode::Tokio),
+            #[cfg(not(feature = "tokio"))]
+            "tokio" => Err(String::from("Runtime mode [tokio] needs a build with the tokio feature")),
+            #[cfg(feature = "mio")]
+            "event_loop" => Ok(RuntimeMode::EventLoop),
+            #[cfg(not(feature = "mio"))]
+            "event_loop" => Err(String::from("Runtime mode [event_loop] needs a build with the mio feature")),
+            _ => Err(format!("Unknown runtime mode [{}], expected one of threads, tokio, event_loop", s))
+        }
+    }
+}
+
+impl Display for RuntimeMode {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            RuntimeMode::Threads => write!(f, "threads"),
+            #[cfg(feature = "tokio")]
+            RuntimeMode::Tokio => write!(f, "tokio"),
+            #[cfg(feature = "mio")]
+            RuntimeMode::EventLoop => write!(f, "event_loop"),
+        }
+    }
 }
 
 pub struct HealthCheckStack {
     /**
         Clone of inner network broker request_sender channel.
     */
-    pub request_sender: Sender<HealthCheckNetworkBrokerMessage>, // Temporary maybe
+    pub request_sender: MeteredSender<HealthCheckNetworkBrokerMessage>, // Temporary maybe
     pub network_broker: HealthCheckNetworkBroker, // todo: make private
     pub health_check_network_broker_message_listener: HealthCheckNetworkBrokerMessageListener, // todo: make private
-    // network_details_store: &'static NetworkDetailsStore
+    pub network_details_store: Arc<NetworkDetailsStore>,
+    /**
+    Probes waiting on their ack, shared so probes sent from outside the scheduler get their rtt recorded too.
+    */
+    pub in_flight_probes: Arc<InFlightProbes>,
+    pub live_settings: LiveSettings,
+    /**
+    Kept by the whole stack, served when metrics.listen_address is set.
+    */
+    pub metrics: Arc<Metrics>,
+    scheduler: HealthCheckScheduler,
+    reaper: NetworkDetailsReaper
 }
 
 impl HealthCheckStack {
 
     pub fn new(network_broker: HealthCheckNetworkBroker,
                health_check_network_broker_message_listener: HealthCheckNetworkBrokerMessageListener,
-               // network_details_store: NetworkDetailsStore
+               network_details_store: Arc<NetworkDetailsStore>,
+               in_flight_probes: Arc<InFlightProbes>,
+               live_settings: LiveSettings,
+               scheduler: HealthCheckScheduler,
+               reaper: NetworkDetailsReaper
     ) -> HealthCheckStack {
 
         return HealthCheckStack {
             request_sender: network_broker.request_sender.clone(),
+            metrics: network_broker.metrics.clone(),
             network_broker,
             health_check_network_broker_message_listener,
-            // network_details_store
+            network_details_store,
+            in_flight_probes,
+            live_settings,
+            scheduler,
+            reaper
         }
     }
 
+    /**
+    Adds a handler for an application opcode before the stack runs, see OpcodeHandlerRegistry::register.
+    */
+    pub fn register_handler(&mut self, opcode: u8, handler: impl OpcodeHandler + 'static) -> Result<(), RegistrationError> {
+        self.health_check_network_broker_message_listener.register_handler(opcode, handler)
+    }
+
+    /**
+    Adds middleware around every handler before the stack runs, see MiddlewareChain.
+    */
+    pub fn add_middleware(&mut self, middleware: impl HandlerMiddleware + 'static) {
+        self.health_check_network_broker_message_listener.add_middleware(middleware);
+    }
+
+    /**
+    Sets the number of threads running handlers, see HealthCheckNetworkBrokerMessageListener::set_workers.
+    */
+    pub fn set_listener_workers(&mut self, workers: usize) {
+        self.health_check_network_broker_message_listener.set_workers(workers);
+    }
+
     pub fn run(self) {
+        if self.network_details_store.is_persistent() {
+            let network_details_store = self.network_details_store.clone();
+            thread::spawn(move || {
+                loop {
+                    thread::sleep(CHECKPOINT_INTERVAL);
+                    if let Err(err) = network_details_store.checkpoint() {
+                        error!("Failed to checkpoint network details store: {}", err);
+                    }
+                }
+            });
+        }
+
+        let metrics = self.metrics.clone();
+        let network_details_store = self.network_details_store.clone();
+        thread::spawn(move || {
+            metrics.watch_store(&network_details_store);
+        });
+
+        let scheduler = self.scheduler;
+        thread::spawn(move || {
+            scheduler.run();
+        });
+
+        let reaper = self.reaper;
+        thread::spawn(move || {
+            reaper.run();
+        });
+
         let listener_handler = thread::spawn(move || {
             self.health_check_network_broker_message_listener.run();
         });
@@ -156,6 +471,64 @@ impl HealthCheckStack {
         broker_handler.join().expect("Joined network broker in HealthCheckStack");
         listener_handler.join().expect("Joined listener in HealthCheckStack");
     }
+
+    /**
+    Like run, with the whole stack on the calling thread, see EventLoop.
+    */
+    #[cfg(feature = "mio")]
+    pub fn run_event_loop(self) {
+        self.into_event_loop().expect("Event loop started").run();
+    }
+
+    /**
+    Binds the socket and hands every component to an event loop, which is left to be run.
+    */
+    #[cfg(feature = "mio")]
+    pub fn into_event_loop(self) -> io::Result<EventLoop> {
+        EventLoop::new(self.network_broker,
+                       self.health_check_network_broker_message_listener,
+                       self.scheduler,
+                       self.reaper,
+                       self.network_details_store,
+                       self.metrics)
+    }
+
+    /**
+    Like run, with every component a task on the current tokio runtime, which needs time and io enabled.
+    Handlers and the scheduler queue their sends without awaiting, with the block drop policy use a multi thread runtime
+    so a full request channel never stops the broker from draining it.
+    The store is watched through a blocking receiver, that one keeps a thread of the runtime's blocking pool.
+    */
+    #[cfg(feature = "tokio")]
+    pub async fn run_async(self) {
+        if self.network_details_store.is_persistent() {
+            let network_details_store = self.network_details_store.clone();
+            tokio::spawn(async move {
+                loop {
+                    tokio::time::sleep(CHECKPOINT_INTERVAL).await;
+                    let network_details_store = network_details_store.clone();
+                    let checkpoint = tokio::task::spawn_blocking(move || network_details_store.checkpoint()).await;
+                    if let Ok(Err(err)) = checkpoint {
+                        error!("Failed to checkpoint network details store: {}", err);
+                    }
+                }
+            });
+        }
+
+        let metrics = self.metrics.clone();
+        let network_details_store = self.network_details_store.clone();
+        tokio::task::spawn_blocking(move || {
+            metrics.watch_store(&network_details_store);
+        });
+
+        tokio::spawn(self.scheduler.run_async());
+        tokio::spawn(self.reaper.run_async());
+        let listener_handler = tokio::spawn(self.health_check_network_broker_message_listener.run_async());
+        let broker_handler = tokio::spawn(self.network_broker.run_async());
+
+        broker_handler.await.expect("Joined network broker in HealthCheckStack");
+        listener_handler.await.expect("Joined listener in HealthCheckStack");
+    }
 }
 
 pub struct HealthCheckFactory {
@@ -185,16 +558,166 @@ pub struct HealthCheckFactory {
 //     }
 // }
 
-pub fn build_health_check_stack(receiver_addr: SocketAddr) -> HealthCheckStack {
-    let (request_sender, request_receiver) = mpsc::channel();
-    let (response_sender, response_receiver) = mpsc::channel();
+pub fn build_health_check_stack(receiver_addr: SocketAddr,
+                                node_id: Uuid,
+                                network_details_store: NetworkDetailsStore,
+                                health_check_defaults: HealthCheckDefaults,
+                                static_peers: Vec<StaticPeer>,
+                                reaper_configuration: ReaperConfiguration,
+                                channel_bounds: ChannelBounds) -> HealthCheckStack {
+    let (request_sender, request_receiver) = bounded_channel(channel_bounds);
+    let (response_sender, response_receiver) = bounded_channel(channel_bounds);
+    let metrics = Arc::new(Metrics::new(request_receiver.depth(), response_receiver.depth()));
+
+    let network_broker = HealthCheckNetworkBroker::new(receiver_addr, node_id, request_sender.clone(), request_receiver, response_sender, metrics.clone());
+    let network_details_store = Arc::new(network_details_store);
+    let health_check_defaults = Arc::new(RwLock::new(health_check_defaults));
+    let static_peers = Arc::new(StaticPeers::new(static_peers));
+    let in_flight_probes = Arc::new(InFlightProbes::new());
+    let health_check_network_broker_message_listener = HealthCheckNetworkBrokerMessageListener::new(response_receiver, request_sender.clone(), network_details_store.clone(), in_flight_probes.clone(), health_check_defaults.clone(), static_peers.clone(), metrics.clone());
 
-    let network_broker = HealthCheckNetworkBroker::new(receiver_addr, request_sender.clone(), request_receiver, response_sender);
-    // let network_details_store = NetworkDetailsStore::new();
-    let health_check_network_broker_message_listener = HealthCheckNetworkBrokerMessageListener::new(response_receiver, request_sender.clone(), NetworkDetailsStore::new());
+    let scheduler = HealthCheckScheduler::new(health_check_defaults.clone(), request_sender.clone(), network_details_store.clone(), in_flight_probes.clone(), static_peers.clone(), metrics.clone());
+
+    let reaper = NetworkDetailsReaper::new(reaper_configuration, network_details_store.clone());
+    let live_settings = LiveSettings {
+        health_check_defaults,
+        static_peers,
+        reaper_configuration: reaper.configuration(),
+    };
 
     return HealthCheckStack::new(
         network_broker,
-        health_check_network_broker_message_listener
+        health_check_network_broker_message_listener,
+        network_details_store,
+        in_flight_probes,
+        live_settings,
+        scheduler,
+        reaper
     )
+}
+#[cfg(test)]
+mod health_check_network_broker_tests {
+    use std::net::{IpAddr, SocketAddr};
+    use uuid::Uuid;
+    #[cfg(any(feature = "tokio", feature = "mio"))]
+    use crate::channel::MeteredSender;
+    #[cfg(any(feature = "tokio", feature = "mio"))]
+    use crate::health_check_network_broker::HealthCheckNetworkBrokerMessage;
+    #[cfg(any(feature = "tokio", feature = "mio"))]
+    use crate::health_check_scheduler::InFlightProbes;
+    use crate::channel::ChannelBounds;
+    use crate::health_check::HEALTH_CHECK_ACK_OPCODE;
+    use crate::health_check_network_broker::build_health_check_stack;
+    use crate::health_check_network_handlers::{HealthCheckHandlerContext, OpcodeHandlerParams, RegistrationError};
+    use crate::middleware::HandlerMiddleware;
+    use crate::network::{HealthCheckDefaults, IP, NetworkDetailsStore};
+    use crate::reaper::ReaperConfiguration;
+
+    struct PassThrough;
+
+    impl HandlerMiddleware for PassThrough {}
+
+    #[test]
+    fn applications_register_handlers_on_the_stack() {
+        let mut stack = build_health_check_stack(SocketAddr::new(IpAddr::V4(IP), 0),
+                                                 Uuid::new_v4(),
+                                                 NetworkDetailsStore::new(),
+                                                 HealthCheckDefaults::default(),
+                                                 Vec::new(),
+                                                 ReaperConfiguration::default(),
+                                                 ChannelBounds::default());
+        let handler = |_: &HealthCheckHandlerContext, _: OpcodeHandlerParams| Ok(());
+        assert_eq!(Ok(()), stack.register_handler(130, handler));
+        assert_eq!(Err(RegistrationError::AlreadyRegistered(130)), stack.register_handler(130, handler));
+        assert_eq!(Err(RegistrationError::Reserved(HEALTH_CHECK_ACK_OPCODE)), stack.register_handler(HEALTH_CHECK_ACK_OPCODE, handler),
+                   "We expect the health check protocol's own opcodes to be off limits");
+        stack.add_middleware(PassThrough);
+    }
+
+    /**
+    Has a peer socket syn the stack and ack a probe from it, checks the stack acked and recorded the peer.
+    */
+    #[cfg(any(feature = "tokio", feature = "mio"))]
+    fn exchange_with_peer(stack_addr: SocketAddr, stack_id: Uuid, request_sender: &MeteredSender<HealthCheckNetworkBrokerMessage>,
+                          in_flight_probes: &InFlightProbes, network_details_store: &NetworkDetailsStore) {
+        use std::net::UdpSocket;
+        use std::time::{Duration, Instant};
+        use crate::health_check::{HEALTH_CHECK_PACKET_SIZE, HEALTH_CHECK_SYN_OPCODE, HealthCheckPacket, SerializePacket};
+        use crate::health_check_scheduler::probe;
+        use crate::utils::generate_nonce;
+
+        let peer = UdpSocket::bind(SocketAddr::new(IpAddr::V4(IP), 0)).unwrap();
+        peer.set_read_timeout(Some(Duration::from_secs(1))).unwrap();
+        let peer_id = Uuid::new_v4();
+        let syn = HealthCheckPacket { header: HEALTH_CHECK_SYN_OPCODE, nonce: generate_nonce(), node_id: peer_id.into_bytes() };
+        let mut buf = [0; HEALTH_CHECK_PACKET_SIZE];
+        // The first one may go out before the stack is bound
+        let ack = (0..10).find_map(|_| {
+            peer.send_to(&syn.serialize(), stack_addr).unwrap();
+            peer.recv_from(&mut buf).ok().map(|_| HealthCheckPacket::read_from(&buf))
+        }).expect("We expect the stack to ack a syn");
+        assert_eq!((HEALTH_CHECK_ACK_OPCODE, syn.nonce, stack_id.into_bytes()), (ack.header, ack.nonce, ack.node_id),
+                   "We expect the ack to carry the syn's nonce and the stack's node id");
+
+        probe(request_sender, in_flight_probes, peer.local_addr().unwrap(), None, Duration::from_secs(1), Instant::now());
+        let (_, stack_addr) = peer.recv_from(&mut buf).unwrap();
+        let mut ack = HealthCheckPacket::read_from(&buf);
+        let nonce = ack.nonce;
+        ack.header = HEALTH_CHECK_ACK_OPCODE;
+        ack.node_id = peer_id.into_bytes();
+        peer.send_to(&ack.serialize(), stack_addr).unwrap();
+        let deadline = Instant::now() + Duration::from_secs(1);
+        while network_details_store.get_network_details_by_node_id(&peer_id).is_err() && Instant::now() < deadline {
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        assert!(network_details_store.get_network_details_by_node_id(&peer_id).is_ok(), "We expect the ack to our probe to add the peer to the store");
+        // The scheduler may already be probing the peer it just learned about, only our probe has to be done
+        assert!(!in_flight_probes.is_in_flight(&nonce));
+    }
+
+    #[cfg(feature = "tokio")]
+    #[test]
+    fn async_stack_acks_syns_and_records_acks() {
+        // The broker binds in run_async, so the port is picked up front
+        let stack_addr = std::net::UdpSocket::bind(SocketAddr::new(IpAddr::V4(IP), 0)).unwrap().local_addr().unwrap();
+        let node_id = Uuid::new_v4();
+        let stack = build_health_check_stack(stack_addr,
+                                             node_id,
+                                             NetworkDetailsStore::new(),
+                                             HealthCheckDefaults::default(),
+                                             Vec::new(),
+                                             ReaperConfiguration::default(),
+                                             ChannelBounds::default());
+        let request_sender = stack.request_sender.clone();
+        let in_flight_probes = stack.in_flight_probes.clone();
+        let network_details_store = stack.network_details_store.clone();
+        let runtime = tokio::runtime::Builder::new_multi_thread().enable_all().build().unwrap();
+        runtime.spawn(stack.run_async());
+
+        exchange_with_peer(stack_addr, node_id, &request_sender, &in_flight_probes, &network_details_store);
+        // The store watcher runs on the blocking pool and never returns
+        runtime.shutdown_background();
+    }
+
+    #[cfg(feature = "mio")]
+    #[test]
+    fn event_loop_acks_syns_and_records_acks() {
+        let node_id = Uuid::new_v4();
+        let stack = build_health_check_stack(SocketAddr::new(IpAddr::V4(IP), 0),
+                                             node_id,
+                                             NetworkDetailsStore::new(),
+                                             HealthCheckDefaults::default(),
+                                             Vec::new(),
+                                             ReaperConfiguration::default(),
+                                             ChannelBounds::default());
+        let request_sender = stack.request_sender.clone();
+        let in_flight_probes = stack.in_flight_probes.clone();
+        let network_details_store = stack.network_details_store.clone();
+        let event_loop = stack.into_event_loop().unwrap();
+        let stack_addr = event_loop.local_addr().unwrap();
+        // The probe is queued from this thread, so it has to wake the loop
+        std::thread::spawn(move || event_loop.run());
+
+        exchange_with_peer(stack_addr, node_id, &request_sender, &in_flight_probes, &network_details_store);
+    }
 }
\ No newline at end of file
@@ -9,60 +9,190 @@
 // TODO: update the network table
 // NOOP - log unexpected message
 
-use std::collections::{HashMap};
-use std::sync::mpsc::{Receiver, Sender};
+use std::collections::HashMap;
+use std::collections::hash_map::DefaultHasher;
+use std::error::Error;
+use std::fmt::{self, Display, Formatter};
+use std::hash::{Hash, Hasher};
+use std::net::SocketAddr;
+use std::sync::mpsc::SendError;
+use std::sync::{Arc, RwLock};
+use std::thread;
+use std::time::{Instant, SystemTime};
 use log::{debug, info, warn};
+use tracing::info_span;
+use uuid::Uuid;
 
-use crate::health_check::{HEALTH_CHECK_ACK_OPCODE, HEALTH_CHECK_SYN_OPCODE, NOOP_OPCODE};
+use crate::channel::{bounded_channel, ChannelBounds, DropPolicy, metered_channel, MeteredReceiver, MeteredSender};
+use crate::health_check::{APPLICATION_OPCODES, format_nonce, HEALTH_CHECK_ACK_OPCODE, HEALTH_CHECK_SYN_OPCODE, NOOP_OPCODE, opcode_name};
 use crate::health_check_network_broker::{HealthCheckNetworkBrokerMessage};
-use crate::network::{HealthCheck, HealthCheckConfiguration, HealthStatus, HealthStatusDetails, NetworkDetails, NetworkDetailsStore};
+use crate::health_check_scheduler::{InFlightProbes, StaticPeers};
+use crate::health_history::{HealthHistory, ProbeOutcome, ProbeResult};
+use crate::metrics::Metrics;
+use crate::middleware::{Annotations, HandlerMiddleware, MiddlewareChain};
+use crate::network::{HealthCheck, HealthCheckDefaults, HealthStatus, HealthStatusDetails, NetworkDetails, NetworkDetailsStore};
 
 pub struct HealthCheckNetworkBrokerMessageListener {
-    health_check_handler_map: HashMap<u8, fn(context: HealthCheckHandlerContext, params: OpcodeHandlerParams)>,
     /**
     Receiver from the network broker.
      */
-    network_broker_receiver: Receiver<HealthCheckNetworkBrokerMessage>,
+    network_broker_receiver: MeteredReceiver<HealthCheckNetworkBrokerMessage>,
+    /**
+    Threads handling messages, with 1 they are handled on the listener thread itself.
+    */
+    workers: usize,
+    dispatcher: HandlerDispatcher
+}
+
+/**
+Everything a message needs to be handled, shared by the listener's workers.
+ */
+struct HandlerDispatcher {
+    health_check_handler_map: OpcodeHandlerRegistry,
+    /**
+    Runs around every handler, see MiddlewareChain.
+    */
+    middleware: MiddlewareChain,
     /**
     Sender to the network broker.
      */
-    network_broker_sender: Sender<HealthCheckNetworkBrokerMessage>,
+    network_broker_sender: MeteredSender<HealthCheckNetworkBrokerMessage>,
+
+    /**
+    Store shared with the rest of the stack.
+    */
+    network_details_store: Arc<NetworkDetailsStore>,
+
+    /**
+    Probes sent by the scheduler, completed when their ack comes back.
+    */
+    in_flight_probes: Arc<InFlightProbes>,
 
     /**
+    Global settings for peers that don't override them.
+    */
+    health_check_defaults: Arc<RwLock<HealthCheckDefaults>>,
 
+    /**
+    Settings for peers from configuration, applied when they first ack.
     */
-    network_details_store: NetworkDetailsStore
+    static_peers: Arc<StaticPeers>,
+
+    metrics: Arc<Metrics>
 }
 
 impl HealthCheckNetworkBrokerMessageListener {
-    pub fn new(network_broker_receiver: Receiver<HealthCheckNetworkBrokerMessage>,
-               network_broker_sender: Sender<HealthCheckNetworkBrokerMessage>,
-    network_details_store: NetworkDetailsStore) -> HealthCheckNetworkBrokerMessageListener {
+    pub fn new(network_broker_receiver: MeteredReceiver<HealthCheckNetworkBrokerMessage>,
+               network_broker_sender: MeteredSender<HealthCheckNetworkBrokerMessage>,
+    network_details_store: Arc<NetworkDetailsStore>,
+    in_flight_probes: Arc<InFlightProbes>,
+    health_check_defaults: Arc<RwLock<HealthCheckDefaults>>,
+    static_peers: Arc<StaticPeers>,
+    metrics: Arc<Metrics>) -> HealthCheckNetworkBrokerMessageListener {
         HealthCheckNetworkBrokerMessageListener {
-            health_check_handler_map: get_health_check_handler_map(),
             network_broker_receiver,
-            network_broker_sender,
-            network_details_store
+            workers: 1,
+            dispatcher: HandlerDispatcher {
+                health_check_handler_map: get_health_check_handler_map(),
+                middleware: MiddlewareChain::default(),
+                network_broker_sender,
+                network_details_store,
+                in_flight_probes,
+                health_check_defaults,
+                static_peers,
+                metrics
+            }
         }
     }
 
+    /**
+    Adds a handler for an application opcode, see OpcodeHandlerRegistry::register.
+    */
+    pub fn register_handler(&mut self, opcode: u8, handler: impl OpcodeHandler + 'static) -> Result<(), RegistrationError> {
+        self.dispatcher.health_check_handler_map.register(opcode, handler)
+    }
+
+    /**
+    Adds middleware, it runs inside the middleware added before it.
+    */
+    pub fn add_middleware(&mut self, middleware: impl HandlerMiddleware + 'static) {
+        self.dispatcher.middleware.push(middleware);
+    }
+
+    /**
+    Handles messages on `workers` threads, so a slow handler only holds up the peers that share its worker.
+    */
+    pub fn set_workers(&mut self, workers: usize) {
+        self.workers = workers.max(1);
+    }
+
+    /**
+    Handles one message on the calling thread, for the event loop which reads the socket itself.
+    */
+    #[cfg(feature = "mio")]
+    pub fn handle(&self, message: HealthCheckNetworkBrokerMessage) {
+        self.dispatcher.dispatch(message);
+    }
+
     pub fn run(self) {
         // let receiver_handle = thread::spawn(move || {
-        loop  {
+        if self.workers == 1 {
+            loop  {
+                let next_message = self.network_broker_receiver.recv().expect("HealthCheckNetworkBrokerMessageListener message received ");
+                self.dispatcher.dispatch(next_message);
+            }
+        }
+
+        let dispatcher = Arc::new(self.dispatcher);
+        let worker_senders: Vec<MeteredSender<HealthCheckNetworkBrokerMessage>> = (0..self.workers).map(|worker| {
+            let (worker_sender, worker_receiver) = worker_channel(&self.network_broker_receiver);
+            let dispatcher = dispatcher.clone();
+            thread::Builder::new()
+                .name(format!("handler-worker-{}", worker))
+                .spawn(move || {
+                    while let Ok(message) = worker_receiver.recv() {
+                        dispatcher.dispatch(message);
+                    }
+                })
+                .expect("Handler worker started");
+            worker_sender
+        }).collect();
+        loop {
             let next_message = self.network_broker_receiver.recv().expect("HealthCheckNetworkBrokerMessageListener message received ");
-            let handler_fn = self.health_check_handler_map
-                .get(&next_message.payload.header)
-                .expect("Handler method to be found from message payload header op code");
+            // A peer always lands on the same worker, which handles its messages in the order they came in
+            let worker = worker_index(&next_message.remote_addr, worker_senders.len());
+            worker_senders[worker].send(next_message).expect("Handler worker running");
+        }
+    }
 
-            let handler_props = OpcodeHandlerParams {
-                message: next_message,
-                sender: self.network_broker_sender.clone()
-            };
-            let context = HealthCheckHandlerContext {
-                network_details_store: &self.network_details_store
-            };
+    /**
+    Like run, with the workers as tasks on the current tokio runtime. Handlers and middleware stay synchronous and run
+    on the runtime's threads, so they shouldn't block, replies they send to a blocking request channel wait for room there.
+    */
+    #[cfg(feature = "tokio")]
+    pub async fn run_async(self) {
+        if self.workers == 1 {
+            loop {
+                let next_message = self.network_broker_receiver.recv_async().await.expect("HealthCheckNetworkBrokerMessageListener message received ");
+                self.dispatcher.dispatch(next_message);
+            }
+        }
 
-            handler_fn(context, handler_props);
+        let dispatcher = Arc::new(self.dispatcher);
+        let worker_senders: Vec<MeteredSender<HealthCheckNetworkBrokerMessage>> = (0..self.workers).map(|_| {
+            let (worker_sender, worker_receiver) = worker_channel(&self.network_broker_receiver);
+            let dispatcher = dispatcher.clone();
+            tokio::spawn(async move {
+                while let Ok(message) = worker_receiver.recv_async().await {
+                    dispatcher.dispatch(message);
+                }
+            });
+            worker_sender
+        }).collect();
+        loop {
+            let next_message = self.network_broker_receiver.recv_async().await.expect("HealthCheckNetworkBrokerMessageListener message received ");
+            let worker = worker_index(&next_message.remote_addr, worker_senders.len());
+            worker_senders[worker].send_async(next_message).await.expect("Handler worker running");
         }
     }
 
@@ -77,93 +207,568 @@ impl HealthCheckNetworkBrokerMessageListener {
 
 }
 
+impl HandlerDispatcher {
+    fn dispatch(&self, mut next_message: HealthCheckNetworkBrokerMessage) {
+        next_message.trace.dequeued();
+        let handle_span = info_span!(parent: &next_message.trace.exchange, "handle", annotations = tracing::field::Empty);
+        // Taken again from the message that reaches the handler, as the middleware may rewrite it
+        let mut opcode = next_message.payload.header;
+        let remote_addr = next_message.remote_addr;
+        let mut nonce = next_message.payload.nonce;
+        // Copied, so a config reload never waits on a handler
+        let health_check_defaults = self.health_check_defaults.read().unwrap().clone();
+        let context = HealthCheckHandlerContext {
+            network_details_store: &self.network_details_store,
+            in_flight_probes: &self.in_flight_probes,
+            health_check_defaults: &health_check_defaults,
+            static_peers: &self.static_peers,
+            metrics: &self.metrics
+        };
+
+        let started_at = Instant::now();
+        let result = handle_span.in_scope(|| self.middleware.dispatch(&context, next_message, |message, annotations| {
+            // Application opcodes get past deserialize whether or not a handler was registered for them
+            if !annotations.is_empty() {
+                handle_span.record("annotations", format_annotations(&annotations));
+            }
+            opcode = message.payload.header;
+            nonce = message.payload.nonce;
+            let handler = self.health_check_handler_map.get(message.payload.header)
+                .ok_or(HandlerError::NoHandler(message.payload.header))?;
+            handler.handle(&context, OpcodeHandlerParams {
+                message,
+                sender: self.network_broker_sender.clone(),
+                annotations
+            })
+        }));
+        self.metrics.observe_handler_duration(opcode, started_at.elapsed());
+        if let Err(err) = result {
+            warn!(peer:% = remote_addr, opcode:% = opcode_name(opcode), nonce:% = format_nonce(&nonce); "Handler for opcode {} failed: {}", opcode, err);
+        }
+    }
+}
+
+/**
+Annotations the middleware left, as sorted key=value pairs so the handle span reads the same for every packet.
+ */
+fn format_annotations(annotations: &Annotations) -> String {
+    let mut pairs: Vec<String> = annotations.iter().map(|(key, value)| format!("{}={}", key, value)).collect();
+    pairs.sort();
+    pairs.join(",")
+}
+
+/**
+Channel to a worker, bounded like the broker's.
+ */
+fn worker_channel<T>(network_broker_receiver: &MeteredReceiver<HealthCheckNetworkBrokerMessage>) -> (MeteredSender<T>, MeteredReceiver<T>) {
+    // A stuck worker blocks the listener, so the broker's channel fills up and its drop policy kicks in
+    match network_broker_receiver.capacity() {
+        Some(capacity) => bounded_channel(ChannelBounds { capacity, drop_policy: DropPolicy::Block }),
+        None => metered_channel()
+    }
+}
+
+fn worker_index(remote_addr: &SocketAddr, workers: usize) -> usize {
+    let mut hasher = DefaultHasher::new();
+    remote_addr.hash(&mut hasher);
+    (hasher.finish() % workers as u64) as usize
+}
+
 
 
 #[derive(Clone, Debug)]
 pub struct OpcodeHandlerParams {
-    message: HealthCheckNetworkBrokerMessage,
-    sender: Sender<HealthCheckNetworkBrokerMessage>
+    pub message: HealthCheckNetworkBrokerMessage,
+    /**
+    Sender to the network broker, for replies.
+    */
+    pub sender: MeteredSender<HealthCheckNetworkBrokerMessage>,
+    /**
+    Left by the middleware the message went through.
+    */
+    pub annotations: Annotations
 }
 
-fn health_check_syn_opcode_handler(context: HealthCheckHandlerContext, params: OpcodeHandlerParams) {
+#[derive(Debug)]
+pub enum HandlerError {
+    /**
+    The network broker is gone, so nothing can be sent anymore.
+    */
+    BrokerDisconnected,
+    NoHandler(u8),
+    Failed(String),
+}
+
+impl Display for HandlerError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            HandlerError::BrokerDisconnected => write!(f, "Network broker is gone"),
+            HandlerError::NoHandler(opcode) => write!(f, "No handler registered for opcode {}, dropping packet", opcode),
+            HandlerError::Failed(message) => write!(f, "{}", message),
+        }
+    }
+}
+
+impl Error for HandlerError {}
+
+impl<T> From<SendError<T>> for HandlerError {
+    fn from(_: SendError<T>) -> HandlerError {
+        HandlerError::BrokerDisconnected
+    }
+}
+
+/**
+Handles the packets of one opcode. The listener's workers call it concurrently for packets from different peers,
+so state kept across packets goes behind an atomic or a lock, packets from one peer arrive one at a time and in order.
+An error is logged with the packet, the listener moves on to the next one.
+Closures taking the same arguments are handlers too.
+ */
+pub trait OpcodeHandler: Send + Sync {
+    fn handle(&self, context: &HealthCheckHandlerContext, params: OpcodeHandlerParams) -> Result<(), HandlerError>;
+}
+
+impl<F> OpcodeHandler for F where F: Fn(&HealthCheckHandlerContext, OpcodeHandlerParams) -> Result<(), HandlerError> + Send + Sync {
+    fn handle(&self, context: &HealthCheckHandlerContext, params: OpcodeHandlerParams) -> Result<(), HandlerError> {
+        self(context, params)
+    }
+}
+
+#[derive(Debug, Eq, PartialEq)]
+pub enum RegistrationError {
+    /**
+    The opcode is outside APPLICATION_OPCODES, those belong to the health check protocol.
+    */
+    Reserved(u8),
+    AlreadyRegistered(u8),
+}
+
+impl Display for RegistrationError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            RegistrationError::Reserved(opcode) => write!(f, "Opcode {} is reserved, applications can use {} to {}", opcode, APPLICATION_OPCODES.start(), APPLICATION_OPCODES.end()),
+            RegistrationError::AlreadyRegistered(opcode) => write!(f, "Opcode {} already has a handler", opcode),
+        }
+    }
+}
+
+impl Error for RegistrationError {}
+
+/**
+Handlers by opcode, the health check protocol's own and the ones applications registered.
+ */
+pub struct OpcodeHandlerRegistry {
+    handlers: HashMap<u8, Box<dyn OpcodeHandler>>
+}
+
+impl OpcodeHandlerRegistry {
+    /**
+    Adds the handler for an opcode in APPLICATION_OPCODES, an opcode gets a single handler.
+    */
+    pub fn register(&mut self, opcode: u8, handler: impl OpcodeHandler + 'static) -> Result<(), RegistrationError> {
+        if !APPLICATION_OPCODES.contains(&opcode) {
+            return Err(RegistrationError::Reserved(opcode));
+        }
+        if self.handlers.contains_key(&opcode) {
+            return Err(RegistrationError::AlreadyRegistered(opcode));
+        }
+        self.handlers.insert(opcode, Box::new(handler));
+        Ok(())
+    }
+
+    pub fn contains(&self, opcode: u8) -> bool {
+        self.handlers.contains_key(&opcode)
+    }
+
+    fn get(&self, opcode: u8) -> Option<&dyn OpcodeHandler> {
+        self.handlers.get(&opcode).map(|handler| handler.as_ref())
+    }
+}
+
+fn health_check_syn_opcode_handler(context: &HealthCheckHandlerContext, params: OpcodeHandlerParams) -> Result<(), HandlerError> {
+    debug!(peer:% = params.message.remote_addr, opcode:% = opcode_name(HEALTH_CHECK_SYN_OPCODE), nonce:% = format_nonce(&params.message.payload.nonce);
+        "Syn received from {}, acking", params.message.remote_addr);
     let mut response_object = params.message.clone();
     response_object.payload.header = HEALTH_CHECK_ACK_OPCODE;
-    params.sender.send(response_object)
-        .expect("Health Check ack response sent to message broker");
-}
-
-const MAX_LIVES_REMAINING:u8 = 3;
-
-fn health_check_ack_opcode_handler(context: HealthCheckHandlerContext, params: OpcodeHandlerParams) {
-    info!("Ack received from {}", params.message.remote_addr);
-    debug!("Params: {:?}", params);
-    // Not sure this is necessary at this point, mainly if we would want to check if a record already exists or not
-    let mut existing_record_retrieve_result = context.network_details_store.get_network_details_by_ip(&params.message.remote_addr.ip().clone());
-    // I think what I actually want to do is just add or update a record in the NetworkDetailsStore, so good to have the existing record for updating
-    let mut new_record;
-    if !existing_record_retrieve_result.is_ok() {
-        info!("record not found in network details store, will create a new one");
-        new_record = NetworkDetails {
-            addr: params.message.remote_addr.ip().clone(),
+    response_object.trace = params.message.trace.request_queued();
+    params.sender.send(response_object)?;
+    Ok(())
+}
+
+fn health_check_ack_opcode_handler(context: &HealthCheckHandlerContext, params: OpcodeHandlerParams) -> Result<(), HandlerError> {
+    let node_id = Uuid::from_bytes(params.message.payload.node_id);
+    // Acks to our own probes carry the nonce we sent, anything else is an ack we didn't ask for
+    let rtt = context.in_flight_probes.complete(&params.message.payload.nonce, Instant::now()).map(|(_, rtt)| rtt);
+    let nonce = format_nonce(&params.message.payload.nonce);
+    match rtt {
+        Some(rtt) => {
+            info!(peer:% = params.message.remote_addr, opcode:% = opcode_name(HEALTH_CHECK_ACK_OPCODE), nonce:% = nonce, node_id:% = node_id, rtt_ms = rtt.as_secs_f64() * 1000.0;
+                "Ack received from {}", params.message.remote_addr);
+            context.metrics.observe_rtt(node_id, params.message.remote_addr, rtt);
+        }
+        None => info!(peer:% = params.message.remote_addr, opcode:% = opcode_name(HEALTH_CHECK_ACK_OPCODE), nonce:% = nonce, node_id:% = node_id;
+            "Unsolicited ack received from {}", params.message.remote_addr)
+    }
+    let probe_outcome = rtt.map(|rtt| ProbeResult::Success { rtt });
+    let existing_record_retrieve_result = context.network_details_store.get_network_details_by_node_id(&node_id);
+    let now = SystemTime::now();
+    if existing_record_retrieve_result.is_err() {
+        info!(peer:% = params.message.remote_addr, opcode:% = opcode_name(HEALTH_CHECK_ACK_OPCODE), nonce:% = nonce, node_id:% = node_id;
+            "Record not found in network details store, will create a new one");
+        // Static peers get their configured settings, anyone else inherits the defaults
+        let mut configuration = context.static_peers.get(&params.message.remote_addr).unwrap_or_default();
+        configuration.health_check_port = params.message.remote_addr.port();
+        let lives_remaining = configuration.effective(context.health_check_defaults).max_lives;
+        let mut new_record = NetworkDetails {
+            node_id,
+            addr: params.message.remote_addr.ip(),
+            last_seen: now,
+            status_since: now,
+            stale: false,
+            health_history: HealthHistory::default(),
             health_check: HealthCheck {
                 status_details: HealthStatusDetails {
                     current_status: HealthStatus::Healthy,
-                    lives_remaining: MAX_LIVES_REMAINING // TODO: Probably make configurable
+                    lives_remaining
                 },
-                configuration: HealthCheckConfiguration {
-                    health_check_port: params.message.remote_addr.port(),
-                }
+                configuration
             }
         };
-    } else {
-        new_record = existing_record_retrieve_result.unwrap();
-        // One thing I'm thinking of is how to handle the health changes, would be nice to introduce a
-        // configurable policy that determines how to refresh the "lives" for the health status
-        // For now I think I'll just set lives to max on a single successful health check
-        // But was thinking about scenarios where you might want to "refill" lives with a different strategy
-        // One option could be to add a live per successful health check, to give a SMA like value for the
-        // Health status. After typing this out I will go with the incrementing policy
-        let old_lives_remaining: u8 = new_record.health_check.status_details.lives_remaining;
-        if old_lives_remaining < MAX_LIVES_REMAINING {
-            new_record.health_check.status_details.lives_remaining = old_lives_remaining + 1;
+        if let Some(result) = probe_outcome {
+            new_record.health_history.record_probe(ProbeOutcome { at: now, result });
         }
+        context.network_details_store.put_network_details(&new_record);
+    } else {
+        // Updated under the store lock, so a concurrent probe timeout or reap isn't lost
+        let _ = context.network_details_store.update_network_details(&node_id, |new_record| {
+            // The node is identified by its id, so follow it if it shows up from a new address
+            new_record.addr = params.message.remote_addr.ip();
+            new_record.health_check.configuration.health_check_port = params.message.remote_addr.port();
+            // Lives are refilled as per the peer's failure policy, see FailurePolicy
+            let configuration = new_record.health_check.configuration.effective(context.health_check_defaults);
+            new_record.record_ack(now, &configuration);
+            if let Some(result) = probe_outcome {
+                new_record.health_history.record_probe(ProbeOutcome { at: now, result });
+            }
+        });
     }
 
-    context.network_details_store.put_network_details(&new_record);
-    info!("Updated network details {:?}", context.network_details_store);
+    debug!(peer:% = params.message.remote_addr, node_id:% = node_id, nonce:% = nonce; "Updated network details for {}", node_id);
+    Ok(())
 }
 
-fn health_check_noop_opcode_handler(context: HealthCheckHandlerContext, params: OpcodeHandlerParams) {
-    warn!("TODO: implement health_check_noop_opcode_handler");
+fn health_check_noop_opcode_handler(_context: &HealthCheckHandlerContext, _params: OpcodeHandlerParams) -> Result<(), HandlerError> {
+    Ok(())
 }
 
-struct HealthCheckHandlerContext<'a> {
-    network_details_store: &'a NetworkDetailsStore
+/**
+What the stack shares with handlers, borrowed for the length of one packet.
+ */
+pub struct HealthCheckHandlerContext<'a> {
+    pub network_details_store: &'a NetworkDetailsStore,
+    pub in_flight_probes: &'a InFlightProbes,
+    pub health_check_defaults: &'a HealthCheckDefaults,
+    pub static_peers: &'a StaticPeers,
+    pub metrics: &'a Metrics
 }
 
 // health_check_handler_map: HashMap<u8, fn(context: &HealthCheckHandlerContext, params: OpcodeHandlerParams)>,
 
-pub fn get_health_check_handler_map() -> HashMap<u8, fn(context: HealthCheckHandlerContext, params: OpcodeHandlerParams)> {
-    let mut map: HashMap<u8, fn(context: HealthCheckHandlerContext, params: OpcodeHandlerParams)> = HashMap::new();
-    map.insert(NOOP_OPCODE, health_check_noop_opcode_handler);
-    map.insert(HEALTH_CHECK_SYN_OPCODE, health_check_syn_opcode_handler);
-    map.insert(HEALTH_CHECK_ACK_OPCODE, health_check_ack_opcode_handler);
-    return map;
+pub fn get_health_check_handler_map() -> OpcodeHandlerRegistry {
+    let mut handlers: HashMap<u8, Box<dyn OpcodeHandler>> = HashMap::new();
+    handlers.insert(NOOP_OPCODE, Box::new(health_check_noop_opcode_handler));
+    handlers.insert(HEALTH_CHECK_SYN_OPCODE, Box::new(health_check_syn_opcode_handler));
+    handlers.insert(HEALTH_CHECK_ACK_OPCODE, Box::new(health_check_ack_opcode_handler));
+    return OpcodeHandlerRegistry { handlers };
     // from((NOOP_OPCODE, health_check_noop_opcode_handler, HEALTH_CHECK_SYN_OPCODE, health_check_syn_opcode_handler, HEALTH_CHECK_ACK_OPCODE, health_check_ack_opcode_handler),);
 }
 
 #[cfg(test)]
 mod health_check_tests {
-    use crate::health_check::{HEALTH_CHECK_ACK_OPCODE, HEALTH_CHECK_SYN_OPCODE, NOOP_OPCODE};
-    use crate::health_check_network_handlers::{get_health_check_handler_map};
+    use std::collections::HashMap;
+    use std::net::{IpAddr, SocketAddr};
+    use std::sync::atomic::{AtomicU8, Ordering};
+    use std::sync::mpsc;
+    use std::sync::{Arc, RwLock};
+    use std::thread;
+    use std::time::{Duration, Instant};
+    use crate::channel::{metered_channel, MeteredSender};
+    use crate::health_check::{APPLICATION_OPCODES, HEALTH_CHECK_ACK_OPCODE, HEALTH_CHECK_SYN_OPCODE, HealthCheckPacket, NOOP_OPCODE};
+    use crate::health_check_network_broker::HealthCheckNetworkBrokerMessage;
+    use crate::health_check_network_handlers::{get_health_check_handler_map, HandlerError, HealthCheckHandlerContext, HealthCheckNetworkBrokerMessageListener, OpcodeHandler, OpcodeHandlerParams, RegistrationError};
+    use crate::health_check_scheduler::{InFlightProbes, StaticPeers};
+    use crate::metrics::Metrics;
+    use crate::middleware::{Annotations, HandlerMiddleware, MiddlewareAction};
+    use crate::network::{HealthCheckDefaults, IP, NetworkDetailsStore};
+    use crate::trace::MessageTrace;
+
+    const APPLICATION_OPCODE: u8 = 200;
+
+    /**
+    Counts the packets it has seen and echoes the count back in the first byte of the nonce.
+    */
+    #[derive(Default)]
+    struct CountingHandler {
+        count: AtomicU8,
+    }
+
+    impl OpcodeHandler for CountingHandler {
+        fn handle(&self, _context: &HealthCheckHandlerContext, params: OpcodeHandlerParams) -> Result<(), HandlerError> {
+            let count = self.count.fetch_add(1, Ordering::SeqCst) + 1;
+            let mut reply = params.message.clone();
+            reply.payload.nonce[0] = count;
+            params.sender.send(reply)?;
+            Ok(())
+        }
+    }
+
+    /**
+    Lets packets from port 3451 through with the peer they came from noted, drops everything else.
+    */
+    struct PortAuth;
+
+    impl HandlerMiddleware for PortAuth {
+        fn before(&self, _context: &HealthCheckHandlerContext, message: &mut HealthCheckNetworkBrokerMessage, annotations: &mut Annotations) -> MiddlewareAction {
+            if message.remote_addr.port() != 3451 {
+                return MiddlewareAction::ShortCircuit(Err(HandlerError::Failed(String::from("Unauthorized"))));
+            }
+            annotations.insert(String::from("peer"), message.remote_addr.to_string());
+            MiddlewareAction::Continue
+        }
+    }
+
+    /**
+    Rewrites the opcode after APPLICATION_OPCODE into APPLICATION_OPCODE.
+    */
+    struct Rewriter;
+
+    impl HandlerMiddleware for Rewriter {
+        fn before(&self, _context: &HealthCheckHandlerContext, message: &mut HealthCheckNetworkBrokerMessage, _annotations: &mut Annotations) -> MiddlewareAction {
+            if message.payload.header == APPLICATION_OPCODE + 1 {
+                message.payload.header = APPLICATION_OPCODE;
+            }
+            MiddlewareAction::Continue
+        }
+    }
+
+    fn message(header: u8) -> HealthCheckNetworkBrokerMessage {
+        HealthCheckNetworkBrokerMessage {
+            payload: HealthCheckPacket {
+                header,
+                nonce: [0; 16],
+                node_id: [0; 16]
+            },
+            remote_addr: SocketAddr::new(IpAddr::V4(IP), 3451),
+            trace: MessageTrace::default()
+        }
+    }
+
+    /**
+    Runs a listener with `workers` threads and `handler` for APPLICATION_OPCODE, returns the sender feeding it.
+    */
+    fn start_listener(workers: usize, handler: impl OpcodeHandler + 'static) -> MeteredSender<HealthCheckNetworkBrokerMessage> {
+        let (response_sender, response_receiver) = metered_channel();
+        let (request_sender, _request_receiver) = metered_channel();
+        let mut listener = HealthCheckNetworkBrokerMessageListener::new(response_receiver,
+                                                                        request_sender,
+                                                                        Arc::new(NetworkDetailsStore::new()),
+                                                                        Arc::new(InFlightProbes::new()),
+                                                                        Arc::new(RwLock::new(HealthCheckDefaults::default())),
+                                                                        Arc::new(StaticPeers::default()),
+                                                                        Arc::new(Metrics::default()));
+        listener.set_workers(workers);
+        listener.register_handler(APPLICATION_OPCODE, handler).unwrap();
+        thread::spawn(move || {
+            listener.run();
+        });
+        response_sender
+    }
+
+    /**
+    Sends `per_peer` messages from each of `peers` peers, interleaved, numbered per peer in the first byte of the nonce.
+    */
+    fn send_from_peers(sender: &MeteredSender<HealthCheckNetworkBrokerMessage>, peers: u16, per_peer: u8) {
+        for sequence in 0..per_peer {
+            for peer in 0..peers {
+                let mut message = message(APPLICATION_OPCODE);
+                message.remote_addr.set_port(4000 + peer);
+                message.payload.nonce[0] = sequence;
+                sender.send(message).unwrap();
+            }
+        }
+    }
 
     #[test]
     fn health_check_handler_map_contains_handlers() {
         let handler_map = get_health_check_handler_map();
-        handler_map.get(&HEALTH_CHECK_SYN_OPCODE).unwrap();
-        handler_map.get(&HEALTH_CHECK_ACK_OPCODE).unwrap();
-        handler_map.get(&NOOP_OPCODE).unwrap();
+        assert!(handler_map.contains(HEALTH_CHECK_SYN_OPCODE));
+        assert!(handler_map.contains(HEALTH_CHECK_ACK_OPCODE));
+        assert!(handler_map.contains(NOOP_OPCODE));
         // let invalid_key: u8 = 10;
         // handler_map.get(&invalid_key).unwrap();
     }
+
+    #[test]
+    fn only_free_application_opcodes_can_be_registered() {
+        let mut handler_map = get_health_check_handler_map();
+        assert_eq!(Err(RegistrationError::Reserved(HEALTH_CHECK_SYN_OPCODE)), handler_map.register(HEALTH_CHECK_SYN_OPCODE, CountingHandler::default()));
+        assert_eq!(Err(RegistrationError::Reserved(*APPLICATION_OPCODES.start() - 1)), handler_map.register(*APPLICATION_OPCODES.start() - 1, CountingHandler::default()));
+        assert_eq!(Ok(()), handler_map.register(APPLICATION_OPCODE, CountingHandler::default()));
+        let closure = |_: &HealthCheckHandlerContext, _: OpcodeHandlerParams| Ok(());
+        assert_eq!(Err(RegistrationError::AlreadyRegistered(APPLICATION_OPCODE)), handler_map.register(APPLICATION_OPCODE, closure),
+                   "We expect a second handler for the same opcode to be rejected");
+        assert!(handler_map.contains(APPLICATION_OPCODE));
+    }
+
+    #[test]
+    fn listener_dispatches_to_registered_handlers_and_keeps_their_state() {
+        let (response_sender, response_receiver) = metered_channel();
+        let (request_sender, request_receiver) = metered_channel();
+        let mut listener = HealthCheckNetworkBrokerMessageListener::new(response_receiver,
+                                                                        request_sender,
+                                                                        Arc::new(NetworkDetailsStore::new()),
+                                                                        Arc::new(InFlightProbes::new()),
+                                                                        Arc::new(RwLock::new(HealthCheckDefaults::default())),
+                                                                        Arc::new(StaticPeers::default()),
+                                                                        Arc::new(Metrics::default()));
+        listener.register_handler(APPLICATION_OPCODE, CountingHandler::default()).unwrap();
+        let (failures_sender, failures_receiver) = mpsc::channel();
+        listener.register_handler(APPLICATION_OPCODE + 1, move |_: &HealthCheckHandlerContext, params: OpcodeHandlerParams| {
+            failures_sender.send(params.message.payload.header).unwrap();
+            Err(HandlerError::Failed(String::from("Nothing to do")))
+        }).unwrap();
+        thread::spawn(move || {
+            listener.run();
+        });
+
+        // An opcode without a handler is dropped, a failing handler doesn't stop the listener
+        response_sender.send(message(APPLICATION_OPCODE + 2)).unwrap();
+        response_sender.send(message(APPLICATION_OPCODE + 1)).unwrap();
+        response_sender.send(message(APPLICATION_OPCODE)).unwrap();
+        response_sender.send(message(APPLICATION_OPCODE)).unwrap();
+
+        assert_eq!(APPLICATION_OPCODE + 1, failures_receiver.recv_timeout(Duration::from_secs(1)).unwrap());
+        let counts: Vec<u8> = (0..2).map(|_| request_receiver.recv_timeout(Duration::from_secs(1)).unwrap().payload.nonce[0]).collect();
+        assert_eq!(vec![1, 2], counts, "We expect the handler to keep its count across packets");
+    }
+
+    #[test]
+    fn listener_runs_handlers_through_middleware() {
+        let (response_sender, response_receiver) = metered_channel();
+        let (request_sender, _request_receiver) = metered_channel();
+        let mut listener = HealthCheckNetworkBrokerMessageListener::new(response_receiver,
+                                                                        request_sender,
+                                                                        Arc::new(NetworkDetailsStore::new()),
+                                                                        Arc::new(InFlightProbes::new()),
+                                                                        Arc::new(RwLock::new(HealthCheckDefaults::default())),
+                                                                        Arc::new(StaticPeers::default()),
+                                                                        Arc::new(Metrics::default()));
+        let (seen_sender, seen_receiver) = mpsc::channel();
+        listener.register_handler(APPLICATION_OPCODE, move |_: &HealthCheckHandlerContext, params: OpcodeHandlerParams| {
+            seen_sender.send(params.annotations.get("peer").cloned()).unwrap();
+            Ok(())
+        }).unwrap();
+        listener.add_middleware(PortAuth);
+        thread::spawn(move || {
+            listener.run();
+        });
+
+        let mut unauthorized = message(APPLICATION_OPCODE);
+        unauthorized.remote_addr.set_port(3452);
+        response_sender.send(unauthorized).unwrap();
+        response_sender.send(message(APPLICATION_OPCODE)).unwrap();
+
+        assert_eq!(Some(String::from("127.0.0.1:3451")), seen_receiver.recv_timeout(Duration::from_secs(1)).unwrap(),
+                   "We expect only the authorized packet to reach the handler, with its annotation");
+        assert!(seen_receiver.recv_timeout(Duration::from_millis(100)).is_err());
+    }
+
+    #[test]
+    fn listener_reports_the_opcode_the_handler_got() {
+        let (response_sender, response_receiver) = metered_channel();
+        let (request_sender, _request_receiver) = metered_channel();
+        let network_details_store = Arc::new(NetworkDetailsStore::new());
+        let metrics = Arc::new(Metrics::default());
+        let mut listener = HealthCheckNetworkBrokerMessageListener::new(response_receiver,
+                                                                        request_sender,
+                                                                        network_details_store.clone(),
+                                                                        Arc::new(InFlightProbes::new()),
+                                                                        Arc::new(RwLock::new(HealthCheckDefaults::default())),
+                                                                        Arc::new(StaticPeers::default()),
+                                                                        metrics.clone());
+        let (seen_sender, seen_receiver) = mpsc::channel();
+        listener.register_handler(APPLICATION_OPCODE, move |_: &HealthCheckHandlerContext, params: OpcodeHandlerParams| {
+            seen_sender.send(params.message.payload.header).unwrap();
+            Ok(())
+        }).unwrap();
+        listener.add_middleware(Rewriter);
+        thread::spawn(move || {
+            listener.run();
+        });
+
+        response_sender.send(message(APPLICATION_OPCODE + 1)).unwrap();
+        assert_eq!(APPLICATION_OPCODE, seen_receiver.recv_timeout(Duration::from_secs(1)).unwrap());
+        // The duration is observed once the handler returns
+        let deadline = Instant::now() + Duration::from_secs(1);
+        let mut rendered = metrics.render(&network_details_store);
+        while !rendered.contains("health_check_handler_duration_seconds_count") && Instant::now() < deadline {
+            thread::sleep(Duration::from_millis(10));
+            rendered = metrics.render(&network_details_store);
+        }
+        assert!(rendered.contains(&format!("health_check_handler_duration_seconds_count{{opcode=\"{}\"}} 1", APPLICATION_OPCODE)), "{}", rendered);
+        assert!(!rendered.contains(&format!("opcode=\"{}\"", APPLICATION_OPCODE + 1)),
+                "We expect the handler duration to be reported for the rewritten opcode");
+    }
+
+    #[test]
+    fn worker_pool_keeps_each_peers_messages_in_order() {
+        let (handled_sender, handled_receiver) = mpsc::channel();
+        let sender = start_listener(4, move |_: &HealthCheckHandlerContext, params: OpcodeHandlerParams| {
+            // Uneven handling times, so workers overtake each other
+            thread::sleep(Duration::from_micros(100 * (params.message.remote_addr.port() % 3) as u64));
+            handled_sender.send((params.message.remote_addr.port(), params.message.payload.nonce[0])).unwrap();
+            Ok(())
+        });
+        send_from_peers(&sender, 8, 20);
+
+        let mut handled: HashMap<u16, Vec<u8>> = HashMap::new();
+        for _ in 0..8 * 20 {
+            let (port, sequence) = handled_receiver.recv_timeout(Duration::from_secs(5)).unwrap();
+            handled.entry(port).or_default().push(sequence);
+        }
+        assert_eq!(8, handled.len());
+        for (port, sequences) in handled {
+            assert_eq!((0..20).collect::<Vec<u8>>(), sequences, "We expect the messages of peer {} to be handled in the order they came in", port);
+        }
+    }
+
+    /**
+    Slow handler throughput of the single threaded loop against a worker pool, run with
+    cargo test --release -- --ignored --nocapture bench_
+    */
+    #[test]
+    #[ignore]
+    fn bench_worker_pool_against_single_threaded_loop() {
+        const PEERS: u16 = 32;
+        const PER_PEER: u8 = 25;
+        let mut elapsed_by_workers = Vec::new();
+        for workers in [1, 2, 4, 8, 16] {
+            let (handled_sender, handled_receiver) = mpsc::channel();
+            let sender = start_listener(workers, move |_: &HealthCheckHandlerContext, _: OpcodeHandlerParams| {
+                // Stands in for a store write or a webhook
+                thread::sleep(Duration::from_millis(1));
+                handled_sender.send(()).unwrap();
+                Ok(())
+            });
+            let started_at = Instant::now();
+            send_from_peers(&sender, PEERS, PER_PEER);
+            for _ in 0..PEERS as usize * PER_PEER as usize {
+                handled_receiver.recv_timeout(Duration::from_secs(30)).unwrap();
+            }
+            let elapsed = started_at.elapsed();
+            println!("workers={:<2} messages={} elapsed={:?} throughput={:.0} msg/s", workers, PEERS as usize * PER_PEER as usize, elapsed,
+                     (PEERS as usize * PER_PEER as usize) as f64 / elapsed.as_secs_f64());
+            elapsed_by_workers.push(elapsed);
+        }
+        assert!(elapsed_by_workers[3] < elapsed_by_workers[0], "We expect 8 workers to beat the single threaded loop on a slow handler");
+    }
 }
\ No newline at end of file
@@ -0,0 +1,547 @@
+// Scheduler
+// Periodically sends a SYN to every known peer, and keeps track of the probes in flight by nonce
+// Static peers from configuration are probed by address until they ack and get a record in the store
+// Acks complete a probe in the ack handler, probes that are not acked within the timeout are recorded as timeouts
+
+use std::collections::{HashMap, HashSet};
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex, RwLock};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
+use log::{debug, error, warn};
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::channel::MeteredSender;
+use crate::health_check::{format_nonce, HEALTH_CHECK_SYN_OPCODE, HealthCheckPacket, opcode_name};
+use crate::health_check_network_broker::HealthCheckNetworkBrokerMessage;
+use crate::metrics::Metrics;
+use crate::network::{HealthCheckConfiguration, HealthCheckDefaults, NetworkDetailsStore};
+use crate::trace::MessageTrace;
+use crate::utils::generate_nonce;
+
+pub const SCHEDULER_TICK: Duration = Duration::from_millis(100);
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct InFlightProbe {
+    pub target: SocketAddr,
+    /**
+    None when probing an address we haven't heard from yet.
+    */
+    pub node_id: Option<Uuid>,
+    pub sent_at: Instant,
+    /**
+    How long to wait for the ack before the probe counts as a timeout.
+    */
+    pub timeout: Duration,
+}
+
+/**
+Probes sent and not yet acked, keyed by nonce. Shared between the scheduler and the ack handler.
+ */
+#[derive(Debug, Default)]
+pub struct InFlightProbes {
+    probes: Mutex<HashMap<[u8; 16], InFlightProbe>>,
+}
+
+impl InFlightProbes {
+    pub fn new() -> InFlightProbes {
+        InFlightProbes::default()
+    }
+
+    pub fn start(&self, nonce: [u8; 16], probe: InFlightProbe) {
+        self.probes.lock().unwrap().insert(nonce, probe);
+    }
+
+    /**
+    Completes the probe for `nonce`, returning it along with its round trip time.
+    */
+    pub fn complete(&self, nonce: &[u8; 16], now: Instant) -> Option<(InFlightProbe, Duration)> {
+        let probe = self.probes.lock().unwrap().remove(nonce)?;
+        let rtt = now.saturating_duration_since(probe.sent_at);
+        Some((probe, rtt))
+    }
+
+    pub fn is_in_flight(&self, nonce: &[u8; 16]) -> bool {
+        self.probes.lock().unwrap().contains_key(nonce)
+    }
+
+    pub fn count(&self) -> usize {
+        self.probes.lock().unwrap().len()
+    }
+
+    /**
+    Removes and returns every probe that has been waiting on its ack for longer than its timeout, along with its nonce.
+    */
+    pub fn expire(&self, now: Instant) -> Vec<([u8; 16], InFlightProbe)> {
+        let mut probes = self.probes.lock().unwrap();
+        let expired_nonces: Vec<[u8; 16]> = probes.iter()
+            .filter(|(_, probe)| now.saturating_duration_since(probe.sent_at) >= probe.timeout)
+            .map(|(nonce, _)| *nonce)
+            .collect();
+        expired_nonces.iter()
+            .filter_map(|nonce| probes.remove(nonce).map(|probe| (*nonce, probe)))
+            .collect()
+    }
+}
+
+/**
+A peer from configuration or added at runtime, its node id is only learnt once it acks.
+ */
+#[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
+pub struct StaticPeer {
+    pub addr: SocketAddr,
+    pub configuration: HealthCheckConfiguration,
+}
+
+/**
+Static peers by address. Shared with the ack handler, so the record created on the first ack gets the configured settings,
+with the config reloader, which swaps in the peers of a new config, and with the control socket, which adds peers at runtime.
+ */
+#[derive(Debug, Default)]
+pub struct StaticPeers {
+    peers: RwLock<StaticPeerMaps>,
+}
+
+#[derive(Debug, Default)]
+struct StaticPeerMaps {
+    configured: HashMap<SocketAddr, HealthCheckConfiguration>,
+    /**
+    Kept across config reloads, and take precedence over a configured peer at the same address.
+    */
+    added_at_runtime: HashMap<SocketAddr, HealthCheckConfiguration>,
+}
+
+impl StaticPeerMaps {
+    fn get(&self, addr: &SocketAddr) -> Option<&HealthCheckConfiguration> {
+        self.added_at_runtime.get(addr).or_else(|| self.configured.get(addr))
+    }
+
+    fn addrs(&self) -> HashSet<SocketAddr> {
+        self.configured.keys().chain(self.added_at_runtime.keys()).cloned().collect()
+    }
+}
+
+/**
+What changed when static peers were replaced, by address.
+ */
+#[derive(Debug, Default, Eq, PartialEq)]
+pub struct StaticPeersDiff {
+    pub added: Vec<SocketAddr>,
+    pub removed: Vec<SocketAddr>,
+    pub updated: Vec<SocketAddr>,
+}
+
+impl StaticPeersDiff {
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.removed.is_empty() && self.updated.is_empty()
+    }
+}
+
+impl StaticPeers {
+    pub fn new(static_peers: Vec<StaticPeer>) -> StaticPeers {
+        StaticPeers {
+            peers: RwLock::new(StaticPeerMaps {
+                configured: to_peer_map(static_peers),
+                added_at_runtime: HashMap::new(),
+            })
+        }
+    }
+
+    pub fn get(&self, addr: &SocketAddr) -> Option<HealthCheckConfiguration> {
+        self.peers.read().unwrap().get(addr).cloned()
+    }
+
+    pub fn list(&self) -> Vec<StaticPeer> {
+        let peers = self.peers.read().unwrap();
+        peers.addrs().into_iter()
+            .filter_map(|addr| peers.get(&addr).map(|configuration| StaticPeer {
+                addr,
+                configuration: configuration.clone()
+            }))
+            .collect()
+    }
+
+    /**
+    Replaces the configured static peers with `static_peers`, returning what changed. Peers added at runtime are kept.
+    Addresses in each list are sorted.
+    */
+    pub fn replace(&self, static_peers: Vec<StaticPeer>) -> StaticPeersDiff {
+        let mut peers = self.peers.write().unwrap();
+        let old_addrs = peers.addrs();
+        let old_configurations: HashMap<SocketAddr, HealthCheckConfiguration> = old_addrs.iter()
+            .filter_map(|addr| peers.get(addr).map(|configuration| (*addr, configuration.clone())))
+            .collect();
+        peers.configured = to_peer_map(static_peers);
+
+        let new_addrs = peers.addrs();
+        let mut diff = StaticPeersDiff::default();
+        for addr in new_addrs.iter() {
+            match old_configurations.get(addr) {
+                None => diff.added.push(*addr),
+                Some(old_configuration) if Some(old_configuration) != peers.get(addr) => diff.updated.push(*addr),
+                Some(_) => {}
+            }
+        }
+        diff.removed = old_addrs.difference(&new_addrs).cloned().collect();
+        diff.added.sort();
+        diff.removed.sort();
+        diff.updated.sort();
+        diff
+    }
+
+    /**
+    Adds or replaces a peer at runtime, returns false if one was already added at that address.
+    */
+    pub fn add(&self, static_peer: StaticPeer) -> bool {
+        self.peers.write().unwrap().added_at_runtime.insert(static_peer.addr, static_peer.configuration).is_none()
+    }
+
+    /**
+    Removes the peer at `addr`, returns false if there was none. A peer from the config file comes back on the next reload.
+    */
+    pub fn remove(&self, addr: &SocketAddr) -> bool {
+        let mut peers = self.peers.write().unwrap();
+        let added_at_runtime = peers.added_at_runtime.remove(addr).is_some();
+        let configured = peers.configured.remove(addr).is_some();
+        added_at_runtime || configured
+    }
+}
+
+fn to_peer_map(static_peers: Vec<StaticPeer>) -> HashMap<SocketAddr, HealthCheckConfiguration> {
+    static_peers.into_iter()
+        .map(|static_peer| (static_peer.addr, static_peer.configuration))
+        .collect()
+}
+
+pub struct HealthCheckScheduler {
+    /**
+    Global settings for peers that don't override them.
+    */
+    health_check_defaults: Arc<RwLock<HealthCheckDefaults>>,
+    /**
+    Sender to the network broker.
+    */
+    network_broker_sender: MeteredSender<HealthCheckNetworkBrokerMessage>,
+    network_details_store: Arc<NetworkDetailsStore>,
+    in_flight_probes: Arc<InFlightProbes>,
+    static_peers: Arc<StaticPeers>,
+    metrics: Arc<Metrics>,
+    next_probe_at: HashMap<Uuid, Instant>,
+    next_static_probe_at: HashMap<SocketAddr, Instant>,
+}
+
+impl HealthCheckScheduler {
+    pub fn new(health_check_defaults: Arc<RwLock<HealthCheckDefaults>>,
+               network_broker_sender: MeteredSender<HealthCheckNetworkBrokerMessage>,
+               network_details_store: Arc<NetworkDetailsStore>,
+               in_flight_probes: Arc<InFlightProbes>,
+               static_peers: Arc<StaticPeers>,
+               metrics: Arc<Metrics>) -> HealthCheckScheduler {
+        HealthCheckScheduler {
+            health_check_defaults,
+            network_broker_sender,
+            network_details_store,
+            in_flight_probes,
+            static_peers,
+            metrics,
+            next_probe_at: HashMap::new(),
+            next_static_probe_at: HashMap::new(),
+        }
+    }
+
+    pub fn run(mut self) {
+        loop {
+            self.tick(Instant::now(), SystemTime::now());
+            thread::sleep(SCHEDULER_TICK);
+        }
+    }
+
+    /**
+    Like run, ticking on the current tokio runtime.
+    */
+    #[cfg(feature = "tokio")]
+    pub async fn run_async(mut self) {
+        let mut ticks = tokio::time::interval(SCHEDULER_TICK);
+        // Like the sleep in run, a late tick pushes back the ones after it
+        ticks.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+        loop {
+            ticks.tick().await;
+            self.tick(Instant::now(), SystemTime::now());
+        }
+    }
+
+    /**
+    Sends a SYN to every enabled peer that is due a probe, highest priority first, and records the probes that timed out.
+    */
+    pub fn tick(&mut self, now: Instant, wall_clock_now: SystemTime) {
+        let snapshot = self.network_details_store.snapshot();
+        // Copied once per tick, so a config reload doesn't wait on a whole tick
+        let health_check_defaults = self.health_check_defaults.read().unwrap().clone();
+        // Built once per tick, so forgetting peers and matching static peers stay linear in the peer count
+        let known_node_ids: HashSet<Uuid> = snapshot.iter().map(|record| record.node_id).collect();
+        let known_socket_addrs: HashSet<SocketAddr> = snapshot.iter().map(|record| record.socket_addr()).collect();
+        let mut due = Vec::new();
+        for record in snapshot.iter() {
+            let configuration = record.health_check.configuration.effective(&health_check_defaults);
+            if !configuration.enabled {
+                self.next_probe_at.remove(&record.node_id);
+                continue;
+            }
+            let is_due = match self.next_probe_at.get(&record.node_id) {
+                Some(next_probe_at) => *next_probe_at <= now,
+                None => true
+            };
+            if is_due {
+                due.push((record, configuration));
+            }
+        }
+        // Stable sort, so peers of equal priority keep the store order
+        due.sort_by_key(|(_, configuration)| std::cmp::Reverse(configuration.priority));
+        for (record, configuration) in due {
+            probe(&self.network_broker_sender, &self.in_flight_probes, record.socket_addr(), Some(record.node_id), configuration.probe_timeout, now);
+            self.next_probe_at.insert(record.node_id, now + configuration.probe_interval);
+        }
+        // Forget peers that left the store
+        self.next_probe_at.retain(|node_id, _| known_node_ids.contains(node_id));
+
+        for static_peer in self.static_peers.list() {
+            let configuration = static_peer.configuration.effective(&health_check_defaults);
+            let known = known_socket_addrs.contains(&static_peer.addr);
+            if known || !configuration.enabled {
+                self.next_static_probe_at.remove(&static_peer.addr);
+                continue;
+            }
+            let is_due = match self.next_static_probe_at.get(&static_peer.addr) {
+                Some(next_probe_at) => *next_probe_at <= now,
+                None => true
+            };
+            if is_due {
+                probe(&self.network_broker_sender, &self.in_flight_probes, static_peer.addr, None, configuration.probe_timeout, now);
+                self.next_static_probe_at.insert(static_peer.addr, now + configuration.probe_interval);
+            }
+        }
+
+        for (nonce, expired) in self.in_flight_probes.expire(now) {
+            self.metrics.ack_timeout();
+            match expired.node_id {
+                Some(node_id) => {
+                    warn!(peer:% = expired.target, node_id:% = node_id, nonce:% = format_nonce(&nonce); "Health check probe to {} ({}) timed out", expired.target, node_id);
+                    let _ = self.network_details_store.update_network_details(&node_id, |record| {
+                        let configuration = record.health_check.configuration.effective(&health_check_defaults);
+                        record.record_probe_timeout(wall_clock_now, &configuration)
+                    });
+                }
+                None => warn!(peer:% = expired.target, nonce:% = format_nonce(&nonce); "Health check probe to {} timed out", expired.target)
+            }
+        }
+    }
+}
+
+/**
+Sends a SYN to `target` through the network broker and tracks it as in flight, returns the nonce of the probe.
+ */
+pub fn probe(network_broker_sender: &MeteredSender<HealthCheckNetworkBrokerMessage>,
+             in_flight_probes: &InFlightProbes,
+             target: SocketAddr,
+             node_id: Option<Uuid>,
+             timeout: Duration,
+             now: Instant) -> [u8; 16] {
+    let nonce = generate_nonce();
+    in_flight_probes.start(nonce, InFlightProbe {
+        target,
+        node_id,
+        sent_at: now,
+        timeout,
+    });
+    debug!(peer:% = target, opcode:% = opcode_name(HEALTH_CHECK_SYN_OPCODE), nonce:% = format_nonce(&nonce); "Probing {}", target);
+    let sent = network_broker_sender.send(HealthCheckNetworkBrokerMessage {
+        payload: HealthCheckPacket {
+            header: HEALTH_CHECK_SYN_OPCODE,
+            nonce,
+            node_id: [0; 16] // Stamped by the network broker
+        },
+        remote_addr: target,
+        trace: MessageTrace::start(&format_nonce(&nonce), target, &opcode_name(HEALTH_CHECK_SYN_OPCODE), "outbound").request_queued(),
+    });
+    if sent.is_err() {
+        error!("Network broker is gone, could not probe {}", target);
+    }
+    nonce
+}
+
+#[cfg(test)]
+mod health_check_scheduler_tests {
+    use std::net::{IpAddr, SocketAddr};
+    use std::sync::{Arc, RwLock};
+    use std::time::{Duration, Instant, UNIX_EPOCH};
+    use uuid::Uuid;
+    use crate::channel::metered_channel;
+    use crate::health_check::HEALTH_CHECK_SYN_OPCODE;
+    use crate::health_check_scheduler::{HealthCheckScheduler, InFlightProbes, StaticPeer, StaticPeers, StaticPeersDiff};
+    use crate::health_history::{HealthHistory, ProbeResult};
+    use crate::metrics::Metrics;
+    use crate::network::{HealthCheck, HealthCheckConfiguration, HealthCheckDefaults, HealthStatus, HealthStatusDetails, IP, NetworkDetails, NetworkDetailsStore};
+
+    fn dummy_record() -> NetworkDetails {
+        NetworkDetails {
+            node_id: Uuid::new_v4(),
+            addr: IpAddr::V4(IP),
+            last_seen: UNIX_EPOCH,
+            status_since: UNIX_EPOCH,
+            stale: false,
+            health_history: HealthHistory::default(),
+            health_check: HealthCheck {
+                status_details: HealthStatusDetails {
+                    current_status: HealthStatus::Healthy,
+                    lives_remaining: 3,
+                },
+                configuration: HealthCheckConfiguration {
+                    health_check_port: 3450,
+                    ..HealthCheckConfiguration::default()
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn scheduler_probes_peers_and_records_timeouts() {
+        let (sender, receiver) = metered_channel();
+        let store = Arc::new(NetworkDetailsStore::new());
+        let record = dummy_record();
+        store.put_network_details(&record);
+        let in_flight_probes = Arc::new(InFlightProbes::new());
+        let metrics = Arc::new(Metrics::default());
+        let mut scheduler = HealthCheckScheduler::new(Arc::new(RwLock::new(HealthCheckDefaults {
+            probe_interval: Duration::from_secs(5),
+            probe_timeout: Duration::from_secs(2),
+            ..HealthCheckDefaults::default()
+        })), sender, store.clone(), in_flight_probes.clone(), Arc::new(StaticPeers::default()), metrics.clone());
+
+        let start = Instant::now();
+        scheduler.tick(start, UNIX_EPOCH);
+        let probe = receiver.try_recv().unwrap();
+        assert_eq!(HEALTH_CHECK_SYN_OPCODE, probe.payload.header);
+        assert_eq!(record.socket_addr(), probe.remote_addr);
+
+        // Not due again until the probe interval passed
+        scheduler.tick(start + Duration::from_secs(1), UNIX_EPOCH);
+        assert!(receiver.try_recv().is_err());
+
+        scheduler.tick(start + Duration::from_secs(2), UNIX_EPOCH + Duration::from_secs(2));
+        let updated = store.get_network_details_by_node_id(&record.node_id).unwrap();
+        assert_eq!(HealthStatus::AtRisk, updated.health_check.status_details.current_status);
+        assert_eq!(ProbeResult::Timeout, updated.health_history.probes.back().unwrap().result);
+        assert!(in_flight_probes.complete(&probe.payload.nonce, start).is_none());
+        assert!(metrics.render(&store).contains("health_check_ack_timeouts_total 1\n"), "We expect the timeout to be counted");
+    }
+
+    #[test]
+    fn in_flight_probe_completes_with_rtt() {
+        let (sender, _receiver) = metered_channel();
+        let in_flight_probes = InFlightProbes::new();
+        let start = Instant::now();
+        let nonce = crate::health_check_scheduler::probe(&sender, &in_flight_probes, dummy_record().socket_addr(), None, Duration::from_secs(1), start);
+
+        let (probe, rtt) = in_flight_probes.complete(&nonce, start + Duration::from_millis(7)).unwrap();
+        assert_eq!(None, probe.node_id);
+        assert_eq!(Duration::from_millis(7), rtt);
+        assert!(in_flight_probes.complete(&nonce, start).is_none());
+    }
+
+    #[test]
+    fn scheduler_honors_per_peer_configuration() {
+        let (sender, receiver) = metered_channel();
+        let store = Arc::new(NetworkDetailsStore::new());
+        let mut disabled = dummy_record();
+        disabled.health_check.configuration.enabled = false;
+        let mut low_priority = dummy_record();
+        low_priority.health_check.configuration.health_check_port = 3451;
+        let mut high_priority = dummy_record();
+        high_priority.health_check.configuration.health_check_port = 3452;
+        high_priority.health_check.configuration.priority = Some(10);
+        high_priority.health_check.configuration.probe_interval = Some(Duration::from_secs(1));
+        for record in [&disabled, &low_priority, &high_priority] {
+            store.put_network_details(record);
+        }
+        let mut scheduler = HealthCheckScheduler::new(Arc::new(RwLock::new(HealthCheckDefaults::default())), sender, store, Arc::new(InFlightProbes::new()), Arc::new(StaticPeers::default()), Arc::new(Metrics::default()));
+
+        let start = Instant::now();
+        scheduler.tick(start, UNIX_EPOCH);
+        let targets: Vec<_> = receiver.try_iter().map(|message| message.remote_addr).collect();
+        assert_eq!(vec![high_priority.socket_addr(), low_priority.socket_addr()], targets, "We expect disabled peers to be skipped and higher priorities to go first");
+
+        scheduler.tick(start + Duration::from_secs(1), UNIX_EPOCH);
+        let targets: Vec<_> = receiver.try_iter().map(|message| message.remote_addr).collect();
+        assert_eq!(vec![high_priority.socket_addr()], targets);
+    }
+
+    #[test]
+    fn scheduler_probes_static_peers_until_they_are_known() {
+        let (sender, receiver) = metered_channel();
+        let store = Arc::new(NetworkDetailsStore::new());
+        let record = dummy_record();
+        let static_peers = Arc::new(StaticPeers::new(vec![StaticPeer {
+            addr: record.socket_addr(),
+            configuration: HealthCheckConfiguration {
+                health_check_port: record.socket_addr().port(),
+                probe_interval: Some(Duration::from_secs(1)),
+                ..HealthCheckConfiguration::default()
+            }
+        }]));
+        let mut scheduler = HealthCheckScheduler::new(Arc::new(RwLock::new(HealthCheckDefaults::default())), sender, store.clone(), Arc::new(InFlightProbes::new()), static_peers, Arc::new(Metrics::default()));
+
+        let start = Instant::now();
+        scheduler.tick(start, UNIX_EPOCH);
+        assert_eq!(record.socket_addr(), receiver.try_recv().unwrap().remote_addr);
+
+        // Once it acked it is probed like any other peer in the store, and no longer by address
+        store.put_network_details(&record);
+        scheduler.tick(start + Duration::from_secs(1), UNIX_EPOCH);
+        assert_eq!(1, receiver.try_iter().count());
+    }
+
+    #[test]
+    fn static_peers_replace_reports_the_diff() {
+        let static_peer = |port: u16, priority: Option<u8>| StaticPeer {
+            addr: SocketAddr::new(IpAddr::V4(IP), port),
+            configuration: HealthCheckConfiguration {
+                health_check_port: port,
+                priority,
+                ..HealthCheckConfiguration::default()
+            }
+        };
+        let static_peers = StaticPeers::new(vec![static_peer(3450, None), static_peer(3451, None)]);
+
+        let diff = static_peers.replace(vec![static_peer(3451, Some(1)), static_peer(3452, None)]);
+        assert_eq!(StaticPeersDiff {
+            added: vec![static_peer(3452, None).addr],
+            removed: vec![static_peer(3450, None).addr],
+            updated: vec![static_peer(3451, None).addr],
+        }, diff);
+        assert_eq!(Some(1), static_peers.get(&static_peer(3451, None).addr).unwrap().priority);
+        assert!(static_peers.replace(static_peers.list()).is_empty());
+    }
+
+    #[test]
+    fn static_peers_added_at_runtime_survive_replace() {
+        let static_peer = |port: u16| StaticPeer {
+            addr: SocketAddr::new(IpAddr::V4(IP), port),
+            configuration: HealthCheckConfiguration {
+                health_check_port: port,
+                ..HealthCheckConfiguration::default()
+            }
+        };
+        let static_peers = StaticPeers::new(vec![static_peer(3450)]);
+        assert!(static_peers.add(static_peer(3451)));
+        assert!(!static_peers.add(static_peer(3451)));
+
+        let diff = static_peers.replace(Vec::new());
+        assert_eq!(vec![static_peer(3450).addr], diff.removed);
+        assert_eq!(vec![static_peer(3451)], static_peers.list());
+
+        assert!(static_peers.remove(&static_peer(3451).addr));
+        assert!(!static_peers.remove(&static_peer(3451).addr));
+        assert!(static_peers.list().is_empty());
+    }
+}
@@ -0,0 +1,217 @@
+// Health history
+// Bounded history of probe outcomes and status transitions kept on every NetworkDetails record,
+// used to work out availability over recent windows. Availability is counted in fixed width buckets
+// so the history written with every probe stays the same size whatever the peer's probe interval
+
+use std::collections::VecDeque;
+use std::time::{Duration, SystemTime};
+use serde::{Deserialize, Serialize};
+
+use crate::network::HealthStatus;
+
+pub const ONE_MINUTE: Duration = Duration::from_secs(60);
+pub const FIVE_MINUTES: Duration = Duration::from_secs(5 * 60);
+pub const ONE_HOUR: Duration = Duration::from_secs(60 * 60);
+
+/**
+Probe counts are kept for the longest uptime window, whatever the peer's probe interval.
+ */
+pub const PROBE_HISTORY_RETENTION: Duration = ONE_HOUR;
+/**
+Width of the buckets probes are counted in, windows are accurate to a bucket.
+ */
+pub const AVAILABILITY_BUCKET_WIDTH: Duration = Duration::from_secs(10);
+/**
+Only the latest outcomes are kept as is, for their rtt and timestamp.
+ */
+pub const PROBE_HISTORY_CAPACITY: usize = 64;
+pub const STATUS_TRANSITION_HISTORY_CAPACITY: usize = 64;
+
+#[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
+pub enum ProbeResult {
+    Success {
+        rtt: Duration
+    },
+    Timeout,
+}
+
+#[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
+pub struct ProbeOutcome {
+    pub at: SystemTime,
+    pub result: ProbeResult,
+}
+
+/**
+Probes that landed in the `AVAILABILITY_BUCKET_WIDTH` starting at `start`.
+ */
+#[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
+pub struct AvailabilityBucket {
+    pub start: SystemTime,
+    pub successes: u32,
+    pub total: u32,
+}
+
+#[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
+pub struct StatusTransition {
+    pub at: SystemTime,
+    pub from: HealthStatus,
+    pub to: HealthStatus,
+}
+
+#[derive(Clone, Debug, Default, Eq, PartialEq, Serialize, Deserialize)]
+pub struct HealthHistory {
+    /**
+    Oldest first, the oldest outcome is dropped once `PROBE_HISTORY_CAPACITY` is reached.
+    */
+    pub probes: VecDeque<ProbeOutcome>,
+    /**
+    Oldest first, buckets ending more than `PROBE_HISTORY_RETENTION` before the newest probe are dropped.
+    */
+    #[serde(default)]
+    pub buckets: VecDeque<AvailabilityBucket>,
+    /**
+    Oldest first, the oldest transition is dropped once `STATUS_TRANSITION_HISTORY_CAPACITY` is reached.
+    */
+    pub transitions: VecDeque<StatusTransition>,
+}
+
+/**
+Share of successful probes over each window, None when no probe landed in the window.
+ */
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct UptimeStatistics {
+    pub one_minute: Option<f64>,
+    pub five_minutes: Option<f64>,
+    pub one_hour: Option<f64>,
+}
+
+impl HealthHistory {
+    pub fn record_probe(&mut self, outcome: ProbeOutcome) {
+        self.count_probe(&outcome);
+        while self.probes.len() >= PROBE_HISTORY_CAPACITY {
+            self.probes.pop_front();
+        }
+        self.probes.push_back(outcome);
+    }
+
+    fn count_probe(&mut self, outcome: &ProbeOutcome) {
+        let retained_from = outcome.at.checked_sub(PROBE_HISTORY_RETENTION).unwrap_or(SystemTime::UNIX_EPOCH);
+        while self.buckets.front().is_some_and(|bucket| bucket.start + AVAILABILITY_BUCKET_WIDTH <= retained_from) {
+            self.buckets.pop_front();
+        }
+        // A probe older than the newest bucket, after a clock step back, is counted in the newest bucket
+        if self.buckets.back().is_none_or(|bucket| outcome.at >= bucket.start + AVAILABILITY_BUCKET_WIDTH) {
+            self.buckets.push_back(AvailabilityBucket {
+                start: bucket_start(outcome.at),
+                successes: 0,
+                total: 0,
+            });
+        }
+        let bucket = self.buckets.back_mut().unwrap();
+        bucket.total += 1;
+        if let ProbeResult::Success { .. } = outcome.result {
+            bucket.successes += 1;
+        }
+    }
+
+    pub fn record_transition(&mut self, transition: StatusTransition) {
+        if self.transitions.len() == STATUS_TRANSITION_HISTORY_CAPACITY {
+            self.transitions.pop_front();
+        }
+        self.transitions.push_back(transition);
+    }
+
+    /**
+    Percentage of successful probes in the buckets starting within the `window` before `now`.
+    */
+    pub fn availability(&self, window: Duration, now: SystemTime) -> Option<f64> {
+        let window_start = now.checked_sub(window).unwrap_or(SystemTime::UNIX_EPOCH);
+        let mut total = 0u64;
+        let mut successes = 0u64;
+        // Newest first, so we can stop at the first bucket outside the window
+        for bucket in self.buckets.iter().rev() {
+            if bucket.start < window_start {
+                break;
+            }
+            total += bucket.total as u64;
+            successes += bucket.successes as u64;
+        }
+        if total == 0 {
+            return None;
+        }
+        Some(successes as f64 * 100.0 / total as f64)
+    }
+
+    pub fn uptime_statistics(&self, now: SystemTime) -> UptimeStatistics {
+        UptimeStatistics {
+            one_minute: self.availability(ONE_MINUTE, now),
+            five_minutes: self.availability(FIVE_MINUTES, now),
+            one_hour: self.availability(ONE_HOUR, now),
+        }
+    }
+}
+
+fn bucket_start(at: SystemTime) -> SystemTime {
+    let since_epoch = at.duration_since(SystemTime::UNIX_EPOCH).unwrap_or_default();
+    let width = AVAILABILITY_BUCKET_WIDTH.as_secs();
+    SystemTime::UNIX_EPOCH + Duration::from_secs(since_epoch.as_secs() / width * width)
+}
+
+#[cfg(test)]
+mod health_history_tests {
+    use std::time::{Duration, UNIX_EPOCH};
+    use crate::health_history::{AvailabilityBucket, HealthHistory, PROBE_HISTORY_CAPACITY, ProbeOutcome, ProbeResult, UptimeStatistics};
+
+    fn probe(at_secs: u64, result: ProbeResult) -> ProbeOutcome {
+        ProbeOutcome {
+            at: UNIX_EPOCH + Duration::from_secs(at_secs),
+            result
+        }
+    }
+
+    #[test]
+    fn probe_history_keeps_the_last_hour_at_any_interval() {
+        let mut history = HealthHistory::default();
+        // Ten probes a second, every other one a timeout
+        for i in 0..2 * 3600 * 10 {
+            let result = if i % 2 == 0 { ProbeResult::Timeout } else { ProbeResult::Success { rtt: Duration::from_millis(3) } };
+            history.record_probe(ProbeOutcome { at: UNIX_EPOCH + Duration::from_millis(i * 100), result });
+        }
+        assert_eq!(361, history.buckets.len());
+        assert_eq!(AvailabilityBucket {
+            start: UNIX_EPOCH + Duration::from_secs(3590),
+            successes: 50,
+            total: 100,
+        }, history.buckets[0]);
+        assert_eq!(Some(50.0), history.availability(Duration::from_secs(3600), UNIX_EPOCH + Duration::from_secs(2 * 3600)));
+    }
+
+    #[test]
+    fn probe_history_is_bounded() {
+        let mut history = HealthHistory::default();
+        for _ in 0..PROBE_HISTORY_CAPACITY + 10 {
+            history.record_probe(probe(0, ProbeResult::Timeout));
+        }
+        assert_eq!(PROBE_HISTORY_CAPACITY, history.probes.len());
+    }
+
+    #[test]
+    fn availability_is_computed_per_window() {
+        let mut history = HealthHistory::default();
+        let success = ProbeResult::Success { rtt: Duration::from_millis(3) };
+        // Four hours in, timeouts an hour and half an hour ago, then two successes and a timeout in the last five minutes
+        history.record_probe(probe(3 * 3600, ProbeResult::Timeout));
+        history.record_probe(probe(3 * 3600 + 1800, ProbeResult::Timeout));
+        history.record_probe(probe(4 * 3600 - 240, success.clone()));
+        history.record_probe(probe(4 * 3600 - 30, success.clone()));
+        history.record_probe(probe(4 * 3600 - 10, ProbeResult::Timeout));
+
+        let statistics = history.uptime_statistics(UNIX_EPOCH + Duration::from_secs(4 * 3600));
+        assert_eq!(UptimeStatistics {
+            one_minute: Some(50.0),
+            five_minutes: Some(100.0 * 2.0 / 3.0),
+            one_hour: Some(40.0),
+        }, statistics);
+        assert_eq!(None, HealthHistory::default().availability(Duration::from_secs(60), UNIX_EPOCH));
+    }
+}
@@ -0,0 +1,30 @@
+// Library target
+// Everything the swizzy_decent binary runs, exposed so applications can build a health check stack of their own
+// and register handlers and middleware for their opcodes, see OpcodeHandlerRegistry and MiddlewareChain
+
+pub mod health_check;
+pub mod network;
+pub mod health_check_network_broker;
+pub mod health_check_network_handlers;
+mod example;
+pub mod utils;
+pub mod persistence;
+pub mod peer_store;
+pub mod reaper;
+pub mod health_history;
+pub mod health_check_scheduler;
+pub mod config;
+pub mod config_reload;
+pub mod cli;
+pub mod control;
+pub mod probe;
+pub mod repl;
+pub mod admin_api;
+pub mod logging;
+pub mod channel;
+pub mod metrics;
+pub mod trace;
+pub mod middleware;
+pub mod batch_io;
+#[cfg(feature = "mio")]
+pub mod event_loop;
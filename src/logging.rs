@@ -0,0 +1,162 @@
+// Logging
+// Every log line goes through the log crate, formatted for people or as one JSON object per line
+// Packet logs carry peer, opcode and nonce fields, the nonce of a SYN comes back on its ACK, so one
+// exchange can be followed across both nodes' logs
+
+use std::fmt::{self, Display, Formatter};
+use std::io::Write;
+use std::str::FromStr;
+use chrono::{DateTime, Local, SecondsFormat};
+use env_logger::Builder;
+use log::kv::{self, Key, Value, VisitSource};
+use log::{LevelFilter, Record};
+use serde_json::{Map, Value as JsonValue};
+
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum LogFormat {
+    /**
+    Timestamp, level and message, followed by the fields as key=value.
+    */
+    #[default]
+    Human,
+    /**
+    One JSON object per line, the fields next to timestamp, level, target and message.
+    */
+    Json,
+}
+
+impl FromStr for LogFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "human" => Ok(LogFormat::Human),
+            "json" => Ok(LogFormat::Json),
+            _ => Err(format!("Unknown log format {}", s))
+        }
+    }
+}
+
+impl Display for LogFormat {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            LogFormat::Human => write!(f, "human"),
+            LogFormat::Json => write!(f, "json"),
+        }
+    }
+}
+
+/**
+Installs the logger, once per process. The level can be changed later with log::set_max_level.
+ */
+pub fn init(format: LogFormat, level: LevelFilter) {
+    Builder::new()
+        .format(move |buf, record| {
+            writeln!(buf, "{}", format_record(format, Local::now(), record))
+        })
+        // Everything passes the logger, the level is set below so it can be changed at runtime
+        .filter(None, LevelFilter::Trace)
+        .init();
+    log::set_max_level(level);
+}
+
+pub fn format_record(format: LogFormat, now: DateTime<Local>, record: &Record) -> String {
+    let mut fields = Fields::default();
+    // A field that fails to format is left out rather than losing the line
+    let _ = record.key_values().visit(&mut fields);
+    match format {
+        LogFormat::Human => {
+            let mut line = format!("{} [{}] - {}", now.format("%Y-%m-%dT%H:%M:%S"), record.level(), record.args());
+            for (key, value) in fields.0 {
+                let value = match value {
+                    JsonValue::String(value) => value,
+                    value => value.to_string()
+                };
+                line.push_str(&format!(" {}={}", key, value));
+            }
+            line
+        }
+        LogFormat::Json => {
+            let mut object = Map::new();
+            object.insert("timestamp".to_string(), JsonValue::from(now.to_rfc3339_opts(SecondsFormat::Millis, true)));
+            object.insert("level".to_string(), JsonValue::from(record.level().as_str()));
+            object.insert("target".to_string(), JsonValue::from(record.target()));
+            object.insert("message".to_string(), JsonValue::from(record.args().to_string()));
+            for (key, value) in fields.0 {
+                object.insert(key, value);
+            }
+            JsonValue::Object(object).to_string()
+        }
+    }
+}
+
+/**
+The record's key values in the order they were logged, numbers and booleans kept as such for JSON.
+ */
+#[derive(Default)]
+struct Fields(Vec<(String, JsonValue)>);
+
+impl<'kvs> VisitSource<'kvs> for Fields {
+    fn visit_pair(&mut self, key: Key<'kvs>, value: Value<'kvs>) -> Result<(), kv::Error> {
+        let value = if let Some(value) = value.to_u64() {
+            JsonValue::from(value)
+        } else if let Some(value) = value.to_i64() {
+            JsonValue::from(value)
+        } else if let Some(value) = value.to_f64() {
+            JsonValue::from(value)
+        } else if let Some(value) = value.to_bool() {
+            JsonValue::from(value)
+        } else {
+            JsonValue::from(value.to_string())
+        };
+        self.0.push((key.as_str().to_string(), value));
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod logging_tests {
+    use chrono::{Local, TimeZone};
+    use log::{Level, Record};
+    use serde_json::{json, Value};
+    use crate::logging::{format_record, LogFormat};
+
+    #[test]
+    fn records_are_formatted_with_their_fields() {
+        let now = Local.with_ymd_and_hms(2024, 4, 1, 12, 30, 5).unwrap();
+        let fields: [(&str, log::kv::Value); 3] = [
+            ("peer", log::kv::Value::from("127.0.0.1:3451")),
+            ("opcode", log::kv::Value::from("syn")),
+            ("size", log::kv::Value::from(33u64)),
+        ];
+        let line = format_record(LogFormat::Json, now, &Record::builder()
+            .level(Level::Info)
+            .target("swizzy_decent::broker")
+            .args(format_args!("Received {}", "packet"))
+            .key_values(&fields)
+            .build());
+
+        let line: Value = serde_json::from_str(&line).unwrap();
+        assert_eq!("INFO", line["level"]);
+        assert_eq!("swizzy_decent::broker", line["target"]);
+        assert_eq!("Received packet", line["message"]);
+        assert_eq!(json!("127.0.0.1:3451"), line["peer"]);
+        assert_eq!(json!(33), line["size"], "We expect numbers to stay numbers");
+        assert_eq!(now, chrono::DateTime::parse_from_rfc3339(line["timestamp"].as_str().unwrap()).unwrap());
+
+        let line = format_record(LogFormat::Human, now, &Record::builder()
+            .level(Level::Warn)
+            .args(format_args!("Received {}", "packet"))
+            .key_values(&fields)
+            .build());
+        assert_eq!("2024-04-01T12:30:05 [WARN] - Received packet peer=127.0.0.1:3451 opcode=syn size=33", line);
+    }
+
+    #[test]
+    fn log_format_parses_from_config_values() {
+        assert_eq!(Ok(LogFormat::Json), "json".parse());
+        assert_eq!(Ok(LogFormat::Human), "human".parse());
+        assert!("xml".parse::<LogFormat>().is_err());
+        assert_eq!("json", LogFormat::Json.to_string());
+    }
+}
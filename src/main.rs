@@ -1,101 +1,227 @@
-use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::net::Ipv4Addr;
 use std::sync::mpsc;
-use std::{env, io, thread};
-use std::io::BufRead;
-use std::str::FromStr;
+use std::{env, io, process, thread};
 use std::thread::sleep;
-use std::time::{Duration, SystemTime, UNIX_EPOCH};
-use chrono::Local;
-use env_logger::Builder;
-use std::io::Write;
-use log::{info, LevelFilter};
-use crate::health_check::{DeserializePacket, HEALTH_CHECK_SYN_OPCODE, HealthCheckPacket, SerializePacket};
-use crate::health_check_network_broker::{build_health_check_stack, HealthCheckNetworkBroker, HealthCheckNetworkBrokerMessage};
-use crate::health_check_network_handlers::HealthCheckNetworkBrokerMessageListener;
-use crate::network::{health_check_receiver, health_check_sender, IP, NetworkDetailsStore, RECEIVER_PORT, SENDER_PORT};
-use crate::utils::generate_nonce;
-
-mod health_check;
-mod network;
-mod health_check_network_broker;
-mod health_check_network_handlers;
-mod example;
-mod utils;
-
-const IP_ADDRESS_ENV_KEY: &str = "HEALTH_CHECK_IP_ADDRESS";
-const UDP_PORT_ENV_KEY: &str = "HEALTH_CHECK_UDP_PORT";
-const UDP_PORT_DEFAULT: u16 = 3450;
-const DEFAULT_IP_ADDRESS: &str = "127.0.0.1";
+use std::time::Duration;
+use log::{error, info};
+use swizzy_decent::health_check::{DeserializePacket, SerializePacket};
+use swizzy_decent::health_check_network_broker::{build_health_check_stack, HealthCheckNetworkBroker, HealthCheckStack, RuntimeMode};
+use swizzy_decent::health_check_network_handlers::HealthCheckNetworkBrokerMessageListener;
+use swizzy_decent::network::{health_check_receiver, health_check_sender, IP, NetworkDetailsStore, RECEIVER_PORT, SENDER_PORT};
+use swizzy_decent::admin_api::AdminApi;
+use swizzy_decent::cli::{Cli, Command};
+use swizzy_decent::config::Configuration;
+use swizzy_decent::config_reload::ConfigurationReloader;
+use swizzy_decent::control::{control_socket_path, ControlRequest, ControlResponse, PeerControl, send_control_request};
+#[cfg(unix)]
+use swizzy_decent::control::ControlServer;
+use swizzy_decent::metrics::MetricsServer;
+use swizzy_decent::probe::{nagios_output, probe_many, ProbeOptions, ProbeStatus};
+use swizzy_decent::repl::{format_peers, Repl};
+use swizzy_decent::utils::load_or_generate_node_id;
+use swizzy_decent::{logging, trace};
+
+const NODE_ID_FILE_NAME: &str = "node_id";
 
 fn main() {
-    Builder::new()
-        .format(|buf, record| {
-            writeln!(buf,
-                     "{} [{}] - {}",
-                     Local::now().format("%Y-%m-%dT%H:%M:%S"),
-                     record.level(),
-                     record.args()
-            )
-        })
-        .filter(None, LevelFilter::Debug)
-        .init();
+    let args: Vec<String> = env::args().skip(1).collect();
+    let cli = match Cli::parse(args.clone()) {
+        Ok(cli) => cli,
+        Err(err) => {
+            eprintln!("{}", err);
+            // Monitoring reads anything but 0 to 2 from a probe as UNKNOWN, a bad invocation is reported as such
+            let exit_code = if args.first().is_some_and(|command| command == "probe") { ProbeStatus::Unknown.exit_code() } else { 2 };
+            process::exit(exit_code);
+        }
+    };
+    if let Command::Probe(options) = &cli.command {
+        // Doesn't need a configuration, so it works next to a node with a broken one
+        process::exit(probe_command(options, cli.json));
+    }
+    let configuration = match Configuration::load(&cli.arguments, |key| env::var(key).ok()) {
+        Ok(configuration) => configuration,
+        Err(err) => {
+            eprintln!("{}", err);
+            process::exit(1);
+        }
+    };
+    if cli.arguments.check_config {
+        println!("Configuration OK");
+        print!("{}", toml::to_string_pretty(&configuration.redacted()).expect("Configuration serialized"));
+        return;
+    }
+
+    match cli.command {
+        Command::Serve => {}
+        Command::Config => {
+            if cli.json {
+                println!("{}", serde_json::to_string_pretty(&configuration.redacted()).expect("Configuration serialized"));
+            } else {
+                print!("{}", toml::to_string_pretty(&configuration.redacted()).expect("Configuration serialized"));
+            }
+            return;
+        }
+        Command::Peers => process::exit(control_command(&configuration, ControlRequest::Peers, cli.json)),
+        Command::AddPeer(static_peer) => process::exit(control_command(&configuration, ControlRequest::AddPeer(static_peer), cli.json)),
+        Command::RemovePeer(addr) => process::exit(control_command(&configuration, ControlRequest::RemovePeer { addr }, cli.json)),
+        Command::Probe(_) => unreachable!("Probe handled before the configuration is loaded")
+    }
+
+    logging::init(configuration.log_format(), configuration.log_level());
     // main_with_stacks()
     // main_health_check_broker_example()
     // main_load_example()
     // main_with_receiver_handler()
     // main_receiver_poc()
-    single_instance_main()
+    single_instance_main(cli, configuration)
+}
+
+/**
+Probes the target and prints the result, returns the Nagios plugin exit code, see ProbeStatus.
+ */
+fn probe_command(options: &ProbeOptions, json: bool) -> i32 {
+    let statistics = match probe_many(options) {
+        Ok(statistics) => statistics,
+        Err(err) => {
+            if json {
+                println!("{}", serde_json::json!({ "addr": options.addr, "status": ProbeStatus::Unknown.to_string(), "error": err.to_string() }));
+            } else {
+                println!("{} - could not probe {}: {}", ProbeStatus::Unknown, options.addr, err);
+            }
+            return ProbeStatus::Unknown.exit_code();
+        }
+    };
+    let status = statistics.status(&options.thresholds);
+    if json {
+        let millis = |rtt: Option<Duration>| rtt.map(|rtt| rtt.as_secs_f64() * 1000.0);
+        println!("{}", serde_json::json!({
+            "addr": statistics.addr,
+            "status": status.to_string(),
+            "node_id": statistics.node_id,
+            "sent": statistics.sent,
+            "received": statistics.received(),
+            "loss_percent": statistics.loss_percent(),
+            "rtt_min_ms": millis(statistics.min_rtt()),
+            "rtt_avg_ms": millis(statistics.avg_rtt()),
+            "rtt_max_ms": millis(statistics.max_rtt())
+        }));
+    } else {
+        println!("{}", nagios_output(&statistics, &options.thresholds));
+    }
+    status.exit_code()
+}
+
+/**
+Sends `request` to the node running with this configuration's data directory and prints the response,
+returns the exit code: 0 on success, 1 when the node refused the request or couldn't be reached.
+ */
+fn control_command(configuration: &Configuration, request: ControlRequest, json: bool) -> i32 {
+    let response = match send_control_request(&control_socket_path(&configuration.node.data_dir), &request) {
+        Ok(response) => response,
+        Err(err) => ControlResponse::Error(err.to_string())
+    };
+    let exit_code = if matches!(response, ControlResponse::Error(_)) { 1 } else { 0 };
+    if json {
+        let output = match response {
+            ControlResponse::Peers(peers) => serde_json::to_string_pretty(&peers),
+            ControlResponse::PeerAdded { addr, new } => serde_json::to_string(&serde_json::json!({ "added": addr, "new": new })),
+            ControlResponse::PeerRemoved { addr } => serde_json::to_string(&serde_json::json!({ "removed": addr })),
+            ControlResponse::Error(message) => serde_json::to_string(&serde_json::json!({ "error": message })),
+        };
+        println!("{}", output.expect("Control response serialized"));
+        return exit_code;
+    }
+    match response {
+        ControlResponse::Peers(peers) => println!("{}", format_peers(&peers)),
+        ControlResponse::PeerAdded { addr, new: true } => println!("Added peer {}", addr),
+        ControlResponse::PeerAdded { addr, new: false } => println!("Replaced peer {}", addr),
+        ControlResponse::PeerRemoved { addr } => println!("Removed peer {}", addr),
+        ControlResponse::Error(message) => eprintln!("{}", message),
+    }
+    exit_code
 }
 
 /**
 Starts a single health check server instance.
-Pulls configuration from env variables, with defaults.
+Configuration is layered from an optional TOML file (--config or HEALTH_CHECK_CONFIG), env variables and
+command line arguments, see health_check.example.toml for every setting and its default.
 
-Defaults:
+The config file is reloaded on SIGHUP or when it changes, see ConfigurationReloader.
+The admin HTTP API is served when admin.listen_address is set, see AdminApi.
+Prometheus metrics are served when metrics.listen_address is set, see MetricsServer.
+The stack runs on threads, on a tokio runtime or on a single thread event loop as per runtime.mode, see RuntimeMode.
+The repl, control socket, config reloader, admin API and metrics server run next to it on a thread each, whatever the mode.
+Spans of every packet are logged or exported when the tracing section asks for it, see trace.
+Commands typed on stdin are run by the Repl, type help for the list.
 
-Default port = 3450
-Default IP = 127.0.0.1
+The node id and the network details store are persisted in the data directory, so a restarted node
+keeps its identity and its view of the network.
  */
-fn single_instance_main() {
-
-    let ip_address_str = env::var(IP_ADDRESS_ENV_KEY).unwrap_or(String::from(DEFAULT_IP_ADDRESS));
-    let listener_port_str = env::var(UDP_PORT_ENV_KEY).unwrap_or(UDP_PORT_DEFAULT.to_string());
-    let listener_port: u16 = listener_port_str.parse().expect("Valid string number");
-    let ip = IpAddr::from_str(&ip_address_str.as_str()).expect("Valid IP address");
+fn single_instance_main(cli: Cli, configuration: Configuration) {
+    let data_dir = configuration.node.data_dir.clone();
+    let node_id = load_or_generate_node_id(&data_dir.join(NODE_ID_FILE_NAME)).expect("Node id loaded from data directory");
+    trace::init(configuration.tracing.log_spans, configuration.otlp_destination(), node_id);
 
-    let sender_addr = SocketAddr::new(ip, listener_port);
-    let stack = build_health_check_stack(sender_addr);
-    let request_sender = stack.request_sender.clone();
+    let sender_addr = configuration.bind_addr();
+    let network_details_store = NetworkDetailsStore::open(&configuration.store_backend(), &data_dir).expect("Network details store opened from data directory");
+    let mut stack = build_health_check_stack(sender_addr,
+                                             node_id,
+                                             network_details_store,
+                                             configuration.health_check_defaults(),
+                                             configuration.static_peers(),
+                                             configuration.reaper_configuration(),
+                                             configuration.channel_bounds());
+    stack.set_listener_workers(configuration.listener.workers);
+    let repl = Repl::new(stack.request_sender.clone(),
+                         stack.network_details_store.clone(),
+                         stack.in_flight_probes.clone(),
+                         stack.live_settings.health_check_defaults.clone());
+    #[cfg(unix)]
+    {
+        let control_server = ControlServer::bind(&control_socket_path(&data_dir), PeerControl::new(stack.live_settings.static_peers.clone(), stack.network_details_store.clone()))
+            .expect("Control socket bound in data directory");
+        thread::spawn(move || {
+            control_server.run();
+        });
+    }
+    if let Some(admin_listen_addr) = configuration.admin_listen_addr() {
+        let admin_api = AdminApi::bind(admin_listen_addr,
+                                       node_id,
+                                       sender_addr,
+                                       stack.request_sender.clone(),
+                                       stack.network_details_store.clone(),
+                                       stack.in_flight_probes.clone(),
+                                       stack.live_settings.clone())
+            .expect("Admin API bound to its listen address");
+        thread::spawn(move || {
+            admin_api.run();
+        });
+    }
+    if let Some(metrics_listen_addr) = configuration.metrics_listen_addr() {
+        let metrics_server = MetricsServer::bind(metrics_listen_addr, stack.metrics.clone(), stack.network_details_store.clone())
+            .expect("Metrics bound to their listen address");
+        thread::spawn(move || {
+            metrics_server.run();
+        });
+    }
+    let runtime_mode = configuration.runtime_mode();
+    let reloader = ConfigurationReloader::new(cli.arguments, configuration, stack.live_settings.clone(), stack.network_details_store.clone());
+    thread::spawn(move || {
+        reloader.run();
+    });
 
     let stack_handle = thread::spawn(move || {
-        stack.run();
+        run_stack(stack, runtime_mode);
     });
 
-    info!("Started health check server on {}:{}", ip_address_str, listener_port);
+    info!("Started health check server on {} with node id {}", sender_addr, node_id);
+    if cli.json {
+        println!("{}", serde_json::json!({ "bind_address": sender_addr, "node_id": node_id, "data_dir": data_dir }));
+    }
 
     let cli_handle = thread::spawn(move || {
-        let stdin = io::stdin();
-        loop {
-            for line in stdin.lock().lines() {
-                println!("{}", line.unwrap());
-                let start = SystemTime::now();
-                let since_the_epoch = start
-                    .duration_since(UNIX_EPOCH)
-                    .expect("Time went backwards");
-                request_sender.send(HealthCheckNetworkBrokerMessage {
-                    payload: HealthCheckPacket {
-                        header: HEALTH_CHECK_SYN_OPCODE,
-                        nonce: generate_nonce()
-                    },
-                    remote_addr: sender_addr,
-                }).unwrap();
-
-                let end = SystemTime::now();
-                let end_since_the_epoch = end
-                    .duration_since(UNIX_EPOCH)
-                    .expect("Time went backwards");
-                println!("Start: [{:?}] End: [{:?}] TotalDuration: [{:?}]", since_the_epoch, end_since_the_epoch, end_since_the_epoch-since_the_epoch);
-            }
+        if let Err(err) = repl.run(io::stdin().lock(), io::stdout()) {
+            error!("Stopped reading commands from stdin: {}", err);
         }
     });
 
@@ -103,3 +229,19 @@ fn single_instance_main() {
     stack_handle.join().unwrap();
 }
 
+
+fn run_stack(stack: HealthCheckStack, runtime_mode: RuntimeMode) {
+    match runtime_mode {
+        RuntimeMode::Threads => stack.run(),
+        #[cfg(feature = "tokio")]
+        RuntimeMode::Tokio => {
+            let runtime = tokio::runtime::Builder::new_multi_thread()
+                .enable_all()
+                .build()
+                .expect("Tokio runtime started");
+            runtime.block_on(stack.run_async());
+        }
+        #[cfg(feature = "mio")]
+        RuntimeMode::EventLoop => stack.run_event_loop(),
+    }
+}
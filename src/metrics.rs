@@ -0,0 +1,430 @@
+// Metrics
+// Counters, gauges and histograms kept by the broker, the listener, the scheduler and the store,
+// served in the Prometheus text format on GET /metrics when metrics.listen_address is set
+//   health_check_packets_sent_total{opcode}           packets handed to the socket
+//   health_check_packets_received_total{opcode}       well formed packets read from the socket
+//   health_check_malformed_packets_total              packets of the wrong size or with an unknown opcode
+//   health_check_ack_timeouts_total                   probes that got no ack before their timeout
+//   health_check_peer_rtt_seconds{node_id,address}    rtt of acked probes, per peer
+//   health_check_handler_duration_seconds{opcode}     time the listener spent handling a packet
+//   health_check_peers{status}                        peers in the store by health status
+//   health_check_queue_depth{channel}                 messages waiting on the broker's request and response channels
+//   health_check_queue_dropped_total{channel}         messages those channels dropped when full, see DropPolicy
+//   health_check_store_puts_total                     records written to the store
+//   health_check_store_deletes_total                  records deleted from the store
+
+use std::collections::HashMap;
+use std::fmt::Write as _;
+use std::io::{self, BufReader};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use log::{info, warn};
+use uuid::Uuid;
+
+use crate::admin_api::{CONNECTION_TIMEOUT, read_request, write_http_response};
+use crate::channel::QueueDepth;
+use crate::health_check::{HEALTH_CHECK_ACK_OPCODE, HEALTH_CHECK_SYN_OPCODE, opcode_name};
+use crate::network::{HealthStatus, NetworkDetailsStore};
+use crate::peer_store::PeerStoreEvent;
+
+/**
+Upper bounds of the rtt buckets, from a peer on the same host to one on another continent.
+ */
+const RTT_BUCKETS_SECONDS: [f64; 12] = [0.0005, 0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5];
+/**
+Upper bounds of the handler duration buckets, handlers only touch memory so they are much finer.
+ */
+const HANDLER_DURATION_BUCKETS_SECONDS: [f64; 10] = [0.00001, 0.000025, 0.00005, 0.0001, 0.00025, 0.0005, 0.001, 0.0025, 0.01, 0.1];
+const HEALTH_STATUSES: [HealthStatus; 4] = [HealthStatus::Healthy, HealthStatus::AtRisk, HealthStatus::Unhealthy, HealthStatus::Tombstone];
+const CONTENT_TYPE: &str = "text/plain; version=0.0.4";
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Histogram {
+    bounds: &'static [f64],
+    /**
+    Observations per bucket, not cumulative, the last one counts those above every bound.
+    */
+    buckets: Vec<u64>,
+    sum: f64,
+    count: u64,
+}
+
+impl Histogram {
+    pub fn new(bounds: &'static [f64]) -> Histogram {
+        Histogram {
+            bounds,
+            buckets: vec![0; bounds.len() + 1],
+            sum: 0.0,
+            count: 0,
+        }
+    }
+
+    pub fn observe(&mut self, value: Duration) {
+        let seconds = value.as_secs_f64();
+        let bucket = self.bounds.iter().position(|bound| seconds <= *bound).unwrap_or(self.bounds.len());
+        self.buckets[bucket] += 1;
+        self.sum += seconds;
+        self.count += 1;
+    }
+
+    fn render(&self, output: &mut String, name: &str, labels: &str) {
+        let separator = if labels.is_empty() { "" } else { "," };
+        let mut cumulative = 0;
+        for (bound, observations) in self.bounds.iter().zip(&self.buckets) {
+            cumulative += observations;
+            let _ = writeln!(output, "{}_bucket{{{}{}le=\"{}\"}} {}", name, labels, separator, bound, cumulative);
+        }
+        let _ = writeln!(output, "{}_bucket{{{}{}le=\"+Inf\"}} {}", name, labels, separator, self.count);
+        let _ = writeln!(output, "{}_sum{{{}}} {}", name, labels, self.sum);
+        let _ = writeln!(output, "{}_count{{{}}} {}", name, labels, self.count);
+    }
+}
+
+#[derive(Debug)]
+struct PeerRtt {
+    addr: SocketAddr,
+    histogram: Histogram,
+}
+
+/**
+Shared by the whole stack, every update is a lock free counter bump except for the histograms.
+ */
+#[derive(Debug)]
+pub struct Metrics {
+    packets_sent: [AtomicU64; 256],
+    packets_received: [AtomicU64; 256],
+    malformed_packets: AtomicU64,
+    ack_timeouts: AtomicU64,
+    store_puts: AtomicU64,
+    store_deletes: AtomicU64,
+    peer_rtt: Mutex<HashMap<Uuid, PeerRtt>>,
+    handler_duration: Mutex<HashMap<u8, Histogram>>,
+    request_queue_depth: QueueDepth,
+    response_queue_depth: QueueDepth,
+}
+
+impl Metrics {
+    pub fn new(request_queue_depth: QueueDepth, response_queue_depth: QueueDepth) -> Metrics {
+        Metrics {
+            packets_sent: std::array::from_fn(|_| AtomicU64::new(0)),
+            packets_received: std::array::from_fn(|_| AtomicU64::new(0)),
+            malformed_packets: AtomicU64::new(0),
+            ack_timeouts: AtomicU64::new(0),
+            store_puts: AtomicU64::new(0),
+            store_deletes: AtomicU64::new(0),
+            peer_rtt: Mutex::new(HashMap::new()),
+            handler_duration: Mutex::new(HashMap::new()),
+            request_queue_depth,
+            response_queue_depth,
+        }
+    }
+
+    pub fn packet_sent(&self, opcode: u8) {
+        self.packets_sent[opcode as usize].fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn packet_received(&self, opcode: u8) {
+        self.packets_received[opcode as usize].fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn malformed_packet(&self) {
+        self.malformed_packets.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn ack_timeout(&self) {
+        self.ack_timeouts.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn observe_rtt(&self, node_id: Uuid, addr: SocketAddr, rtt: Duration) {
+        let mut peer_rtt = self.peer_rtt.lock().unwrap();
+        let entry = peer_rtt.entry(node_id).or_insert_with(|| PeerRtt { addr, histogram: Histogram::new(&RTT_BUCKETS_SECONDS) });
+        // The series follows the node, like its record does
+        entry.addr = addr;
+        entry.histogram.observe(rtt);
+    }
+
+    pub fn observe_handler_duration(&self, opcode: u8, duration: Duration) {
+        self.handler_duration.lock().unwrap()
+            .entry(opcode)
+            .or_insert_with(|| Histogram::new(&HANDLER_DURATION_BUCKETS_SECONDS))
+            .observe(duration);
+    }
+
+    /**
+    Counts a store write or delete, a deleted peer's rtt series is dropped with it.
+    */
+    pub fn store_event(&self, event: &PeerStoreEvent) {
+        match event {
+            PeerStoreEvent::Put(_) => {
+                self.store_puts.fetch_add(1, Ordering::Relaxed);
+            }
+            PeerStoreEvent::Delete(record) => {
+                self.store_deletes.fetch_add(1, Ordering::Relaxed);
+                self.peer_rtt.lock().unwrap().remove(&record.node_id);
+            }
+        }
+    }
+
+    /**
+    Counts the store's events, blocks for as long as the store is around.
+    */
+    pub fn watch_store(&self, network_details_store: &NetworkDetailsStore) {
+        for event in network_details_store.watch() {
+            self.store_event(&event);
+        }
+    }
+
+    /**
+    Every metric in the Prometheus text exposition format, the peer gauges are read from the store now.
+    */
+    pub fn render(&self, network_details_store: &NetworkDetailsStore) -> String {
+        let mut output = String::new();
+        render_by_opcode(&mut output, "health_check_packets_sent_total", "Packets handed to the socket.", &self.packets_sent);
+        render_by_opcode(&mut output, "health_check_packets_received_total", "Well formed packets read from the socket.", &self.packets_received);
+        render_single(&mut output, "health_check_malformed_packets_total", "counter", "Packets of the wrong size or with an unknown opcode.", self.malformed_packets.load(Ordering::Relaxed));
+        render_single(&mut output, "health_check_ack_timeouts_total", "counter", "Probes that got no ack before their timeout.", self.ack_timeouts.load(Ordering::Relaxed));
+
+        render_header(&mut output, "health_check_peer_rtt_seconds", "histogram", "Round trip time of acked probes.");
+        let peer_rtt = self.peer_rtt.lock().unwrap();
+        let mut node_ids: Vec<&Uuid> = peer_rtt.keys().collect();
+        node_ids.sort();
+        for node_id in node_ids {
+            let entry = &peer_rtt[node_id];
+            entry.histogram.render(&mut output, "health_check_peer_rtt_seconds", &format!("node_id=\"{}\",address=\"{}\"", node_id, entry.addr));
+        }
+        drop(peer_rtt);
+
+        render_header(&mut output, "health_check_handler_duration_seconds", "histogram", "Time the listener spent handling a packet.");
+        let handler_duration = self.handler_duration.lock().unwrap();
+        let mut opcodes: Vec<&u8> = handler_duration.keys().collect();
+        opcodes.sort();
+        for opcode in opcodes {
+            handler_duration[opcode].render(&mut output, "health_check_handler_duration_seconds", &format!("opcode=\"{}\"", opcode_name(*opcode)));
+        }
+        drop(handler_duration);
+
+        render_header(&mut output, "health_check_peers", "gauge", "Peers in the store by health status.");
+        let snapshot = network_details_store.snapshot();
+        for status in HEALTH_STATUSES.iter() {
+            let peers = snapshot.iter().filter(|record| record.health_check.status_details.current_status == *status).count();
+            let _ = writeln!(output, "health_check_peers{{status=\"{}\"}} {}", status_label(status), peers);
+        }
+
+        render_header(&mut output, "health_check_queue_depth", "gauge", "Messages waiting on the network broker's channels.");
+        let _ = writeln!(output, "health_check_queue_depth{{channel=\"request\"}} {}", self.request_queue_depth.get());
+        let _ = writeln!(output, "health_check_queue_depth{{channel=\"response\"}} {}", self.response_queue_depth.get());
+        render_header(&mut output, "health_check_queue_dropped_total", "counter", "Messages the network broker's channels dropped when full.");
+        let _ = writeln!(output, "health_check_queue_dropped_total{{channel=\"request\"}} {}", self.request_queue_depth.dropped());
+        let _ = writeln!(output, "health_check_queue_dropped_total{{channel=\"response\"}} {}", self.response_queue_depth.dropped());
+
+        render_single(&mut output, "health_check_store_puts_total", "counter", "Records written to the store.", self.store_puts.load(Ordering::Relaxed));
+        render_single(&mut output, "health_check_store_deletes_total", "counter", "Records deleted from the store.", self.store_deletes.load(Ordering::Relaxed));
+        output
+    }
+}
+
+impl Default for Metrics {
+    fn default() -> Metrics {
+        Metrics::new(QueueDepth::default(), QueueDepth::default())
+    }
+}
+
+fn render_header(output: &mut String, name: &str, metric_type: &str, help: &str) {
+    let _ = writeln!(output, "# HELP {} {}", name, help);
+    let _ = writeln!(output, "# TYPE {} {}", name, metric_type);
+}
+
+fn render_single(output: &mut String, name: &str, metric_type: &str, help: &str, value: u64) {
+    render_header(output, name, metric_type, help);
+    let _ = writeln!(output, "{} {}", name, value);
+}
+
+/**
+Known opcodes are always exported, so a rate() over them works from the first scrape, others only once seen.
+ */
+fn render_by_opcode(output: &mut String, name: &str, help: &str, counters: &[AtomicU64; 256]) {
+    render_header(output, name, "counter", help);
+    for (opcode, counter) in counters.iter().enumerate() {
+        let opcode = opcode as u8;
+        let value = counter.load(Ordering::Relaxed);
+        if value > 0 || [HEALTH_CHECK_SYN_OPCODE, HEALTH_CHECK_ACK_OPCODE].contains(&opcode) {
+            let _ = writeln!(output, "{}{{opcode=\"{}\"}} {}", name, opcode_name(opcode), value);
+        }
+    }
+}
+
+fn status_label(status: &HealthStatus) -> &'static str {
+    match status {
+        HealthStatus::Healthy => "healthy",
+        HealthStatus::AtRisk => "at_risk",
+        HealthStatus::Unhealthy => "unhealthy",
+        HealthStatus::Tombstone => "tombstone",
+    }
+}
+
+pub struct MetricsServer {
+    listener: TcpListener,
+    metrics: Arc<Metrics>,
+    network_details_store: Arc<NetworkDetailsStore>,
+}
+
+impl MetricsServer {
+    pub fn bind(listen_addr: SocketAddr, metrics: Arc<Metrics>, network_details_store: Arc<NetworkDetailsStore>) -> io::Result<MetricsServer> {
+        Ok(MetricsServer {
+            listener: TcpListener::bind(listen_addr)?,
+            metrics,
+            network_details_store,
+        })
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.listener.local_addr()
+    }
+
+    pub fn run(self) {
+        info!("Metrics listening on {}", self.listener.local_addr().map(|addr| addr.to_string()).unwrap_or_default());
+        for stream in self.listener.incoming() {
+            let result = stream.and_then(|stream| self.serve_connection(stream));
+            if let Err(err) = result {
+                warn!("Metrics connection failed: {}", err);
+            }
+        }
+    }
+
+    fn serve_connection(&self, stream: TcpStream) -> io::Result<()> {
+        stream.set_read_timeout(Some(CONNECTION_TIMEOUT))?;
+        stream.set_write_timeout(Some(CONNECTION_TIMEOUT))?;
+        let mut writer = stream.try_clone()?;
+        let (status, body) = match read_request(&mut BufReader::new(stream)) {
+            Ok(request) if request.path != "/metrics" => (404, "Not found\n".to_string()),
+            Ok(request) if request.method != "GET" => (405, "Method not allowed\n".to_string()),
+            Ok(_) => (200, self.metrics.render(&self.network_details_store)),
+            Err(err) if err.kind() == io::ErrorKind::InvalidData => (400, format!("{}\n", err)),
+            Err(err) => return Err(err)
+        };
+        write_http_response(&mut writer, status, CONTENT_TYPE, body.as_bytes())
+    }
+}
+
+#[cfg(test)]
+mod metrics_tests {
+    use std::io::{Read, Write};
+    use std::net::{IpAddr, SocketAddr, TcpStream};
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::{Duration, UNIX_EPOCH};
+    use uuid::Uuid;
+    use crate::channel::metered_channel;
+    use crate::health_check::{HEALTH_CHECK_ACK_OPCODE, HEALTH_CHECK_SYN_OPCODE};
+    use crate::health_history::HealthHistory;
+    use crate::metrics::{Histogram, Metrics, MetricsServer, RTT_BUCKETS_SECONDS};
+    use crate::network::{HealthCheck, HealthCheckConfiguration, HealthStatus, HealthStatusDetails, IP, NetworkDetails, NetworkDetailsStore};
+    use crate::peer_store::PeerStoreEvent;
+
+    fn dummy_record(port: u16, status: HealthStatus) -> NetworkDetails {
+        NetworkDetails {
+            node_id: Uuid::new_v4(),
+            addr: IpAddr::V4(IP),
+            last_seen: UNIX_EPOCH,
+            status_since: UNIX_EPOCH,
+            stale: false,
+            health_history: HealthHistory::default(),
+            health_check: HealthCheck {
+                status_details: HealthStatusDetails {
+                    current_status: status,
+                    lives_remaining: 3,
+                },
+                configuration: HealthCheckConfiguration {
+                    health_check_port: port,
+                    ..HealthCheckConfiguration::default()
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn histogram_buckets_are_cumulative() {
+        let mut histogram = Histogram::new(&RTT_BUCKETS_SECONDS);
+        histogram.observe(Duration::from_micros(300));
+        histogram.observe(Duration::from_millis(3));
+        histogram.observe(Duration::from_secs(10));
+        let mut output = String::new();
+        histogram.render(&mut output, "rtt", "peer=\"a\"");
+        assert!(output.contains("rtt_bucket{peer=\"a\",le=\"0.0005\"} 1\n"), "Got {}", output);
+        assert!(output.contains("rtt_bucket{peer=\"a\",le=\"0.005\"} 2\n"), "Got {}", output);
+        assert!(output.contains("rtt_bucket{peer=\"a\",le=\"2.5\"} 2\n"), "We expect an observation above every bound only in +Inf, got {}", output);
+        assert!(output.contains("rtt_bucket{peer=\"a\",le=\"+Inf\"} 3\n"), "Got {}", output);
+        assert!(output.contains("rtt_count{peer=\"a\"} 3\n"), "Got {}", output);
+    }
+
+    #[test]
+    fn render_exports_counters_gauges_and_histograms() {
+        let (sender, receiver) = metered_channel();
+        let metrics = Metrics::new(sender.depth(), receiver.depth());
+        sender.send(()).unwrap();
+        let store = NetworkDetailsStore::new();
+        let healthy = dummy_record(3451, HealthStatus::Healthy);
+        store.put_network_details(&healthy);
+        store.put_network_details(&dummy_record(3452, HealthStatus::Unhealthy));
+
+        metrics.packet_sent(HEALTH_CHECK_SYN_OPCODE);
+        metrics.packet_sent(HEALTH_CHECK_SYN_OPCODE);
+        metrics.packet_received(HEALTH_CHECK_ACK_OPCODE);
+        metrics.malformed_packet();
+        metrics.ack_timeout();
+        metrics.observe_rtt(healthy.node_id, healthy.socket_addr(), Duration::from_millis(2));
+        metrics.observe_handler_duration(HEALTH_CHECK_ACK_OPCODE, Duration::from_micros(20));
+
+        let output = metrics.render(&store);
+        for expected in [
+            "health_check_packets_sent_total{opcode=\"syn\"} 2\n",
+            "health_check_packets_sent_total{opcode=\"ack\"} 0\n",
+            "health_check_packets_received_total{opcode=\"ack\"} 1\n",
+            "health_check_malformed_packets_total 1\n",
+            "health_check_ack_timeouts_total 1\n",
+            "health_check_handler_duration_seconds_count{opcode=\"ack\"} 1\n",
+            "health_check_peers{status=\"healthy\"} 1\n",
+            "health_check_peers{status=\"unhealthy\"} 1\n",
+            "health_check_peers{status=\"tombstone\"} 0\n",
+            "health_check_queue_depth{channel=\"request\"} 1\n",
+            "health_check_queue_depth{channel=\"response\"} 1\n",
+            "health_check_queue_dropped_total{channel=\"response\"} 0\n",
+        ] {
+            assert!(output.contains(expected), "We expect {:?} in\n{}", expected, output);
+        }
+        let rtt_count = format!("health_check_peer_rtt_seconds_count{{node_id=\"{}\",address=\"{}\"}} 1\n", healthy.node_id, healthy.socket_addr());
+        assert!(output.contains(&rtt_count), "We expect {:?} in\n{}", rtt_count, output);
+
+        metrics.store_event(&PeerStoreEvent::Delete(Arc::new(healthy.clone())));
+        let output = metrics.render(&store);
+        assert!(!output.contains(&healthy.node_id.to_string()), "We expect a deleted peer's rtt series to be dropped, got\n{}", output);
+        assert!(output.contains("health_check_store_deletes_total 1\n"), "Got\n{}", output);
+    }
+
+    #[test]
+    fn server_answers_scrapes() {
+        let metrics = Arc::new(Metrics::default());
+        metrics.ack_timeout();
+        let listen_addr = SocketAddr::new(IpAddr::V4(IP), 0);
+        let server = MetricsServer::bind(listen_addr, metrics, Arc::new(NetworkDetailsStore::new())).unwrap();
+        let server_addr = server.local_addr().unwrap();
+        thread::spawn(move || server.run());
+
+        let scrape = |request: &str| {
+            let mut stream = TcpStream::connect(server_addr).unwrap();
+            stream.write_all(request.as_bytes()).unwrap();
+            let mut response = String::new();
+            stream.read_to_string(&mut response).unwrap();
+            response
+        };
+        let response = scrape("GET /metrics HTTP/1.1\r\nHost: localhost\r\n\r\n");
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "Got {}", response);
+        assert!(response.contains("Content-Type: text/plain; version=0.0.4\r\n"), "Got {}", response);
+        assert!(response.contains("health_check_ack_timeouts_total 1\n"), "Got {}", response);
+
+        let response = scrape("GET /peers HTTP/1.1\r\n\r\n");
+        assert!(response.starts_with("HTTP/1.1 404 "), "Got {}", response);
+    }
+}
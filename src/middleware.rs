@@ -0,0 +1,181 @@
+// Handler middleware
+// Runs around every handler the listener dispatches to, for concerns shared by all opcodes like auth, rate limits or filtering
+// Middleware runs in the order it was added before the handler, and in reverse after it, like layers of an onion:
+//   first.before -> second.before -> handler -> second.after -> first.after
+// A middleware can change the message, annotate it for the handler and the middleware after it, or answer in place of
+// the handler by short circuiting, the middleware that already ran still get their after call
+
+use std::collections::HashMap;
+use crate::health_check_network_broker::HealthCheckNetworkBrokerMessage;
+use crate::health_check_network_handlers::{HandlerError, HealthCheckHandlerContext};
+
+/**
+Notes middleware leaves on a message, handed to the handler in OpcodeHandlerParams and to every later middleware.
+ */
+pub type Annotations = HashMap<String, String>;
+
+#[derive(Debug)]
+pub enum MiddlewareAction {
+    Continue,
+    /**
+    Skips the handler and the middleware after this one, the result stands in for the handler's.
+    */
+    ShortCircuit(Result<(), HandlerError>),
+}
+
+/**
+Called around each handler, concurrently from the listener's workers like handlers are, see OpcodeHandler.
+ */
+pub trait HandlerMiddleware: Send + Sync {
+    /**
+    Called before the handler is looked up, so changing the opcode of the message changes the handler it goes to.
+    */
+    fn before(&self, _context: &HealthCheckHandlerContext, _message: &mut HealthCheckNetworkBrokerMessage, _annotations: &mut Annotations) -> MiddlewareAction {
+        MiddlewareAction::Continue
+    }
+
+    /**
+    Called with the message the handler got and its result, which can be replaced.
+    */
+    fn after(&self, _context: &HealthCheckHandlerContext, _message: &HealthCheckNetworkBrokerMessage, _annotations: &Annotations, _result: &mut Result<(), HandlerError>) {}
+}
+
+#[derive(Default)]
+pub struct MiddlewareChain {
+    middleware: Vec<Box<dyn HandlerMiddleware>>,
+}
+
+impl MiddlewareChain {
+    pub fn push(&mut self, middleware: impl HandlerMiddleware + 'static) {
+        self.middleware.push(Box::new(middleware));
+    }
+
+    /**
+    Runs the message through the chain, `handler` is called with the message and annotations the middleware left
+    unless one of them short circuits.
+    */
+    pub fn dispatch(&self,
+                    context: &HealthCheckHandlerContext,
+                    mut message: HealthCheckNetworkBrokerMessage,
+                    handler: impl FnOnce(HealthCheckNetworkBrokerMessage, Annotations) -> Result<(), HandlerError>) -> Result<(), HandlerError> {
+        let mut annotations = Annotations::new();
+        let mut ran = 0;
+        let mut short_circuit = None;
+        for middleware in self.middleware.iter() {
+            ran += 1;
+            if let MiddlewareAction::ShortCircuit(result) = middleware.before(context, &mut message, &mut annotations) {
+                short_circuit = Some(result);
+                break;
+            }
+        }
+
+        let mut result = match short_circuit {
+            Some(result) => result,
+            // Nothing to hand the message back to, no need to copy it
+            None if ran == 0 => return handler(message, annotations),
+            None => handler(message.clone(), annotations.clone())
+        };
+        for middleware in self.middleware[..ran].iter().rev() {
+            middleware.after(context, &message, &annotations, &mut result);
+        }
+        result
+    }
+}
+
+#[cfg(test)]
+mod middleware_tests {
+    use std::net::{IpAddr, SocketAddr};
+    use std::sync::{Arc, Mutex};
+    use crate::health_check::{HEALTH_CHECK_ACK_OPCODE, HEALTH_CHECK_SYN_OPCODE, HealthCheckPacket};
+    use crate::health_check_network_broker::HealthCheckNetworkBrokerMessage;
+    use crate::health_check_network_handlers::{HandlerError, HealthCheckHandlerContext};
+    use crate::health_check_scheduler::{InFlightProbes, StaticPeers};
+    use crate::metrics::Metrics;
+    use crate::middleware::{Annotations, HandlerMiddleware, MiddlewareAction, MiddlewareChain};
+    use crate::network::{HealthCheckDefaults, IP, NetworkDetailsStore};
+    use crate::trace::MessageTrace;
+
+    /**
+    Records its before and after calls in a log shared by the test, short circuits SYNs when `drop_syn` is set.
+    */
+    struct Recorder {
+        name: &'static str,
+        log: Arc<Mutex<Vec<String>>>,
+        drop_syn: bool,
+    }
+
+    impl HandlerMiddleware for Recorder {
+        fn before(&self, _context: &HealthCheckHandlerContext, message: &mut HealthCheckNetworkBrokerMessage, annotations: &mut Annotations) -> MiddlewareAction {
+            self.log.lock().unwrap().push(format!("{}.before", self.name));
+            annotations.insert(self.name.to_string(), String::from("seen"));
+            if self.drop_syn && message.payload.header == HEALTH_CHECK_SYN_OPCODE {
+                return MiddlewareAction::ShortCircuit(Err(HandlerError::Failed(String::from("Dropped"))));
+            }
+            MiddlewareAction::Continue
+        }
+
+        fn after(&self, _context: &HealthCheckHandlerContext, _message: &HealthCheckNetworkBrokerMessage, _annotations: &Annotations, result: &mut Result<(), HandlerError>) {
+            self.log.lock().unwrap().push(format!("{}.after ok={}", self.name, result.is_ok()));
+        }
+    }
+
+    /**
+    Rewrites ACKs into SYNs.
+    */
+    struct Rewriter;
+
+    impl HandlerMiddleware for Rewriter {
+        fn before(&self, _context: &HealthCheckHandlerContext, message: &mut HealthCheckNetworkBrokerMessage, _annotations: &mut Annotations) -> MiddlewareAction {
+            if message.payload.header == HEALTH_CHECK_ACK_OPCODE {
+                message.payload.header = HEALTH_CHECK_SYN_OPCODE;
+            }
+            MiddlewareAction::Continue
+        }
+    }
+
+    fn message(header: u8) -> HealthCheckNetworkBrokerMessage {
+        HealthCheckNetworkBrokerMessage {
+            payload: HealthCheckPacket {
+                header,
+                nonce: [0; 16],
+                node_id: [0; 16]
+            },
+            remote_addr: SocketAddr::new(IpAddr::V4(IP), 3451),
+            trace: MessageTrace::default()
+        }
+    }
+
+    #[test]
+    fn middleware_wraps_the_handler_and_can_short_circuit_it() {
+        let store = NetworkDetailsStore::new();
+        let in_flight_probes = InFlightProbes::new();
+        let static_peers = StaticPeers::default();
+        let metrics = Metrics::default();
+        let context = HealthCheckHandlerContext {
+            network_details_store: &store,
+            in_flight_probes: &in_flight_probes,
+            health_check_defaults: &HealthCheckDefaults::default(),
+            static_peers: &static_peers,
+            metrics: &metrics
+        };
+        let log = Arc::new(Mutex::new(Vec::new()));
+        let mut chain = MiddlewareChain::default();
+        chain.push(Recorder { name: "outer", log: log.clone(), drop_syn: false });
+        chain.push(Recorder { name: "inner", log: log.clone(), drop_syn: true });
+        chain.push(Rewriter);
+
+        let result = chain.dispatch(&context, message(HEALTH_CHECK_ACK_OPCODE), |message, annotations| {
+            log.lock().unwrap().push(format!("handler opcode={} annotations={}", message.payload.header, annotations.len()));
+            Ok(())
+        });
+        assert!(result.is_ok());
+        assert_eq!(vec!["outer.before", "inner.before", "handler opcode=1 annotations=2", "inner.after ok=true", "outer.after ok=true"],
+                   *log.lock().unwrap(), "We expect the handler to get the rewritten message and the annotations");
+
+        log.lock().unwrap().clear();
+        let result = chain.dispatch(&context, message(HEALTH_CHECK_SYN_OPCODE), |_, _| panic!("Handler called for a dropped message"));
+        assert!(result.is_err());
+        assert_eq!(vec!["outer.before", "inner.before", "inner.after ok=false", "outer.after ok=false"],
+                   *log.lock().unwrap(), "We expect the middleware that ran to see the short circuit");
+    }
+}
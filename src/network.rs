@@ -1,21 +1,38 @@
-use std::collections::HashMap;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::hash::Hash;
+use std::io;
 use std::net::{IpAddr, Ipv4Addr, SocketAddr, UdpSocket};
-use std::sync::Mutex;
-use crate::health_check::{DeserializePacket, get_health_check_opcodes, HEALTH_CHECK_ACK_OPCODE, HEALTH_CHECK_PACKET_SIZE, HEALTH_CHECK_SYN_OPCODE, HealthCheckPacket, SerializePacket};
+use std::ops::Bound::{Excluded, Unbounded};
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex, RwLock};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::Receiver;
+use std::time::{Duration, SystemTime};
+use log::{debug, error, warn};
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+use crate::health_check::{DeserializePacket, format_nonce, get_health_check_opcodes, HEALTH_CHECK_ACK_OPCODE, HEALTH_CHECK_PACKET_SIZE, HEALTH_CHECK_SYN_OPCODE, HealthCheckPacket, opcode_name, SerializePacket};
+use crate::health_history::{HealthHistory, ProbeOutcome, ProbeResult, StatusTransition, UptimeStatistics};
+use crate::peer_store::{InMemoryPeerStore, open_peer_store, PeerStore, PeerStoreBackend, PeerStoreEvent};
 
 pub const IP: Ipv4Addr = Ipv4Addr::new(127,0,0,1);
 pub const RECEIVER_PORT: u16 = 3451;
 pub const SENDER_PORT: u16 = 3450;
 
 
-#[derive(Clone,Debug, Eq, PartialEq)]
+#[derive(Clone,Debug, Eq, PartialEq, Hash, Serialize, Deserialize)]
 pub enum HealthStatus {
     Healthy,
     AtRisk,
     Unhealthy,
+    /**
+    Unhealthy for longer than the reaper TTL, kept only so other nodes learn the peer is gone before it is purged.
+    */
+    Tombstone,
 }
 
-#[derive(Clone,Debug, Eq, PartialEq)]
+#[derive(Clone,Debug, Eq, PartialEq, Serialize, Deserialize)]
 pub struct HealthStatusDetails {
     pub current_status: HealthStatus,
     /**
@@ -24,111 +41,669 @@ pub struct HealthStatusDetails {
     pub lives_remaining: u8 // TBD: proper value size
 }
 
-#[derive(Clone,Debug, Eq, PartialEq)]
+#[derive(Clone,Debug, Eq, PartialEq, Serialize, Deserialize)]
 pub struct HealthCheck {
     pub configuration: HealthCheckConfiguration,
     pub status_details: HealthStatusDetails
 }
 
-#[derive(Clone,Debug, Eq, PartialEq)]
+/**
+How a peer's lives are spent on missed probes and refilled on acks.
+*/
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Serialize, Deserialize)]
+pub enum FailurePolicy {
+    /**
+    Lose a life per missed probe, win one back per ack.
+    */
+    Gradual,
+    /**
+    Lose a life per missed probe, a single ack refills every life.
+    */
+    ResetOnSuccess,
+    /**
+    Unhealthy on the first missed probe, a single ack refills every life.
+    */
+    FailFast,
+}
+
+impl FromStr for FailurePolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "gradual" => Ok(FailurePolicy::Gradual),
+            "reset_on_success" => Ok(FailurePolicy::ResetOnSuccess),
+            "fail_fast" => Ok(FailurePolicy::FailFast),
+            _ => Err(format!("Unknown failure policy [{}], expected one of gradual, reset_on_success, fail_fast", s))
+        }
+    }
+}
+
+/**
+Global health check settings, used for every peer that doesn't override them in its HealthCheckConfiguration.
+*/
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct HealthCheckDefaults {
+    pub probe_interval: Duration,
+    pub probe_timeout: Duration,
+    pub max_lives: u8,
+    pub failure_policy: FailurePolicy,
+    pub priority: u8,
+}
+
+impl Default for HealthCheckDefaults {
+    fn default() -> Self {
+        HealthCheckDefaults {
+            probe_interval: Duration::from_secs(5),
+            probe_timeout: Duration::from_secs(2),
+            max_lives: 3,
+            failure_policy: FailurePolicy::Gradual,
+            priority: 0,
+        }
+    }
+}
+
+#[derive(Clone,Debug, Eq, PartialEq, Serialize, Deserialize)]
 pub struct HealthCheckConfiguration {
+    /**
+    Port of this endpoint, a host can run several endpoints on different ports, each with its own record.
+    */
     pub health_check_port: u16,
-    // ttl: u32
+    /**
+    Free form labels used to group peers, e.g. by region or role.
+    */
+    pub tags: BTreeSet<String>,
+    /**
+    Disabled peers are kept in the store but never probed.
+    */
+    #[serde(default = "enabled_by_default")]
+    pub enabled: bool,
+    // Overrides of HealthCheckDefaults, None inherits the global setting
+    #[serde(default)]
+    pub probe_interval: Option<Duration>,
+    #[serde(default)]
+    pub probe_timeout: Option<Duration>,
+    #[serde(default)]
+    pub max_lives: Option<u8>,
+    #[serde(default)]
+    pub failure_policy: Option<FailurePolicy>,
+    /**
+    Peers with a higher priority are probed first when several are due at once.
+    */
+    #[serde(default)]
+    pub priority: Option<u8>,
+}
 
+fn enabled_by_default() -> bool {
+    true
 }
 
-#[derive(Clone,Debug, Eq, PartialEq)]
+impl Default for HealthCheckConfiguration {
+    fn default() -> Self {
+        HealthCheckConfiguration {
+            health_check_port: 0,
+            tags: BTreeSet::new(),
+            enabled: true,
+            probe_interval: None,
+            probe_timeout: None,
+            max_lives: None,
+            failure_policy: None,
+            priority: None,
+        }
+    }
+}
+
+impl HealthCheckConfiguration {
+    /**
+    Fills in every setting the peer doesn't override from `defaults`.
+    */
+    pub fn effective(&self, defaults: &HealthCheckDefaults) -> EffectiveHealthCheckConfiguration {
+        EffectiveHealthCheckConfiguration {
+            enabled: self.enabled,
+            probe_interval: self.probe_interval.unwrap_or(defaults.probe_interval),
+            probe_timeout: self.probe_timeout.unwrap_or(defaults.probe_timeout),
+            max_lives: self.max_lives.unwrap_or(defaults.max_lives),
+            failure_policy: self.failure_policy.unwrap_or(defaults.failure_policy),
+            priority: self.priority.unwrap_or(defaults.priority),
+        }
+    }
+}
+
+/**
+Settings actually applied to a peer, its own overrides on top of the HealthCheckDefaults.
+*/
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct EffectiveHealthCheckConfiguration {
+    pub enabled: bool,
+    pub probe_interval: Duration,
+    pub probe_timeout: Duration,
+    pub max_lives: u8,
+    pub failure_policy: FailurePolicy,
+    pub priority: u8,
+}
+
+#[derive(Clone,Debug, Eq, PartialEq, Serialize, Deserialize)]
 pub struct NetworkDetails {
+    /**
+    Stable identity of the remote node, carried in every packet it sends.
+    */
+    pub node_id: Uuid,
     pub addr: IpAddr,
     pub health_check: HealthCheck,
+    /**
+    Last time a packet was received from the node.
+    */
+    pub last_seen: SystemTime,
+    /**
+    When the node moved into its current health status.
+    */
+    pub status_since: SystemTime,
+    /**
+    Set on records recovered from disk, cleared once the node has been heard from again.
+    */
+    pub stale: bool,
+    pub health_history: HealthHistory,
+}
+
+impl NetworkDetails {
+    pub fn socket_addr(&self) -> SocketAddr {
+        SocketAddr::new(self.addr, self.health_check.configuration.health_check_port)
+    }
+
+    /**
+    Moves the node to `status`, `status_since` is only reset when the status actually changes.
+    */
+    pub fn set_status(&mut self, status: HealthStatus, now: SystemTime) {
+        if self.health_check.status_details.current_status != status {
+            let from = std::mem::replace(&mut self.health_check.status_details.current_status, status.clone());
+            self.health_history.record_transition(StatusTransition {
+                at: now,
+                from,
+                to: status
+            });
+            self.status_since = now;
+        }
+    }
+
+    /**
+    Records a probe that was never acked, the node loses lives as per its failure policy and is Unhealthy
+    once it runs out. Tombstones stay tombstones, only hearing back from the node brings it back.
+    */
+    pub fn record_probe_timeout(&mut self, now: SystemTime, configuration: &EffectiveHealthCheckConfiguration) {
+        self.health_history.record_probe(ProbeOutcome {
+            at: now,
+            result: ProbeResult::Timeout
+        });
+        if self.health_check.status_details.current_status == HealthStatus::Tombstone {
+            return;
+        }
+        let lives_remaining = match configuration.failure_policy {
+            FailurePolicy::Gradual | FailurePolicy::ResetOnSuccess => self.health_check.status_details.lives_remaining.saturating_sub(1),
+            FailurePolicy::FailFast => 0
+        };
+        self.health_check.status_details.lives_remaining = lives_remaining;
+        if lives_remaining == 0 {
+            self.set_status(HealthStatus::Unhealthy, now);
+        } else {
+            self.set_status(HealthStatus::AtRisk, now);
+        }
+    }
+
+    /**
+    Records hearing back from the node, which brings it back to Healthy even from Unhealthy or a tombstone.
+    Lives are refilled as per its failure policy, never past its max lives.
+    */
+    pub fn record_ack(&mut self, now: SystemTime, configuration: &EffectiveHealthCheckConfiguration) {
+        self.last_seen = now;
+        self.stale = false;
+        self.set_status(HealthStatus::Healthy, now);
+        let lives_remaining = match configuration.failure_policy {
+            FailurePolicy::Gradual => self.health_check.status_details.lives_remaining.saturating_add(1),
+            FailurePolicy::ResetOnSuccess | FailurePolicy::FailFast => configuration.max_lives
+        };
+        self.health_check.status_details.lives_remaining = lives_remaining.min(configuration.max_lives);
+    }
+}
+
+/**
+Every endpoint known at one IP, with their statuses rolled up into one for the host.
+*/
+#[derive(Clone, Debug, PartialEq)]
+pub struct HostHealth {
+    pub addr: IpAddr,
+    pub status: HealthStatus,
+    /**
+    Ordered by port.
+    */
+    pub endpoints: Vec<NetworkDetails>,
+}
+
+impl HostHealth {
+    fn new(addr: IpAddr, mut endpoints: Vec<NetworkDetails>) -> Option<HostHealth> {
+        let status = aggregate_health_status(endpoints.iter().map(|endpoint| &endpoint.health_check.status_details.current_status))?;
+        endpoints.sort_by_key(|endpoint| endpoint.health_check.configuration.health_check_port);
+        Some(HostHealth {
+            addr,
+            status,
+            endpoints
+        })
+    }
+}
+
+/**
+Rolls endpoint statuses up into a host status. The host is Healthy only if every endpoint is, Unhealthy once
+none of them are reachable, a Tombstone once all of them are, and AtRisk anywhere in between.
+Returns None when there are no endpoints.
+*/
+pub fn aggregate_health_status<'a>(statuses: impl IntoIterator<Item = &'a HealthStatus>) -> Option<HealthStatus> {
+    let mut count = 0;
+    let mut healthy = 0;
+    let mut reachable = 0;
+    let mut tombstones = 0;
+    for status in statuses {
+        count += 1;
+        match status {
+            HealthStatus::Healthy => {
+                healthy += 1;
+                reachable += 1;
+            }
+            HealthStatus::AtRisk => reachable += 1,
+            HealthStatus::Unhealthy => {}
+            HealthStatus::Tombstone => tombstones += 1,
+        }
+    }
+    if count == 0 {
+        None
+    } else if healthy == count {
+        Some(HealthStatus::Healthy)
+    } else if tombstones == count {
+        Some(HealthStatus::Tombstone)
+    } else if reachable == 0 {
+        Some(HealthStatus::Unhealthy)
+    } else {
+        Some(HealthStatus::AtRisk)
+    }
+}
+
+/**
+Secondary indexes over the records in the PeerStore, all keyed back to node id.
+*/
+#[derive(Debug, Default)]
+struct NetworkDetailsIndexes {
+    ip_index: HashMap<IpAddr, HashSet<Uuid>>,
+    socket_addr_index: HashMap<SocketAddr, Uuid>,
+    status_index: HashMap<HealthStatus, HashSet<Uuid>>,
+    port_index: HashMap<u16, HashSet<Uuid>>,
+    tag_index: HashMap<String, HashSet<Uuid>>,
+    /**
+    Ordered by last seen time so time range queries don't need a full scan.
+    */
+    last_seen_index: BTreeSet<(SystemTime, Uuid)>,
+}
+
+fn remove_from_index<K: Hash + Eq>(index: &mut HashMap<K, HashSet<Uuid>>, key: &K, node_id: &Uuid) {
+    if let Some(node_ids) = index.get_mut(key) {
+        node_ids.remove(node_id);
+        if node_ids.is_empty() {
+            index.remove(key);
+        }
+    }
 }
 
+impl NetworkDetailsIndexes {
+    fn remove(&mut self, network_details: &NetworkDetails) {
+        let node_id = &network_details.node_id;
+        remove_from_index(&mut self.ip_index, &network_details.addr, node_id);
+        let socket_addr = network_details.socket_addr();
+        if self.socket_addr_index.get(&socket_addr) == Some(node_id) {
+            self.socket_addr_index.remove(&socket_addr);
+        }
+        remove_from_index(&mut self.status_index, &network_details.health_check.status_details.current_status, node_id);
+        remove_from_index(&mut self.port_index, &network_details.health_check.configuration.health_check_port, node_id);
+        for tag in &network_details.health_check.configuration.tags {
+            remove_from_index(&mut self.tag_index, tag, node_id);
+        }
+        self.last_seen_index.remove(&(network_details.last_seen, *node_id));
+    }
+
+    fn add(&mut self, network_details: &NetworkDetails) {
+        let node_id = network_details.node_id;
+        self.ip_index.entry(network_details.addr).or_default().insert(node_id);
+        // Only one node can be listening on a socket address, the latest one to report from it wins
+        self.socket_addr_index.insert(network_details.socket_addr(), node_id);
+        self.status_index.entry(network_details.health_check.status_details.current_status.clone()).or_default().insert(node_id);
+        self.port_index.entry(network_details.health_check.configuration.health_check_port).or_default().insert(node_id);
+        for tag in &network_details.health_check.configuration.tags {
+            self.tag_index.entry(tag.clone()).or_default().insert(node_id);
+        }
+        self.last_seen_index.insert((network_details.last_seen, node_id));
+    }
+}
+
+fn index_lookup<K: Hash + Eq>(index: &HashMap<K, HashSet<Uuid>>, key: &K) -> Vec<Uuid> {
+    match index.get(key) {
+        Some(node_ids) => node_ids.iter().cloned().collect(),
+        None => Vec::new()
+    }
+}
+
+/**
+Every record in the store at one point in time, shared between readers until the next write.
+*/
+pub type NetworkDetailsSnapshot = Arc<Vec<Arc<NetworkDetails>>>;
+
+const WRITE_LOCK_COUNT: usize = 16;
+
 #[derive(Debug)]
 pub struct NetworkDetailsStore {
-    // add id?
+    /**
+    Records keyed by node id.
+    */
+    peer_store: Box<dyn PeerStore>,
+    /**
+    Held across the read and write to the peer store for a node, split by node id so writes to
+    different nodes don't wait on each other's disk I/O.
+    */
+    write_locks: Vec<Mutex<()>>,
+    /**
+    Only write locked to apply a write that already landed in the peer store, queries only hold
+    the read lock while looking up node ids.
+    */
+    indexes: RwLock<NetworkDetailsIndexes>,
+    /**
+    Bumped on every write, invalidates the cached snapshot.
+    */
+    generation: AtomicU64,
+    snapshot: RwLock<(u64, NetworkDetailsSnapshot)>,
+}
 
-    // What should the key be? IP is probably best for now, can create "secondary indexes" if necessary
-    host_map: Mutex<HashMap<IpAddr, NetworkDetails>>,
+impl Default for NetworkDetailsStore {
+    fn default() -> Self {
+        NetworkDetailsStore::new()
+    }
 }
 
+// Lookups and writes report an unknown node as Err(()), failures of the peer store itself are logged where they happen
+#[allow(clippy::result_unit_err)]
 impl NetworkDetailsStore {
 
+    /**
+    Creates a store only kept in memory.
+    */
     pub fn new() -> NetworkDetailsStore {
-        let actual_map = HashMap::new();
-        return NetworkDetailsStore {
-            host_map: Mutex::new(actual_map),
-        }
+        NetworkDetailsStore::from_parts(Box::new(InMemoryPeerStore::new()), NetworkDetailsIndexes::default())
+    }
+
+    /**
+    Opens a store on the configured backend with its files in `data_dir`, recovering the records from the last run.
+    Recovered records are marked stale until the node is heard from again.
+    */
+    pub fn open(backend: &PeerStoreBackend, data_dir: &Path) -> io::Result<NetworkDetailsStore> {
+        NetworkDetailsStore::with_peer_store(open_peer_store(backend, data_dir)?)
     }
 
-    pub fn get_network_details_by_ip(&self, ip: &IpAddr) ->  Result<NetworkDetails, ()>  {
-        let host_map = self.host_map.lock().unwrap();
-        let record = host_map.get(ip);
-        if record.is_none() {
-            return Err(())
+    pub fn with_peer_store(peer_store: Box<dyn PeerStore>) -> io::Result<NetworkDetailsStore> {
+        let mut indexes = NetworkDetailsIndexes::default();
+        for record in peer_store.scan()? {
+            let mut record = record.as_ref().clone();
+            if !record.stale {
+                record.stale = true;
+                peer_store.put(record.clone())?;
+            }
+            indexes.add(&record);
         }
+        Ok(NetworkDetailsStore::from_parts(peer_store, indexes))
+    }
 
-        return Ok(record.unwrap().clone())
+    fn from_parts(peer_store: Box<dyn PeerStore>, indexes: NetworkDetailsIndexes) -> NetworkDetailsStore {
+        NetworkDetailsStore {
+            peer_store,
+            write_locks: (0..WRITE_LOCK_COUNT).map(|_| Mutex::new(())).collect(),
+            indexes: RwLock::new(indexes),
+            // Starts ahead of the empty cached snapshot, so the first read builds it
+            generation: AtomicU64::new(1),
+            snapshot: RwLock::new((0, Arc::new(Vec::new()))),
+        }
     }
 
-    pub fn put_network_details(&self, network_details: &NetworkDetails) {
-        let mut host_map = self.host_map.lock().unwrap();
-        host_map.insert(network_details.clone().addr, network_details.clone());
+    pub fn is_persistent(&self) -> bool {
+        self.peer_store.is_persistent()
     }
-}
 
-// Let's write some tests
-#[cfg(test)]
-mod network_tests {
-    use std::net::IpAddr;
-    use crate::network::{HealthCheck, HealthCheckConfiguration, HealthStatus, HealthStatusDetails, IP, NetworkDetails, NetworkDetailsStore};
+    /**
+    Compacts or flushes the durable state of the peer store.
+    */
+    pub fn checkpoint(&self) -> io::Result<()> {
+        self.peer_store.checkpoint()
+    }
 
-    #[test]
-    fn network_details_store_initializes_successfully() {
-        let store = NetworkDetailsStore::new();
+    /**
+    Returns a receiver for every change made to the store after the call.
+    */
+    pub fn watch(&self) -> Receiver<PeerStoreEvent> {
+        self.peer_store.watch()
     }
 
-    #[test]
-    fn network_details_store_returns_data() {
-        let mut store = NetworkDetailsStore::new();
-        let dummy_record = NetworkDetails {
-            addr: IpAddr::V4(IP),
-            health_check: HealthCheck {
-                status_details: HealthStatusDetails {
-                    current_status: HealthStatus::Healthy,
-                    lives_remaining: 0,
-                },
-                configuration: HealthCheckConfiguration {
-                    health_check_port: 0,
-                }
+    /**
+    Returns every record in the store. The snapshot is cached and shared between callers until the next write,
+    so read heavy callers like listing peers don't copy the table each time.
+    */
+    pub fn snapshot(&self) -> NetworkDetailsSnapshot {
+        let generation = self.generation.load(Ordering::Acquire);
+        {
+            let cached = self.snapshot.read().unwrap();
+            if cached.0 == generation {
+                return cached.1.clone();
+            }
+        }
+
+        let records = match self.peer_store.scan() {
+            Ok(records) => Arc::new(records),
+            Err(err) => {
+                error!("Failed to scan network details from peer store: {}", err);
+                return Arc::new(Vec::new());
             }
         };
-        store.host_map.get_mut().unwrap().insert(IpAddr::V4(IP), dummy_record.clone());
+        let mut cached = self.snapshot.write().unwrap();
+        // Another reader may have rebuilt it from a newer generation in the meantime
+        if cached.0 < generation {
+            *cached = (generation, records.clone());
+        }
+        records
+    }
 
-        let result = store.get_network_details_by_ip(&IpAddr::V4(IP)).unwrap().clone();
-        println!("{:?}", result);
-        println!("{:?}", store);
-        assert_eq!(dummy_record, result);
+    fn write_lock(&self, node_id: &Uuid) -> &Mutex<()> {
+        &self.write_locks[(node_id.as_u128() % WRITE_LOCK_COUNT as u128) as usize]
     }
 
-    #[test]
-    fn network_details_store_stores_and_returns_data() {
+    /**
+    Moves `node_id` in the indexes from its `previous` record to `current`, once the write landed in the peer store.
+    */
+    fn reindex(&self, previous: Option<&NetworkDetails>, current: Option<&NetworkDetails>) {
+        let mut indexes = self.indexes.write().unwrap();
+        if let Some(previous) = previous {
+            indexes.remove(previous);
+        }
+        if let Some(current) = current {
+            indexes.add(current);
+        }
+        self.generation.fetch_add(1, Ordering::AcqRel);
+    }
 
-        let dummy_record = NetworkDetails {
-            addr: IpAddr::V4(IP),
-            health_check: HealthCheck {
-                status_details: HealthStatusDetails {
-                    current_status: HealthStatus::Healthy,
-                    lives_remaining: 0,
-                },
-                configuration: HealthCheckConfiguration {
-                    health_check_port: 0,
-                }
+    fn get(&self, node_id: &Uuid) -> Option<Arc<NetworkDetails>> {
+        match self.peer_store.get(node_id) {
+            Ok(record) => record,
+            Err(err) => {
+                error!("Failed to read network details for {} from peer store: {}", node_id, err);
+                None
             }
-        };
-        let mut store = NetworkDetailsStore::new();
-        store.put_network_details(&dummy_record);
-        store.get_network_details_by_ip(&dummy_record.addr);
-        // store.host_map
+        }
+    }
+
+    /**
+    Fetches the records for `node_ids` found through an index. The index lock is already released,
+    so records are checked against `predicate` again in case they changed in between.
+    */
+    fn collect(&self, node_ids: Vec<Uuid>, predicate: impl Fn(&NetworkDetails) -> bool) -> Vec<NetworkDetails> {
+        node_ids.iter()
+            .filter_map(|node_id| self.get(node_id))
+            .filter(|record| predicate(record))
+            .map(|record| record.as_ref().clone())
+            .collect()
+    }
+
+    pub fn get_network_details_by_node_id(&self, node_id: &Uuid) -> Result<NetworkDetails, ()> {
+        self.get(node_id).map(|record| record.as_ref().clone()).ok_or(())
+    }
+
+    /**
+    Returns every node known at the given IP, there can be more than one node behind a single address.
+    */
+    pub fn get_network_details_by_ip(&self, ip: &IpAddr) -> Vec<NetworkDetails> {
+        let node_ids = index_lookup(&self.indexes.read().unwrap().ip_index, ip);
+        self.collect(node_ids, |record| record.addr == *ip)
+    }
+
+    pub fn get_network_details_by_socket_addr(&self, socket_addr: &SocketAddr) -> Result<NetworkDetails, ()> {
+        let node_id = self.indexes.read().unwrap().socket_addr_index.get(socket_addr).cloned().ok_or(())?;
+        self.collect(vec![node_id], |record| record.socket_addr() == *socket_addr)
+            .pop()
+            .ok_or(())
+    }
+
+    pub fn get_all_network_details(&self) -> Vec<NetworkDetails> {
+        self.snapshot().iter().map(|record| record.as_ref().clone()).collect()
+    }
+
+    pub fn get_network_details_by_status(&self, status: &HealthStatus) -> Vec<NetworkDetails> {
+        let node_ids = index_lookup(&self.indexes.read().unwrap().status_index, status);
+        self.collect(node_ids, |record| record.health_check.status_details.current_status == *status)
+    }
+
+    pub fn get_network_details_by_port(&self, port: u16) -> Vec<NetworkDetails> {
+        let node_ids = index_lookup(&self.indexes.read().unwrap().port_index, &port);
+        self.collect(node_ids, |record| record.health_check.configuration.health_check_port == port)
+    }
+
+    pub fn get_network_details_by_tag(&self, tag: &str) -> Vec<NetworkDetails> {
+        let node_ids = index_lookup(&self.indexes.read().unwrap().tag_index, &tag.to_string());
+        self.collect(node_ids, |record| record.health_check.configuration.tags.contains(tag))
+    }
+
+    /**
+    Returns nodes last seen strictly before `time`, oldest first.
+    */
+    pub fn get_network_details_last_seen_before(&self, time: SystemTime) -> Vec<NetworkDetails> {
+        let node_ids = self.indexes.read().unwrap().last_seen_index
+            .range(..(time, Uuid::nil()))
+            .map(|(_, node_id)| *node_id)
+            .collect();
+        self.collect(node_ids, |record| record.last_seen < time)
+    }
+
+    /**
+    Returns nodes last seen strictly after `time`, oldest first.
+    */
+    pub fn get_network_details_last_seen_after(&self, time: SystemTime) -> Vec<NetworkDetails> {
+        let node_ids = self.indexes.read().unwrap().last_seen_index
+            .range((Excluded((time, Uuid::max())), Unbounded))
+            .map(|(_, node_id)| *node_id)
+            .collect();
+        self.collect(node_ids, |record| record.last_seen > time)
+    }
+
+    pub fn put_network_details(&self, network_details: &NetworkDetails) {
+        let _write_lock = self.write_lock(&network_details.node_id).lock().unwrap();
+        match self.peer_store.put(network_details.clone()) {
+            Ok(previous) => self.reindex(previous.as_deref(), Some(network_details)),
+            Err(err) => error!("Failed to write network details for {} to peer store: {}", network_details.node_id, err)
+        }
+    }
+
+    /**
+    Applies `update` to the record for `node_id` and writes it back, no other write to the node can land in between.
+    Returns the updated record, or Err if the node is unknown.
+    */
+    pub fn update_network_details(&self, node_id: &Uuid, update: impl FnOnce(&mut NetworkDetails)) -> Result<NetworkDetails, ()> {
+        let _write_lock = self.write_lock(node_id).lock().unwrap();
+        let previous = self.get(node_id).ok_or(())?;
+        let mut network_details = previous.as_ref().clone();
+        update(&mut network_details);
+        match self.peer_store.put(network_details.clone()) {
+            Ok(_) => {
+                self.reindex(Some(&previous), Some(&network_details));
+                Ok(network_details)
+            }
+            Err(err) => {
+                error!("Failed to write network details for {} to peer store: {}", node_id, err);
+                Err(())
+            }
+        }
+    }
+
+    /**
+    Returns every endpoint at `ip` along with the aggregate status of the host, or Err if nothing is known at `ip`.
+    */
+    pub fn get_host_health(&self, ip: &IpAddr) -> Result<HostHealth, ()> {
+        HostHealth::new(*ip, self.get_network_details_by_ip(ip)).ok_or(())
+    }
+
+    /**
+    Groups every endpoint in the store by host, ordered by IP.
+    */
+    pub fn get_all_host_health(&self) -> Vec<HostHealth> {
+        let mut hosts: BTreeMap<IpAddr, Vec<NetworkDetails>> = BTreeMap::new();
+        for record in self.snapshot().iter() {
+            hosts.entry(record.addr).or_default().push(record.as_ref().clone());
+        }
+        hosts.into_iter()
+            .filter_map(|(addr, endpoints)| HostHealth::new(addr, endpoints))
+            .collect()
+    }
+
+    pub fn get_health_history(&self, node_id: &Uuid) -> Result<HealthHistory, ()> {
+        self.get(node_id).map(|record| record.health_history.clone()).ok_or(())
+    }
+
+    /**
+    Availability of the node over the last minute, five minutes and hour.
+    */
+    pub fn get_uptime_statistics(&self, node_id: &Uuid, now: SystemTime) -> Result<UptimeStatistics, ()> {
+        self.get(node_id).map(|record| record.health_history.uptime_statistics(now)).ok_or(())
+    }
+
+    pub fn delete_network_details(&self, node_id: &Uuid) -> Result<NetworkDetails, ()> {
+        self.delete_network_details_if(node_id, |_| true)
+    }
+
+    /**
+    Deletes the record for `node_id` only if it still matches `predicate`, no other write to the node can land
+    in between. Returns the deleted record, or Err if the node is unknown or didn't match.
+    */
+    pub fn delete_network_details_if(&self, node_id: &Uuid, predicate: impl FnOnce(&NetworkDetails) -> bool) -> Result<NetworkDetails, ()> {
+        let _write_lock = self.write_lock(node_id).lock().unwrap();
+        if !predicate(self.get(node_id).ok_or(())?.as_ref()) {
+            return Err(());
+        }
+        match self.peer_store.delete(node_id) {
+            Ok(Some(previous)) => {
+                self.reindex(Some(&previous), None);
+                Ok(previous.as_ref().clone())
+            }
+            Ok(None) => Err(()),
+            Err(err) => {
+                error!("Failed to delete network details for {} from peer store: {}", node_id, err);
+                Err(())
+            }
+        }
+    }
+
+    /**
+    Deletes the node currently at `socket_addr`, or Err if there is none.
+    */
+    pub fn delete_network_details_by_socket_addr(&self, socket_addr: &SocketAddr) -> Result<NetworkDetails, ()> {
+        let node_id = self.get_network_details_by_socket_addr(socket_addr)?.node_id;
+        self.delete_network_details(&node_id)
     }
 }
 
@@ -144,16 +719,16 @@ pub fn health_check_receiver() -> std::io::Result<()> {
         let buf_vec = buf.to_vec();
         let health_check_packet = HealthCheckPacket::deserialize(buf_vec);
         let mut response_object = health_check_packet.clone();
-        println!("Received: {:?}", health_check_packet);
+        debug!(peer:% = src, opcode:% = opcode_name(health_check_packet.header), nonce:% = format_nonce(&health_check_packet.nonce); "Received: {:?}", health_check_packet);
         if health_check_packet.header == HEALTH_CHECK_SYN_OPCODE {
             response_object.header = HEALTH_CHECK_ACK_OPCODE;
         } else if health_check_packet.header == HEALTH_CHECK_ACK_OPCODE {
             // TODO: update network table
         } else {
             if get_health_check_opcodes().contains(&health_check_packet.header) {
-                println!("Valid op code [{}] provided, but not handled!", health_check_packet.header);
+                warn!(opcode = health_check_packet.header; "Valid op code [{}] provided, but not handled!", health_check_packet.header);
             } else {
-                println!("Invalid op code received of [{}]", health_check_packet.header);
+                warn!(opcode = health_check_packet.header; "Invalid op code received of [{}]", health_check_packet.header);
             }
             return Ok(());
         }
@@ -177,7 +752,8 @@ pub fn health_check_sender() -> std::io::Result<()> {
         let socket = UdpSocket::bind("127.0.0.1:3450")?;
         let request_object = HealthCheckPacket {
             header: HEALTH_CHECK_SYN_OPCODE,
-            nonce: [2,3,6,1,7,3,2,3,4,9,3,2,1,7,7,3]
+            nonce: [2,3,6,1,7,3,2,3,4,9,3,2,1,7,7,3],
+            node_id: [0; 16]
         };
         let buf = &mut [0;HEALTH_CHECK_PACKET_SIZE];
         let raw = request_object.serialize();
@@ -192,13 +768,13 @@ pub fn health_check_sender() -> std::io::Result<()> {
 
         let mut buf = [0; HEALTH_CHECK_PACKET_SIZE+1];
 
-        let (_amt, _src) = socket.recv_from(&mut buf)?;
+        let (_amt, src) = socket.recv_from(&mut buf)?;
 
         let buf = &buf[..HEALTH_CHECK_PACKET_SIZE];
         let buf_vec = buf.to_vec();
         let health_check_packet = HealthCheckPacket::deserialize(buf_vec);
         let mut response_object = health_check_packet.clone();
-        println!("Received: {:?}", response_object);
+        debug!(peer:% = src, opcode:% = opcode_name(response_object.header), nonce:% = format_nonce(&response_object.nonce); "Received: {:?}", response_object);
         if health_check_packet.header == HEALTH_CHECK_SYN_OPCODE {
             response_object.header = HEALTH_CHECK_ACK_OPCODE;
         } else if health_check_packet.header == HEALTH_CHECK_ACK_OPCODE {
@@ -206,12 +782,393 @@ pub fn health_check_sender() -> std::io::Result<()> {
             // TODO: update network table
         } else {
             if get_health_check_opcodes().contains(&health_check_packet.header) {
-                println!("Valid op code [{}] provided, but not handled!", health_check_packet.header);
+                warn!(opcode = health_check_packet.header; "Valid op code [{}] provided, but not handled!", health_check_packet.header);
             } else {
-                println!("Invalid op code received of [{}]", health_check_packet.header);
+                warn!(opcode = health_check_packet.header; "Invalid op code received of [{}]", health_check_packet.header);
             }
             return Ok(());
         }
     } // the socket is closed here
     Ok(())
+}
+
+// Let's write some tests
+#[cfg(test)]
+mod network_tests {
+    use std::collections::{BTreeSet, HashMap};
+    use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+    use std::sync::{Arc, Mutex};
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::thread;
+    use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+    use uuid::Uuid;
+    use crate::health_history::{HealthHistory, ProbeResult};
+    use crate::network::{aggregate_health_status, FailurePolicy, HealthCheck, HealthCheckConfiguration, HealthCheckDefaults, HealthStatus, HealthStatusDetails, IP, NetworkDetails, NetworkDetailsStore};
+    use crate::peer_store::PeerStoreBackend;
+
+    fn dummy_record(node_id: Uuid, addr: IpAddr, port: u16) -> NetworkDetails {
+        NetworkDetails {
+            node_id,
+            addr,
+            last_seen: UNIX_EPOCH,
+            status_since: UNIX_EPOCH,
+            stale: false,
+            health_history: HealthHistory::default(),
+            health_check: HealthCheck {
+                status_details: HealthStatusDetails {
+                    current_status: HealthStatus::Healthy,
+                    lives_remaining: 0,
+                },
+                configuration: HealthCheckConfiguration {
+                    health_check_port: port,
+                    ..HealthCheckConfiguration::default()
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn network_details_store_initializes_successfully() {
+        let _store = NetworkDetailsStore::new();
+    }
+
+    #[test]
+    fn network_details_store_returns_data() {
+        let store = NetworkDetailsStore::new();
+        let dummy_record = dummy_record(Uuid::new_v4(), IpAddr::V4(IP), 0);
+        store.peer_store.put(dummy_record.clone()).unwrap();
+
+        let result = store.get_network_details_by_node_id(&dummy_record.node_id).unwrap();
+        println!("{:?}", result);
+        println!("{:?}", store);
+        assert_eq!(dummy_record, result);
+    }
+
+    #[test]
+    fn network_details_store_stores_and_returns_data() {
+        let dummy_record = dummy_record(Uuid::new_v4(), IpAddr::V4(IP), 3450);
+        let store = NetworkDetailsStore::new();
+        store.put_network_details(&dummy_record);
+
+        assert_eq!(dummy_record, store.get_network_details_by_node_id(&dummy_record.node_id).unwrap());
+        assert_eq!(vec![dummy_record.clone()], store.get_network_details_by_ip(&dummy_record.addr));
+        assert_eq!(dummy_record, store.get_network_details_by_socket_addr(&dummy_record.socket_addr()).unwrap());
+    }
+
+    #[test]
+    fn network_details_store_keeps_nodes_behind_one_ip_apart() {
+        let first = dummy_record(Uuid::new_v4(), IpAddr::V4(IP), 3450);
+        let second = dummy_record(Uuid::new_v4(), IpAddr::V4(IP), 3451);
+        let store = NetworkDetailsStore::new();
+        store.put_network_details(&first);
+        store.put_network_details(&second);
+
+        assert_eq!(2, store.get_network_details_by_ip(&IpAddr::V4(IP)).len());
+        assert_eq!(first, store.get_network_details_by_socket_addr(&SocketAddr::new(IpAddr::V4(IP), 3450)).unwrap());
+        assert_eq!(second, store.get_network_details_by_socket_addr(&SocketAddr::new(IpAddr::V4(IP), 3451)).unwrap());
+    }
+
+    #[test]
+    fn network_details_store_reindexes_node_that_changed_ip() {
+        let node_id = Uuid::new_v4();
+        let old_record = dummy_record(node_id, IpAddr::V4(IP), 3450);
+        let new_record = dummy_record(node_id, IpAddr::V4(Ipv4Addr::new(10, 0, 0, 2)), 3450);
+        let store = NetworkDetailsStore::new();
+        store.put_network_details(&old_record);
+        store.put_network_details(&new_record);
+
+        assert_eq!(new_record, store.get_network_details_by_node_id(&node_id).unwrap());
+        assert!(store.get_network_details_by_ip(&old_record.addr).is_empty());
+        assert!(store.get_network_details_by_socket_addr(&old_record.socket_addr()).is_err());
+        assert_eq!(vec![new_record.clone()], store.get_network_details_by_ip(&new_record.addr));
+    }
+
+    #[test]
+    fn network_details_store_queries_secondary_indexes() {
+        let mut tagged = dummy_record(Uuid::new_v4(), IpAddr::V4(IP), 3450);
+        tagged.health_check.configuration.tags.insert(String::from("us-west"));
+        tagged.last_seen = UNIX_EPOCH + Duration::from_secs(10);
+        let mut at_risk = dummy_record(Uuid::new_v4(), IpAddr::V4(IP), 3451);
+        at_risk.health_check.status_details.current_status = HealthStatus::AtRisk;
+        at_risk.last_seen = UNIX_EPOCH + Duration::from_secs(20);
+        let store = NetworkDetailsStore::new();
+        store.put_network_details(&tagged);
+        store.put_network_details(&at_risk);
+
+        assert_eq!(2, store.get_all_network_details().len());
+        assert_eq!(vec![tagged.clone()], store.get_network_details_by_status(&HealthStatus::Healthy));
+        assert_eq!(vec![at_risk.clone()], store.get_network_details_by_status(&HealthStatus::AtRisk));
+        assert_eq!(vec![at_risk.clone()], store.get_network_details_by_port(3451));
+        assert_eq!(vec![tagged.clone()], store.get_network_details_by_tag("us-west"));
+        assert!(store.get_network_details_by_tag("eu-central").is_empty());
+        assert_eq!(vec![tagged.clone()], store.get_network_details_last_seen_before(UNIX_EPOCH + Duration::from_secs(20)));
+        assert_eq!(vec![at_risk.clone()], store.get_network_details_last_seen_after(UNIX_EPOCH + Duration::from_secs(10)));
+    }
+
+    #[test]
+    fn network_details_store_keeps_indexes_consistent_on_update() {
+        let mut record = dummy_record(Uuid::new_v4(), IpAddr::V4(IP), 3450);
+        record.health_check.configuration.tags.insert(String::from("us-west"));
+        let store = NetworkDetailsStore::new();
+        store.put_network_details(&record);
+
+        record.health_check.status_details.current_status = HealthStatus::Unhealthy;
+        record.health_check.configuration.tags = BTreeSet::from([String::from("eu-central")]);
+        record.last_seen = UNIX_EPOCH + Duration::from_secs(30);
+        store.put_network_details(&record);
+
+        assert!(store.get_network_details_by_status(&HealthStatus::Healthy).is_empty());
+        assert_eq!(vec![record.clone()], store.get_network_details_by_status(&HealthStatus::Unhealthy));
+        assert!(store.get_network_details_by_tag("us-west").is_empty());
+        assert_eq!(vec![record.clone()], store.get_network_details_by_tag("eu-central"));
+        assert!(store.get_network_details_last_seen_before(UNIX_EPOCH + Duration::from_secs(30)).is_empty());
+        assert_eq!(vec![record.clone()], store.get_network_details_last_seen_after(UNIX_EPOCH));
+    }
+
+    #[test]
+    fn network_details_store_marks_recovered_records_stale() {
+        let data_dir = std::env::temp_dir().join(format!("swizzy_decent_store_{}", Uuid::new_v4()));
+        let record = dummy_record(Uuid::new_v4(), IpAddr::V4(IP), 3450);
+        {
+            let store = NetworkDetailsStore::open(&PeerStoreBackend::Memory, &data_dir).unwrap();
+            store.put_network_details(&record);
+        }
+
+        let store = NetworkDetailsStore::open(&PeerStoreBackend::Memory, &data_dir).unwrap();
+        let recovered = store.get_network_details_by_node_id(&record.node_id).unwrap();
+        assert!(recovered.stale, "We expect records recovered from disk to be stale until re-probed");
+        assert_eq!(vec![recovered], store.get_network_details_by_port(3450));
+        std::fs::remove_dir_all(data_dir).unwrap();
+    }
+
+    #[test]
+    fn network_details_store_removes_deleted_records_from_indexes() {
+        let record = dummy_record(Uuid::new_v4(), IpAddr::V4(IP), 3450);
+        let store = NetworkDetailsStore::new();
+        store.put_network_details(&record);
+
+        assert_eq!(record, store.delete_network_details(&record.node_id).unwrap());
+        assert!(store.delete_network_details(&record.node_id).is_err());
+        assert!(store.delete_network_details_by_socket_addr(&record.socket_addr()).is_err());
+        assert!(store.get_network_details_by_ip(&record.addr).is_empty());
+        assert!(store.get_network_details_by_socket_addr(&record.socket_addr()).is_err());
+        assert!(store.get_network_details_by_status(&HealthStatus::Healthy).is_empty());
+    }
+
+    #[test]
+    fn network_details_store_snapshot_is_shared_until_next_write() {
+        let store = NetworkDetailsStore::new();
+        store.put_network_details(&dummy_record(Uuid::new_v4(), IpAddr::V4(IP), 3450));
+
+        let first = store.snapshot();
+        let second = store.snapshot();
+        assert!(Arc::ptr_eq(&first, &second), "We expect the cached snapshot to be reused without writes in between");
+
+        store.put_network_details(&dummy_record(Uuid::new_v4(), IpAddr::V4(IP), 3451));
+        let third = store.snapshot();
+        assert_eq!(1, first.len());
+        assert_eq!(2, third.len());
+    }
+
+    #[test]
+    fn network_details_store_records_probe_timeouts_and_transitions() {
+        let mut record = dummy_record(Uuid::new_v4(), IpAddr::V4(IP), 3450);
+        record.health_check.status_details.lives_remaining = 2;
+        let store = NetworkDetailsStore::new();
+        store.put_network_details(&record);
+
+        let first_timeout = UNIX_EPOCH + Duration::from_secs(10);
+        let second_timeout = UNIX_EPOCH + Duration::from_secs(20);
+        let configuration = record.health_check.configuration.effective(&HealthCheckDefaults::default());
+        store.update_network_details(&record.node_id, |record| record.record_probe_timeout(first_timeout, &configuration)).unwrap();
+        let updated = store.update_network_details(&record.node_id, |record| record.record_probe_timeout(second_timeout, &configuration)).unwrap();
+
+        assert_eq!(HealthStatus::Unhealthy, updated.health_check.status_details.current_status);
+        assert_eq!(second_timeout, updated.status_since);
+        let history = store.get_health_history(&record.node_id).unwrap();
+        assert_eq!(2, history.probes.len());
+        assert!(history.probes.iter().all(|probe| probe.result == ProbeResult::Timeout));
+        let transitions: Vec<(HealthStatus, HealthStatus)> = history.transitions.iter()
+            .map(|transition| (transition.from.clone(), transition.to.clone()))
+            .collect();
+        assert_eq!(vec![(HealthStatus::Healthy, HealthStatus::AtRisk), (HealthStatus::AtRisk, HealthStatus::Unhealthy)], transitions);
+        assert_eq!(Some(0.0), store.get_uptime_statistics(&record.node_id, second_timeout).unwrap().one_minute);
+        assert_eq!(vec![updated], store.get_network_details_by_status(&HealthStatus::Unhealthy));
+        assert!(store.update_network_details(&Uuid::new_v4(), |_| {}).is_err());
+    }
+
+    #[test]
+    fn network_details_store_rolls_endpoints_up_into_host_health() {
+        let other_host = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 2));
+        let mut second = dummy_record(Uuid::new_v4(), IpAddr::V4(IP), 3451);
+        second.health_check.status_details.current_status = HealthStatus::Unhealthy;
+        let first = dummy_record(Uuid::new_v4(), IpAddr::V4(IP), 3450);
+        let remote = dummy_record(Uuid::new_v4(), other_host, 3450);
+        let store = NetworkDetailsStore::new();
+        store.put_network_details(&second);
+        store.put_network_details(&first);
+        store.put_network_details(&remote);
+
+        let host_health = store.get_host_health(&IpAddr::V4(IP)).unwrap();
+        assert_eq!(HealthStatus::AtRisk, host_health.status, "We expect a host with one unhealthy endpoint out of two to be at risk");
+        assert_eq!(vec![first.clone(), second.clone()], host_health.endpoints);
+
+        let all_host_health = store.get_all_host_health();
+        assert_eq!(vec![other_host, IpAddr::V4(IP)], all_host_health.iter().map(|host| host.addr).collect::<Vec<_>>());
+        assert_eq!(HealthStatus::Healthy, all_host_health[0].status);
+        assert!(store.get_host_health(&IpAddr::V4(Ipv4Addr::new(10, 0, 0, 3))).is_err());
+    }
+
+    #[test]
+    fn health_check_configuration_inherits_defaults() {
+        let defaults = HealthCheckDefaults::default();
+        let mut configuration = HealthCheckConfiguration::default();
+        let effective = configuration.effective(&defaults);
+        assert_eq!(defaults.probe_interval, effective.probe_interval);
+        assert_eq!(defaults.max_lives, effective.max_lives);
+
+        configuration.probe_timeout = Some(Duration::from_millis(500));
+        configuration.failure_policy = Some(FailurePolicy::FailFast);
+        let effective = configuration.effective(&defaults);
+        assert_eq!(Duration::from_millis(500), effective.probe_timeout);
+        assert_eq!(FailurePolicy::FailFast, effective.failure_policy);
+        assert_eq!(defaults.probe_interval, effective.probe_interval);
+    }
+
+    #[test]
+    fn failure_policies_spend_and_refill_lives() {
+        let defaults = HealthCheckDefaults::default();
+        let now = UNIX_EPOCH + Duration::from_secs(10);
+        let mut record = dummy_record(Uuid::new_v4(), IpAddr::V4(IP), 3450);
+        record.health_check.status_details.lives_remaining = 3;
+
+        let gradual = record.health_check.configuration.effective(&defaults);
+        record.record_probe_timeout(now, &gradual);
+        record.record_probe_timeout(now, &gradual);
+        assert_eq!(1, record.health_check.status_details.lives_remaining);
+        assert_eq!(HealthStatus::AtRisk, record.health_check.status_details.current_status);
+        record.record_ack(now, &gradual);
+        assert_eq!(2, record.health_check.status_details.lives_remaining);
+        assert_eq!(HealthStatus::Healthy, record.health_check.status_details.current_status);
+
+        record.health_check.configuration.failure_policy = Some(FailurePolicy::FailFast);
+        record.health_check.configuration.max_lives = Some(5);
+        let fail_fast = record.health_check.configuration.effective(&defaults);
+        record.record_probe_timeout(now, &fail_fast);
+        assert_eq!(HealthStatus::Unhealthy, record.health_check.status_details.current_status);
+        record.record_ack(now, &fail_fast);
+        assert_eq!(5, record.health_check.status_details.lives_remaining);
+    }
+
+    #[test]
+    fn aggregate_health_status_rolls_up_endpoint_statuses() {
+        use HealthStatus::*;
+        assert_eq!(None, aggregate_health_status(&[]));
+        assert_eq!(Some(Healthy), aggregate_health_status(&[Healthy, Healthy]));
+        assert_eq!(Some(AtRisk), aggregate_health_status(&[Healthy, AtRisk]));
+        assert_eq!(Some(AtRisk), aggregate_health_status(&[Healthy, Tombstone]));
+        assert_eq!(Some(Unhealthy), aggregate_health_status(&[Unhealthy, Tombstone]));
+        assert_eq!(Some(Tombstone), aggregate_health_status(&[Tombstone, Tombstone]));
+    }
+
+    /**
+    Runs `reader` on many threads against `node_ids` while one writer keeps updating them,
+    returns reads per second across all readers.
+    */
+    fn run_store_benchmark<S: Send + Sync + 'static>(store: Arc<S>,
+                                                      node_ids: Arc<Vec<Uuid>>,
+                                                      reader_threads: usize,
+                                                      reads_per_thread: usize,
+                                                      reader: fn(&S, &Uuid),
+                                                      writer: fn(&S, &Uuid)) -> f64 {
+        let done = Arc::new(AtomicBool::new(false));
+        let writer_handle = {
+            let store = store.clone();
+            let node_ids = node_ids.clone();
+            let done = done.clone();
+            thread::spawn(move || {
+                let mut i = 0;
+                while !done.load(Ordering::Relaxed) {
+                    writer(&store, &node_ids[i % node_ids.len()]);
+                    i += 1;
+                }
+            })
+        };
+
+        let start = Instant::now();
+        let reader_handles: Vec<_> = (0..reader_threads).map(|t| {
+            let store = store.clone();
+            let node_ids = node_ids.clone();
+            thread::spawn(move || {
+                for i in 0..reads_per_thread {
+                    reader(&store, &node_ids[(i * 31 + t) % node_ids.len()]);
+                }
+            })
+        }).collect();
+        for handle in reader_handles {
+            handle.join().unwrap();
+        }
+        let elapsed = start.elapsed();
+        done.store(true, Ordering::Relaxed);
+        writer_handle.join().unwrap();
+
+        (reader_threads * reads_per_thread) as f64 / elapsed.as_secs_f64()
+    }
+
+    fn benchmark_record(node_id: Uuid, port: u16) -> NetworkDetails {
+        NetworkDetails {
+            last_seen: SystemTime::now(),
+            status_since: SystemTime::now(),
+            health_check: HealthCheck {
+                status_details: HealthStatusDetails {
+                    current_status: HealthStatus::Healthy,
+                    lives_remaining: 3,
+                },
+                configuration: HealthCheckConfiguration {
+                    health_check_port: port,
+                    tags: BTreeSet::from([String::from("benchmark")]),
+                    ..HealthCheckConfiguration::default()
+                }
+            },
+            ..dummy_record(node_id, IpAddr::V4(IP), port)
+        }
+    }
+
+    /**
+    Read throughput of the NetworkDetailsStore against a single Mutex<HashMap> (the original store design)
+    with thousands of peers, many concurrent readers and a constant writer, run with
+    cargo test --release -- --ignored --nocapture bench_
+    */
+    #[test]
+    #[ignore]
+    fn bench_store_reads_against_mutex_map() {
+        const PEERS: usize = 5000;
+        const READER_THREADS: usize = 8;
+        const READS_PER_THREAD: usize = 200_000;
+        let node_ids: Arc<Vec<Uuid>> = Arc::new((0..PEERS).map(|_| Uuid::new_v4()).collect());
+
+        let mutex_map: Arc<Mutex<HashMap<Uuid, NetworkDetails>>> = Arc::new(Mutex::new(HashMap::new()));
+        for (i, node_id) in node_ids.iter().enumerate() {
+            mutex_map.lock().unwrap().insert(*node_id, benchmark_record(*node_id, i as u16));
+        }
+        let store = Arc::new(NetworkDetailsStore::new());
+        for (i, node_id) in node_ids.iter().enumerate() {
+            store.put_network_details(&benchmark_record(*node_id, i as u16));
+        }
+
+        let mutex_get = run_store_benchmark(mutex_map.clone(), node_ids.clone(), READER_THREADS, READS_PER_THREAD,
+            |map, node_id| { let _ = map.lock().unwrap().get(node_id).cloned(); },
+            |map, node_id| { map.lock().unwrap().insert(*node_id, benchmark_record(*node_id, 0)); });
+        let store_get = run_store_benchmark(store.clone(), node_ids.clone(), READER_THREADS, READS_PER_THREAD,
+            |store, node_id| { let _ = store.get_network_details_by_node_id(node_id); },
+            |store, node_id| store.put_network_details(&benchmark_record(*node_id, 0)));
+        let store_snapshot = run_store_benchmark(store.clone(), node_ids.clone(), READER_THREADS, READS_PER_THREAD,
+            |store, _| { store.snapshot(); },
+            |store, node_id| store.put_network_details(&benchmark_record(*node_id, 0)));
+
+        println!("peers={} reader_threads={} reads_per_thread={}", PEERS, READER_THREADS, READS_PER_THREAD);
+        println!("Mutex<HashMap> get:           {:.0} reads/sec", mutex_get);
+        println!("NetworkDetailsStore get:      {:.0} reads/sec", store_get);
+        println!("NetworkDetailsStore snapshot: {:.0} reads/sec", store_snapshot);
+    }
 }
\ No newline at end of file
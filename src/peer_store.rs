@@ -0,0 +1,367 @@
+// Storage backends for peer state
+// The NetworkDetailsStore keeps its secondary indexes in memory, the records themselves live in a PeerStore
+// memory - HashMap, made durable with the snapshot and change log in persistence.rs when given a data directory
+// sled - embedded on-disk database in the data directory
+
+use std::collections::HashMap;
+use std::fmt::Debug;
+use std::io;
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex, RwLock};
+use std::sync::mpsc;
+use std::sync::mpsc::{Receiver, Sender};
+use std::time::Duration;
+use log::error;
+use uuid::Uuid;
+
+use crate::network::NetworkDetails;
+use crate::persistence::{ChangeLogEntry, NetworkDetailsPersistence};
+
+const SLED_DIR_NAME: &str = "network_details.sled";
+const SHARD_COUNT: usize = 16;
+
+/**
+How often the stack checkpoints a persistent peer store.
+ */
+pub const CHECKPOINT_INTERVAL: Duration = Duration::from_secs(60);
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum PeerStoreEvent {
+    Put(Arc<NetworkDetails>),
+    Delete(Arc<NetworkDetails>),
+}
+
+pub trait PeerStore: Send + Sync + Debug {
+    fn get(&self, node_id: &Uuid) -> io::Result<Option<Arc<NetworkDetails>>>;
+
+    /**
+    Inserts or replaces the record for its node id, returning the record it replaced.
+    */
+    fn put(&self, network_details: NetworkDetails) -> io::Result<Option<Arc<NetworkDetails>>>;
+
+    fn delete(&self, node_id: &Uuid) -> io::Result<Option<Arc<NetworkDetails>>>;
+
+    fn scan(&self) -> io::Result<Vec<Arc<NetworkDetails>>>;
+
+    /**
+    Returns a receiver for every put and delete made after the call.
+    */
+    fn watch(&self) -> Receiver<PeerStoreEvent>;
+
+    /**
+    Called periodically by the stack to compact or flush durable state.
+    */
+    fn checkpoint(&self) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn is_persistent(&self) -> bool;
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum PeerStoreBackend {
+    Memory,
+    Sled,
+}
+
+impl FromStr for PeerStoreBackend {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "memory" => Ok(PeerStoreBackend::Memory),
+            "sled" => Ok(PeerStoreBackend::Sled),
+            _ => Err(format!("Unknown peer store backend [{}], expected one of memory, sled", s))
+        }
+    }
+}
+
+/**
+Opens the configured backend with its files in `data_dir`.
+ */
+pub fn open_peer_store(backend: &PeerStoreBackend, data_dir: &Path) -> io::Result<Box<dyn PeerStore>> {
+    match backend {
+        PeerStoreBackend::Memory => Ok(Box::new(InMemoryPeerStore::open(data_dir)?)),
+        PeerStoreBackend::Sled => Ok(Box::new(SledPeerStore::open(&data_dir.join(SLED_DIR_NAME))?)),
+    }
+}
+
+#[derive(Debug, Default)]
+struct PeerStoreWatchers {
+    senders: Mutex<Vec<Sender<PeerStoreEvent>>>,
+}
+
+impl PeerStoreWatchers {
+    fn watch(&self) -> Receiver<PeerStoreEvent> {
+        let (sender, receiver) = mpsc::channel();
+        self.senders.lock().unwrap().push(sender);
+        receiver
+    }
+
+    fn notify(&self, event: PeerStoreEvent) {
+        // Watchers that hung up are dropped on the next event
+        self.senders.lock().unwrap().retain(|sender| sender.send(event.clone()).is_ok());
+    }
+}
+
+type PeerStoreShard = RwLock<HashMap<Uuid, Arc<NetworkDetails>>>;
+
+#[derive(Debug)]
+pub struct InMemoryPeerStore {
+    /**
+    Records split across shards by node id, so writers to different nodes don't contend and readers
+    only take a shared lock. Records are held behind an Arc so reads never deep clone under the lock.
+    */
+    shards: Vec<PeerStoreShard>,
+    /**
+    When set every change is written to the change log, otherwise the store only lives in memory.
+    */
+    persistence: Option<NetworkDetailsPersistence>,
+    watchers: PeerStoreWatchers,
+}
+
+impl InMemoryPeerStore {
+    pub fn new() -> InMemoryPeerStore {
+        InMemoryPeerStore {
+            shards: (0..SHARD_COUNT).map(|_| RwLock::new(HashMap::new())).collect(),
+            persistence: None,
+            watchers: PeerStoreWatchers::default(),
+        }
+    }
+
+    /**
+    Opens a store persisted in `data_dir`, recovering the records from the last run.
+    */
+    pub fn open(data_dir: &Path) -> io::Result<InMemoryPeerStore> {
+        let (persistence, records) = NetworkDetailsPersistence::open(data_dir)?;
+        let mut peer_store = InMemoryPeerStore::new();
+        for record in records {
+            peer_store.shard(&record.node_id).write().unwrap().insert(record.node_id, Arc::new(record));
+        }
+        peer_store.persistence = Some(persistence);
+        Ok(peer_store)
+    }
+
+    fn shard(&self, node_id: &Uuid) -> &PeerStoreShard {
+        // v4 uuids are random, so the low bits spread evenly across the shards
+        &self.shards[(node_id.as_u128() % SHARD_COUNT as u128) as usize]
+    }
+
+    fn append(&self, entry: ChangeLogEntry) {
+        if let Some(persistence) = &self.persistence {
+            if let Err(err) = persistence.append(&entry) {
+                error!("Failed to append {:?} to change log: {}", entry, err);
+            }
+        }
+    }
+}
+
+impl Default for InMemoryPeerStore {
+    fn default() -> Self {
+        InMemoryPeerStore::new()
+    }
+}
+
+impl PeerStore for InMemoryPeerStore {
+    fn get(&self, node_id: &Uuid) -> io::Result<Option<Arc<NetworkDetails>>> {
+        Ok(self.shard(node_id).read().unwrap().get(node_id).cloned())
+    }
+
+    fn put(&self, network_details: NetworkDetails) -> io::Result<Option<Arc<NetworkDetails>>> {
+        let network_details = Arc::new(network_details);
+        let mut shard = self.shard(&network_details.node_id).write().unwrap();
+        let previous = shard.insert(network_details.node_id, network_details.clone());
+        // Appended while holding the shard lock, so the change log order matches the map for each node
+        self.append(ChangeLogEntry::put(&network_details));
+        drop(shard);
+        self.watchers.notify(PeerStoreEvent::Put(network_details));
+        Ok(previous)
+    }
+
+    fn delete(&self, node_id: &Uuid) -> io::Result<Option<Arc<NetworkDetails>>> {
+        let mut shard = self.shard(node_id).write().unwrap();
+        let previous = shard.remove(node_id);
+        if previous.is_some() {
+            self.append(ChangeLogEntry::Delete(*node_id));
+        }
+        drop(shard);
+        if let Some(previous) = &previous {
+            self.watchers.notify(PeerStoreEvent::Delete(previous.clone()));
+        }
+        Ok(previous)
+    }
+
+    fn scan(&self) -> io::Result<Vec<Arc<NetworkDetails>>> {
+        let mut records = Vec::new();
+        for shard in &self.shards {
+            records.extend(shard.read().unwrap().values().cloned());
+        }
+        Ok(records)
+    }
+
+    fn watch(&self) -> Receiver<PeerStoreEvent> {
+        self.watchers.watch()
+    }
+
+    /**
+    Compacts the change log into a new snapshot, does nothing for an in memory store.
+    */
+    fn checkpoint(&self) -> io::Result<()> {
+        let persistence = match &self.persistence {
+            Some(persistence) => persistence,
+            None => return Ok(())
+        };
+        // Every shard is read locked before the change log, the same order puts take them in,
+        // so no put can land between copying the records and truncating the log
+        let shards: Vec<_> = self.shards.iter().map(|shard| shard.read().unwrap()).collect();
+        let records: Vec<Arc<NetworkDetails>> = shards.iter()
+            .flat_map(|shard| shard.values().cloned())
+            .collect();
+        let persistence_guard = persistence.lock();
+        drop(shards);
+        persistence_guard.write_snapshot(&records.iter().map(|record| record.as_ref()).collect::<Vec<_>>())
+    }
+
+    fn is_persistent(&self) -> bool {
+        self.persistence.is_some()
+    }
+}
+
+#[derive(Debug)]
+pub struct SledPeerStore {
+    db: sled::Db,
+    watchers: PeerStoreWatchers,
+}
+
+impl SledPeerStore {
+    pub fn open(path: &Path) -> io::Result<SledPeerStore> {
+        Ok(SledPeerStore {
+            db: sled::open(path)?,
+            watchers: PeerStoreWatchers::default(),
+        })
+    }
+}
+
+fn decode(value: &[u8]) -> io::Result<Arc<NetworkDetails>> {
+    Ok(Arc::new(serde_json::from_slice(value)?))
+}
+
+impl PeerStore for SledPeerStore {
+    fn get(&self, node_id: &Uuid) -> io::Result<Option<Arc<NetworkDetails>>> {
+        self.db.get(node_id.as_bytes())?
+            .map(|value| decode(&value))
+            .transpose()
+    }
+
+    fn put(&self, network_details: NetworkDetails) -> io::Result<Option<Arc<NetworkDetails>>> {
+        let value = serde_json::to_vec(&network_details)?;
+        let previous = self.db.insert(network_details.node_id.as_bytes(), value)?
+            .map(|value| decode(&value))
+            .transpose()?;
+        self.watchers.notify(PeerStoreEvent::Put(Arc::new(network_details)));
+        Ok(previous)
+    }
+
+    fn delete(&self, node_id: &Uuid) -> io::Result<Option<Arc<NetworkDetails>>> {
+        let previous = self.db.remove(node_id.as_bytes())?
+            .map(|value| decode(&value))
+            .transpose()?;
+        if let Some(previous) = &previous {
+            self.watchers.notify(PeerStoreEvent::Delete(previous.clone()));
+        }
+        Ok(previous)
+    }
+
+    fn scan(&self) -> io::Result<Vec<Arc<NetworkDetails>>> {
+        self.db.iter()
+            .map(|entry| decode(&entry?.1))
+            .collect()
+    }
+
+    fn watch(&self) -> Receiver<PeerStoreEvent> {
+        self.watchers.watch()
+    }
+
+    fn checkpoint(&self) -> io::Result<()> {
+        self.db.flush()?;
+        Ok(())
+    }
+
+    fn is_persistent(&self) -> bool {
+        true
+    }
+}
+
+#[cfg(test)]
+mod peer_store_tests {
+    use std::fs;
+    use std::net::IpAddr;
+    use std::sync::Arc;
+    use std::time::UNIX_EPOCH;
+    use uuid::Uuid;
+    use crate::health_history::HealthHistory;
+    use crate::network::{HealthCheck, HealthCheckConfiguration, HealthStatus, HealthStatusDetails, IP, NetworkDetails};
+    use crate::peer_store::{InMemoryPeerStore, open_peer_store, PeerStore, PeerStoreBackend, PeerStoreEvent};
+
+    fn dummy_record(port: u16) -> NetworkDetails {
+        NetworkDetails {
+            node_id: Uuid::new_v4(),
+            addr: IpAddr::V4(IP),
+            last_seen: UNIX_EPOCH,
+            status_since: UNIX_EPOCH,
+            stale: false,
+            health_history: HealthHistory::default(),
+            health_check: HealthCheck {
+                status_details: HealthStatusDetails {
+                    current_status: HealthStatus::Healthy,
+                    lives_remaining: 3,
+                },
+                configuration: HealthCheckConfiguration {
+                    health_check_port: port,
+                    ..HealthCheckConfiguration::default()
+                }
+            }
+        }
+    }
+
+    fn exercise_peer_store(peer_store: &dyn PeerStore) {
+        let watcher = peer_store.watch();
+        let mut record = dummy_record(3450);
+
+        assert_eq!(None, peer_store.put(record.clone()).unwrap());
+        record.health_check.status_details.lives_remaining = 1;
+        assert_eq!(3, peer_store.put(record.clone()).unwrap().unwrap().health_check.status_details.lives_remaining);
+        let record = Arc::new(record);
+        assert_eq!(Some(record.clone()), peer_store.get(&record.node_id).unwrap());
+        assert_eq!(vec![record.clone()], peer_store.scan().unwrap());
+        assert_eq!(Some(record.clone()), peer_store.delete(&record.node_id).unwrap());
+        assert_eq!(None, peer_store.get(&record.node_id).unwrap());
+
+        assert!(matches!(watcher.recv().unwrap(), PeerStoreEvent::Put(_)));
+        assert_eq!(PeerStoreEvent::Put(record.clone()), watcher.recv().unwrap());
+        assert_eq!(PeerStoreEvent::Delete(record), watcher.recv().unwrap());
+    }
+
+    #[test]
+    fn in_memory_peer_store_gets_puts_deletes_and_watches() {
+        exercise_peer_store(&InMemoryPeerStore::new());
+    }
+
+    #[test]
+    fn sled_peer_store_gets_puts_deletes_and_watches() {
+        let data_dir = std::env::temp_dir().join(format!("swizzy_decent_sled_{}", Uuid::new_v4()));
+        {
+            let peer_store = open_peer_store(&PeerStoreBackend::Sled, &data_dir).unwrap();
+            exercise_peer_store(peer_store.as_ref());
+        }
+        fs::remove_dir_all(data_dir).unwrap();
+    }
+
+    #[test]
+    fn peer_store_backend_parses_from_configuration() {
+        assert_eq!(PeerStoreBackend::Memory, "memory".parse().unwrap());
+        assert_eq!(PeerStoreBackend::Sled, "sled".parse().unwrap());
+        assert!("sqlite".parse::<PeerStoreBackend>().is_err());
+    }
+}
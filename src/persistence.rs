@@ -0,0 +1,335 @@
+// Persistence for the NetworkDetailsStore
+// Snapshot of every record, plus an append only change log of puts and deletes since the last snapshot
+// On startup the snapshot is loaded and the change log replayed on top of it
+// Health history is only written with the snapshot, change log puts leave it out to keep them small
+
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, MutexGuard};
+use std::{fs, io};
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::health_history::HealthHistory;
+use crate::network::NetworkDetails;
+
+const SNAPSHOT_FILE_NAME: &str = "network_details.snapshot.json";
+const SNAPSHOT_TEMP_FILE_NAME: &str = "network_details.snapshot.json.tmp";
+const CHANGE_LOG_FILE_NAME: &str = "network_details.changes.log";
+
+#[derive(Debug, Serialize, Deserialize)]
+pub enum ChangeLogEntry {
+    Put(NetworkDetails),
+    Delete(Uuid),
+}
+
+impl ChangeLogEntry {
+    /**
+    Put entry for `network_details` without its health history, replay keeps the history already recovered
+    for the node.
+     */
+    pub fn put(network_details: &NetworkDetails) -> ChangeLogEntry {
+        ChangeLogEntry::Put(NetworkDetails {
+            node_id: network_details.node_id,
+            addr: network_details.addr,
+            health_check: network_details.health_check.clone(),
+            last_seen: network_details.last_seen,
+            status_since: network_details.status_since,
+            stale: network_details.stale,
+            health_history: HealthHistory::default(),
+        })
+    }
+}
+
+#[derive(Debug)]
+pub struct NetworkDetailsPersistence {
+    data_dir: PathBuf,
+    /**
+    Open handle to the change log, also held while a snapshot is written so no entry lands between
+    the snapshot and the log truncation.
+     */
+    change_log: Mutex<File>,
+}
+
+impl NetworkDetailsPersistence {
+
+    /**
+    Opens the persistence files in `data_dir`, creating the directory if needed.
+    Returns the persistence handle along with the records recovered from the snapshot and change log.
+     */
+    pub fn open(data_dir: &Path) -> io::Result<(NetworkDetailsPersistence, Vec<NetworkDetails>)> {
+        fs::create_dir_all(data_dir)?;
+        let mut records = read_snapshot(&data_dir.join(SNAPSHOT_FILE_NAME))?;
+        let replayed = replay_change_log(&data_dir.join(CHANGE_LOG_FILE_NAME), &mut records)?;
+        info!("Recovered {} network details records from {:?}, replayed {} change log entries", records.len(), data_dir, replayed);
+
+        let change_log = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(data_dir.join(CHANGE_LOG_FILE_NAME))?;
+
+        let persistence = NetworkDetailsPersistence {
+            data_dir: data_dir.to_path_buf(),
+            change_log: Mutex::new(change_log),
+        };
+        // Compact straight away, so new entries are never appended after a torn one
+        persistence.write_snapshot(&records.iter().collect::<Vec<_>>())?;
+        Ok((persistence, records))
+    }
+
+    /**
+    Appends an entry to the change log. The entry is handed to the OS before returning but only synced
+    to disk with the next snapshot, so a crashed process loses nothing and a crashed machine loses at most
+    one checkpoint interval.
+     */
+    pub fn append(&self, entry: &ChangeLogEntry) -> io::Result<()> {
+        let mut line = serde_json::to_vec(entry)?;
+        line.push(b'\n');
+        self.change_log.lock().unwrap().write_all(&line)
+    }
+
+    /**
+    Writes `records` as the new snapshot and truncates the change log.
+
+    The snapshot is written to a temporary file and renamed over the old one, so a crash at any point
+    leaves either the old or the new snapshot in place. Replaying a change log over a newer snapshot is
+    harmless since every entry holds the full record apart from the history, which replay leaves alone.
+     */
+    pub fn write_snapshot(&self, records: &[&NetworkDetails]) -> io::Result<()> {
+        self.lock().write_snapshot(records)
+    }
+
+    /**
+    Locks the change log, so the store can take its copy of the records and write them as a snapshot
+    without a concurrent put slipping in between.
+     */
+    pub fn lock(&self) -> NetworkDetailsPersistenceGuard<'_> {
+        NetworkDetailsPersistenceGuard {
+            persistence: self,
+            change_log: self.change_log.lock().unwrap(),
+        }
+    }
+
+    fn write_snapshot_file(&self, records: &[&NetworkDetails]) -> io::Result<()> {
+        let temp_path = self.data_dir.join(SNAPSHOT_TEMP_FILE_NAME);
+        let temp_file = File::create(&temp_path)?;
+        let mut writer = BufWriter::new(temp_file);
+        serde_json::to_writer(&mut writer, records)?;
+        writer.flush()?;
+        writer.get_ref().sync_all()?;
+
+        fs::rename(&temp_path, self.data_dir.join(SNAPSHOT_FILE_NAME))?;
+        // Sync the directory so the rename itself is durable
+        File::open(&self.data_dir)?.sync_all()
+    }
+}
+
+pub struct NetworkDetailsPersistenceGuard<'a> {
+    persistence: &'a NetworkDetailsPersistence,
+    change_log: MutexGuard<'a, File>,
+}
+
+impl NetworkDetailsPersistenceGuard<'_> {
+    pub fn write_snapshot(self, records: &[&NetworkDetails]) -> io::Result<()> {
+        self.persistence.write_snapshot_file(records)?;
+        self.change_log.set_len(0)?;
+        self.change_log.sync_all()
+    }
+}
+
+fn read_snapshot(path: &Path) -> io::Result<Vec<NetworkDetails>> {
+    match File::open(path) {
+        Ok(file) => Ok(serde_json::from_reader(BufReader::new(file))?),
+        Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(Vec::new()),
+        Err(err) => Err(err)
+    }
+}
+
+fn replay_change_log(path: &Path, records: &mut Vec<NetworkDetails>) -> io::Result<usize> {
+    let file = match File::open(path) {
+        Ok(file) => file,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(0),
+        Err(err) => return Err(err)
+    };
+
+    let mut positions: HashMap<Uuid, usize> = records.iter()
+        .enumerate()
+        .map(|(position, record)| (record.node_id, position))
+        .collect();
+    let mut replayed = 0;
+    for line in BufReader::new(file).lines() {
+        let line = line?;
+        if line.is_empty() {
+            continue;
+        }
+        // A torn write from a crash can only be the last line, nothing after it was acknowledged
+        let entry: ChangeLogEntry = match serde_json::from_str(&line) {
+            Ok(entry) => entry,
+            Err(err) => {
+                warn!("Stopping change log replay at unreadable entry: {}", err);
+                break;
+            }
+        };
+        match entry {
+            ChangeLogEntry::Put(network_details) => {
+                match positions.get(&network_details.node_id) {
+                    Some(position) => {
+                        let mut network_details = network_details;
+                        network_details.health_history = std::mem::take(&mut records[*position].health_history);
+                        records[*position] = network_details;
+                    }
+                    None => {
+                        positions.insert(network_details.node_id, records.len());
+                        records.push(network_details);
+                    }
+                }
+            }
+            ChangeLogEntry::Delete(node_id) => {
+                if let Some(position) = positions.remove(&node_id) {
+                    records.swap_remove(position);
+                    if position < records.len() {
+                        positions.insert(records[position].node_id, position);
+                    }
+                }
+            }
+        }
+        replayed += 1;
+    }
+    Ok(replayed)
+}
+
+#[cfg(test)]
+mod persistence_tests {
+    use std::fs;
+    use std::fs::OpenOptions;
+    use std::io::Write;
+    use std::net::IpAddr;
+    use std::path::PathBuf;
+    use std::time::{Duration, UNIX_EPOCH};
+    use uuid::Uuid;
+    use crate::health_history::{HealthHistory, ProbeOutcome, ProbeResult};
+    use crate::network::{HealthCheck, HealthCheckConfiguration, HealthStatus, HealthStatusDetails, IP, NetworkDetails};
+    use crate::persistence::{CHANGE_LOG_FILE_NAME, ChangeLogEntry, NetworkDetailsPersistence};
+
+    fn temp_data_dir() -> PathBuf {
+        std::env::temp_dir().join(format!("swizzy_decent_persistence_{}", Uuid::new_v4()))
+    }
+
+    fn dummy_record(port: u16) -> NetworkDetails {
+        NetworkDetails {
+            node_id: Uuid::new_v4(),
+            addr: IpAddr::V4(IP),
+            last_seen: UNIX_EPOCH,
+            status_since: UNIX_EPOCH,
+            stale: false,
+            health_history: HealthHistory::default(),
+            health_check: HealthCheck {
+                status_details: HealthStatusDetails {
+                    current_status: HealthStatus::Healthy,
+                    lives_remaining: 3,
+                },
+                configuration: HealthCheckConfiguration {
+                    health_check_port: port,
+                    ..HealthCheckConfiguration::default()
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn change_log_is_replayed_on_open() {
+        let data_dir = temp_data_dir();
+        let mut record = dummy_record(3450);
+        {
+            let (persistence, records) = NetworkDetailsPersistence::open(&data_dir).unwrap();
+            assert!(records.is_empty());
+            persistence.append(&ChangeLogEntry::Put(record.clone())).unwrap();
+            record.health_check.status_details.lives_remaining = 1;
+            persistence.append(&ChangeLogEntry::Put(record.clone())).unwrap();
+        }
+
+        let (_persistence, records) = NetworkDetailsPersistence::open(&data_dir).unwrap();
+        assert_eq!(vec![record], records);
+        fs::remove_dir_all(data_dir).unwrap();
+    }
+
+    #[test]
+    fn snapshot_replaces_change_log() {
+        let data_dir = temp_data_dir();
+        let first = dummy_record(3450);
+        let second = dummy_record(3451);
+        {
+            let (persistence, _) = NetworkDetailsPersistence::open(&data_dir).unwrap();
+            persistence.append(&ChangeLogEntry::Put(first.clone())).unwrap();
+            persistence.write_snapshot(&[&first]).unwrap();
+            persistence.append(&ChangeLogEntry::Put(second.clone())).unwrap();
+        }
+
+        let (_persistence, records) = NetworkDetailsPersistence::open(&data_dir).unwrap();
+        assert_eq!(vec![first, second], records);
+        fs::remove_dir_all(data_dir).unwrap();
+    }
+
+    #[test]
+    fn deleted_records_are_not_recovered() {
+        let data_dir = temp_data_dir();
+        let first = dummy_record(3450);
+        let second = dummy_record(3451);
+        {
+            let (persistence, _) = NetworkDetailsPersistence::open(&data_dir).unwrap();
+            persistence.append(&ChangeLogEntry::Put(first.clone())).unwrap();
+            persistence.append(&ChangeLogEntry::Put(second.clone())).unwrap();
+            persistence.append(&ChangeLogEntry::Delete(first.node_id)).unwrap();
+        }
+
+        let (_persistence, records) = NetworkDetailsPersistence::open(&data_dir).unwrap();
+        assert_eq!(vec![second], records);
+        fs::remove_dir_all(data_dir).unwrap();
+    }
+
+    #[test]
+    fn health_history_is_only_written_with_the_snapshot() {
+        let data_dir = temp_data_dir();
+        let mut record = dummy_record(3450);
+        record.health_history.record_probe(ProbeOutcome {
+            at: UNIX_EPOCH + Duration::from_secs(1),
+            result: ProbeResult::Timeout
+        });
+        {
+            let (persistence, _) = NetworkDetailsPersistence::open(&data_dir).unwrap();
+            persistence.write_snapshot(&[&record]).unwrap();
+            record.health_check.status_details.lives_remaining = 1;
+            record.health_history.record_probe(ProbeOutcome {
+                at: UNIX_EPOCH + Duration::from_secs(2),
+                result: ProbeResult::Timeout
+            });
+            persistence.append(&ChangeLogEntry::put(&record)).unwrap();
+        }
+        let change_log = fs::read_to_string(data_dir.join(CHANGE_LOG_FILE_NAME)).unwrap();
+        assert!(change_log.contains("\"probes\":[]"), "We expect change log puts to leave the history out");
+
+        let (_persistence, records) = NetworkDetailsPersistence::open(&data_dir).unwrap();
+        assert_eq!(1, records[0].health_check.status_details.lives_remaining);
+        assert_eq!(1, records[0].health_history.probes.len(), "We expect the history from the snapshot to be kept");
+        fs::remove_dir_all(data_dir).unwrap();
+    }
+
+    #[test]
+    fn torn_change_log_entry_is_ignored() {
+        let data_dir = temp_data_dir();
+        let record = dummy_record(3450);
+        {
+            let (persistence, _) = NetworkDetailsPersistence::open(&data_dir).unwrap();
+            persistence.append(&ChangeLogEntry::Put(record.clone())).unwrap();
+        }
+        let mut change_log = OpenOptions::new().append(true).open(data_dir.join(CHANGE_LOG_FILE_NAME)).unwrap();
+        change_log.write_all(b"{\"Put\":{\"node_id\":").unwrap();
+
+        let (_persistence, records) = NetworkDetailsPersistence::open(&data_dir).unwrap();
+        assert_eq!(vec![record], records);
+        fs::remove_dir_all(data_dir).unwrap();
+    }
+}
@@ -0,0 +1,384 @@
+// One shot probe
+// Sends SYNs from an ephemeral socket and waits for the matching ACKs, without starting a node
+// Nothing is stored, the probed node sees a peer with a random node id that never acks its own probes
+// The result is graded against loss and latency thresholds into a Nagios plugin status, so it can run from cron or monitoring
+
+use std::fmt::{Display, Formatter};
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr, UdpSocket};
+use std::time::{Duration, Instant};
+use std::{fmt, io, thread};
+use uuid::Uuid;
+
+use crate::health_check::{DeserializePacket, HEALTH_CHECK_ACK_OPCODE, HEALTH_CHECK_PACKET_SIZE, HEALTH_CHECK_SYN_OPCODE, HealthCheckPacket, SerializePacket};
+use crate::utils::generate_nonce;
+
+pub const DEFAULT_PROBE_COUNT: u32 = 1;
+pub const DEFAULT_PROBE_INTERVAL: Duration = Duration::from_millis(1000);
+pub const DEFAULT_PROBE_TIMEOUT: Duration = Duration::from_millis(2000);
+pub const DEFAULT_WARNING_LOSS_PERCENT: f64 = 20.0;
+pub const DEFAULT_CRITICAL_LOSS_PERCENT: f64 = 60.0;
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct ProbeReply {
+    pub addr: SocketAddr,
+    /**
+    Node id the probed node stamped on its ack.
+    */
+    pub node_id: Uuid,
+    pub rtt: Duration,
+}
+
+/**
+Loss is compared in percent, latency against the average rtt. Reaching a threshold counts as crossing it.
+ */
+#[derive(Clone, Debug, PartialEq)]
+pub struct ProbeThresholds {
+    pub warning_loss_percent: f64,
+    pub critical_loss_percent: f64,
+    pub warning_rtt: Option<Duration>,
+    pub critical_rtt: Option<Duration>,
+}
+
+impl Default for ProbeThresholds {
+    fn default() -> Self {
+        ProbeThresholds {
+            warning_loss_percent: DEFAULT_WARNING_LOSS_PERCENT,
+            critical_loss_percent: DEFAULT_CRITICAL_LOSS_PERCENT,
+            warning_rtt: None,
+            critical_rtt: None,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct ProbeOptions {
+    pub addr: SocketAddr,
+    pub count: u32,
+    /**
+    Time between sending one probe and the next, the next probe goes out early if the ack came back sooner.
+    */
+    pub interval: Duration,
+    pub timeout: Duration,
+    pub thresholds: ProbeThresholds,
+}
+
+impl ProbeOptions {
+    pub fn new(addr: SocketAddr) -> ProbeOptions {
+        ProbeOptions {
+            addr,
+            count: DEFAULT_PROBE_COUNT,
+            interval: DEFAULT_PROBE_INTERVAL,
+            timeout: DEFAULT_PROBE_TIMEOUT,
+            thresholds: ProbeThresholds::default(),
+        }
+    }
+}
+
+/**
+Nagios plugin statuses, the discriminant is the exit code.
+ */
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Ord, PartialOrd)]
+pub enum ProbeStatus {
+    Ok = 0,
+    Warning = 1,
+    Critical = 2,
+    Unknown = 3,
+}
+
+impl ProbeStatus {
+    pub fn exit_code(self) -> i32 {
+        self as i32
+    }
+}
+
+impl Display for ProbeStatus {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            ProbeStatus::Ok => write!(f, "OK"),
+            ProbeStatus::Warning => write!(f, "WARNING"),
+            ProbeStatus::Critical => write!(f, "CRITICAL"),
+            ProbeStatus::Unknown => write!(f, "UNKNOWN"),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct ProbeStatistics {
+    pub addr: SocketAddr,
+    /**
+    From the last ack, None when nothing acked.
+    */
+    pub node_id: Option<Uuid>,
+    pub sent: u32,
+    /**
+    Round trip time of every acked probe, in the order they were sent.
+    */
+    pub rtts: Vec<Duration>,
+}
+
+impl ProbeStatistics {
+    pub fn received(&self) -> u32 {
+        self.rtts.len() as u32
+    }
+
+    pub fn loss_percent(&self) -> f64 {
+        if self.sent == 0 {
+            return 0.0;
+        }
+        (self.sent - self.received()) as f64 * 100.0 / self.sent as f64
+    }
+
+    pub fn min_rtt(&self) -> Option<Duration> {
+        self.rtts.iter().min().copied()
+    }
+
+    pub fn max_rtt(&self) -> Option<Duration> {
+        self.rtts.iter().max().copied()
+    }
+
+    pub fn avg_rtt(&self) -> Option<Duration> {
+        if self.rtts.is_empty() {
+            return None;
+        }
+        Some(self.rtts.iter().sum::<Duration>() / self.received())
+    }
+
+    /**
+    The worst status any threshold gives, a target that never acked is always critical.
+    */
+    pub fn status(&self, thresholds: &ProbeThresholds) -> ProbeStatus {
+        let Some(avg_rtt) = self.avg_rtt() else {
+            return ProbeStatus::Critical;
+        };
+        let loss_percent = self.loss_percent();
+        let reaches = |threshold: Option<Duration>| threshold.is_some_and(|threshold| avg_rtt >= threshold);
+        if loss_percent >= thresholds.critical_loss_percent || reaches(thresholds.critical_rtt) {
+            ProbeStatus::Critical
+        } else if loss_percent >= thresholds.warning_loss_percent || reaches(thresholds.warning_rtt) {
+            ProbeStatus::Warning
+        } else {
+            ProbeStatus::Ok
+        }
+    }
+}
+
+/**
+The status, one line summary and performance data in the Nagios plugin output format, e.g.
+`OK - 127.0.0.1:3451 3/3 acked, 0% loss, rtt min/avg/max 0.41/0.60/1.10 ms | loss=0%;20;60;0;100 rtt=0.600ms;;;0`
+ */
+pub fn nagios_output(statistics: &ProbeStatistics, thresholds: &ProbeThresholds) -> String {
+    let millis = |rtt: Duration| rtt.as_secs_f64() * 1000.0;
+    let threshold = |rtt: Option<Duration>| rtt.map(|rtt| format!("{:.3}", millis(rtt))).unwrap_or_default();
+    let rtt_summary = match (statistics.min_rtt(), statistics.avg_rtt(), statistics.max_rtt()) {
+        (Some(min), Some(avg), Some(max)) => format!("rtt min/avg/max {:.2}/{:.2}/{:.2} ms", millis(min), millis(avg), millis(max)),
+        _ => String::from("no acks")
+    };
+    // An empty value isn't valid performance data, so rtt is left out when nothing acked
+    let rtt_performance = statistics.avg_rtt()
+        .map(|avg| format!(" rtt={:.3}ms;{};{};0", millis(avg), threshold(thresholds.warning_rtt), threshold(thresholds.critical_rtt)))
+        .unwrap_or_default();
+    format!("{} - {} {}/{} acked, {}% loss, {} | loss={}%;{};{};0;100{}",
+            statistics.status(thresholds),
+            statistics.addr,
+            statistics.received(),
+            statistics.sent,
+            statistics.loss_percent(),
+            rtt_summary,
+            statistics.loss_percent(),
+            thresholds.warning_loss_percent,
+            thresholds.critical_loss_percent,
+            rtt_performance)
+}
+
+/**
+Sends `options.count` probes one after another from the same socket. Only socket errors fail, lost probes are counted.
+ */
+pub fn probe_many(options: &ProbeOptions) -> io::Result<ProbeStatistics> {
+    let socket = bind_ephemeral(options.addr)?;
+    let mut statistics = ProbeStatistics {
+        addr: options.addr,
+        node_id: None,
+        sent: 0,
+        rtts: Vec::new(),
+    };
+    for sequence in 0..options.count {
+        let started_at = Instant::now();
+        statistics.sent += 1;
+        match probe_from(&socket, options.addr, options.timeout) {
+            Ok(reply) => {
+                statistics.node_id = Some(reply.node_id);
+                statistics.rtts.push(reply.rtt);
+            }
+            Err(err) if err.kind() == io::ErrorKind::TimedOut => {}
+            // The target's port being closed shows up as a refused connection on some platforms
+            Err(err) if err.kind() == io::ErrorKind::ConnectionRefused => {}
+            Err(err) => return Err(err)
+        }
+        if sequence + 1 < options.count {
+            thread::sleep(options.interval.saturating_sub(started_at.elapsed()));
+        }
+    }
+    Ok(statistics)
+}
+
+fn bind_ephemeral(addr: SocketAddr) -> io::Result<UdpSocket> {
+    let bind_addr = match addr {
+        SocketAddr::V4(_) => SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0)),
+        SocketAddr::V6(_) => SocketAddr::from((Ipv6Addr::UNSPECIFIED, 0)),
+    };
+    UdpSocket::bind(bind_addr)
+}
+
+/**
+Probes `addr` once, failing with `TimedOut` when no ack carrying our nonce arrives within `timeout`.
+ */
+fn probe_from(socket: &UdpSocket, addr: SocketAddr, timeout: Duration) -> io::Result<ProbeReply> {
+    let nonce = generate_nonce();
+    let syn = HealthCheckPacket {
+        header: HEALTH_CHECK_SYN_OPCODE,
+        nonce,
+        node_id: Uuid::new_v4().into_bytes()
+    };
+
+    let sent_at = Instant::now();
+    let deadline = sent_at + timeout;
+    socket.send_to(&syn.serialize(), addr)?;
+    let mut buf = [0; HEALTH_CHECK_PACKET_SIZE + 1];
+    loop {
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        if remaining.is_zero() {
+            return Err(io::Error::new(io::ErrorKind::TimedOut, format!("No ack from {} within {:?}", addr, timeout)));
+        }
+        socket.set_read_timeout(Some(remaining))?;
+        let (len, remote_addr) = match socket.recv_from(&mut buf) {
+            Ok(received) => received,
+            Err(err) if matches!(err.kind(), io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut) => continue,
+            Err(err) => return Err(err)
+        };
+        // Stray packets, e.g. a late ack to an earlier probe, are skipped rather than failing the probe
+        let packet = HealthCheckPacket::deserialize(buf[..len].to_vec());
+        if remote_addr == addr && packet.header == HEALTH_CHECK_ACK_OPCODE && packet.nonce == nonce {
+            return Ok(ProbeReply {
+                addr,
+                node_id: Uuid::from_bytes(packet.node_id),
+                rtt: sent_at.elapsed()
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod probe_tests {
+    use std::io;
+    use std::net::UdpSocket;
+    use std::thread;
+    use std::time::Duration;
+    use uuid::Uuid;
+    use crate::health_check::{DeserializePacket, HEALTH_CHECK_ACK_OPCODE, HEALTH_CHECK_PACKET_SIZE, HealthCheckPacket, SerializePacket};
+    use crate::probe::{bind_ephemeral, nagios_output, probe_from, probe_many, ProbeOptions, ProbeStatistics, ProbeStatus, ProbeThresholds};
+
+    /**
+    Acks the first `acks` SYNs it receives and drops the rest.
+    */
+    fn responder(node_id: Uuid, syns: usize, acks: usize) -> (UdpSocket, thread::JoinHandle<()>) {
+        let responder = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let socket = responder.try_clone().unwrap();
+        let handle = thread::spawn(move || {
+            for i in 0..syns {
+                let mut buf = [0; HEALTH_CHECK_PACKET_SIZE];
+                let (len, remote_addr) = responder.recv_from(&mut buf).unwrap();
+                let syn = HealthCheckPacket::deserialize(buf[..len].to_vec());
+                if i >= acks {
+                    continue;
+                }
+                // An ack for someone else's probe first, which must be skipped
+                let stray = HealthCheckPacket { header: HEALTH_CHECK_ACK_OPCODE, nonce: [0; 16], node_id: node_id.into_bytes() };
+                responder.send_to(&stray.serialize(), remote_addr).unwrap();
+                let ack = HealthCheckPacket { header: HEALTH_CHECK_ACK_OPCODE, nonce: syn.nonce, node_id: node_id.into_bytes() };
+                responder.send_to(&ack.serialize(), remote_addr).unwrap();
+            }
+        });
+        (socket, handle)
+    }
+
+    fn statistics(sent: u32, rtts_ms: &[u64]) -> ProbeStatistics {
+        ProbeStatistics {
+            addr: "127.0.0.1:3451".parse().unwrap(),
+            node_id: None,
+            sent,
+            rtts: rtts_ms.iter().map(|rtt| Duration::from_millis(*rtt)).collect(),
+        }
+    }
+
+    #[test]
+    fn probe_returns_the_acking_node() {
+        let node_id = Uuid::new_v4();
+        let (socket, responder_handle) = responder(node_id, 1, 1);
+
+        let addr = socket.local_addr().unwrap();
+        let reply = probe_from(&bind_ephemeral(addr).unwrap(), addr, Duration::from_secs(2)).unwrap();
+        responder_handle.join().unwrap();
+        assert_eq!(node_id, reply.node_id, "We expect the node id from the matching ack");
+        assert_eq!(socket.local_addr().unwrap(), reply.addr);
+    }
+
+    #[test]
+    fn probe_times_out_without_an_ack() {
+        let silent = UdpSocket::bind("127.0.0.1:0").unwrap();
+
+        let addr = silent.local_addr().unwrap();
+        let result = probe_from(&bind_ephemeral(addr).unwrap(), addr, Duration::from_millis(100));
+        assert_eq!(io::ErrorKind::TimedOut, result.unwrap_err().kind());
+    }
+
+    #[test]
+    fn probe_many_counts_lost_probes() {
+        let node_id = Uuid::new_v4();
+        let (socket, responder_handle) = responder(node_id, 4, 3);
+        let options = ProbeOptions {
+            count: 4,
+            interval: Duration::from_millis(10),
+            timeout: Duration::from_millis(200),
+            ..ProbeOptions::new(socket.local_addr().unwrap())
+        };
+
+        let statistics = probe_many(&options).unwrap();
+        responder_handle.join().unwrap();
+        assert_eq!(4, statistics.sent);
+        assert_eq!(3, statistics.received());
+        assert_eq!(25.0, statistics.loss_percent());
+        assert_eq!(Some(node_id), statistics.node_id);
+        assert_eq!(ProbeStatus::Warning, statistics.status(&options.thresholds), "We expect 25% loss to cross the default 20% warning threshold");
+    }
+
+    #[test]
+    fn status_is_the_worst_threshold_crossed() {
+        let thresholds = ProbeThresholds {
+            warning_rtt: Some(Duration::from_millis(100)),
+            critical_rtt: Some(Duration::from_millis(500)),
+            ..ProbeThresholds::default()
+        };
+
+        assert_eq!(ProbeStatus::Ok, statistics(5, &[10, 20, 30, 40, 50]).status(&thresholds));
+        assert_eq!(Some(Duration::from_millis(30)), statistics(5, &[10, 20, 30, 40, 50]).avg_rtt());
+        assert_eq!(ProbeStatus::Warning, statistics(5, &[10, 20, 30, 40]).status(&thresholds), "20% loss reaches the warning threshold");
+        assert_eq!(ProbeStatus::Warning, statistics(2, &[100, 120]).status(&thresholds));
+        assert_eq!(ProbeStatus::Critical, statistics(5, &[10, 20]).status(&thresholds), "60% loss reaches the critical threshold");
+        assert_eq!(ProbeStatus::Critical, statistics(2, &[10, 990]).status(&thresholds));
+        assert_eq!(ProbeStatus::Critical, statistics(3, &[]).status(&thresholds), "We expect a target that never acks to be critical");
+        assert_eq!(3, ProbeStatus::Unknown.exit_code());
+    }
+
+    #[test]
+    fn nagios_output_has_the_status_summary_and_performance_data() {
+        let thresholds = ProbeThresholds {
+            critical_rtt: Some(Duration::from_millis(500)),
+            ..ProbeThresholds::default()
+        };
+
+        assert_eq!("WARNING - 127.0.0.1:3451 3/4 acked, 25% loss, rtt min/avg/max 10.00/20.00/30.00 ms | loss=25%;20;60;0;100 rtt=20.000ms;;500.000;0",
+                   nagios_output(&statistics(4, &[10, 20, 30]), &thresholds));
+        assert_eq!("CRITICAL - 127.0.0.1:3451 0/2 acked, 100% loss, no acks | loss=100%;20;60;0;100",
+                   nagios_output(&statistics(2, &[]), &thresholds));
+    }
+}
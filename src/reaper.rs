@@ -0,0 +1,233 @@
+// Reaper
+// Peers that stay Unhealthy past a TTL are tombstoned, tombstones are purged after a second TTL
+// The tombstone is kept around for a while instead of deleting straight away, so it still goes out to
+// anyone watching the store (gossip) and other nodes converge on the peer being gone
+
+use std::sync::{Arc, RwLock};
+use std::thread;
+use std::time::{Duration, SystemTime};
+use log::info;
+
+use crate::network::{HealthStatus, NetworkDetailsStore};
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct ReaperConfiguration {
+    /**
+    How long a peer stays Unhealthy before it is tombstoned.
+    */
+    pub unhealthy_ttl: Duration,
+    /**
+    How long a tombstone is kept before it is purged from the store.
+    */
+    pub tombstone_ttl: Duration,
+    /**
+    How often the reaper runs.
+    */
+    pub interval: Duration,
+}
+
+impl Default for ReaperConfiguration {
+    fn default() -> Self {
+        ReaperConfiguration {
+            unhealthy_ttl: Duration::from_secs(5 * 60),
+            tombstone_ttl: Duration::from_secs(30 * 60),
+            interval: Duration::from_secs(10),
+        }
+    }
+}
+
+#[derive(Debug, Default, Eq, PartialEq)]
+pub struct ReapResult {
+    pub tombstoned: usize,
+    pub purged: usize,
+}
+
+pub struct NetworkDetailsReaper {
+    /**
+    Shared with the config reloader, read at the start of every run.
+    */
+    configuration: Arc<RwLock<ReaperConfiguration>>,
+    network_details_store: Arc<NetworkDetailsStore>,
+}
+
+impl NetworkDetailsReaper {
+    pub fn new(configuration: ReaperConfiguration, network_details_store: Arc<NetworkDetailsStore>) -> NetworkDetailsReaper {
+        NetworkDetailsReaper {
+            configuration: Arc::new(RwLock::new(configuration)),
+            network_details_store,
+        }
+    }
+
+    pub fn configuration(&self) -> Arc<RwLock<ReaperConfiguration>> {
+        self.configuration.clone()
+    }
+
+    pub fn run(self) {
+        loop {
+            thread::sleep(self.interval());
+            self.reap_now();
+        }
+    }
+
+    #[cfg(feature = "tokio")]
+    pub async fn run_async(self) {
+        loop {
+            tokio::time::sleep(self.interval()).await;
+            self.reap_now();
+        }
+    }
+
+    /**
+    Time between two runs, read from the live configuration.
+    */
+    pub fn interval(&self) -> Duration {
+        self.configuration.read().unwrap().interval
+    }
+
+    /**
+    Reaps as of now and logs what was reaped, if anything.
+    */
+    pub fn reap_now(&self) {
+        let result = self.reap(SystemTime::now());
+        if result != ReapResult::default() {
+            info!("Reaper tombstoned {} and purged {} peers", result.tombstoned, result.purged);
+        }
+    }
+
+    /**
+    Tombstones expired Unhealthy peers and purges expired tombstones, as of `now`.
+    */
+    pub fn reap(&self, now: SystemTime) -> ReapResult {
+        let mut result = ReapResult::default();
+        let configuration = self.configuration.read().unwrap().clone();
+
+        let tombstone_ttl = configuration.tombstone_ttl;
+        for tombstone in self.network_details_store.get_network_details_by_status(&HealthStatus::Tombstone) {
+            if !expired(tombstone.status_since, tombstone_ttl, now) {
+                continue;
+            }
+            let purged = self.network_details_store.delete_network_details_if(&tombstone.node_id, |record| {
+                // Checked again under the store lock, the node may have acked since the query
+                record.health_check.status_details.current_status == HealthStatus::Tombstone
+                    && expired(record.status_since, tombstone_ttl, now)
+            });
+            if purged.is_ok() {
+                result.purged += 1;
+            }
+        }
+
+        let unhealthy_ttl = configuration.unhealthy_ttl;
+        for unhealthy in self.network_details_store.get_network_details_by_status(&HealthStatus::Unhealthy) {
+            if !expired(unhealthy.status_since, unhealthy_ttl, now) {
+                continue;
+            }
+            let updated = self.network_details_store.update_network_details(&unhealthy.node_id, |record| {
+                // Checked again under the store lock, the node may have acked since the query
+                if record.health_check.status_details.current_status == HealthStatus::Unhealthy
+                    && expired(record.status_since, unhealthy_ttl, now) {
+                    record.set_status(HealthStatus::Tombstone, now);
+                }
+            });
+            if let Ok(record) = updated {
+                if record.health_check.status_details.current_status == HealthStatus::Tombstone {
+                    result.tombstoned += 1;
+                }
+            }
+        }
+
+        result
+    }
+}
+
+fn expired(since: SystemTime, ttl: Duration, now: SystemTime) -> bool {
+    match now.duration_since(since) {
+        Ok(elapsed) => elapsed >= ttl,
+        Err(_) => false
+    }
+}
+
+#[cfg(test)]
+mod reaper_tests {
+    use std::net::IpAddr;
+    use std::sync::Arc;
+    use std::time::{Duration, UNIX_EPOCH};
+    use uuid::Uuid;
+    use crate::health_history::HealthHistory;
+    use crate::network::{HealthCheck, HealthCheckConfiguration, HealthStatus, HealthStatusDetails, IP, NetworkDetails, NetworkDetailsStore};
+    use crate::peer_store::PeerStoreEvent;
+    use crate::reaper::{NetworkDetailsReaper, ReaperConfiguration, ReapResult};
+
+    fn dummy_record(status: HealthStatus) -> NetworkDetails {
+        NetworkDetails {
+            node_id: Uuid::new_v4(),
+            addr: IpAddr::V4(IP),
+            last_seen: UNIX_EPOCH,
+            status_since: UNIX_EPOCH,
+            stale: false,
+            health_history: HealthHistory::default(),
+            health_check: HealthCheck {
+                status_details: HealthStatusDetails {
+                    current_status: status,
+                    lives_remaining: 0,
+                },
+                configuration: HealthCheckConfiguration {
+                    health_check_port: 3450,
+                    ..HealthCheckConfiguration::default()
+                }
+            }
+        }
+    }
+
+    fn reaper(store: Arc<NetworkDetailsStore>) -> NetworkDetailsReaper {
+        NetworkDetailsReaper::new(ReaperConfiguration {
+            unhealthy_ttl: Duration::from_secs(60),
+            tombstone_ttl: Duration::from_secs(120),
+            interval: Duration::from_secs(1),
+        }, store)
+    }
+
+    #[test]
+    fn reaper_tombstones_then_purges_unhealthy_peers() {
+        let store = Arc::new(NetworkDetailsStore::new());
+        let unhealthy = dummy_record(HealthStatus::Unhealthy);
+        let healthy = dummy_record(HealthStatus::Healthy);
+        store.put_network_details(&unhealthy);
+        store.put_network_details(&healthy);
+        let watcher = store.watch();
+        let reaper = reaper(store.clone());
+
+        assert_eq!(ReapResult::default(), reaper.reap(UNIX_EPOCH + Duration::from_secs(59)));
+
+        let tombstoned_at = UNIX_EPOCH + Duration::from_secs(60);
+        assert_eq!(ReapResult { tombstoned: 1, purged: 0 }, reaper.reap(tombstoned_at));
+        let tombstone = store.get_network_details_by_node_id(&unhealthy.node_id).unwrap();
+        assert_eq!(HealthStatus::Tombstone, tombstone.health_check.status_details.current_status);
+        assert_eq!(tombstoned_at, tombstone.status_since);
+        // The tombstone goes out to watchers, so gossip can spread it
+        assert_eq!(PeerStoreEvent::Put(Arc::new(tombstone)), watcher.try_recv().unwrap());
+
+        assert_eq!(ReapResult::default(), reaper.reap(tombstoned_at + Duration::from_secs(119)));
+        assert_eq!(ReapResult { tombstoned: 0, purged: 1 }, reaper.reap(tombstoned_at + Duration::from_secs(120)));
+        assert!(store.get_network_details_by_node_id(&unhealthy.node_id).is_err());
+        assert!(matches!(watcher.try_recv().unwrap(), PeerStoreEvent::Delete(_)));
+        assert!(store.get_network_details_by_node_id(&healthy.node_id).is_ok());
+    }
+
+    #[test]
+    fn reaper_keeps_tombstones_that_acked_since_the_query() {
+        let store = Arc::new(NetworkDetailsStore::new());
+        let tombstone = dummy_record(HealthStatus::Tombstone);
+        store.put_network_details(&tombstone);
+        let now = UNIX_EPOCH + Duration::from_secs(120);
+
+        // The node acks between the reaper's query and its delete
+        store.update_network_details(&tombstone.node_id, |record| record.set_status(HealthStatus::Healthy, now)).unwrap();
+        let purged = store.delete_network_details_if(&tombstone.node_id, |record| {
+            record.health_check.status_details.current_status == HealthStatus::Tombstone
+        });
+
+        assert!(purged.is_err());
+        assert_eq!(ReapResult::default(), reaper(store.clone()).reap(now));
+        assert!(store.get_network_details_by_node_id(&tombstone.node_id).is_ok());
+    }
+}
@@ -0,0 +1,303 @@
+// REPL
+// Reads commands for the running node from stdin, one per line, and prints what they return
+// Pings go out through the network broker like the scheduler's probes, so their acks update the store as usual
+
+use std::fmt::Write as _;
+use std::io::{self, BufRead, Write};
+use std::net::{IpAddr, SocketAddr, ToSocketAddrs};
+use std::str::FromStr;
+use std::sync::{Arc, RwLock};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
+use log::LevelFilter;
+
+use crate::channel::MeteredSender;
+use crate::health_check_network_broker::HealthCheckNetworkBrokerMessage;
+use crate::health_check_scheduler::{InFlightProbes, probe};
+use crate::health_history::ProbeResult;
+use crate::network::{HealthCheckDefaults, HealthStatus, NetworkDetails, NetworkDetailsStore};
+
+const PROMPT: &str = "> ";
+const PING_POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+pub const HELP: &str = "Commands:
+  ping <host:port>   Probe a node and wait for its ack
+  peers              List every known peer
+  status <ip>        Show the health of the host at <ip> and each of its endpoints
+  forget <ip>        Delete every peer at <ip>, static peers are probed again on the next tick
+  stats              Count peers by status
+  loglevel [level]   Show or set the log level: off, error, warn, info, debug or trace
+  help               Show this help";
+
+pub struct Repl {
+    request_sender: MeteredSender<HealthCheckNetworkBrokerMessage>,
+    network_details_store: Arc<NetworkDetailsStore>,
+    in_flight_probes: Arc<InFlightProbes>,
+    /**
+    Pings wait for as long as the scheduler's probes.
+    */
+    health_check_defaults: Arc<RwLock<HealthCheckDefaults>>,
+}
+
+impl Repl {
+    pub fn new(request_sender: MeteredSender<HealthCheckNetworkBrokerMessage>,
+               network_details_store: Arc<NetworkDetailsStore>,
+               in_flight_probes: Arc<InFlightProbes>,
+               health_check_defaults: Arc<RwLock<HealthCheckDefaults>>) -> Repl {
+        Repl {
+            request_sender,
+            network_details_store,
+            in_flight_probes,
+            health_check_defaults,
+        }
+    }
+
+    /**
+    Executes each line of `input` until it ends.
+    */
+    pub fn run(&self, input: impl BufRead, mut output: impl Write) -> io::Result<()> {
+        write!(output, "{}", PROMPT)?;
+        output.flush()?;
+        for line in input.lines() {
+            let result = self.execute(&line?);
+            if !result.is_empty() {
+                writeln!(output, "{}", result)?;
+            }
+            write!(output, "{}", PROMPT)?;
+            output.flush()?;
+        }
+        Ok(())
+    }
+
+    /**
+    Executes one command line and returns what to print.
+    */
+    pub fn execute(&self, line: &str) -> String {
+        let words: Vec<&str> = line.split_whitespace().collect();
+        match words.as_slice() {
+            [] => String::new(),
+            ["ping", target] => self.ping(target),
+            ["peers"] => format_peers(&self.network_details_store.get_all_network_details()),
+            ["status", ip] => with_ip(ip, |ip| self.status(ip)),
+            ["forget", ip] => with_ip(ip, |ip| self.forget(ip)),
+            ["stats"] => self.stats(),
+            ["loglevel"] => format!("Log level is {}", log::max_level()),
+            ["loglevel", level] => match LevelFilter::from_str(level) {
+                Ok(level) => {
+                    log::set_max_level(level);
+                    format!("Log level set to {}", level)
+                }
+                Err(_) => format!("Unknown log level {}, expected one of off, error, warn, info, debug or trace", level)
+            },
+            ["help"] => String::from(HELP),
+            [command, ..] => format!("Unknown command or arguments for {}, type help for the list of commands", command)
+        }
+    }
+
+    fn ping(&self, target: &str) -> String {
+        let addr = match target.to_socket_addrs().map(|mut addrs| addrs.next()) {
+            Ok(Some(addr)) => addr,
+            Ok(None) | Err(_) => return format!("Could not resolve {}, expected host:port", target)
+        };
+        let node_id = self.network_details_store.get_network_details_by_socket_addr(&addr).ok().map(|record| record.node_id);
+        let timeout = self.health_check_defaults.read().unwrap().probe_timeout;
+        let sent_at = SystemTime::now();
+        let nonce = probe(&self.request_sender, &self.in_flight_probes, addr, node_id, timeout, Instant::now());
+
+        // The ack handler completes the probe and records its rtt, a probe still in flight at the deadline is
+        // left for the scheduler to time out
+        let deadline = Instant::now() + timeout;
+        while Instant::now() < deadline {
+            if !self.in_flight_probes.is_in_flight(&nonce) {
+                if let Some((record, rtt)) = self.reply(&addr, sent_at) {
+                    return format!("Reply from {} node id {} rtt {:?}", addr, record.node_id, rtt);
+                }
+            }
+            thread::sleep(PING_POLL_INTERVAL);
+        }
+        format!("No reply from {} within {:?}", addr, timeout)
+    }
+
+    fn reply(&self, addr: &SocketAddr, sent_at: SystemTime) -> Option<(NetworkDetails, Duration)> {
+        let record = self.network_details_store.get_network_details_by_socket_addr(addr).ok()?;
+        match record.health_history.probes.back() {
+            Some(outcome) if outcome.at >= sent_at => match outcome.result {
+                ProbeResult::Success { rtt } => Some((record, rtt)),
+                ProbeResult::Timeout => None
+            },
+            _ => None
+        }
+    }
+
+    fn status(&self, ip: IpAddr) -> String {
+        let Ok(host_health) = self.network_details_store.get_host_health(&ip) else {
+            return format!("No peers known at {}", ip);
+        };
+        let now = SystemTime::now();
+        let mut status = format!("{} {:?}", host_health.addr, host_health.status);
+        for endpoint in &host_health.endpoints {
+            let uptime = endpoint.health_history.uptime_statistics(now);
+            let _ = write!(status, "\n  :{} {:?} lives {} last seen {} uptime 1m {} 5m {} 1h {}",
+                           endpoint.health_check.configuration.health_check_port,
+                           endpoint.health_check.status_details.current_status,
+                           endpoint.health_check.status_details.lives_remaining,
+                           last_seen(endpoint, now),
+                           percentage(uptime.one_minute),
+                           percentage(uptime.five_minutes),
+                           percentage(uptime.one_hour));
+        }
+        status
+    }
+
+    fn forget(&self, ip: IpAddr) -> String {
+        let forgotten = self.network_details_store.get_network_details_by_ip(&ip).iter()
+            .filter(|record| self.network_details_store.delete_network_details(&record.node_id).is_ok())
+            .count();
+        if forgotten == 0 {
+            return format!("No peers known at {}", ip);
+        }
+        format!("Forgot {} peer(s) at {}", forgotten, ip)
+    }
+
+    fn stats(&self) -> String {
+        let count = |status: HealthStatus| self.network_details_store.get_network_details_by_status(&status).len();
+        format!("Peers: {} on {} hosts\nHealthy: {}\nAtRisk: {}\nUnhealthy: {}\nTombstone: {}\nProbes in flight: {}",
+                self.network_details_store.get_all_network_details().len(),
+                self.network_details_store.get_all_host_health().len(),
+                count(HealthStatus::Healthy),
+                count(HealthStatus::AtRisk),
+                count(HealthStatus::Unhealthy),
+                count(HealthStatus::Tombstone),
+                self.in_flight_probes.count())
+    }
+}
+
+fn with_ip(ip: &str, command: impl FnOnce(IpAddr) -> String) -> String {
+    match IpAddr::from_str(ip) {
+        Ok(ip) => command(ip),
+        Err(_) => format!("{} is not a valid IP address", ip)
+    }
+}
+
+fn last_seen(record: &NetworkDetails, now: SystemTime) -> String {
+    now.duration_since(record.last_seen)
+        .map(|ago| format!("{}s ago", ago.as_secs()))
+        .unwrap_or_else(|_| String::from("now"))
+}
+
+fn percentage(availability: Option<f64>) -> String {
+    availability.map(|availability| format!("{:.1}%", availability)).unwrap_or_else(|| String::from("-"))
+}
+
+/**
+One line per peer under a header, for the peers command and the REPL.
+ */
+pub fn format_peers(peers: &[NetworkDetails]) -> String {
+    let now = SystemTime::now();
+    let mut table = format!("{:<36}  {:<21}  {:<9}  {:>5}  {:>10}  TAGS", "NODE ID", "ADDRESS", "STATUS", "LIVES", "LAST SEEN");
+    for peer in peers {
+        let tags: Vec<&str> = peer.health_check.configuration.tags.iter().map(String::as_str).collect();
+        let _ = write!(table, "\n{:<36}  {:<21}  {:<9}  {:>5}  {:>10}  {}",
+                       peer.node_id,
+                       peer.socket_addr().to_string(),
+                       format!("{:?}", peer.health_check.status_details.current_status),
+                       peer.health_check.status_details.lives_remaining,
+                       last_seen(peer, now),
+                       tags.join(","));
+    }
+    table
+}
+
+#[cfg(test)]
+mod repl_tests {
+    use std::net::{IpAddr, Ipv4Addr};
+    use std::sync::{Arc, RwLock};
+    use std::thread;
+    use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+    use uuid::Uuid;
+    use crate::health_check_network_broker::HealthCheckNetworkBrokerMessage;
+    use crate::channel::{metered_channel, MeteredReceiver};
+    use crate::health_check_scheduler::InFlightProbes;
+    use crate::health_history::{HealthHistory, ProbeOutcome, ProbeResult};
+    use crate::network::{HealthCheck, HealthCheckConfiguration, HealthCheckDefaults, HealthStatus, HealthStatusDetails, IP, NetworkDetails, NetworkDetailsStore};
+    use crate::repl::Repl;
+
+    fn dummy_record(addr: IpAddr, port: u16, status: HealthStatus) -> NetworkDetails {
+        NetworkDetails {
+            node_id: Uuid::new_v4(),
+            addr,
+            last_seen: UNIX_EPOCH,
+            status_since: UNIX_EPOCH,
+            stale: false,
+            health_history: HealthHistory::default(),
+            health_check: HealthCheck {
+                status_details: HealthStatusDetails {
+                    current_status: status,
+                    lives_remaining: 3,
+                },
+                configuration: HealthCheckConfiguration {
+                    health_check_port: port,
+                    ..HealthCheckConfiguration::default()
+                }
+            }
+        }
+    }
+
+    fn repl(store: Arc<NetworkDetailsStore>, in_flight_probes: Arc<InFlightProbes>) -> (Repl, MeteredReceiver<HealthCheckNetworkBrokerMessage>) {
+        let (sender, receiver) = metered_channel();
+        let health_check_defaults = HealthCheckDefaults {
+            probe_timeout: Duration::from_millis(500),
+            ..HealthCheckDefaults::default()
+        };
+        (Repl::new(sender, store, in_flight_probes, Arc::new(RwLock::new(health_check_defaults))), receiver)
+    }
+
+    #[test]
+    fn commands_read_and_update_the_store() {
+        let store = Arc::new(NetworkDetailsStore::new());
+        let other_ip = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 2));
+        store.put_network_details(&dummy_record(IpAddr::V4(IP), 3451, HealthStatus::Healthy));
+        store.put_network_details(&dummy_record(IpAddr::V4(IP), 3452, HealthStatus::Unhealthy));
+        store.put_network_details(&dummy_record(other_ip, 3451, HealthStatus::Healthy));
+        let (repl, _receiver) = repl(store.clone(), Arc::new(InFlightProbes::new()));
+
+        assert_eq!(4, repl.execute("peers").lines().count(), "We expect a header and one line per peer");
+        let status = repl.execute("status 127.0.0.1");
+        assert!(status.starts_with("127.0.0.1 AtRisk"), "We expect the aggregate host status first, got {}", status);
+        assert!(status.contains(":3452 Unhealthy"), "We expect each endpoint, got {}", status);
+        assert!(repl.execute("stats").starts_with("Peers: 3 on 2 hosts\nHealthy: 2\nAtRisk: 0\nUnhealthy: 1"));
+
+        assert_eq!("Forgot 2 peer(s) at 127.0.0.1", repl.execute("forget 127.0.0.1"));
+        assert_eq!(1, store.get_all_network_details().len());
+        assert_eq!("No peers known at 127.0.0.1", repl.execute("status 127.0.0.1"));
+        assert_eq!("localhost is not a valid IP address", repl.execute("forget localhost"));
+        assert!(repl.execute("fly away").starts_with("Unknown command"));
+        assert!(repl.execute("loglevel loud").starts_with("Unknown log level"));
+        assert_eq!("", repl.execute("   "));
+    }
+
+    #[test]
+    fn ping_waits_for_the_ack() {
+        let store = Arc::new(NetworkDetailsStore::new());
+        let in_flight_probes = Arc::new(InFlightProbes::new());
+        let (repl, receiver) = repl(store.clone(), in_flight_probes.clone());
+        let node_id = Uuid::new_v4();
+        // Stands in for the broker and the ack handler
+        let acker = thread::spawn(move || {
+            let syn = receiver.recv().unwrap();
+            let (_, rtt) = in_flight_probes.complete(&syn.payload.nonce, Instant::now()).unwrap();
+            let mut record = dummy_record(syn.remote_addr.ip(), syn.remote_addr.port(), HealthStatus::Healthy);
+            record.node_id = node_id;
+            record.health_history.record_probe(ProbeOutcome { at: SystemTime::now(), result: ProbeResult::Success { rtt } });
+            store.put_network_details(&record);
+            receiver
+        });
+
+        let reply = repl.execute("ping 127.0.0.1:3451");
+        assert!(reply.starts_with(&format!("Reply from 127.0.0.1:3451 node id {}", node_id)), "We expect the ack to be reported, got {}", reply);
+
+        let _receiver = acker.join().unwrap();
+        assert_eq!("No reply from 127.0.0.1:3452 within 500ms", repl.execute("ping 127.0.0.1:3452"));
+        assert!(repl.execute("ping nowhere").starts_with("Could not resolve nowhere"));
+    }
+}
@@ -0,0 +1,522 @@
+// Tracing
+// Every packet gets a health_check span keyed by its nonce, with a child span per hop:
+//   inbound   receive -> response_queue -> handle -> request_queue -> send (the ack)
+//   outbound  request_queue -> send (a probe)
+// The queue spans live in the HealthCheckNetworkBrokerMessage, so they measure the time spent waiting on the channel
+// Closed spans can be logged with their timing and exported as OTLP JSON to a file or an OTLP/HTTP collector,
+// the trace id is the nonce, so a SYN and its ACK land in the same trace on both nodes
+
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::path::PathBuf;
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use log::{debug, warn};
+use serde_json::{json, Value};
+use tracing::field::{Field, Visit};
+use tracing::span::{Attributes, Id, Record};
+use tracing::{info_span, Span, Subscriber};
+use tracing_subscriber::layer::{Context, SubscriberExt};
+use tracing_subscriber::registry::LookupSpan;
+use tracing_subscriber::{Layer, Registry};
+use uuid::Uuid;
+
+const EXPORT_BATCH_SIZE: usize = 512;
+const EXPORT_INTERVAL: Duration = Duration::from_secs(1);
+const COLLECTOR_TIMEOUT: Duration = Duration::from_secs(5);
+
+/**
+Spans a message carries across the broker's channels.
+ */
+#[derive(Clone, Debug)]
+pub struct MessageTrace {
+    /**
+    Span of the whole exchange, closed once every hop is done.
+    */
+    pub exchange: Span,
+    /**
+    Span of the channel the message is waiting on, closed by whoever takes the message off it.
+    */
+    queued: Span,
+}
+
+impl Default for MessageTrace {
+    fn default() -> MessageTrace {
+        MessageTrace {
+            exchange: Span::none(),
+            queued: Span::none(),
+        }
+    }
+}
+
+impl MessageTrace {
+    /**
+    Starts the span of a packet exchange, `direction` is inbound for packets read from the socket and outbound for probes.
+    */
+    pub fn start(nonce: &str, peer: SocketAddr, opcode: &str, direction: &'static str) -> MessageTrace {
+        MessageTrace {
+            exchange: info_span!("health_check", nonce, peer = %peer, opcode, direction),
+            queued: Span::none(),
+        }
+    }
+
+    /**
+    The same exchange, now waiting on the broker's request channel.
+    */
+    pub fn request_queued(&self) -> MessageTrace {
+        MessageTrace {
+            exchange: self.exchange.clone(),
+            queued: info_span!(parent: &self.exchange, "request_queue"),
+        }
+    }
+
+    /**
+    The same exchange, now waiting on the broker's response channel.
+    */
+    pub fn response_queued(&self) -> MessageTrace {
+        MessageTrace {
+            exchange: self.exchange.clone(),
+            queued: info_span!(parent: &self.exchange, "response_queue"),
+        }
+    }
+
+    pub fn dequeued(&mut self) {
+        self.queued = Span::none();
+    }
+}
+
+/**
+Installs the span subscriber, once per process. Nothing is installed when spans are neither logged nor exported,
+so the spans of the stack cost next to nothing.
+ */
+pub fn init(log_spans: bool, destination: Option<ExportDestination>, node_id: Uuid) {
+    if !log_spans && destination.is_none() {
+        return;
+    }
+    let exporter = destination.map(|destination| {
+        let (exporter, sender) = OtlpExporter::new(destination, node_id);
+        thread::spawn(move || {
+            exporter.run();
+        });
+        sender
+    });
+    tracing::subscriber::set_global_default(Registry::default().with(SpanLayer::new(log_spans, exporter)))
+        .expect("Span subscriber installed once");
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum AttributeValue {
+    String(String),
+    Int(i64),
+    Double(f64),
+    Bool(bool),
+}
+
+/**
+A closed span, as logged and exported.
+ */
+#[derive(Clone, Debug, PartialEq)]
+pub struct SpanRecord {
+    /**
+    32 hex characters, the nonce of the exchange when it has one.
+    */
+    pub trace_id: String,
+    pub span_id: String,
+    pub parent_span_id: Option<String>,
+    pub name: &'static str,
+    pub start: SystemTime,
+    pub end: SystemTime,
+    /**
+    Time spent inside the span, queue spans are never entered so theirs is zero.
+    */
+    pub busy: Duration,
+    pub attributes: Vec<(String, AttributeValue)>,
+}
+
+struct SpanTiming {
+    span_id: String,
+    /**
+    Only set on root spans, children take the one of their root.
+    */
+    trace_id: Option<String>,
+    start: SystemTime,
+    busy: Duration,
+    entered_at: Option<Instant>,
+    attributes: Vec<(String, AttributeValue)>,
+}
+
+impl Visit for SpanTiming {
+    fn record_f64(&mut self, field: &Field, value: f64) {
+        self.record(field, AttributeValue::Double(value));
+    }
+
+    fn record_i64(&mut self, field: &Field, value: i64) {
+        self.record(field, AttributeValue::Int(value));
+    }
+
+    fn record_u64(&mut self, field: &Field, value: u64) {
+        self.record(field, AttributeValue::Int(value as i64));
+    }
+
+    fn record_bool(&mut self, field: &Field, value: bool) {
+        self.record(field, AttributeValue::Bool(value));
+    }
+
+    fn record_str(&mut self, field: &Field, value: &str) {
+        self.record(field, AttributeValue::String(value.to_string()));
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn std::fmt::Debug) {
+        self.record(field, AttributeValue::String(format!("{:?}", value)));
+    }
+}
+
+impl SpanTiming {
+    fn record(&mut self, field: &Field, value: AttributeValue) {
+        if let (Some(trace_id), "nonce", AttributeValue::String(nonce)) = (&mut self.trace_id, field.name(), &value) {
+            if nonce.len() == 32 {
+                *trace_id = nonce.clone();
+            }
+        }
+        self.attributes.retain(|(name, _)| name != field.name());
+        self.attributes.push((field.name().to_string(), value));
+    }
+}
+
+fn random_hex(bytes: usize) -> String {
+    Uuid::new_v4().as_bytes()[..bytes].iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+/**
+Times every span, logs closed spans at debug when `log_spans` is set and hands them to the OtlpExporter when there is one.
+ */
+pub struct SpanLayer {
+    log_spans: bool,
+    exporter: Option<Sender<SpanRecord>>,
+}
+
+impl SpanLayer {
+    pub fn new(log_spans: bool, exporter: Option<Sender<SpanRecord>>) -> SpanLayer {
+        SpanLayer {
+            log_spans,
+            exporter,
+        }
+    }
+}
+
+impl<S: Subscriber + for<'a> LookupSpan<'a>> Layer<S> for SpanLayer {
+    fn on_new_span(&self, attrs: &Attributes<'_>, id: &Id, ctx: Context<'_, S>) {
+        let Some(span) = ctx.span(id) else { return };
+        let mut timing = SpanTiming {
+            span_id: random_hex(8),
+            trace_id: span.parent().is_none().then(|| random_hex(16)),
+            start: SystemTime::now(),
+            busy: Duration::ZERO,
+            entered_at: None,
+            attributes: Vec::new(),
+        };
+        attrs.record(&mut timing);
+        span.extensions_mut().insert(timing);
+    }
+
+    fn on_record(&self, id: &Id, values: &Record<'_>, ctx: Context<'_, S>) {
+        let Some(span) = ctx.span(id) else { return };
+        let mut extensions = span.extensions_mut();
+        if let Some(timing) = extensions.get_mut::<SpanTiming>() {
+            values.record(timing);
+        }
+    }
+
+    fn on_enter(&self, id: &Id, ctx: Context<'_, S>) {
+        let Some(span) = ctx.span(id) else { return };
+        let mut extensions = span.extensions_mut();
+        if let Some(timing) = extensions.get_mut::<SpanTiming>() {
+            timing.entered_at = Some(Instant::now());
+        }
+    }
+
+    fn on_exit(&self, id: &Id, ctx: Context<'_, S>) {
+        let Some(span) = ctx.span(id) else { return };
+        let mut extensions = span.extensions_mut();
+        if let Some(timing) = extensions.get_mut::<SpanTiming>() {
+            if let Some(entered_at) = timing.entered_at.take() {
+                timing.busy += entered_at.elapsed();
+            }
+        }
+    }
+
+    fn on_close(&self, id: Id, ctx: Context<'_, S>) {
+        let Some(span) = ctx.span(&id) else { return };
+        let trace_id = span.scope().from_root().next()
+            .and_then(|root| root.extensions().get::<SpanTiming>().and_then(|timing| timing.trace_id.clone()))
+            .unwrap_or_else(|| random_hex(16));
+        let parent_span_id = span.parent()
+            .and_then(|parent| parent.extensions().get::<SpanTiming>().map(|timing| timing.span_id.clone()));
+        let Some(timing) = span.extensions_mut().remove::<SpanTiming>() else { return };
+        let record = SpanRecord {
+            trace_id,
+            span_id: timing.span_id,
+            parent_span_id,
+            name: span.name(),
+            start: timing.start,
+            end: SystemTime::now(),
+            busy: timing.busy,
+            attributes: timing.attributes,
+        };
+        if self.log_spans {
+            let duration = record.end.duration_since(record.start).unwrap_or_default();
+            debug!(span = record.name, trace_id:% = record.trace_id, duration_us = duration.as_micros() as u64, busy_us = record.busy.as_micros() as u64;
+                "Span {} closed after {}us", record.name, duration.as_micros());
+        }
+        if let Some(exporter) = &self.exporter {
+            // The exporter only stops once every sender is gone, a failed send just loses the span
+            let _ = exporter.send(record);
+        }
+    }
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum ExportDestination {
+    /**
+    Appends one OTLP JSON export request per line.
+    */
+    File(PathBuf),
+    /**
+    POSTs OTLP JSON export requests to /v1/traces of a collector.
+    */
+    Collector(SocketAddr),
+}
+
+/**
+Batches closed spans and writes them to their destination, runs until every SpanLayer sending to it is dropped.
+ */
+pub struct OtlpExporter {
+    destination: ExportDestination,
+    node_id: Uuid,
+    receiver: Receiver<SpanRecord>,
+}
+
+impl OtlpExporter {
+    pub fn new(destination: ExportDestination, node_id: Uuid) -> (OtlpExporter, Sender<SpanRecord>) {
+        let (sender, receiver) = mpsc::channel();
+        (OtlpExporter { destination, node_id, receiver }, sender)
+    }
+
+    pub fn run(self) {
+        let mut file = None;
+        while let Ok(first) = self.receiver.recv() {
+            let mut batch = vec![first];
+            let deadline = Instant::now() + EXPORT_INTERVAL;
+            while batch.len() < EXPORT_BATCH_SIZE {
+                match self.receiver.recv_timeout(deadline.saturating_duration_since(Instant::now())) {
+                    Ok(record) => batch.push(record),
+                    Err(RecvTimeoutError::Timeout) | Err(RecvTimeoutError::Disconnected) => break
+                }
+            }
+            if let Err(err) = self.export(&batch, &mut file) {
+                warn!("Failed to export {} spans to {:?}: {}", batch.len(), self.destination, err);
+            }
+        }
+    }
+
+    fn export(&self, batch: &[SpanRecord], file: &mut Option<File>) -> io::Result<()> {
+        let body = serde_json::to_vec(&export_request(self.node_id, batch))?;
+        match &self.destination {
+            ExportDestination::File(path) => {
+                if file.is_none() {
+                    *file = Some(OpenOptions::new().create(true).append(true).open(path)?);
+                }
+                let file = file.as_mut().expect("Export file opened");
+                file.write_all(&body)?;
+                file.write_all(b"\n")?;
+                file.flush()
+            }
+            ExportDestination::Collector(addr) => post_to_collector(*addr, &body)
+        }
+    }
+}
+
+fn post_to_collector(addr: SocketAddr, body: &[u8]) -> io::Result<()> {
+    let mut stream = TcpStream::connect_timeout(&addr, COLLECTOR_TIMEOUT)?;
+    stream.set_read_timeout(Some(COLLECTOR_TIMEOUT))?;
+    stream.set_write_timeout(Some(COLLECTOR_TIMEOUT))?;
+    write!(stream, "POST /v1/traces HTTP/1.1\r\nHost: {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n", addr, body.len())?;
+    stream.write_all(body)?;
+    stream.flush()?;
+    let mut status_line = String::new();
+    BufReader::new(stream).read_line(&mut status_line)?;
+    match status_line.split_whitespace().nth(1) {
+        Some(status) if status.starts_with('2') => Ok(()),
+        _ => Err(io::Error::other(format!("Collector answered {}", status_line.trim())))
+    }
+}
+
+/**
+An OTLP ExportTraceServiceRequest in its JSON encoding.
+ */
+pub fn export_request(node_id: Uuid, batch: &[SpanRecord]) -> Value {
+    let spans: Vec<Value> = batch.iter().map(|record| {
+        let mut span = json!({
+            "traceId": record.trace_id,
+            "spanId": record.span_id,
+            "name": record.name,
+            "kind": 1,
+            "startTimeUnixNano": unix_nanos(record.start).to_string(),
+            "endTimeUnixNano": unix_nanos(record.end).to_string(),
+            "attributes": record.attributes.iter().map(|(key, value)| attribute(key, value)).collect::<Vec<Value>>(),
+        });
+        if let Some(parent_span_id) = &record.parent_span_id {
+            span["parentSpanId"] = json!(parent_span_id);
+        }
+        span
+    }).collect();
+    json!({
+        "resourceSpans": [{
+            "resource": {
+                "attributes": [
+                    attribute("service.name", &AttributeValue::String(env!("CARGO_PKG_NAME").to_string())),
+                    attribute("service.instance.id", &AttributeValue::String(node_id.to_string())),
+                ]
+            },
+            "scopeSpans": [{
+                "scope": { "name": env!("CARGO_PKG_NAME") },
+                "spans": spans
+            }]
+        }]
+    })
+}
+
+fn attribute(key: &str, value: &AttributeValue) -> Value {
+    let value = match value {
+        AttributeValue::String(value) => json!({ "stringValue": value }),
+        // 64 bit integers are strings in OTLP JSON
+        AttributeValue::Int(value) => json!({ "intValue": value.to_string() }),
+        AttributeValue::Double(value) => json!({ "doubleValue": value }),
+        AttributeValue::Bool(value) => json!({ "boolValue": value }),
+    };
+    json!({ "key": key, "value": value })
+}
+
+fn unix_nanos(time: SystemTime) -> u128 {
+    time.duration_since(UNIX_EPOCH).unwrap_or_default().as_nanos()
+}
+
+#[cfg(test)]
+mod trace_tests {
+    use std::io::{BufRead, BufReader, Read, Write};
+    use std::net::{IpAddr, SocketAddr, TcpListener};
+    use std::sync::mpsc;
+    use std::thread;
+    use serde_json::Value;
+    use tracing::info_span;
+    use tracing_subscriber::layer::SubscriberExt;
+    use tracing_subscriber::Registry;
+    use uuid::Uuid;
+    use crate::network::IP;
+    use crate::trace::{ExportDestination, MessageTrace, OtlpExporter, SpanLayer, SpanRecord};
+
+    const NONCE: &str = "000102030405060708090a0b0c0d0e0f";
+
+    /**
+    Runs an inbound exchange the way the broker and listener do, returns the closed spans in the order they closed.
+    */
+    fn inbound_exchange() -> Vec<SpanRecord> {
+        let (sender, receiver) = mpsc::channel();
+        let subscriber = Registry::default().with(SpanLayer::new(false, Some(sender)));
+        tracing::subscriber::with_default(subscriber, || {
+            let peer = SocketAddr::new(IpAddr::V4(IP), 3451);
+            let inbound = MessageTrace::start(NONCE, peer, "syn", "inbound");
+            let mut queued = {
+                let _receive = info_span!(parent: &inbound.exchange, "receive").entered();
+                inbound.response_queued()
+            };
+            drop(inbound);
+            queued.dequeued();
+            let mut reply = {
+                let _handle = info_span!(parent: &queued.exchange, "handle").entered();
+                queued.request_queued()
+            };
+            drop(queued);
+            reply.dequeued();
+            let _send = info_span!(parent: &reply.exchange, "send").entered();
+        });
+        receiver.try_iter().collect()
+    }
+
+    #[test]
+    fn every_hop_is_a_child_of_the_exchange_keyed_by_nonce() {
+        let records = inbound_exchange();
+        let names: Vec<&str> = records.iter().map(|record| record.name).collect();
+        assert_eq!(vec!["receive", "response_queue", "handle", "request_queue", "send", "health_check"], names);
+        let exchange = records.last().unwrap();
+        assert_eq!(None, exchange.parent_span_id);
+        for record in &records {
+            assert_eq!(NONCE, record.trace_id, "We expect the nonce to be the trace id of {}", record.name);
+            if record.name != "health_check" {
+                assert_eq!(Some(&exchange.span_id), record.parent_span_id.as_ref(), "We expect {} to be a hop of the exchange", record.name);
+            }
+            assert!(record.start <= record.end);
+        }
+    }
+
+    #[test]
+    fn exporter_writes_otlp_json_to_a_file_and_a_collector() {
+        let records = inbound_exchange();
+        let node_id = Uuid::new_v4();
+
+        let path = std::env::temp_dir().join(format!("swizzy_decent_spans_{}.jsonl", Uuid::new_v4()));
+        let (exporter, sender) = OtlpExporter::new(ExportDestination::File(path.clone()), node_id);
+        for record in records.iter().cloned() {
+            sender.send(record).unwrap();
+        }
+        drop(sender);
+        exporter.run();
+        let contents = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(path).unwrap();
+        assert_eq!(1, contents.lines().count(), "We expect one export request per batch");
+        let request: Value = serde_json::from_str(&contents).unwrap();
+        let resource = &request["resourceSpans"][0];
+        assert_eq!(node_id.to_string(), resource["resource"]["attributes"][1]["value"]["stringValue"]);
+        let spans = resource["scopeSpans"][0]["spans"].as_array().unwrap();
+        assert_eq!(records.len(), spans.len());
+        let exchange = spans.iter().find(|span| span["name"] == "health_check").unwrap();
+        assert_eq!(NONCE, exchange["traceId"]);
+        assert!(exchange["attributes"].as_array().unwrap().iter().any(|attribute| attribute["key"] == "peer" && attribute["value"]["stringValue"] == "127.0.0.1:3451"));
+
+        let collector = TcpListener::bind(SocketAddr::new(IpAddr::V4(IP), 0)).unwrap();
+        let collector_addr = collector.local_addr().unwrap();
+        let collector_handle = thread::spawn(move || {
+            let (stream, _) = collector.accept().unwrap();
+            let mut writer = stream.try_clone().unwrap();
+            let mut reader = BufReader::new(stream);
+            let mut request_line = String::new();
+            reader.read_line(&mut request_line).unwrap();
+            let mut content_length = 0;
+            loop {
+                let mut header = String::new();
+                reader.read_line(&mut header).unwrap();
+                if header.trim().is_empty() {
+                    break;
+                }
+                if let Some(value) = header.to_ascii_lowercase().strip_prefix("content-length:") {
+                    content_length = value.trim().parse().unwrap();
+                }
+            }
+            let mut body = vec![0; content_length];
+            reader.read_exact(&mut body).unwrap();
+            writer.write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n").unwrap();
+            (request_line, serde_json::from_slice::<Value>(&body).unwrap())
+        });
+        let (exporter, sender) = OtlpExporter::new(ExportDestination::Collector(collector_addr), node_id);
+        for record in records.iter().cloned() {
+            sender.send(record).unwrap();
+        }
+        drop(sender);
+        exporter.run();
+        let (request_line, request) = collector_handle.join().unwrap();
+        assert_eq!("POST /v1/traces HTTP/1.1", request_line.trim());
+        assert_eq!(records.len(), request["resourceSpans"][0]["scopeSpans"][0]["spans"].as_array().unwrap().len());
+    }
+}
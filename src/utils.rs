@@ -1,5 +1,50 @@
+use std::{fs, io};
+use std::path::Path;
+use std::str::FromStr;
 use uuid::Uuid;
 
 pub fn generate_nonce() -> [u8;16] {
     return Uuid::new_v4().into_bytes()
-}
\ No newline at end of file
+}
+
+/**
+Loads the node id persisted at `path`, generating and persisting a new v4 uuid if the file does not exist yet.
+
+The node id is what identifies this node to its peers, so it must survive restarts and IP address changes.
+ */
+pub fn load_or_generate_node_id(path: &Path) -> io::Result<Uuid> {
+    match fs::read_to_string(path) {
+        Ok(contents) => {
+            return Uuid::from_str(contents.trim())
+                .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err));
+        }
+        Err(err) if err.kind() == io::ErrorKind::NotFound => {}
+        Err(err) => return Err(err)
+    }
+
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    let node_id = Uuid::new_v4();
+    fs::write(path, node_id.to_string())?;
+    Ok(node_id)
+}
+
+#[cfg(test)]
+mod utils_tests {
+    use std::fs;
+    use uuid::Uuid;
+    use crate::utils::load_or_generate_node_id;
+
+    #[test]
+    fn node_id_is_generated_once_and_reloaded() {
+        let dir = std::env::temp_dir().join(format!("swizzy_decent_node_id_{}", Uuid::new_v4()));
+        let path = dir.join("node_id");
+
+        let generated = load_or_generate_node_id(&path).unwrap();
+        let reloaded = load_or_generate_node_id(&path).unwrap();
+        assert_eq!(generated, reloaded, "We expect the persisted node id to be returned on the second load");
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+}